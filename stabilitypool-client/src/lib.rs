@@ -1,6 +1,10 @@
+use anyhow::Context as _;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{KeyPair, Secp256k1};
+use bitcoin::XOnlyPublicKey;
 use fedimint_client::module::gen::ClientModuleGen;
 use fedimint_client::module::ClientModule;
-use fedimint_client::sm::{DynState, OperationId, State, StateTransition};
+use fedimint_client::sm::{Context, DynState, OperationId, State, StateTransition};
 use fedimint_core::core::{IntoDynInstance, ModuleInstanceId};
 use fedimint_core::db::Database;
 use fedimint_core::encoding::{Decodable, Encodable};
@@ -8,7 +12,11 @@ use fedimint_core::module::ExtendsCommonModuleGen;
 use fedimint_core::{apply, async_trait_maybe_send};
 use stabilitypool::common::PoolModuleTypes;
 use stabilitypool::config::PoolConfigClient;
-use stabilitypool::PoolCommonGen;
+use stabilitypool::{
+    AccountBalance, Action, ActionProposed, EpochId, EpochOutcome, LockedBalance, OracleClient,
+    PoolCommonGen, ProviderBid, SeekerAction, SignedAction,
+};
+use time::OffsetDateTime;
 
 #[derive(Debug, Clone)]
 pub struct PoolClientGen;
@@ -22,25 +30,387 @@ impl ClientModuleGen for PoolClientGen {
     type Module = PoolClientModule;
     type Config = PoolConfigClient;
 
-    async fn init(&self, _cfg: Self::Config, _db: Database) -> anyhow::Result<Self::Module> {
-        Ok(PoolClientModule {})
+    async fn init(&self, cfg: Self::Config, _db: Database) -> anyhow::Result<Self::Module> {
+        Ok(PoolClientModule::new(cfg))
     }
 }
 
 #[derive(Debug)]
-pub struct PoolClientModule {}
+pub struct PoolClientModule {
+    cfg: PoolConfigClient,
+    /// Client of `cfg.oracle`, built once since HTTP oracles set up a new
+    /// connection pool every time
+    oracle: Box<dyn OracleClient>,
+}
+
+impl PoolClientModule {
+    pub fn new(cfg: PoolConfigClient) -> Self {
+        let oracle = cfg.oracle.oracle_client(&cfg.quote_currency);
+        Self { cfg, oracle }
+    }
+
+    pub fn config(&self) -> &PoolConfigClient {
+        &self.cfg
+    }
+
+    /// Client of the oracle configured for the federation.
+    pub fn oracle(&self) -> &dyn OracleClient {
+        self.oracle.as_ref()
+    }
+
+    /// Fetch the current price (cents/BTC) from the oracle configured for the
+    /// federation, so it can be compared to what the federation settles at.
+    pub async fn oracle_price(&self) -> anyhow::Result<u64> {
+        self.oracle
+            .price_now()
+            .await
+            .context("failed to fetch price from oracle")
+    }
+
+    /// Check the price `outcome` settled at against what the federation's
+    /// oracle reports for `price_time`, the time peers read the price for the
+    /// epoch: `start_epoch_at + epoch_id * epoch_length` of `/epoch_params`.
+    /// Outcomes settled by operators through a forced settlement are checked
+    /// too, but are not expected to agree.
+    pub async fn verify_epoch_price(
+        &self,
+        outcome: &EpochOutcome,
+        price_time: OffsetDateTime,
+        tolerance_ppm: u64,
+    ) -> anyhow::Result<EpochPriceCheck> {
+        let settled_price = outcome.settled_price.context("epoch has not settled yet")?;
+        let oracle_price = self
+            .oracle
+            .price_at_time(price_time)
+            .await
+            .context("failed to fetch price from oracle")?;
+        Ok(check_settled_price(
+            settled_price,
+            oracle_price,
+            tolerance_ppm,
+        ))
+    }
+}
+
+/// Relative difference (ppm) up to which a settled price is taken to agree
+/// with the oracle. Peers settle at an aggregate of their own oracle readings,
+/// so it is not expected to match a single reading exactly.
+pub const DEFAULT_PRICE_TOLERANCE_PPM: u64 = 5_000;
+
+/// How the price an epoch settled at compares to the oracle's.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EpochPriceCheck {
+    pub settled_price: u64,
+    pub oracle_price: u64,
+    /// Difference of the prices relative to the oracle price (ppm)
+    pub deviation_ppm: u64,
+    /// Whether the deviation is within the tolerance
+    pub agrees: bool,
+}
+
+/// Compare `settled_price` to `oracle_price`, both in cents/BTC, which agree
+/// if they differ by at most `tolerance_ppm` of the oracle price.
+pub fn check_settled_price(
+    settled_price: u64,
+    oracle_price: u64,
+    tolerance_ppm: u64,
+) -> EpochPriceCheck {
+    let deviation_ppm = if oracle_price == 0 {
+        if settled_price == 0 {
+            0
+        } else {
+            u64::MAX
+        }
+    } else {
+        let deviation = settled_price.abs_diff(oracle_price) as u128 * 1_000_000;
+        u64::try_from(deviation / oracle_price as u128).unwrap_or(u64::MAX)
+    };
+    EpochPriceCheck {
+        settled_price,
+        oracle_price,
+        deviation_ppm,
+        agrees: deviation_ppm <= tolerance_ppm,
+    }
+}
+
+/// Why a request to the pool's API failed, telling a record the federation
+/// does not have apart from the federation not answering.
+#[derive(Debug)]
+pub enum PoolClientError {
+    /// The federation could not be reached or its answer could not be read
+    Transport(anyhow::Error),
+    /// The federation has no such record, e.g. no action is staged for the
+    /// account
+    NotFound(String),
+    /// The action was signed for an epoch that no longer accepts actions, it
+    /// can be re-signed for the next one
+    EpochClosed(String),
+    /// The action was signed for an epoch after the next one
+    EpochTooFarAhead(String),
+    /// The current epoch is about to end and no longer accepts actions, they
+    /// can be signed for the epoch after and submitted once it has ended
+    ActionWindowClosed(String),
+    /// The federation refused the request for another reason
+    Rejected { code: i32, message: String },
+}
+
+impl PoolClientError {
+    /// Map an error response of the pool's API by its code.
+    pub fn from_api_error(code: i32, message: String) -> Self {
+        match code {
+            404 => Self::NotFound(message),
+            // the server's `api::EPOCH_CLOSED`
+            410 => Self::EpochClosed(message),
+            // the server's `api::EPOCH_TOO_FAR_AHEAD`
+            425 => Self::EpochTooFarAhead(message),
+            // the server's `api::ACTION_WINDOW_CLOSED`
+            423 => Self::ActionWindowClosed(message),
+            code => Self::Rejected { code, message },
+        }
+    }
+}
+
+impl std::fmt::Display for PoolClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Transport(e) => write!(f, "federation did not answer: {}", e),
+            Self::NotFound(message)
+            | Self::EpochClosed(message)
+            | Self::EpochTooFarAhead(message)
+            | Self::ActionWindowClosed(message) => write!(f, "{}", message),
+            Self::Rejected { code, message } => {
+                write!(f, "federation refused the request ({}): {}", code, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PoolClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Transport(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Operation id of a withdrawal of `amount` from `account` staged for
+/// `epoch_id`. It is derived from these parameters so that retrying a
+/// withdrawal, e.g. after a crash, maps to the same operation.
+pub fn withdrawal_operation_id(
+    account: XOnlyPublicKey,
+    amount: fedimint_core::Amount,
+    epoch_id: EpochId,
+) -> OperationId {
+    let mut engine = sha256::Hash::engine();
+    engine.input(b"stabilitypool-withdrawal");
+    engine.input(&account.serialize());
+    engine.input(&amount.msats.to_be_bytes());
+    engine.input(&epoch_id.0.to_be_bytes());
+    OperationId(sha256::Hash::from_engine(engine).into_inner())
+}
+
+/// How a withdrawal is carried out when it may unlock what the unlocked
+/// balance lacks from the account's seeker position (`--auto-unlock`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AutoUnlockWithdrawal {
+    /// The unlocked balance covers the withdrawal, so it can happen right away
+    Withdraw,
+    /// Stage the unlock and withdraw once the epoch it is staged for has
+    /// settled
+    UnlockFirst(SeekerAction),
+}
+
+/// Plan a withdrawal of `amount` from `balance`, unlocking the shortfall from
+/// a seeker position if the unlocked balance is not enough. Provider
+/// collateral is not unlocked, providers lower their bid instead.
+pub fn plan_auto_unlock_withdrawal(
+    balance: &AccountBalance,
+    amount: fedimint_core::Amount,
+) -> anyhow::Result<AutoUnlockWithdrawal> {
+    let shortfall = match amount.msats.checked_sub(balance.unlocked.msats) {
+        None | Some(0) => return Ok(AutoUnlockWithdrawal::Withdraw),
+        Some(shortfall) => fedimint_core::msats(shortfall),
+    };
+    match balance.locked {
+        LockedBalance::Seeker(locked) if locked >= shortfall => {
+            Ok(AutoUnlockWithdrawal::UnlockFirst(SeekerAction::Unlock {
+                amount: shortfall,
+            }))
+        }
+        LockedBalance::Provider(_) => anyhow::bail!(
+            "withdrawing {} needs {} of provider collateral, lower the bid instead",
+            amount,
+            shortfall
+        ),
+        _ => anyhow::bail!(
+            "withdrawing {} needs {} more than the account holds",
+            amount,
+            shortfall.msats - balance.locked.amount().msats
+        ),
+    }
+}
+
+/// What unlocking an account's whole position takes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnlockAll {
+    /// Nothing is locked, so there is nothing to submit
+    NothingLocked,
+    /// Unlock the whole seeker position
+    Seeker(SeekerAction),
+    /// Cancel the standing provider bid, whose collateral is released once
+    /// the epoch the cancellation is staged for settles
+    Provider(ProviderBid),
+}
+
+impl std::fmt::Display for UnlockAll {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NothingLocked => write!(f, "nothing is locked, there is nothing to unlock"),
+            Self::Seeker(_) => write!(f, "unlocking the whole seeker position"),
+            Self::Provider(_) => write!(f, "cancelling provider bid to release its collateral"),
+        }
+    }
+}
+
+/// Plan unlocking the whole locked position of `balance`, whichever side of
+/// the pool it is on.
+pub fn plan_unlock_all(balance: &AccountBalance) -> UnlockAll {
+    match balance.locked {
+        LockedBalance::Seeker(amount) if amount.msats > 0 => {
+            UnlockAll::Seeker(SeekerAction::Unlock { amount })
+        }
+        LockedBalance::Provider(amount) if amount.msats > 0 => UnlockAll::Provider(ProviderBid {
+            min_feerate: 0,
+            max_amount: fedimint_core::Amount::ZERO,
+        }),
+        _ => UnlockAll::NothingLocked,
+    }
+}
+
+/// The signed action of `account` that unlocks all of `balance` in the
+/// staging epoch `epoch_id`, or [`None`] if nothing is locked.
+pub fn unlock_all_action(
+    keypair: &KeyPair,
+    balance: &AccountBalance,
+    epoch_id: EpochId,
+    sequence: u64,
+) -> anyhow::Result<Option<ActionProposed>> {
+    let account_id = keypair.x_only_public_key().0;
+    let action = match plan_unlock_all(balance) {
+        UnlockAll::NothingLocked => return Ok(None),
+        UnlockAll::Seeker(body) => ActionProposed::Seeker(sign_action(
+            keypair,
+            Action {
+                epoch_id,
+                sequence,
+                account_id,
+                body,
+            },
+        )?),
+        UnlockAll::Provider(body) => ActionProposed::Provider(sign_action(
+            keypair,
+            Action {
+                epoch_id,
+                sequence,
+                account_id,
+                body,
+            },
+        )?),
+    };
+    Ok(Some(action))
+}
+
+/// Sign `action` with `keypair`, checking the result the way the federation
+/// will before it is submitted, so that signing mistakes are caught locally.
+pub fn sign_action<T: Encodable>(
+    keypair: &KeyPair,
+    action: Action<T>,
+) -> anyhow::Result<SignedAction<T>> {
+    let hash = sha256::Hash::hash(
+        &action
+            .consensus_encode_to_vec()
+            .context("failed to encode action")?,
+    );
+    let signature = Secp256k1::signing_only().sign_schnorr_no_aux_rand(&hash.into(), keypair);
+    let signed_action = SignedAction { action, signature };
+    verify_signed_action(&signed_action)?;
+    Ok(signed_action)
+}
+
+/// Check that `signed_action` carries a valid signature by its account, which
+/// the federation requires of every action.
+pub fn verify_signed_action<T: Encodable>(signed_action: &SignedAction<T>) -> anyhow::Result<()> {
+    signed_action.verify_signature().with_context(|| {
+        format!(
+            "signature of action {} for epoch {} does not verify against account {}",
+            signed_action.sequence, signed_action.epoch_id, signed_action.account_id
+        )
+    })
+}
+
+/// Parse a user-entered amount with a unit suffix, e.g. `1000sat` or
+/// `1000msat`. A bare number is in msats, the same as when parsing a
+/// [`fedimint_core::Amount`]. Fractions are accepted as long as they come to
+/// whole msats, so `1.5sat` is fine but `1.5msat` is rejected.
+pub fn parse_amount(amount: &str) -> anyhow::Result<fedimint_core::Amount> {
+    let amount = amount.trim();
+    let (number, decimals) = if let Some(number) = amount.strip_suffix("msat") {
+        (number, 0)
+    } else if let Some(number) = amount.strip_suffix("sat") {
+        (number, 3)
+    } else {
+        (amount, 0)
+    };
+
+    let number = number.trim();
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+    let fraction = fraction.trim_end_matches('0');
+    anyhow::ensure!(
+        !whole.is_empty()
+            && [whole, fraction]
+                .iter()
+                .all(|digits| digits.chars().all(|c| c.is_ascii_digit())),
+        "invalid amount {:?}",
+        amount
+    );
+    anyhow::ensure!(
+        fraction.len() <= decimals,
+        "amount {:?} is more precise than 1 msat",
+        amount
+    );
+
+    let unit = 10_u64.pow(decimals as u32);
+    let fraction_msats = if fraction.is_empty() {
+        0
+    } else {
+        fraction.parse::<u64>()? * 10_u64.pow((decimals - fraction.len()) as u32)
+    };
+    whole
+        .parse::<u64>()?
+        .checked_mul(unit)
+        .and_then(|msats| msats.checked_add(fraction_msats))
+        .map(fedimint_core::msats)
+        .with_context(|| format!("amount {:?} is too large", amount))
+}
 
 impl ClientModule for PoolClientModule {
     type Common = PoolModuleTypes;
-    type ModuleStateMachineContext = ();
+    type ModuleStateMachineContext = PoolClientContext;
     type GlobalStateMachineContext = ();
     type States = PoolClientStates;
 
     fn context(&self) -> Self::ModuleStateMachineContext {
-        unimplemented!()
+        PoolClientContext
     }
 }
 
+/// Context handed to the pool client's state machines.
+#[derive(Debug, Clone)]
+pub struct PoolClientContext;
+
+impl Context for PoolClientContext {}
+
 #[derive(Debug, Clone, Eq, PartialEq, Decodable, Encodable)]
 pub enum PoolClientStates {}
 
@@ -53,7 +423,7 @@ impl IntoDynInstance for PoolClientStates {
 }
 
 impl State<()> for PoolClientStates {
-    type ModuleContext = ();
+    type ModuleContext = PoolClientContext;
 
     fn transitions(
         &self,
@@ -67,3 +437,248 @@ impl State<()> for PoolClientStates {
         unimplemented!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn withdrawal_operation_id_is_deterministic() {
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        let (_, public_key) = secp.generate_keypair(&mut rand::thread_rng());
+        let account = public_key.x_only_public_key().0;
+        let amount = fedimint_core::msats(1_000);
+
+        assert_eq!(
+            withdrawal_operation_id(account, amount, EpochId(3)),
+            withdrawal_operation_id(account, amount, EpochId(3))
+        );
+        assert_ne!(
+            withdrawal_operation_id(account, amount, EpochId(3)),
+            withdrawal_operation_id(account, amount, EpochId(4))
+        );
+        assert_ne!(
+            withdrawal_operation_id(account, amount, EpochId(3)),
+            withdrawal_operation_id(account, fedimint_core::msats(1_001), EpochId(3))
+        );
+    }
+
+    #[test]
+    fn api_errors_map_to_client_errors() {
+        let map = |code| PoolClientError::from_api_error(code, "message".to_string());
+        assert!(matches!(map(404), PoolClientError::NotFound(m) if m == "message"));
+        assert!(matches!(map(410), PoolClientError::EpochClosed(_)));
+        assert!(matches!(map(425), PoolClientError::EpochTooFarAhead(_)));
+        assert!(matches!(map(423), PoolClientError::ActionWindowClosed(_)));
+        assert!(matches!(
+            map(400),
+            PoolClientError::Rejected { code: 400, .. }
+        ));
+        assert!(matches!(
+            map(500),
+            PoolClientError::Rejected { code: 500, .. }
+        ));
+
+        let transport = PoolClientError::Transport(anyhow::anyhow!("connection refused"));
+        assert!(std::error::Error::source(&transport).is_some());
+        assert!(transport.to_string().contains("connection refused"));
+    }
+
+    #[test]
+    fn amounts_parse_with_units() {
+        let parse = |amount| parse_amount(amount).map(|amount| amount.msats).ok();
+        assert_eq!(parse("1000sat"), Some(1_000_000));
+        assert_eq!(parse("1000msat"), Some(1_000));
+        // msats are the default unit
+        assert_eq!(parse("1000"), Some(1_000));
+        assert_eq!(parse("1.5sat"), Some(1_500));
+        assert_eq!(parse("2.000sat"), Some(2_000));
+
+        assert_eq!(parse("0.0001sat"), None);
+        assert_eq!(parse("1.5msat"), None);
+        assert_eq!(parse("1.5"), None);
+        assert_eq!(parse("sat"), None);
+        assert_eq!(parse("-1sat"), None);
+        assert_eq!(parse("18446744073709551615sat"), None);
+    }
+
+    #[test]
+    fn auto_unlock_withdrawals_unlock_the_shortfall() {
+        let balance = |unlocked, locked| AccountBalance {
+            unlocked: fedimint_core::msats(unlocked),
+            locked,
+            ..Default::default()
+        };
+        let seeker = balance(300, LockedBalance::Seeker(fedimint_core::msats(1_000)));
+        let plan =
+            |balance, msats| plan_auto_unlock_withdrawal(balance, fedimint_core::msats(msats));
+
+        assert_eq!(plan(&seeker, 300).unwrap(), AutoUnlockWithdrawal::Withdraw);
+        assert_eq!(
+            plan(&seeker, 800).unwrap(),
+            AutoUnlockWithdrawal::UnlockFirst(SeekerAction::Unlock {
+                amount: fedimint_core::msats(500)
+            })
+        );
+        assert_eq!(
+            plan(&seeker, 1_300).unwrap(),
+            AutoUnlockWithdrawal::UnlockFirst(SeekerAction::Unlock {
+                amount: fedimint_core::msats(1_000)
+            })
+        );
+        assert!(plan(&seeker, 1_301).is_err());
+
+        let provider = balance(300, LockedBalance::Provider(fedimint_core::msats(1_000)));
+        assert_eq!(
+            plan(&provider, 300).unwrap(),
+            AutoUnlockWithdrawal::Withdraw
+        );
+        assert!(plan(&provider, 301).is_err());
+        assert!(plan(&balance(300, LockedBalance::None), 301).is_err());
+    }
+
+    #[test]
+    fn unlock_all_unlocks_the_whole_position() {
+        let keypair = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng());
+        let balance = |locked| AccountBalance {
+            unlocked: fedimint_core::msats(300),
+            locked,
+            ..Default::default()
+        };
+        let unlock_all = |locked| unlock_all_action(&keypair, &balance(locked), EpochId(3), 2);
+
+        let seeker = LockedBalance::Seeker(fedimint_core::msats(1_000));
+        match unlock_all(seeker).unwrap() {
+            Some(ActionProposed::Seeker(signed)) => {
+                assert_eq!(
+                    signed.body,
+                    SeekerAction::Unlock {
+                        amount: fedimint_core::msats(1_000)
+                    }
+                );
+                assert_eq!(signed.epoch_id, EpochId(3));
+                assert_eq!(signed.sequence, 2);
+            }
+            other => panic!("expected a seeker unlock, got {:?}", other),
+        }
+
+        let provider = LockedBalance::Provider(fedimint_core::msats(1_000));
+        match unlock_all(provider).unwrap() {
+            Some(ActionProposed::Provider(signed)) => {
+                assert_eq!(signed.body.max_amount, fedimint_core::Amount::ZERO);
+            }
+            other => panic!("expected a cancelled bid, got {:?}", other),
+        }
+
+        assert_eq!(unlock_all(LockedBalance::None).unwrap(), None);
+        assert_eq!(
+            plan_unlock_all(&balance(LockedBalance::None)),
+            UnlockAll::NothingLocked
+        );
+        assert_eq!(
+            plan_unlock_all(&balance(LockedBalance::Seeker(fedimint_core::Amount::ZERO))),
+            UnlockAll::NothingLocked
+        );
+    }
+
+    #[test]
+    fn signatures_are_checked_before_submitting() {
+        let secp = Secp256k1::new();
+        let keypair = KeyPair::new(&secp, &mut rand::thread_rng());
+        let action = Action {
+            epoch_id: EpochId(1),
+            sequence: 1,
+            account_id: keypair.x_only_public_key().0,
+            body: stabilitypool::SeekerAction::Lock {
+                amount: fedimint_core::msats(1_000),
+            },
+        };
+        let signed_action = sign_action(&keypair, action.clone()).unwrap();
+        verify_signed_action(&signed_action).unwrap();
+
+        // a signature over other data is caught
+        let other = sha256::Hash::hash(b"not the action");
+        let corrupted = SignedAction {
+            signature: secp.sign_schnorr_no_aux_rand(&other.into(), &keypair),
+            ..signed_action
+        };
+        assert!(verify_signed_action(&corrupted).is_err());
+
+        // as is signing with a key that does not belong to the account
+        let other_keypair = KeyPair::new(&secp, &mut rand::thread_rng());
+        assert!(sign_action(&other_keypair, action).is_err());
+    }
+
+    #[test]
+    fn settled_prices_are_checked_within_tolerance() {
+        let check = check_settled_price(2_000_000, 2_000_000, DEFAULT_PRICE_TOLERANCE_PPM);
+        assert_eq!(check.deviation_ppm, 0);
+        assert!(check.agrees);
+
+        // 0.5% either way is within the default tolerance
+        assert!(check_settled_price(2_010_000, 2_000_000, DEFAULT_PRICE_TOLERANCE_PPM).agrees);
+        assert!(check_settled_price(1_990_000, 2_000_000, DEFAULT_PRICE_TOLERANCE_PPM).agrees);
+
+        let check = check_settled_price(2_100_000, 2_000_000, DEFAULT_PRICE_TOLERANCE_PPM);
+        assert_eq!(check.deviation_ppm, 50_000);
+        assert!(!check.agrees);
+        assert!(!check_settled_price(1, 0, DEFAULT_PRICE_TOLERANCE_PPM).agrees);
+    }
+
+    #[derive(Debug)]
+    struct FixedOracle(u64);
+
+    #[async_trait::async_trait]
+    impl OracleClient for FixedOracle {
+        async fn price_at_time(&self, _datetime: OffsetDateTime) -> anyhow::Result<u64> {
+            Ok(self.0)
+        }
+    }
+
+    #[tokio::test]
+    async fn epoch_prices_are_verified_against_the_oracle() {
+        let module = PoolClientModule {
+            cfg: client_config(),
+            oracle: Box::new(FixedOracle(2_000_000)),
+        };
+        let outcome = |settled_price| EpochOutcome {
+            settled_price,
+            settled_at: Some(1_700_000_000),
+            ..Default::default()
+        };
+        for (settled_price, agrees) in [(2_000_000, true), (2_500_000, false)] {
+            let check = module
+                .verify_epoch_price(
+                    &outcome(Some(settled_price)),
+                    OffsetDateTime::UNIX_EPOCH,
+                    DEFAULT_PRICE_TOLERANCE_PPM,
+                )
+                .await
+                .unwrap();
+            assert_eq!(check.agrees, agrees);
+        }
+        // an unsettled epoch has nothing to verify
+        assert!(module
+            .verify_epoch_price(
+                &outcome(None),
+                OffsetDateTime::UNIX_EPOCH,
+                DEFAULT_PRICE_TOLERANCE_PPM
+            )
+            .await
+            .is_err());
+    }
+
+    fn client_config() -> PoolConfigClient {
+        PoolConfigClient {
+            oracle: Default::default(),
+            collateral_ratio: Default::default(),
+            quote_currency: stabilitypool::config::default_quote_currency(),
+        }
+    }
+
+    #[test]
+    fn context_is_available() {
+        let module = PoolClientModule::new(client_config());
+        let _context: PoolClientContext = module.context();
+    }
+}
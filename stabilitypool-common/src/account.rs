@@ -1,6 +1,13 @@
+use fedimint_core::core::ModuleInstanceId;
+use fedimint_core::db::ModuleDatabaseTransaction;
 use fedimint_core::encoding::{Decodable, Encodable};
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 
+use crate::action::{ActionProposed, ProviderBid, SeekerAction, SignedAction};
+use crate::db;
+use crate::epoch::EpochId;
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize, Encodable, Decodable)]
 pub enum LockedBalance {
     #[serde(rename = "seeker")]
@@ -21,11 +28,16 @@ impl LockedBalance {
     }
 }
 
-/// TODO: Add `last_seq`, `last_epoch`
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Encodable, Decodable)]
 pub struct AccountBalance {
     pub unlocked: fedimint_core::Amount,
     pub locked: LockedBalance,
+    /// Sequence of the latest action the account staged, for `last_epoch`
+    #[serde(default)]
+    pub last_seq: u64,
+    /// Epoch the latest action the account staged was for
+    #[serde(default)]
+    pub last_epoch: EpochId,
 }
 
 impl Default for AccountBalance {
@@ -33,10 +45,20 @@ impl Default for AccountBalance {
         Self {
             unlocked: fedimint_core::Amount::ZERO,
             locked: LockedBalance::None,
+            last_seq: 0,
+            last_epoch: EpochId(0),
         }
     }
 }
 
+/// [`AccountBalance`] as stored up to database version 1, before it tracked
+/// the account's latest staged action. Only read to migrate.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Encodable, Decodable)]
+pub struct AccountBalanceV1 {
+    pub unlocked: fedimint_core::Amount,
+    pub locked: LockedBalance,
+}
+
 impl AccountBalance {
     /// Obtain total balance with overflow checks. Returns [`None`] on overflow.
     pub fn total_balance(&self) -> Option<fedimint_core::Amount> {
@@ -60,10 +82,43 @@ impl AccountBalance {
     }
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, Encodable, Decodable, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, Serialize, Deserialize, Encodable, Decodable, PartialEq, Eq, Hash)]
 pub struct AccountDeposit {
     pub account: bitcoin::XOnlyPublicKey,
     pub amount: fedimint_core::Amount,
+    /// Put the deposited amount to work at the next epoch, see
+    /// [`crate::action::stage_deposit_intent`].
+    #[serde(default)]
+    pub intent: Option<DepositIntent>,
+}
+
+/// Action staged together with a deposit, signed by the depositing account
+/// like any other action.
+#[derive(Clone, Debug, Serialize, Deserialize, Encodable, Decodable, PartialEq, Eq, Hash)]
+pub enum DepositIntent {
+    #[serde(rename = "seeker")]
+    Seeker(SignedAction<SeekerAction>),
+    #[serde(rename = "provider")]
+    Provider(SignedAction<ProviderBid>),
+}
+
+impl DepositIntent {
+    pub fn account_id(&self) -> bitcoin::XOnlyPublicKey {
+        ActionProposed::from(self.clone()).account_id()
+    }
+
+    pub fn verify_signature(&self) -> Result<(), secp256k1_zkp::UpstreamError> {
+        ActionProposed::from(self.clone()).verify_signature()
+    }
+}
+
+impl From<DepositIntent> for ActionProposed {
+    fn from(value: DepositIntent) -> Self {
+        match value {
+            DepositIntent::Seeker(signed) => Self::Seeker(signed),
+            DepositIntent::Provider(signed) => Self::Provider(signed),
+        }
+    }
 }
 
 impl core::fmt::Display for AccountDeposit {
@@ -83,3 +138,101 @@ impl core::fmt::Display for AccountWithdrawal {
         write!(f, "-{}@{}", self.amount, self.account)
     }
 }
+
+/// Total value (msats) of all accounts, as tracked by deposits and
+/// withdrawals since the database migration that computed it.
+pub async fn total_balance(dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>) -> u64 {
+    db::get(dbtx, &db::TotalBalanceKey).await.unwrap_or(0)
+}
+
+/// Total balance (msats) of every account.
+pub async fn account_totals(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+) -> Vec<u64> {
+    dbtx.find_by_prefix(&db::AccountBalanceKeyPrefix)
+        .await
+        .map(|(_, account)| {
+            account
+                .total_balance()
+                .expect("account balance cannot overflow")
+                .msats
+        })
+        .collect::<Vec<_>>()
+        .await
+}
+
+/// Why an account balance changed.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Encodable, Decodable, PartialEq, Eq, Hash)]
+pub enum BalanceChangeReason {
+    #[serde(rename = "deposit")]
+    Deposit,
+    #[serde(rename = "withdrawal")]
+    Withdrawal,
+    /// Locked balance paid out when an epoch settled
+    #[serde(rename = "settlement")]
+    Settlement,
+    /// Balance moved between keys by
+    /// [`GovernanceAction::ReassignAccount`](crate::governance::GovernanceAction::ReassignAccount)
+    #[serde(rename = "reassignment")]
+    Reassignment,
+}
+
+/// Change to the total balance of an account, logged for indexers.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Encodable, Decodable, PartialEq, Eq, Hash)]
+pub struct BalanceChange {
+    pub account: bitcoin::XOnlyPublicKey,
+    pub reason: BalanceChangeReason,
+    /// Epoch the change happened in, or the settled epoch for settlements
+    pub epoch_id: EpochId,
+    /// Amount added to the account
+    pub credit: fedimint_core::Amount,
+    /// Amount removed from the account
+    pub debit: fedimint_core::Amount,
+}
+
+impl BalanceChange {
+    /// Net change of the account balance in msats.
+    pub fn delta_msats(&self) -> i64 {
+        self.credit.msats as i64 - self.debit.msats as i64
+    }
+}
+
+/// Append `change` to the balance change log. When `retention` is set, only
+/// that many of the most recent records are kept.
+pub async fn log_balance_change(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    retention: Option<u64>,
+    change: BalanceChange,
+) {
+    let seq = db::get(dbtx, &db::BalanceChangeSeqKey).await.unwrap_or(0);
+    db::set(dbtx, &db::BalanceChangeKey(seq), &change).await;
+    db::set(dbtx, &db::BalanceChangeSeqKey, &(seq + 1)).await;
+
+    if let Some(pruned) = retention.and_then(|retention| seq.checked_sub(retention)) {
+        db::pop(dbtx, &db::BalanceChangeKey(pruned)).await;
+    }
+}
+
+/// Up to `limit` logged balance changes with their sequence numbers, starting
+/// after `after` (or the oldest retained record).
+pub async fn balance_changes(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    retention: Option<u64>,
+    after: Option<u64>,
+    limit: u64,
+) -> Vec<(u64, BalanceChange)> {
+    let next_seq = db::get(dbtx, &db::BalanceChangeSeqKey).await.unwrap_or(0);
+    let oldest = retention.map_or(0, |retention| next_seq.saturating_sub(retention));
+    let start = after.map_or(0, |after| after + 1).max(oldest);
+
+    let mut changes = Vec::new();
+    for seq in start..next_seq {
+        if changes.len() as u64 >= limit {
+            break;
+        }
+        if let Some(change) = db::get(dbtx, &db::BalanceChangeKey(seq)).await {
+            changes.push((seq, change));
+        }
+    }
+    changes
+}
@@ -10,8 +10,10 @@ use fedimint_core::BitcoinHash;
 use secp256k1_zkp::Secp256k1;
 use serde::{Deserialize, Serialize};
 
-use crate::epoch::{self, EpochState};
-use crate::{db, ConsensusItemOutcome, PoolConsensusItem};
+use crate::account::AccountDeposit;
+use crate::config::EpochConfig;
+use crate::epoch::{self, EpochId, EpochState};
+use crate::{db, governance, ConsensusItemOutcome, PoolConsensusItem};
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Encodable, Decodable)]
 pub enum ActionProposed {
@@ -27,7 +29,7 @@ impl ActionProposed {
         }
     }
 
-    pub fn epoch_id(&self) -> u64 {
+    pub fn epoch_id(&self) -> EpochId {
         match self {
             ActionProposed::Seeker(sa) => sa.epoch_id,
             ActionProposed::Provider(sa) => sa.epoch_id,
@@ -79,7 +81,7 @@ impl From<ActionProposed> for ActionStaged {
 }
 
 impl ActionStaged {
-    pub fn epoch_id(&self) -> u64 {
+    pub fn epoch_id(&self) -> EpochId {
         match self {
             ActionStaged::Seeker(a) => a.epoch_id,
             ActionStaged::Provider(a) => a.epoch_id,
@@ -103,7 +105,7 @@ impl ActionStaged {
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct Action<T> {
-    pub epoch_id: u64,
+    pub epoch_id: EpochId,
     pub sequence: u64,
     pub account_id: secp256k1_zkp::XOnlyPublicKey,
     pub body: T,
@@ -126,7 +128,7 @@ impl<T: Decodable> Decodable for Action<T> {
         modules: &fedimint_core::module::registry::ModuleDecoderRegistry,
     ) -> Result<Self, fedimint_core::encoding::DecodeError> {
         Ok(Self {
-            epoch_id: u64::consensus_decode(r, modules)?,
+            epoch_id: EpochId::consensus_decode(r, modules)?,
             sequence: u64::consensus_decode(r, modules)?,
             account_id: secp256k1_zkp::XOnlyPublicKey::consensus_decode(r, modules)?,
             body: T::consensus_decode(r, modules)?,
@@ -188,16 +190,28 @@ pub enum SeekerAction {
     Unlock { amount: fedimint_core::Amount },
 }
 
-// Continually reentering according to bid -> unlock by setting max amount 0.
+/// A provider's bid is a standing one: unlike seeker actions, it stays staged
+/// after being matched and is re-entered in every following epoch until it is
+/// replaced by a new bid. A bid with a `max_amount` of 0 cancels it.
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Encodable, Decodable)]
 pub struct ProviderBid {
     pub min_feerate: u64,
     pub max_amount: fedimint_core::Amount,
 }
 
+/// Number of actions an account has staged for `epoch_id`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize, Encodable, Decodable)]
+pub struct ActionCount {
+    pub epoch_id: EpochId,
+    pub count: u64,
+}
+
 #[derive(Debug, Default)]
 pub struct ActionProposedDb {
     actions: Mutex<BTreeMap<secp256k1_zkp::XOnlyPublicKey, ActionProposed>>,
+    /// Account of the last action we proposed, the next round continues after
+    /// it
+    last_proposed: Mutex<Option<secp256k1_zkp::XOnlyPublicKey>>,
 }
 
 impl ActionProposedDb {
@@ -216,28 +230,44 @@ impl ActionProposedDb {
             .insert(action.account_id(), action);
     }
 
-    pub fn remove_expired(&self, next_epoch_id: u64) {
+    pub fn remove_expired(&self, next_epoch_id: EpochId) {
         let actions = &mut *self.actions.lock().unwrap();
         actions.retain(|_, a| a.epoch_id() >= next_epoch_id)
     }
 
-    pub fn has_epoch_items(&self, epoch_id: u64) -> bool {
+    pub fn has_epoch_items(&self, epoch_id: EpochId) -> bool {
         // TODO: filter out items that have sequences lower than entry in consensus item
         // db!
         let actions = &*self.actions.lock().unwrap();
         actions.values().any(|a| a.epoch_id() == epoch_id)
     }
 
-    pub fn epoch_items(&self, epoch_id: u64) -> Vec<PoolConsensusItem> {
+    /// Actions for `epoch_id` to propose, at most `limit` of them. Accounts
+    /// take turns: each call continues after the account proposed last, so
+    /// actions left over by the limit go first in the next round.
+    pub fn epoch_items(&self, epoch_id: EpochId, limit: Option<u64>) -> Vec<PoolConsensusItem> {
         // TODO: filter out items that have sequences lower than entry in consensus item
         // db!
         let actions = &*self.actions.lock().unwrap();
-        actions
+        let mut last_proposed = self.last_proposed.lock().unwrap();
+        let mut items = actions
             .values()
             .filter(move |&a| a.epoch_id() == epoch_id)
-            .cloned()
-            .map(Into::into)
-            .collect()
+            .collect::<Vec<_>>();
+        if let Some(last_proposed) = *last_proposed {
+            let next = items
+                .iter()
+                .position(|a| a.account_id() > last_proposed)
+                .unwrap_or(0);
+            items.rotate_left(next);
+        }
+        if let Some(limit) = limit {
+            items.truncate(limit as usize);
+        }
+        if let Some(last) = items.last() {
+            *last_proposed = Some(last.account_id());
+        }
+        items.into_iter().cloned().map(Into::into).collect()
     }
 
     pub fn pop_entry(&self, action: &ActionProposed) {
@@ -258,25 +288,170 @@ pub async fn can_propose(
     epoch_state.is_settled() && proposal_db.has_epoch_items(epoch_state.staging_epoch_id())
 }
 
-/// Provide consensus proposals.
+/// Provide consensus proposals, at most `max_actions` of them.
 pub async fn consensus_proposal(
     dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
     proposal_db: &ActionProposedDb,
+    max_actions: Option<u64>,
 ) -> Vec<PoolConsensusItem> {
     let state = epoch::EpochState::from_db(dbtx).await;
-    proposal_db.epoch_items(state.staging_epoch_id())
+    proposal_db.epoch_items(state.staging_epoch_id(), max_actions)
+}
+
+/// Number of actions `account_id` has already staged for `epoch_id`.
+pub async fn staged_action_count(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    account_id: secp256k1_zkp::XOnlyPublicKey,
+    epoch_id: EpochId,
+) -> u64 {
+    db::get(dbtx, &db::ActionCountKey(account_id))
+        .await
+        .filter(|c| c.epoch_id == epoch_id)
+        .map_or(0, |c| c.count)
 }
 
 pub async fn process_consensus_item(
     dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &EpochConfig,
     proposal_db: &ActionProposedDb,
     incoming_action: ActionProposed,
 ) -> ConsensusItemOutcome {
-    if incoming_action.verify_signature().is_err() {
+    // while the epoch is unsettled a valid action is kept to be proposed again
+    let settled = EpochState::from_db(dbtx).await.is_settled();
+    let outcome = match incoming_action.clone() {
+        ActionProposed::Seeker(signed) => process_action(dbtx, config, signed).await,
+        ActionProposed::Provider(signed) => process_action(dbtx, config, signed).await,
+    };
+    // keep the signature of what was staged so it can be verified later
+    if matches!(outcome, ConsensusItemOutcome::Applied) {
+        let key = db::ActionSignedKey(incoming_action.account_id());
+        db::set(dbtx, &key, &incoming_action).await;
+    }
+    if settled || matches!(outcome, ConsensusItemOutcome::Banned(_)) {
         proposal_db.pop_entry(&incoming_action);
+    }
+    outcome
+}
+
+/// Body of a user action, i.e. what a participant role asks the pool to do.
+/// Action processing only relies on this trait, so a new role needs an
+/// implementation and a variant in [`ActionProposed`].
+#[async_trait::async_trait]
+pub trait ActionBody: Encodable + Clone + Debug + Send + Sync {
+    /// Check the body when the action is proposed to a peer, returning why it
+    /// is rejected. The checks common to all actions, such as the signature
+    /// and sequence, are done separately.
+    async fn validate(
+        &self,
+        dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+        config: &EpochConfig,
+        account_id: secp256k1_zkp::XOnlyPublicKey,
+    ) -> Result<(), String>;
+
+    /// Apply `action` in consensus once it passed the common checks, with
+    /// `count` actions already staged by the account for its epoch.
+    async fn apply(
+        dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+        action: Action<Self>,
+        count: u64,
+    );
+}
+
+#[async_trait::async_trait]
+impl ActionBody for SeekerAction {
+    async fn validate(
+        &self,
+        dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+        _config: &EpochConfig,
+        account_id: secp256k1_zkp::XOnlyPublicKey,
+    ) -> Result<(), String> {
+        match self {
+            SeekerAction::Lock { amount } | SeekerAction::Unlock { amount }
+                if *amount == fedimint_core::Amount::ZERO =>
+            {
+                Err("seeker action must be for a positive amount".to_string())
+            }
+            // staging an action replaces the account's previous one, so no
+            // other staged action holds on to part of the unlocked balance
+            SeekerAction::Lock { amount } => {
+                let unlocked = db::get(dbtx, &db::AccountBalanceKey(account_id))
+                    .await
+                    .map_or(fedimint_core::Amount::ZERO, |account| account.unlocked);
+                if *amount > unlocked {
+                    return Err(format!(
+                        "seeker lock of {} exceeds unlocked balance of {}",
+                        amount, unlocked
+                    ));
+                }
+                Ok(())
+            }
+            SeekerAction::Unlock { .. } => Ok(()),
+        }
+    }
+
+    async fn apply(
+        dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+        action: Action<Self>,
+        count: u64,
+    ) {
+        stage_action(dbtx, ActionStaged::Seeker(action), count).await;
+    }
+}
+
+#[async_trait::async_trait]
+impl ActionBody for ProviderBid {
+    async fn validate(
+        &self,
+        dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+        config: &EpochConfig,
+        account_id: secp256k1_zkp::XOnlyPublicKey,
+    ) -> Result<(), String> {
+        let max_feerate = governance::max_feerate_ppm(dbtx, config, account_id).await;
+        if self.min_feerate > max_feerate {
+            return Err(format!(
+                "provider min_feerate {} exceeds max feerate {}",
+                self.min_feerate, max_feerate
+            ));
+        }
+        // a bid may exceed the unlocked balance as locked collateral is
+        // unlocked and bid again at settlement, but an account without any
+        // balance has nothing to bid. Cancelling a bid is always possible
+        if self.max_amount > fedimint_core::Amount::ZERO {
+            let balance = db::get(dbtx, &db::AccountBalanceKey(account_id))
+                .await
+                .unwrap_or_default();
+            if balance.unlocked + balance.locked.amount() == fedimint_core::Amount::ZERO {
+                return Err("provider bid from an account without balance".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    async fn apply(
+        dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+        action: Action<Self>,
+        count: u64,
+    ) {
+        stage_action(dbtx, ActionStaged::Provider(action), count).await;
+    }
+}
+
+/// Process an action proposed in consensus: check what applies to every
+/// action, such as its signature, epoch, sequence and the account's action
+/// limit, and hand it to [`ActionBody::apply`] if it passes.
+pub async fn process_action<T: ActionBody>(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &EpochConfig,
+    incoming_action: SignedAction<T>,
+) -> ConsensusItemOutcome {
+    if incoming_action.verify_signature().is_err() {
         return ConsensusItemOutcome::Banned(format!("proposed user action has invalid signature"));
     }
 
+    if governance::is_closed(dbtx).await {
+        return ConsensusItemOutcome::Ignored(format!("pool is closed, cannot stage user action"));
+    }
+
     let epoch_state = EpochState::from_db(dbtx).await;
     if !epoch_state.is_settled() {
         return ConsensusItemOutcome::Ignored(format!(
@@ -285,37 +460,458 @@ pub async fn process_consensus_item(
     }
 
     let next_epoch_id = epoch_state.staging_epoch_id();
-    if next_epoch_id != incoming_action.epoch_id() {
-        proposal_db.pop_entry(&incoming_action);
+    if next_epoch_id != incoming_action.epoch_id {
         return ConsensusItemOutcome::Ignored(format!(
             "proposed action's epoch ({}) is not the next epoch ({})",
-            incoming_action.epoch_id(),
-            next_epoch_id
+            incoming_action.epoch_id, next_epoch_id
         ));
     }
 
-    let db_key = db::ActionStagedKey(incoming_action.account_id());
+    let db_key = db::ActionStagedKey(incoming_action.account_id);
 
     // existing staged action
     let existing_action = db::get(dbtx, &db_key).await;
 
-    let min_sequence = existing_action.map_or(0_u64, |a| {
+    let min_sequence = existing_action.as_ref().map_or(0_u64, |a| {
         if a.epoch_id() < next_epoch_id {
             0_u64
         } else {
             a.sequence() + 1
         }
     });
-    if incoming_action.sequence() < min_sequence {
-        proposal_db.pop_entry(&incoming_action);
+    if incoming_action.sequence < min_sequence {
         return ConsensusItemOutcome::Ignored(format!(
             "action: invalid sequence ({}), min_sequence ({})",
-            incoming_action.sequence(),
-            min_sequence,
+            incoming_action.sequence, min_sequence,
+        ));
+    }
+    if let Some(existing_action) = existing_action.filter(|a| a.epoch_id() == next_epoch_id) {
+        let max_sequence = config.max_sequence(existing_action.sequence());
+        if incoming_action.sequence > max_sequence {
+            return ConsensusItemOutcome::Ignored(format!(
+                "action: invalid sequence ({}), max_sequence ({})",
+                incoming_action.sequence, max_sequence,
+            ));
+        }
+    }
+
+    let account_id = incoming_action.account_id;
+    let count = staged_action_count(dbtx, account_id, next_epoch_id).await;
+    if let Some(max_actions) = config.max_actions_per_epoch {
+        if count >= max_actions {
+            return ConsensusItemOutcome::Ignored(format!(
+                "action: account reached the limit of {} actions for epoch {}",
+                max_actions, next_epoch_id,
+            ));
+        }
+    }
+
+    T::apply(dbtx, incoming_action.action, count).await;
+    ConsensusItemOutcome::Applied
+}
+
+/// Stage the action a deposit carries as its intent, in the same transaction
+/// that credits the deposit.
+///
+/// The intent is signed by the account and passes the same checks as an
+/// action proposed in consensus, including its epoch and sequence. It never
+/// replaces an action the account already has staged, such as a standing
+/// bid, and is skipped if any check fails. Returns whether it was staged.
+pub async fn stage_deposit_intent(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &EpochConfig,
+    deposit: &AccountDeposit,
+) -> bool {
+    let intent = match &deposit.intent {
+        Some(intent) if intent.account_id() == deposit.account => intent.clone(),
+        _ => return false,
+    };
+
+    if db::get(dbtx, &db::ActionStagedKey(deposit.account))
+        .await
+        .is_some()
+    {
+        return false;
+    }
+
+    let proposed = ActionProposed::from(intent);
+    let staged = match proposed.clone() {
+        ActionProposed::Seeker(signed) => stage_signed_action(dbtx, config, signed).await,
+        ActionProposed::Provider(signed) => stage_signed_action(dbtx, config, signed).await,
+    };
+    if staged {
+        db::set(dbtx, &db::ActionSignedKey(deposit.account), &proposed).await;
+    }
+    staged
+}
+
+/// Validate and stage `signed` as [`process_action`] would in consensus,
+/// returning whether it was staged.
+async fn stage_signed_action<T: ActionBody>(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &EpochConfig,
+    signed: SignedAction<T>,
+) -> bool {
+    if signed
+        .body
+        .validate(dbtx, config, signed.account_id)
+        .await
+        .is_err()
+    {
+        return false;
+    }
+    process_action(dbtx, config, signed).await == ConsensusItemOutcome::Applied
+}
+
+/// Store `action` as the account's staged action, counting it towards the
+/// `count` actions already staged for its epoch.
+async fn stage_action(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    action: ActionStaged,
+    count: u64,
+) {
+    let account_id = action.account_id();
+    let epoch_id = action.epoch_id();
+    // the account record keeps the latest sequence for replay protection
+    if let Some(mut account) = db::get(dbtx, &db::AccountBalanceKey(account_id)).await {
+        account.last_seq = action.sequence();
+        account.last_epoch = epoch_id;
+        db::set(dbtx, &db::AccountBalanceKey(account_id), &account).await;
+    }
+    db::set(dbtx, &db::ActionStagedKey(account_id), &action).await;
+    // the replaced action's signature no longer matches what is staged
+    db::pop(dbtx, &db::ActionSignedKey(account_id)).await;
+    db::set(
+        dbtx,
+        &db::ActionCountKey(account_id),
+        &ActionCount {
+            epoch_id,
+            count: count + 1,
+        },
+    )
+    .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::secp256k1::KeyPair;
+
+    use super::*;
+    use crate::account::{AccountBalance, DepositIntent};
+
+    fn epoch_config(max_actions_per_epoch: Option<u64>) -> EpochConfig {
+        EpochConfig {
+            max_actions_per_epoch,
+            ..Default::default()
+        }
+    }
+
+    fn sign<T: Encodable>(keypair: &KeyPair, action: Action<T>) -> SignedAction<T> {
+        let b = action
+            .consensus_encode_to_vec()
+            .expect("encoding to vec can't fail");
+        let hash = bitcoin::hashes::sha256::Hash::hash(&b);
+        let secp = Secp256k1::signing_only();
+        let signature = secp.sign_schnorr_no_aux_rand(&hash.into(), keypair);
+        SignedAction { action, signature }
+    }
+
+    fn seeker_lock(keypair: &KeyPair, epoch_id: EpochId, sequence: u64) -> ActionProposed {
+        sign(
+            keypair,
+            Action {
+                epoch_id,
+                sequence,
+                account_id: keypair.x_only_public_key().0,
+                body: SeekerAction::Lock {
+                    amount: fedimint_core::msats(1_000),
+                },
+            },
+        )
+        .into()
+    }
+
+    #[tokio::test]
+    async fn actions_per_epoch_are_capped() {
+        test_dbtx!(dbtx);
+        let config = epoch_config(Some(2));
+        let proposal_db = ActionProposedDb::default();
+        let keypair = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng());
+
+        // nothing has ended yet so actions are staged for epoch 1
+        for sequence in 0..2 {
+            let action = seeker_lock(&keypair, EpochId(1), sequence);
+            let outcome = process_consensus_item(&mut dbtx, &config, &proposal_db, action).await;
+            assert_eq!(outcome, ConsensusItemOutcome::Applied);
+        }
+
+        let action = seeker_lock(&keypair, EpochId(1), 2);
+        let outcome = process_consensus_item(&mut dbtx, &config, &proposal_db, action).await;
+        assert!(matches!(outcome, ConsensusItemOutcome::Ignored(_)));
+
+        let account_id = keypair.x_only_public_key().0;
+        assert_eq!(
+            staged_action_count(&mut dbtx, account_id, EpochId(1)).await,
+            2
+        );
+        // the count does not carry over to other epochs
+        assert_eq!(
+            staged_action_count(&mut dbtx, account_id, EpochId(2)).await,
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn capped_proposals_take_turns() {
+        test_dbtx!(dbtx);
+        let proposal_db = ActionProposedDb::default();
+        let mut actions = (0..5)
+            .map(|_| {
+                let keypair = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng());
+                seeker_lock(&keypair, EpochId(1), 0)
+            })
+            .collect::<Vec<_>>();
+        actions.sort_by_key(ActionProposed::account_id);
+        for action in &actions {
+            proposal_db.insert(action.clone());
+        }
+        let items = |actions: &[&ActionProposed]| {
+            actions
+                .iter()
+                .map(|&a| PoolConsensusItem::from(a.clone()))
+                .collect::<Vec<_>>()
+        };
+
+        // while actions stay pending, e.g. in an unsettled epoch, every
+        // account still gets its turn
+        let mut rounds = vec![];
+        for _ in 0..3 {
+            rounds.push(consensus_proposal(&mut dbtx, &proposal_db, Some(2)).await);
+        }
+        assert_eq!(
+            rounds,
+            vec![
+                items(&[&actions[0], &actions[1]]),
+                items(&[&actions[2], &actions[3]]),
+                items(&[&actions[4], &actions[0]]),
+            ]
+        );
+
+        // processed actions leave the rest to the following rounds
+        for action in &actions[..2] {
+            proposal_db.pop_entry(action);
+        }
+        assert_eq!(
+            consensus_proposal(&mut dbtx, &proposal_db, Some(2)).await,
+            items(&[&actions[2], &actions[3]])
+        );
+        assert_eq!(
+            consensus_proposal(&mut dbtx, &proposal_db, None).await,
+            items(&[&actions[4], &actions[2], &actions[3]])
+        );
+    }
+
+    /// A third role, tipping the pool, only counted towards the action limit.
+    #[derive(Debug, Clone, Encodable)]
+    struct Tip {
+        amount: u64,
+    }
+
+    #[async_trait::async_trait]
+    impl ActionBody for Tip {
+        async fn validate(
+            &self,
+            _dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+            _config: &EpochConfig,
+            _account_id: secp256k1_zkp::XOnlyPublicKey,
+        ) -> Result<(), String> {
+            if self.amount == 0 {
+                return Err("tip must not be empty".to_string());
+            }
+            Ok(())
+        }
+
+        async fn apply(
+            dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+            action: Action<Self>,
+            count: u64,
+        ) {
+            let action_count = ActionCount {
+                epoch_id: action.epoch_id,
+                count: count + 1,
+            };
+            db::set(dbtx, &db::ActionCountKey(action.account_id), &action_count).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn new_action_bodies_get_common_checks() {
+        test_dbtx!(dbtx);
+        let config = epoch_config(Some(1));
+        let keypair = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng());
+        let account_id = keypair.x_only_public_key().0;
+        let tip = |sequence, amount| {
+            sign(
+                &keypair,
+                Action {
+                    epoch_id: EpochId(1),
+                    sequence,
+                    account_id,
+                    body: Tip { amount },
+                },
+            )
+        };
+
+        let empty = Tip { amount: 0 };
+        assert!(empty
+            .validate(&mut dbtx, &config, account_id)
+            .await
+            .is_err());
+
+        let outcome = process_action(&mut dbtx, &config, tip(0, 10)).await;
+        assert_eq!(outcome, ConsensusItemOutcome::Applied);
+        assert_eq!(
+            staged_action_count(&mut dbtx, account_id, EpochId(1)).await,
+            1
+        );
+
+        // the action limit applies to tips like any other action
+        let outcome = process_action(&mut dbtx, &config, tip(1, 10)).await;
+        assert!(matches!(outcome, ConsensusItemOutcome::Ignored(_)));
+
+        let mut forged = tip(1, 10);
+        forged.action.body.amount = 1_000;
+        let outcome = process_action(&mut dbtx, &config, forged).await;
+        assert!(matches!(outcome, ConsensusItemOutcome::Banned(_)));
+    }
+
+    #[tokio::test]
+    async fn sequence_gap_is_capped() {
+        test_dbtx!(dbtx);
+        let config = EpochConfig {
+            max_sequence_gap: Some(100),
+            ..epoch_config(None)
+        };
+        let proposal_db = ActionProposedDb::default();
+        let keypair = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng());
+
+        // the first action of an epoch can start anywhere
+        let action = seeker_lock(&keypair, EpochId(1), 1_000);
+        let outcome = process_consensus_item(&mut dbtx, &config, &proposal_db, action).await;
+        assert_eq!(outcome, ConsensusItemOutcome::Applied);
+
+        let action = seeker_lock(&keypair, EpochId(1), 1_100);
+        let outcome = process_consensus_item(&mut dbtx, &config, &proposal_db, action).await;
+        assert_eq!(outcome, ConsensusItemOutcome::Applied);
+
+        let action = seeker_lock(&keypair, EpochId(1), u64::MAX);
+        let outcome = process_consensus_item(&mut dbtx, &config, &proposal_db, action).await;
+        assert!(matches!(outcome, ConsensusItemOutcome::Ignored(_)));
+
+        let staged = db::get(
+            &mut dbtx,
+            &db::ActionStagedKey(keypair.x_only_public_key().0),
+        )
+        .await
+        .unwrap();
+        assert_eq!(staged.sequence(), 1_100);
+    }
+
+    #[tokio::test]
+    async fn deposit_intent_stages_signed_action() {
+        test_dbtx!(dbtx);
+        let config = epoch_config(None);
+        let proposal_db = ActionProposedDb::default();
+        let keypair = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng());
+        let account = keypair.x_only_public_key().0;
+        // the deposit is credited before its intent is staged
+        let balance = AccountBalance {
+            unlocked: fedimint_core::msats(5_000),
+            ..Default::default()
+        };
+        db::set(&mut dbtx, &db::AccountBalanceKey(account), &balance).await;
+        let deposit = |intent| AccountDeposit {
+            account,
+            amount: fedimint_core::msats(5_000),
+            intent,
+        };
+        let bid = Action {
+            epoch_id: EpochId(1),
+            sequence: 0,
+            account_id: account,
+            body: ProviderBid {
+                min_feerate: 300,
+                max_amount: fedimint_core::msats(5_000),
+            },
+        };
+
+        assert!(!stage_deposit_intent(&mut dbtx, &config, &deposit(None)).await);
+
+        // an intent signed by another account is not staged
+        let other = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng());
+        let intent = DepositIntent::Provider(sign(
+            &other,
+            Action {
+                account_id: other.x_only_public_key().0,
+                ..bid.clone()
+            },
         ));
+        assert!(!stage_deposit_intent(&mut dbtx, &config, &deposit(Some(intent))).await);
+        assert_eq!(
+            db::get(&mut dbtx, &db::ActionStagedKey(account)).await,
+            None
+        );
+
+        let intent = DepositIntent::Provider(sign(&keypair, bid.clone()));
+        assert!(stage_deposit_intent(&mut dbtx, &config, &deposit(Some(intent.clone()))).await);
+        assert_eq!(
+            db::get(&mut dbtx, &db::ActionStagedKey(account)).await,
+            Some(ActionStaged::Provider(bid))
+        );
+        assert_eq!(
+            db::get(&mut dbtx, &db::ActionSignedKey(account)).await,
+            Some(ActionProposed::from(intent))
+        );
+        assert_eq!(staged_action_count(&mut dbtx, account, EpochId(1)).await, 1);
+
+        // a later intent does not replace the staged action, even when it is
+        // signed with the next sequence
+        let intent = match seeker_lock(&keypair, EpochId(1), 1) {
+            ActionProposed::Seeker(signed) => DepositIntent::Seeker(signed),
+            _ => unreachable!(),
+        };
+        assert!(!stage_deposit_intent(&mut dbtx, &config, &deposit(Some(intent))).await);
+
+        // the account's own actions follow the staged one
+        let action = seeker_lock(&keypair, EpochId(1), 0);
+        let outcome = process_consensus_item(&mut dbtx, &config, &proposal_db, action).await;
+        assert!(matches!(outcome, ConsensusItemOutcome::Ignored(_)));
+        let action = seeker_lock(&keypair, EpochId(1), 1);
+        let outcome = process_consensus_item(&mut dbtx, &config, &proposal_db, action).await;
+        assert_eq!(outcome, ConsensusItemOutcome::Applied);
     }
 
-    proposal_db.pop_entry(&incoming_action);
-    db::set(dbtx, &db_key, &incoming_action.into()).await;
-    return ConsensusItemOutcome::Applied;
+    #[tokio::test]
+    async fn deposit_intent_waits_for_settled_epoch() {
+        test_dbtx!(dbtx);
+        let config = epoch_config(None);
+        let keypair = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng());
+        let account = keypair.x_only_public_key().0;
+        db::set(&mut dbtx, &db::LastEpochEndedKey, &EpochId(0)).await;
+
+        let intent = match seeker_lock(&keypair, EpochId(1), 0) {
+            ActionProposed::Seeker(signed) => DepositIntent::Seeker(signed),
+            _ => unreachable!(),
+        };
+        let deposit = AccountDeposit {
+            account,
+            amount: fedimint_core::msats(5_000),
+            intent: Some(intent),
+        };
+        assert!(!stage_deposit_intent(&mut dbtx, &config, &deposit).await);
+        assert_eq!(
+            db::get(&mut dbtx, &db::ActionStagedKey(account)).await,
+            None
+        );
+    }
 }
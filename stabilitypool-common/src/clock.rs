@@ -0,0 +1,17 @@
+use time::OffsetDateTime;
+
+/// Source of the current time for everything the module times by the wall
+/// clock, such as when to vote for ending an epoch.
+pub trait Clock: Sync + Send + core::fmt::Debug {
+    fn now(&self) -> OffsetDateTime;
+}
+
+/// The system's clock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> OffsetDateTime {
+        OffsetDateTime::now_utc()
+    }
+}
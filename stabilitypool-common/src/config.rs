@@ -4,12 +4,16 @@ use std::str::FromStr;
 use fedimint_core::config::{
     TypedClientModuleConfig, TypedServerModuleConfig, TypedServerModuleConsensusConfig,
 };
-use fedimint_core::encoding::Encodable;
+use fedimint_core::encoding::{Decodable, Encodable};
 use fedimint_core::PeerId;
 use serde::{Deserialize, Serialize};
-use time::OffsetDateTime;
+use time::{Duration, OffsetDateTime};
 
-use crate::price::{BitMexOracle, MockOracle, OracleClient};
+use crate::epoch::EpochId;
+use crate::price::{
+    BackOffConfig, BitMexOracle, MockOracle, OracleClient, UnixSocketOracle,
+    UNIX_SOCKET_ORACLE_TIMEOUT,
+};
 use crate::stability_core::CollateralRatio;
 use crate::{FileOracle, KIND};
 
@@ -24,6 +28,13 @@ pub struct PoolConfig {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PoolConfigPrivate {
     pub peer_id: PeerId,
+    /// How long to wait before retrying a failing oracle
+    #[serde(default)]
+    pub backoff: BackOffConfig,
+    /// The maximum number of user actions we propose per consensus round,
+    /// unlimited if [`None`]
+    #[serde(default)]
+    pub max_actions_per_proposal: Option<u64>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Encodable)]
@@ -31,13 +42,82 @@ pub struct PoolConfigConsensus {
     // TODO: What fields do we need?
     pub epoch: EpochConfig,
     pub oracle: OracleConfig,
+    /// Currency the pool keeps stable. Prices are in cents of this currency
+    /// per BTC.
+    #[serde(default = "default_quote_currency")]
+    pub quote_currency: String,
+    /// Maximum total value (msats) of all accounts, uncapped if [`None`]. Can
+    /// be raised through governance.
+    #[serde(default)]
+    pub max_tvl_msat: Option<u64>,
+    /// Smallest deposit (msats) the pool accepts
+    #[serde(default)]
+    pub min_deposit_msat: u64,
+    /// Fee (msats) kept by the module out of every withdrawal
+    #[serde(default)]
+    pub withdrawal_fee_msat: u64,
+    /// Whether a deposit creates the account it is for. Otherwise accounts
+    /// have to be registered by operators through
+    /// [`GovernanceAction::RegisterAccount`](crate::governance::GovernanceAction::RegisterAccount)
+    /// before they can receive deposits.
+    #[serde(default = "default_allow_account_autocreation")]
+    pub allow_account_autocreation: bool,
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, Encodable)]
+/// A consensus config with the default [`EpochConfig`] and the defaults
+/// older configs are read with.
+impl Default for PoolConfigConsensus {
+    fn default() -> Self {
+        Self {
+            epoch: EpochConfig::default(),
+            oracle: OracleConfig::default(),
+            quote_currency: default_quote_currency(),
+            max_tvl_msat: None,
+            min_deposit_msat: 0,
+            withdrawal_fee_msat: 0,
+            allow_account_autocreation: default_allow_account_autocreation(),
+        }
+    }
+}
+
+pub const DEFAULT_QUOTE_CURRENCY: &str = "USD";
+
+// The default global max feerate.
+// TODO: Have this actually in config.
+pub const DEFAULT_GLOBAL_MAX_FEERATE: u64 = 100_000;
+
+/// The default epoch length is 24hrs (represented in seconds).
+// pub const DEFAULT_EPOCH_LENGTH: u64 = 24 * 60 * 60;
+pub const DEFAULT_EPOCH_LENGTH: u64 = 40; // TODO: This is just for testing
+
+pub fn default_quote_currency() -> String {
+    DEFAULT_QUOTE_CURRENCY.to_string()
+}
+
+pub fn default_allow_account_autocreation() -> bool {
+    true
+}
+
+/// Price source of the federation, part of the consensus config.
+///
+/// Configs of running federations must keep decoding, and hashing to the same
+/// value, after this enum is extended:
+/// - configs are stored as JSON, where variants are identified by name, so
+///   existing variants must never be renamed
+/// - the consensus encoding identifies variants by declaration order, so new
+///   variants are only ever appended and existing ones never removed or
+///   reordered
+///
+/// The fixtures in the tests below pin both encodings of every variant; a new
+/// variant has to be added to them.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, Encodable, Decodable)]
 pub enum OracleConfig {
     BitMex,
     Mock(String),
     File(String),
+    /// Path of a unix socket a local price feed writes the price to, in the
+    /// format of the file oracle, for every connection
+    UnixSocket(String),
 }
 
 impl Default for OracleConfig {
@@ -47,16 +127,31 @@ impl Default for OracleConfig {
 }
 
 impl OracleConfig {
-    pub fn oracle_client(&self) -> Box<dyn OracleClient> {
+    /// Oracle client fetching prices in `quote_currency`.
+    pub fn oracle_client(&self, quote_currency: &str) -> Box<dyn OracleClient> {
         match self {
             OracleConfig::BitMex => Box::new(BitMexOracle {}),
             OracleConfig::Mock(url) => Box::new(MockOracle {
                 url: reqwest::Url::parse(url).expect("invalid Url"),
+                quote_currency: quote_currency.to_string(),
             }),
             OracleConfig::File(path) => {
                 let path = PathBuf::from_str(&path).expect("must be valid path");
                 Box::new(FileOracle { path })
             }
+            OracleConfig::UnixSocket(path) => Box::new(UnixSocketOracle {
+                path: PathBuf::from(path),
+                timeout: UNIX_SOCKET_ORACLE_TIMEOUT,
+            }),
+        }
+    }
+
+    /// Whether the oracle can provide prices in `quote_currency`. The file
+    /// oracle is agnostic of what it reads.
+    pub fn supports_quote_currency(&self, quote_currency: &str) -> bool {
+        match self {
+            OracleConfig::BitMex => quote_currency == DEFAULT_QUOTE_CURRENCY,
+            OracleConfig::Mock(_) | OracleConfig::File(_) | OracleConfig::UnixSocket(_) => true,
         }
     }
 }
@@ -65,26 +160,204 @@ impl OracleConfig {
 pub struct EpochConfig {
     pub start_epoch_at: u64,
     pub epoch_length: u64,
-    /// Number of peers that have to agree on price before it's used
+    /// Round `start_epoch_at` to the nearest multiple of this many seconds
+    /// since the unix epoch, e.g. `3600` for epochs starting on the hour
+    #[serde(default)]
+    pub align_to_secs: Option<u64>,
+    /// Number of peers that have to vote to end an epoch
     pub price_threshold: u32,
+    /// Number of peers in the federation, [`None`] for configs generated
+    /// before it was recorded
+    #[serde(default)]
+    pub num_peers: Option<u32>,
+    /// Number of peers that have to submit an oracle price to settle an
+    /// epoch, [`Self::price_threshold`] if [`None`]
+    #[serde(default)]
+    pub oracle_quorum: Option<u32>,
+    /// How the submitted prices are combined into the settled price
+    #[serde(default)]
+    pub price_aggregation: PriceAggregation,
+    /// The lowest feerate an epoch settles at in parts per million of locked
+    /// principal, so providers earn at least this much on what they lock.
+    /// Bids below it are matched as if they asked for it
+    #[serde(default)]
+    pub min_feerate_ppm: u64,
     /// The maximum a provider can charge per epoch in parts per million of
     /// locked principal
     pub max_feerate_ppm: u64,
     /// The ratio of seeker position to provider collateral
     pub collateral_ratio: CollateralRatio,
+    /// The maximum number of actions an account can stage for a single epoch,
+    /// unlimited if [`None`]
+    #[serde(default)]
+    pub max_actions_per_epoch: Option<u64>,
+    /// How far the sequence of an account's action can jump ahead of the
+    /// previous action it staged for the same epoch, unlimited if [`None`]
+    #[serde(default)]
+    pub max_sequence_gap: Option<u64>,
+    /// The maximum consensus-encoded size in bytes of a proposed action,
+    /// unlimited if [`None`]
+    #[serde(default)]
+    pub max_action_bytes: Option<u64>,
+    /// Seconds to wait after an epoch ends before proposing its settlement
+    /// price
+    #[serde(default)]
+    pub settlement_delay_secs: u64,
+    /// Seconds before the current epoch ends from which actions for the next
+    /// epoch are refused, no cutoff if 0
+    #[serde(default)]
+    pub action_cutoff_secs_before_epoch_end: u64,
+    /// Number of balance change records kept for indexers, unlimited if
+    /// [`None`]
+    #[serde(default)]
+    pub balance_change_retention: Option<u64>,
+    /// How much older than the time it is requested for an oracle price can
+    /// be before peers refuse to propose it for settlement, unbounded if
+    /// [`None`]
+    #[serde(default)]
+    pub max_price_staleness_secs: Option<u64>,
+    /// The number of epochs a seeker position has to stay locked before an
+    /// unlock of it is applied, unrestricted if [`None`]
+    #[serde(default)]
+    pub min_lock_epochs: Option<u64>,
+    /// Whether peers exchange a digest of the state each settlement leaves
+    /// behind, to detect them diverging
+    #[serde(default)]
+    pub settlement_digests: bool,
+}
+
+/// An epoch config starting at the unix epoch that a single vote ends and
+/// settles, with every optional limit disabled. Config generation sets every
+/// field explicitly, this is a base for tests to override.
+impl Default for EpochConfig {
+    fn default() -> Self {
+        Self {
+            start_epoch_at: 0,
+            epoch_length: DEFAULT_EPOCH_LENGTH,
+            align_to_secs: None,
+            price_threshold: 1,
+            num_peers: None,
+            oracle_quorum: None,
+            price_aggregation: PriceAggregation::default(),
+            min_feerate_ppm: 0,
+            max_feerate_ppm: DEFAULT_GLOBAL_MAX_FEERATE,
+            collateral_ratio: CollateralRatio::default(),
+            max_actions_per_epoch: None,
+            max_sequence_gap: None,
+            max_action_bytes: None,
+            settlement_delay_secs: 0,
+            action_cutoff_secs_before_epoch_end: 0,
+            balance_change_retention: None,
+            max_price_staleness_secs: None,
+            min_lock_epochs: None,
+            settlement_digests: false,
+        }
+    }
+}
+
+/// How the prices peers submit for an epoch are combined into its settled
+/// price.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Encodable)]
+pub enum PriceAggregation {
+    /// The middle price, or the lower of the two middle ones for an even
+    /// number of prices, so the settled price is always one a peer submitted.
+    Median,
+    /// The mean of the prices left after dropping the lowest and highest
+    /// quarter.
+    TrimmedMean,
+    /// The mean of all prices.
+    Mean,
+}
+
+impl Default for PriceAggregation {
+    fn default() -> Self {
+        PriceAggregation::Median
+    }
+}
+
+impl PriceAggregation {
+    /// Combine `prices` into one. Panics if `prices` is empty.
+    pub fn aggregate(&self, prices: &[u64]) -> u64 {
+        assert!(!prices.is_empty(), "no prices to aggregate");
+        let mut prices = prices.to_vec();
+        prices.sort_unstable();
+
+        let mean = |prices: &[u64]| {
+            let sum = prices.iter().map(|&price| price as u128).sum::<u128>();
+            (sum / prices.len() as u128) as u64
+        };
+        match self {
+            PriceAggregation::Median => prices[(prices.len() - 1) / 2],
+            PriceAggregation::TrimmedMean => {
+                let trim = prices.len() / 4;
+                mean(&prices[trim..prices.len() - trim])
+            }
+            PriceAggregation::Mean => mean(&prices),
+        }
+    }
 }
 
 impl EpochConfig {
-    pub fn epoch_id_for_time(&self, time: OffsetDateTime) -> u64 {
+    pub fn epoch_id_for_time(&self, time: OffsetDateTime) -> EpochId {
         if time < self.start_epoch_at() {
-            0
+            EpochId(0)
         } else {
-            (time - self.start_epoch_at()).whole_seconds() as u64 / self.epoch_length + 1
+            EpochId((time - self.start_epoch_at()).whole_seconds() as u64 / self.epoch_length + 1)
         }
     }
 
+    /// Highest sequence accepted for an action following one staged with
+    /// `previous` sequence in the same epoch.
+    pub fn max_sequence(&self, previous: u64) -> u64 {
+        self.max_sequence_gap
+            .map_or(u64::MAX, |gap| previous.saturating_add(gap))
+    }
+
+    /// Number of matching oracle prices required to settle an epoch.
+    pub fn oracle_quorum(&self) -> u32 {
+        self.oracle_quorum.unwrap_or(self.price_threshold)
+    }
+
+    /// Fewest votes that always include one from an honest peer, `f + 1` for
+    /// a federation tolerating `f` faulty peers. Without a recorded number of
+    /// peers `f` is bounded by the price threshold, which is at least `2f + 1`.
+    pub fn min_honest_quorum(&self) -> u32 {
+        let max_faulty = match self.num_peers {
+            Some(num_peers) => num_peers.saturating_sub(1) / 3,
+            None => self.price_threshold.saturating_sub(1) / 2,
+        };
+        max_faulty + 1
+    }
+
+    /// Earliest time peers propose the settlement price of `epoch_id`, which
+    /// is the end of the epoch plus [`Self::settlement_delay_secs`].
+    pub fn settlement_time(&self, epoch_id: EpochId) -> OffsetDateTime {
+        self.epoch_end(epoch_id) + Duration::new(self.settlement_delay_secs as _, 0)
+    }
+
+    /// Time from which actions are refused while `epoch_id` is the current
+    /// epoch, which is the end of the epoch minus
+    /// [`Self::action_cutoff_secs_before_epoch_end`].
+    pub fn action_cutoff(&self, epoch_id: EpochId) -> OffsetDateTime {
+        self.epoch_end(epoch_id) - Duration::new(self.action_cutoff_secs_before_epoch_end as _, 0)
+    }
+
+    fn epoch_end(&self, epoch_id: EpochId) -> OffsetDateTime {
+        self.start_epoch_at() + Duration::new((epoch_id.0 * self.epoch_length) as _, 0)
+    }
+
+    /// Start of the first epoch, which is `start_epoch_at` rounded to the
+    /// nearest [`Self::align_to_secs`] boundary. All epoch boundaries follow
+    /// from it, so they are aligned too if the epoch length is a multiple of
+    /// the alignment.
     pub fn start_epoch_at(&self) -> OffsetDateTime {
-        OffsetDateTime::from_unix_timestamp(self.start_epoch_at as _)
+        let start_epoch_at = match self.align_to_secs {
+            Some(align) if align > 0 => {
+                (self.start_epoch_at.saturating_add(align / 2)) / align * align
+            }
+            _ => self.start_epoch_at,
+        };
+        OffsetDateTime::from_unix_timestamp(start_epoch_at as _)
             .expect("must be valid unix timestamp")
     }
 }
@@ -93,6 +366,8 @@ impl EpochConfig {
 pub struct PoolConfigClient {
     pub oracle: OracleConfig,
     pub collateral_ratio: CollateralRatio,
+    #[serde(default = "default_quote_currency")]
+    pub quote_currency: String,
 }
 
 impl TypedServerModuleConfig for PoolConfig {
@@ -116,6 +391,54 @@ impl TypedServerModuleConfig for PoolConfig {
     }
 
     fn validate_config(&self, _identity: &fedimint_core::PeerId) -> anyhow::Result<()> {
+        if !self
+            .consensus
+            .oracle
+            .supports_quote_currency(&self.consensus.quote_currency)
+        {
+            anyhow::bail!(
+                "oracle {:?} does not support quote currency {}",
+                self.consensus.oracle,
+                self.consensus.quote_currency
+            );
+        }
+        if let Some(max_tvl_msat) = self.consensus.max_tvl_msat {
+            if self.consensus.min_deposit_msat > max_tvl_msat {
+                anyhow::bail!(
+                    "minimum deposit {} exceeds the tvl cap {}",
+                    self.consensus.min_deposit_msat,
+                    max_tvl_msat
+                );
+            }
+        }
+        let epoch = &self.consensus.epoch;
+        // a single peer federation has a threshold of 1, its own vote
+        if epoch.price_threshold == 0 {
+            anyhow::bail!("price threshold must be at least 1");
+        }
+        if epoch.oracle_quorum() < epoch.price_threshold {
+            anyhow::bail!(
+                "oracle quorum {} is below the price threshold {}",
+                epoch.oracle_quorum(),
+                epoch.price_threshold
+            );
+        }
+        if let Some(num_peers) = epoch.num_peers {
+            if epoch.oracle_quorum() > num_peers {
+                anyhow::bail!(
+                    "oracle quorum {} exceeds the number of peers {}",
+                    epoch.oracle_quorum(),
+                    num_peers
+                );
+            }
+        }
+        if epoch.min_feerate_ppm > epoch.max_feerate_ppm {
+            anyhow::bail!(
+                "feerate floor {} exceeds the feerate cap {}",
+                epoch.min_feerate_ppm,
+                epoch.max_feerate_ppm
+            );
+        }
         Ok(())
     }
 }
@@ -127,6 +450,7 @@ impl TypedServerModuleConsensusConfig for PoolConfigConsensus {
             &PoolConfigClient {
                 oracle: self.oracle.clone(),
                 collateral_ratio: self.epoch.collateral_ratio,
+                quote_currency: self.quote_currency.clone(),
             },
         )
         .expect("serialization cannot fail")
@@ -138,3 +462,160 @@ impl TypedClientModuleConfig for PoolConfigClient {
         KIND
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use fedimint_core::module::registry::ModuleDecoderRegistry;
+
+    use super::*;
+
+    fn aligned_config(start_epoch_at: u64, epoch_length: u64, align_to_secs: u64) -> EpochConfig {
+        EpochConfig {
+            start_epoch_at,
+            epoch_length,
+            align_to_secs: Some(align_to_secs),
+            ..Default::default()
+        }
+    }
+
+    fn at(unix_timestamp: u64) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(unix_timestamp as _).unwrap()
+    }
+
+    #[test]
+    fn epochs_align_to_the_hour() {
+        const HOUR: u64 = 60 * 60;
+        // 10:20 rounds down to 10:00, 10:40 up to 11:00
+        let config = aligned_config(10 * HOUR + 20 * 60, HOUR, HOUR);
+        assert_eq!(config.start_epoch_at(), at(10 * HOUR));
+        assert_eq!(
+            aligned_config(10 * HOUR + 40 * 60, HOUR, HOUR).start_epoch_at(),
+            at(11 * HOUR)
+        );
+
+        assert_eq!(config.epoch_id_for_time(at(10 * HOUR - 1)), EpochId(0));
+        assert_eq!(config.epoch_id_for_time(at(10 * HOUR)), EpochId(1));
+        assert_eq!(config.epoch_id_for_time(at(11 * HOUR - 1)), EpochId(1));
+        assert_eq!(config.epoch_id_for_time(at(11 * HOUR)), EpochId(2));
+        assert_eq!(config.settlement_time(EpochId(2)), at(12 * HOUR));
+    }
+
+    #[test]
+    fn epochs_align_to_the_day() {
+        const DAY: u64 = 24 * 60 * 60;
+        // 23:59 on day 100 rounds to midnight starting day 101
+        let config = aligned_config(101 * DAY - 60, DAY, DAY);
+        assert_eq!(config.start_epoch_at(), at(101 * DAY));
+
+        // the configured start is before the aligned one, so still epoch 0
+        assert_eq!(config.epoch_id_for_time(at(101 * DAY - 30)), EpochId(0));
+        assert_eq!(config.epoch_id_for_time(at(101 * DAY)), EpochId(1));
+        assert_eq!(config.epoch_id_for_time(at(102 * DAY - 1)), EpochId(1));
+        assert_eq!(config.epoch_id_for_time(at(102 * DAY)), EpochId(2));
+
+        // hourly epochs aligned to the day
+        let config = aligned_config(101 * DAY + 60, 60 * 60, DAY);
+        assert_eq!(
+            config.epoch_id_for_time(at(101 * DAY + 60 * 60)),
+            EpochId(2)
+        );
+    }
+
+    #[test]
+    fn min_honest_quorum_is_one_more_than_the_faulty_peers() {
+        for (num_peers, price_threshold, expected) in [
+            (Some(1), 1, 1),
+            (Some(4), 3, 2),
+            (Some(7), 5, 3),
+            (Some(10), 7, 4),
+            (None, 3, 2),
+            (None, 5, 3),
+        ] {
+            let config = EpochConfig {
+                num_peers,
+                price_threshold,
+                ..Default::default()
+            };
+            assert_eq!(config.min_honest_quorum(), expected);
+        }
+    }
+
+    #[test]
+    fn price_aggregation_edge_cases() {
+        // the lower middle price for an even number of prices
+        assert_eq!(PriceAggregation::Median.aggregate(&[4, 1, 3, 2]), 2);
+        assert_eq!(PriceAggregation::TrimmedMean.aggregate(&[1, 2, 4, 100]), 3);
+        // fewer than four prices are not trimmed
+        assert_eq!(PriceAggregation::TrimmedMean.aggregate(&[1, 2, 100]), 34);
+        assert_eq!(
+            PriceAggregation::Mean.aggregate(&[u64::MAX, u64::MAX]),
+            u64::MAX
+        );
+    }
+
+    /// Every [`OracleConfig`] variant with its consensus discriminant and JSON
+    /// form as generated by earlier releases.
+    fn oracle_config_fixtures() -> Vec<(OracleConfig, u64, &'static str)> {
+        let fixtures = vec![
+            (OracleConfig::BitMex, 0, r#""BitMex""#),
+            (
+                OracleConfig::Mock("http://127.0.0.1:8080/".to_string()),
+                1,
+                r#"{"Mock":"http://127.0.0.1:8080/"}"#,
+            ),
+            (
+                OracleConfig::File("./misc/offline_oracle".to_string()),
+                2,
+                r#"{"File":"./misc/offline_oracle"}"#,
+            ),
+            (
+                OracleConfig::UnixSocket("/run/price-feed.sock".to_string()),
+                3,
+                r#"{"UnixSocket":"/run/price-feed.sock"}"#,
+            ),
+        ];
+
+        // fails to compile when a variant is added, as a reminder to extend
+        // the fixtures above
+        for (config, _, _) in &fixtures {
+            match config {
+                OracleConfig::BitMex
+                | OracleConfig::Mock(_)
+                | OracleConfig::File(_)
+                | OracleConfig::UnixSocket(_) => {}
+            }
+        }
+
+        fixtures
+    }
+
+    #[test]
+    fn oracle_config_json_is_stable() {
+        for (config, _, json) in oracle_config_fixtures() {
+            let decoded: OracleConfig = serde_json::from_str(json).expect("fixture must decode");
+            assert_eq!(decoded, config);
+            assert_eq!(serde_json::to_string(&config).unwrap(), json);
+        }
+    }
+
+    #[test]
+    fn oracle_config_consensus_encoding_is_stable() {
+        for (config, discriminant, _) in oracle_config_fixtures() {
+            let encoded = config.consensus_encode_to_vec().unwrap();
+            let prefix = discriminant.consensus_encode_to_vec().unwrap();
+            assert!(
+                encoded.starts_with(&prefix),
+                "{:?} must keep discriminant {}",
+                config,
+                discriminant
+            );
+
+            let decoded = OracleConfig::consensus_decode(
+                &mut std::io::Cursor::new(encoded),
+                &ModuleDecoderRegistry::default(),
+            )
+            .expect("encoding must round trip");
+            assert_eq!(decoded, config);
+        }
+    }
+}
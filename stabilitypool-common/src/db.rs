@@ -8,9 +8,12 @@ use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use strum_macros::EnumIter;
 
-use crate::action::ActionStaged;
-use crate::epoch::EpochOutcome;
-use crate::{AccountBalance, EpochEnd};
+use crate::action::{ActionCount, ActionProposed, ActionStaged};
+use crate::epoch::{
+    EpochFees, EpochId, EpochOutcome, EpochOutcomeV1, ForceSettle, SettlementDigest,
+};
+use crate::governance::{DegradedQuorumPolicy, GovernanceAction};
+use crate::{AccountBalance, AccountBalanceV1, BalanceChange, EpochEnd, LockedBalance};
 
 #[repr(u8)]
 #[derive(Clone, EnumIter, Debug)]
@@ -45,6 +48,114 @@ pub enum DbKeyPrefix {
     ///   Key: x-only-pubkey (account id)
     /// Value: action::ActionStaged
     ActionStaged,
+
+    /// The last valid `force_settle` item we got from given peer (Consensus
+    /// Item).
+    ///   Key: PeerId
+    /// Value: ForceSettle
+    ForceSettle,
+
+    /// Number of actions an account staged for the staging epoch
+    ///   Key: x-only-pubkey (account id)
+    /// Value: action::ActionCount
+    ActionCount,
+
+    /// Log of account balance changes
+    ///   Key: sequence number
+    /// Value: account::BalanceChange
+    BalanceChange,
+
+    /// Sequence number of the next balance change
+    ///   Key: ~
+    /// Value: u64
+    BalanceChangeSeq,
+
+    /// The pending governance action each peer voted for (Consensus Item)
+    ///   Key: PeerId
+    /// Value: governance::GovernanceAction
+    GovernanceVote,
+
+    /// TVL cap (msats) raised through governance, overriding the config
+    ///   Key: ~
+    /// Value: u64
+    TvlCap,
+
+    /// Running total of all account balances (msats)
+    ///   Key: ~
+    /// Value: u64
+    TotalBalance,
+
+    /// Account frozen through governance
+    ///   Key: x-only-pubkey (account id)
+    /// Value: epoch_id the account was frozen in
+    FrozenAccount,
+
+    /// Withdrawal fees (msats) collected and held by the module
+    ///   Key: ~
+    /// Value: u64
+    CollectedFees,
+
+    /// Provider feerate cap set through governance, overriding the global one
+    ///   Key: x-only-pubkey (account id)
+    /// Value: max feerate (ppm)
+    FeerateCap,
+
+    /// Degraded quorum policy set through governance
+    ///   Key: ~
+    /// Value: governance::DegradedQuorumPolicy
+    DegradedQuorumPolicy,
+
+    /// The consensus session in which we last got a consensus item from given
+    /// peer, see [`ConsensusSessions`](DbKeyPrefix::ConsensusSessions)
+    ///   Key: PeerId
+    /// Value: u64
+    PeerLastSeen,
+
+    /// Collateral a provider had matched in an epoch
+    ///   Key: epoch_id, x-only-pubkey (account id)
+    /// Value: u64 (msats)
+    ProviderLocked,
+
+    /// Epochs in which an account had a locked position
+    ///   Key: x-only-pubkey (account id), epoch_id
+    /// Value: LockedBalance
+    AccountEpoch,
+
+    /// The epoch in which the pool was closed through governance
+    ///   Key: ~
+    /// Value: epoch_id
+    PoolClosed,
+
+    /// Fees charged across the pool in a settled epoch
+    ///   Key: epoch_id
+    /// Value: EpochFees
+    EpochFees,
+
+    /// The epoch from which a seeker's position has been locked without a
+    /// break
+    ///   Key: x-only-pubkey (account id)
+    /// Value: epoch_id
+    SeekerLockedSince,
+
+    /// Digest of the state left by our latest settlement
+    ///   Key: ~
+    /// Value: SettlementDigest
+    SettlementDigest,
+
+    /// Latest settlement digest submitted by each peer
+    ///   Key: peer_id
+    /// Value: SettlementDigest
+    PeerSettlementDigest,
+
+    /// The action staged for an account as it was signed, if it was signed
+    ///   Key: x-only-pubkey (account id)
+    /// Value: action::ActionProposed
+    ActionSigned,
+
+    /// Number of consensus sessions processed by the module
+    ///   Key: ~
+    /// Value: u64
+    ConsensusSessions,
 }
 
 impl std::fmt::Display for DbKeyPrefix {
@@ -71,6 +182,25 @@ impl_db_lookup!(
     query_prefix = AccountBalanceKeyPrefix
 );
 
+/// [`AccountBalanceKey`] with the value encoding of database version 1.
+#[derive(Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct AccountBalanceV1Key(pub secp256k1_zkp::XOnlyPublicKey);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct AccountBalanceV1KeyPrefix;
+
+impl_db_record!(
+    key = AccountBalanceV1Key,
+    value = AccountBalanceV1,
+    db_prefix = DbKeyPrefix::Account,
+);
+impl_db_lookup!(
+    key = AccountBalanceV1Key,
+    query_prefix = AccountBalanceV1KeyPrefix
+);
+
 #[derive(Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct DepositOutcomeKey(pub fedimint_core::OutPoint);
 
@@ -89,7 +219,7 @@ impl_db_lookup!(key = DepositOutcomeKey, query_prefix = DepositOutcomePrefix);
 #[derive(
     Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
 )]
-pub struct EpochOutcomeKey(pub u64);
+pub struct EpochOutcomeKey(pub EpochId);
 
 #[derive(Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct EpochOutcomeKeyPrefix;
@@ -101,6 +231,25 @@ impl_db_record!(
 );
 impl_db_lookup!(key = EpochOutcomeKey, query_prefix = EpochOutcomeKeyPrefix);
 
+/// [`EpochOutcomeKey`] with the value encoding of database version 4.
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct EpochOutcomeV1Key(pub EpochId);
+
+#[derive(Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct EpochOutcomeV1KeyPrefix;
+
+impl_db_record!(
+    key = EpochOutcomeV1Key,
+    value = EpochOutcomeV1,
+    db_prefix = DbKeyPrefix::EpochOutcome,
+);
+impl_db_lookup!(
+    key = EpochOutcomeV1Key,
+    query_prefix = EpochOutcomeV1KeyPrefix
+);
+
 #[derive(Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct LastEpochSettledKey;
 
@@ -111,7 +260,7 @@ pub struct LastEpochSettledPrefix;
 
 impl_db_record!(
     key = LastEpochSettledKey,
-    value = u64,
+    value = EpochId,
     db_prefix = DbKeyPrefix::LastEpochSettled,
 );
 impl_db_lookup!(
@@ -129,7 +278,7 @@ pub struct LastEpochEndedPrefix;
 
 impl_db_record!(
     key = LastEpochEndedKey,
-    value = u64,
+    value = EpochId,
     db_prefix = DbKeyPrefix::LastEpochEnded,
 );
 impl_db_lookup!(key = LastEpochEndedKey, query_prefix = LastEpochEndedPrefix);
@@ -168,6 +317,385 @@ impl_db_record!(
 );
 impl_db_lookup!(key = ActionStagedKey, query_prefix = ActionStagedKeyPrefix);
 
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct ForceSettleKey(pub fedimint_core::PeerId);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct ForceSettleKeyPrefix;
+
+impl_db_record!(
+    key = ForceSettleKey,
+    value = ForceSettle,
+    db_prefix = DbKeyPrefix::ForceSettle,
+);
+impl_db_lookup!(key = ForceSettleKey, query_prefix = ForceSettleKeyPrefix);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct ActionCountKey(pub secp256k1_zkp::XOnlyPublicKey);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct ActionCountKeyPrefix;
+
+impl_db_record!(
+    key = ActionCountKey,
+    value = ActionCount,
+    db_prefix = DbKeyPrefix::ActionCount,
+);
+impl_db_lookup!(key = ActionCountKey, query_prefix = ActionCountKeyPrefix);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct BalanceChangeKey(pub u64);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct BalanceChangeKeyPrefix;
+
+impl_db_record!(
+    key = BalanceChangeKey,
+    value = BalanceChange,
+    db_prefix = DbKeyPrefix::BalanceChange,
+);
+impl_db_lookup!(
+    key = BalanceChangeKey,
+    query_prefix = BalanceChangeKeyPrefix
+);
+
+#[derive(Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct BalanceChangeSeqKey;
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct BalanceChangeSeqPrefix;
+
+impl_db_record!(
+    key = BalanceChangeSeqKey,
+    value = u64,
+    db_prefix = DbKeyPrefix::BalanceChangeSeq,
+);
+impl_db_lookup!(
+    key = BalanceChangeSeqKey,
+    query_prefix = BalanceChangeSeqPrefix
+);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct GovernanceVoteKey(pub fedimint_core::PeerId);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct GovernanceVoteKeyPrefix;
+
+impl_db_record!(
+    key = GovernanceVoteKey,
+    value = GovernanceAction,
+    db_prefix = DbKeyPrefix::GovernanceVote,
+);
+impl_db_lookup!(
+    key = GovernanceVoteKey,
+    query_prefix = GovernanceVoteKeyPrefix
+);
+
+#[derive(Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct TvlCapKey;
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct TvlCapPrefix;
+
+impl_db_record!(
+    key = TvlCapKey,
+    value = u64,
+    db_prefix = DbKeyPrefix::TvlCap,
+);
+impl_db_lookup!(key = TvlCapKey, query_prefix = TvlCapPrefix);
+
+#[derive(Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct TotalBalanceKey;
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct TotalBalancePrefix;
+
+impl_db_record!(
+    key = TotalBalanceKey,
+    value = u64,
+    db_prefix = DbKeyPrefix::TotalBalance,
+);
+impl_db_lookup!(key = TotalBalanceKey, query_prefix = TotalBalancePrefix);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct FrozenAccountKey(pub secp256k1_zkp::XOnlyPublicKey);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct FrozenAccountKeyPrefix;
+
+impl_db_record!(
+    key = FrozenAccountKey,
+    value = EpochId,
+    db_prefix = DbKeyPrefix::FrozenAccount,
+);
+impl_db_lookup!(
+    key = FrozenAccountKey,
+    query_prefix = FrozenAccountKeyPrefix
+);
+
+#[derive(Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct CollectedFeesKey;
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct CollectedFeesPrefix;
+
+impl_db_record!(
+    key = CollectedFeesKey,
+    value = u64,
+    db_prefix = DbKeyPrefix::CollectedFees,
+);
+impl_db_lookup!(key = CollectedFeesKey, query_prefix = CollectedFeesPrefix);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct FeerateCapKey(pub secp256k1_zkp::XOnlyPublicKey);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct FeerateCapKeyPrefix;
+
+impl_db_record!(
+    key = FeerateCapKey,
+    value = u64,
+    db_prefix = DbKeyPrefix::FeerateCap,
+);
+impl_db_lookup!(key = FeerateCapKey, query_prefix = FeerateCapKeyPrefix);
+
+#[derive(Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct DegradedQuorumPolicyKey;
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct DegradedQuorumPolicyPrefix;
+
+impl_db_record!(
+    key = DegradedQuorumPolicyKey,
+    value = DegradedQuorumPolicy,
+    db_prefix = DbKeyPrefix::DegradedQuorumPolicy,
+);
+impl_db_lookup!(
+    key = DegradedQuorumPolicyKey,
+    query_prefix = DegradedQuorumPolicyPrefix
+);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct PeerLastSeenKey(pub fedimint_core::PeerId);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct PeerLastSeenKeyPrefix;
+
+impl_db_record!(
+    key = PeerLastSeenKey,
+    value = u64,
+    db_prefix = DbKeyPrefix::PeerLastSeen,
+);
+impl_db_lookup!(key = PeerLastSeenKey, query_prefix = PeerLastSeenKeyPrefix);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct ProviderLockedKey(pub EpochId, pub secp256k1_zkp::XOnlyPublicKey);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct ProviderLockedKeyPrefix;
+
+impl_db_record!(
+    key = ProviderLockedKey,
+    value = u64,
+    db_prefix = DbKeyPrefix::ProviderLocked,
+);
+impl_db_lookup!(
+    key = ProviderLockedKey,
+    query_prefix = ProviderLockedKeyPrefix
+);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct AccountEpochKey(pub secp256k1_zkp::XOnlyPublicKey, pub EpochId);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct AccountEpochKeyPrefix;
+
+/// All [`AccountEpochKey`]s of a single account.
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct AccountEpochKeyAccountPrefix(pub secp256k1_zkp::XOnlyPublicKey);
+
+impl_db_record!(
+    key = AccountEpochKey,
+    value = LockedBalance,
+    db_prefix = DbKeyPrefix::AccountEpoch,
+);
+impl_db_lookup!(key = AccountEpochKey, query_prefix = AccountEpochKeyPrefix);
+impl_db_lookup!(
+    key = AccountEpochKey,
+    query_prefix = AccountEpochKeyAccountPrefix
+);
+
+#[derive(Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct PoolClosedKey;
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct PoolClosedPrefix;
+
+impl_db_record!(
+    key = PoolClosedKey,
+    value = EpochId,
+    db_prefix = DbKeyPrefix::PoolClosed,
+);
+impl_db_lookup!(key = PoolClosedKey, query_prefix = PoolClosedPrefix);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct EpochFeesKey(pub EpochId);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct EpochFeesKeyPrefix;
+
+impl_db_record!(
+    key = EpochFeesKey,
+    value = EpochFees,
+    db_prefix = DbKeyPrefix::EpochFees,
+);
+impl_db_lookup!(key = EpochFeesKey, query_prefix = EpochFeesKeyPrefix);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct SeekerLockedSinceKey(pub secp256k1_zkp::XOnlyPublicKey);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct SeekerLockedSinceKeyPrefix;
+
+impl_db_record!(
+    key = SeekerLockedSinceKey,
+    value = EpochId,
+    db_prefix = DbKeyPrefix::SeekerLockedSince,
+);
+impl_db_lookup!(
+    key = SeekerLockedSinceKey,
+    query_prefix = SeekerLockedSinceKeyPrefix
+);
+
+#[derive(Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct SettlementDigestKey;
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct SettlementDigestPrefix;
+
+impl_db_record!(
+    key = SettlementDigestKey,
+    value = SettlementDigest,
+    db_prefix = DbKeyPrefix::SettlementDigest,
+);
+impl_db_lookup!(
+    key = SettlementDigestKey,
+    query_prefix = SettlementDigestPrefix
+);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct PeerSettlementDigestKey(pub fedimint_core::PeerId);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct PeerSettlementDigestKeyPrefix;
+
+impl_db_record!(
+    key = PeerSettlementDigestKey,
+    value = SettlementDigest,
+    db_prefix = DbKeyPrefix::PeerSettlementDigest,
+);
+impl_db_lookup!(
+    key = PeerSettlementDigestKey,
+    query_prefix = PeerSettlementDigestKeyPrefix
+);
+
+#[derive(Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct ActionSignedKey(pub secp256k1_zkp::XOnlyPublicKey);
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct ActionSignedKeyPrefix;
+
+impl_db_record!(
+    key = ActionSignedKey,
+    value = ActionProposed,
+    db_prefix = DbKeyPrefix::ActionSigned,
+);
+impl_db_lookup!(key = ActionSignedKey, query_prefix = ActionSignedKeyPrefix);
+
+#[derive(Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct ConsensusSessionsKey;
+
+#[derive(
+    Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash, Serialize, Deserialize, Ord, PartialOrd,
+)]
+pub struct ConsensusSessionsPrefix;
+
+impl_db_record!(
+    key = ConsensusSessionsKey,
+    value = u64,
+    db_prefix = DbKeyPrefix::ConsensusSessions,
+);
+impl_db_lookup!(
+    key = ConsensusSessionsKey,
+    query_prefix = ConsensusSessionsPrefix
+);
+
 pub async fn get<K>(
     dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
     key: &K,
@@ -1,32 +1,85 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
 
+use bitcoin::hashes::{sha256, Hash};
 use bitcoin::XOnlyPublicKey;
 use fedimint_core::core::ModuleInstanceId;
 use fedimint_core::db::ModuleDatabaseTransaction;
 use fedimint_core::encoding::{Decodable, Encodable};
+use fedimint_core::module::registry::ModuleDecoderRegistry;
 use fedimint_core::PeerId;
 use futures::StreamExt;
 use serde::{Deserialize, Serialize};
-use time::OffsetDateTime;
+use time::{Date, OffsetDateTime};
 
 use crate::action::{Action, ActionStaged, ProviderBid, SeekerAction};
 use crate::config::EpochConfig;
 use crate::stability_core::{self, EpochFeerate};
 use crate::{
-    db, AccountBalance, BackOff, ConsensusItemOutcome, LockedBalance, OracleClient,
-    PoolConsensusItem,
+    db, governance, log_balance_change, AccountBalance, BackOff, BalanceChange,
+    BalanceChangeReason, ConsensusItemOutcome, LockedBalance, OracleClient, PoolConsensusItem,
 };
 
+/// Identifier of an epoch, counting up from 0. Encodes and serializes as the
+/// plain number.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Hash,
+    Serialize,
+    Deserialize,
+    Encodable,
+    Decodable,
+)]
+#[serde(transparent)]
+pub struct EpochId(pub u64);
+
+impl EpochId {
+    pub fn next(self) -> Self {
+        Self(self.0 + 1)
+    }
+
+    /// The previous epoch, [`None`] for the first one.
+    pub fn prev(self) -> Option<Self> {
+        self.0.checked_sub(1).map(Self)
+    }
+
+    /// The previous epoch, or the first one if this is it.
+    pub fn saturating_prev(self) -> Self {
+        Self(self.0.saturating_sub(1))
+    }
+}
+
+impl std::fmt::Display for EpochId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize, Encodable, Decodable)]
 pub struct EpochEnd {
     /// The price is an option because we may not know what the price is due to
     /// inability to fetch. We still want to indicate that we think the
     /// epoch has ended.
     pub price: Option<u64>,
-    pub epoch_id: u64,
+    pub epoch_id: EpochId,
+    /// Unix timestamp of the voting peer's clock when it proposed the vote.
+    /// Consensus only uses the median of the votes, see [`agreed_time`].
+    #[serde(default)]
+    pub time: u64,
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize, Encodable, Decodable)]
+/// Outcome of an epoch, written when the epoch starts and completed when it
+/// settles. The default is the outcome of an epoch no position was locked in.
+#[derive(
+    Debug, Clone, Copy, Default, Eq, PartialEq, Hash, Serialize, Deserialize, Encodable, Decodable,
+)]
 pub struct EpochOutcome {
     pub total_seeker_locked: u64,
     pub total_provider_locked: u64,
@@ -35,31 +88,194 @@ pub struct EpochOutcome {
     pub settled_price: Option<u64>,
     /// Start feerate
     pub feerate: EpochFeerate,
+    /// Whether `settled_price` was set by operators through [`ForceSettle`]
+    /// rather than agreed from oracle prices
+    pub forced: bool,
+    /// Number of seekers with a locked position in the epoch, 0 for epochs
+    /// started before database version 5 as they were not counted
+    pub num_seekers: u64,
+    /// Number of providers with a locked position in the epoch, 0 for epochs
+    /// started before database version 5 as they were not counted
+    pub num_providers: u64,
+    /// Unix timestamp at which the epoch settled, as agreed by the votes that
+    /// settled it, see [`agreed_time`]. Like `settled_price` it is only set
+    /// once the epoch settles, and it is not known for epochs settled before
+    /// database version 5
+    pub settled_at: Option<i64>,
+    /// The lowered number of oracle prices the epoch was settled with, if
+    /// peers were offline
+    pub degraded_quorum: Option<u32>,
+}
+
+/// [`EpochOutcome`] as stored up to database version 4, before it recorded
+/// how the epoch settled. Only read to migrate.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize, Encodable, Decodable)]
+pub struct EpochOutcomeV1 {
+    pub total_seeker_locked: u64,
+    pub total_provider_locked: u64,
+    pub settled_price: Option<u64>,
+    pub feerate: EpochFeerate,
+}
+
+/// Fees charged across the pool in a settled epoch.
+///
+/// Fees are not burned: what seekers pay is paid out to the providers. The
+/// two totals only differ by the rounding of the fees of the individual seeker
+/// positions.
+#[derive(
+    Debug, Clone, Copy, Default, Eq, PartialEq, Hash, Serialize, Deserialize, Encodable, Decodable,
+)]
+pub struct EpochFees {
+    /// Sum of the fees of every seeker position
+    pub seeker_fees_msat: u64,
+    /// Fee on the total locked by seekers, shared between the providers
+    pub provider_fees_msat: u64,
+}
+
+/// Version byte leading an epoch history encoded by
+/// [`encode_epoch_history`].
+pub const EPOCH_HISTORY_VERSION: u8 = 1;
+
+/// Outcome of an epoch as part of an encoded epoch history.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize, Encodable, Decodable)]
+pub struct EpochHistoryEntry {
+    pub epoch_id: EpochId,
+    pub outcome: EpochOutcome,
+}
+
+/// Entries are ordered by epoch id, so sorting a history puts it in epoch
+/// order. [`EpochOutcome`] itself isn't ordered as it doesn't know its epoch.
+///
+/// Entries of the same epoch, which a history never holds, fall back to
+/// comparing the encoding of their outcomes to stay consistent with `Eq`.
+impl Ord for EpochHistoryEntry {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.epoch_id.cmp(&other.epoch_id).then_with(|| {
+            let encode = |outcome: &EpochOutcome| {
+                outcome
+                    .consensus_encode_to_vec()
+                    .expect("encoding to vec can't fail")
+            };
+            encode(&self.outcome).cmp(&encode(&other.outcome))
+        })
+    }
+}
+
+impl PartialOrd for EpochHistoryEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Compact binary encoding of epoch outcomes: [`EPOCH_HISTORY_VERSION`]
+/// followed by the consensus encoding of `entries`, which starts with their
+/// count.
+pub fn encode_epoch_history(entries: &[EpochHistoryEntry]) -> Vec<u8> {
+    let mut bytes = vec![EPOCH_HISTORY_VERSION];
+    entries
+        .consensus_encode(&mut bytes)
+        .expect("encoding to vec can't fail");
+    bytes
+}
+
+/// Decode an epoch history encoded by [`encode_epoch_history`].
+pub fn decode_epoch_history(bytes: &[u8]) -> anyhow::Result<Vec<EpochHistoryEntry>> {
+    let (version, mut entries) = bytes
+        .split_first()
+        .ok_or_else(|| anyhow::format_err!("epoch history is empty"))?;
+    anyhow::ensure!(
+        *version == EPOCH_HISTORY_VERSION,
+        "unsupported epoch history version {}",
+        version
+    );
+    let history =
+        Vec::<EpochHistoryEntry>::consensus_decode(&mut entries, &ModuleDecoderRegistry::default())
+            .map_err(|e| anyhow::format_err!("failed to decode epoch history: {}", e))?;
+    anyhow::ensure!(entries.is_empty(), "epoch history has trailing bytes");
+    Ok(history)
+}
+
+/// Operator override settling an epoch that is stuck waiting on the oracle at
+/// the given `price` (cents/BTC). It is kept apart from [`EpochEnd`] so that
+/// overrides remain auditable, and only takes effect once `price_threshold`
+/// peers have proposed the same item.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize, Encodable, Decodable)]
+pub struct ForceSettle {
+    pub epoch_id: EpochId,
+    pub price: u64,
+}
+
+/// Force settlement submitted by our operator, proposed until the epoch is
+/// settled or our vote is recorded.
+#[derive(Debug, Default)]
+pub struct ForceSettleProposal {
+    item: Mutex<Option<ForceSettle>>,
+}
+
+impl ForceSettleProposal {
+    pub fn get(&self) -> Option<ForceSettle> {
+        *self.item.lock().unwrap()
+    }
+
+    pub fn set(&self, item: ForceSettle) {
+        self.item.lock().unwrap().replace(item);
+    }
+
+    pub fn clear(&self) {
+        self.item.lock().unwrap().take();
+    }
+}
+
+/// Digest of the state a peer was left with after settling `epoch_id`, see
+/// [`settlement_digest`]. Peers exchange them to notice when their settlement
+/// math diverges.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize, Encodable, Decodable)]
+pub struct SettlementDigest {
+    pub epoch_id: EpochId,
+    pub digest: sha256::Hash,
 }
 
 pub struct EpochState {
-    pub latest_ended: Option<u64>, // both epoch ids
-    pub latest_settled: Option<u64>,
+    pub latest_ended: Option<EpochId>,
+    pub latest_settled: Option<EpochId>,
 }
 
 impl EpochState {
     pub async fn from_db(dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>) -> Self {
+        let latest_ended = db::get(dbtx, &db::LastEpochEndedKey).await;
+        let latest_settled = db::get(dbtx, &db::LastEpochSettledKey).await;
+
+        // an epoch can only settle after it ended, so the staging epoch always
+        // comes after the settled one. Should the records ever disagree, treat
+        // the settled epoch as ended rather than accept actions for it.
+        if latest_settled > latest_ended {
+            tracing::error!(
+                ?latest_ended,
+                ?latest_settled,
+                "latest settled epoch is ahead of latest ended epoch"
+            );
+            return Self {
+                latest_ended: latest_settled,
+                latest_settled,
+            };
+        }
+
         Self {
-            latest_ended: db::get(dbtx, &db::LastEpochEndedKey).await,
-            latest_settled: db::get(dbtx, &db::LastEpochSettledKey).await,
+            latest_ended,
+            latest_settled,
         }
     }
 
     /// Returns the only epoch_id that we can accept user actions with. Returns
     /// [`None`] if epoch state is NOT settled (we will not process user
     /// actions).
-    pub fn staging_epoch_id(&self) -> u64 {
-        self.current_epoch_id() + 1
+    pub fn staging_epoch_id(&self) -> EpochId {
+        self.current_epoch_id().next()
     }
 
     /// Returns the current epoch_id.
-    pub fn current_epoch_id(&self) -> u64 {
-        self.latest_ended.map_or(0, |id| id + 1)
+    pub fn current_epoch_id(&self) -> EpochId {
+        self.latest_ended.map_or(EpochId(0), EpochId::next)
     }
 
     /// Returns whether we are in a settled state.
@@ -76,9 +292,9 @@ impl EpochState {
     /// requirements for incoming epoch_end (expected_epoch_id, needs_price)
     /// * expected_epoch_id: the expected value of the epoch_id field
     /// * needs_price: whether the epoch_end.price field is expected to be some
-    pub fn expected_epoch_end_id(&self) -> (u64, bool) {
+    pub fn expected_epoch_end_id(&self) -> (EpochId, bool) {
         if self.is_settled() {
-            (self.latest_ended.map(|id| id + 1).unwrap_or(0), false)
+            (self.latest_ended.map_or(EpochId(0), EpochId::next), false)
         } else {
             (
                 self.latest_ended
@@ -89,37 +305,98 @@ impl EpochState {
     }
 }
 
+/// `time` in unix seconds, saturating at the latest supported date.
+fn unix_time(time: u64) -> OffsetDateTime {
+    i64::try_from(time)
+        .ok()
+        .and_then(|time| OffsetDateTime::from_unix_timestamp(time).ok())
+        .unwrap_or_else(|| Date::MAX.midnight().assume_utc())
+}
+
+/// The time our proposals go by: our clock `now`, unless the votes recorded
+/// for the epoch we are waiting on agree on a later time, see [`agreed_time`].
+/// A peer whose clock lags then follows the federation instead of holding back
+/// its vote. Only as many votes as can't have their median set by faulty peers
+/// are followed.
+async fn proposal_time(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &EpochConfig,
+    now: OffsetDateTime,
+) -> OffsetDateTime {
+    let (epoch_id, needs_price) = EpochState::from_db(dbtx).await.expected_epoch_end_id();
+    let votes = dbtx
+        .find_by_prefix(&db::EpochEndKeyPrefix)
+        .await
+        .map(|(_, vote)| vote)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .filter(|vote| vote.epoch_id == epoch_id && (!needs_price || vote.price.is_some()))
+        .count();
+    if votes < (2 * config.min_honest_quorum() - 1) as usize {
+        return now;
+    }
+    let agreed = if needs_price {
+        agreed_settlement_time(dbtx, epoch_id).await
+    } else {
+        agreed_time(dbtx, epoch_id).await
+    };
+    agreed.map(unix_time).map_or(now, |agreed| agreed.max(now))
+}
+
+/// Whether we have an [`EpochEnd`] vote to propose at our [`proposal_time`].
+///
+/// Clocks only decide when peers vote. An epoch ends once enough peers voted
+/// for it and the [`agreed_time`] of their votes says it is due, and settles
+/// once enough prices are in and their [`agreed_settlement_time`] is past the
+/// settlement delay. That happens at the same point in consensus for every
+/// peer, so a skewed clock makes a peer vote early or late but cannot make
+/// peers disagree about the epoch.
 pub async fn can_propose(
     dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
     backoff: &BackOff,
     config: &EpochConfig,
+    now: OffsetDateTime,
 ) -> bool {
-    let now = OffsetDateTime::now_utc();
     if !backoff.can_retry(now) {
         return false;
     }
+    let now = proposal_time(dbtx, config, now).await;
 
     // expected epoch based on current time
     let expected_epoch = config.epoch_id_for_time(now);
 
+    // an ended epoch waits out the settlement delay before we propose its price
+    let epoch_state = EpochState::from_db(dbtx).await;
+    if let (false, Some(latest_ended)) = (epoch_state.is_settled(), epoch_state.latest_ended) {
+        if now < config.settlement_time(latest_ended) {
+            return false;
+        }
+    }
+
     // last epoch in which price is settled
-    let last_epoch_settled = db::get(dbtx, &db::LastEpochSettledKey).await;
+    let last_epoch_settled = epoch_state.latest_settled;
 
     // we only need `last_settled_epoch` as `last_ended_epoch` is always equal or
     // greater
     last_epoch_settled < Some(expected_epoch)
 }
 
+/// Our [`EpochEnd`] vote, if our clock `now` says one is due, see
+/// [`can_propose`].
 pub async fn consensus_proposal(
     dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
     backoff: &BackOff,
     config: &EpochConfig,
     oracle: &dyn OracleClient,
+    now: OffsetDateTime,
 ) -> Vec<PoolConsensusItem> {
-    let now = OffsetDateTime::now_utc();
+    // our vote carries our own clock, even when we follow the federation's
+    let time = now.unix_timestamp() as u64;
     if !backoff.can_retry(now) {
         return vec![];
     }
+    let now = proposal_time(dbtx, config, now).await;
 
     // epoch consensus state
     let expected_epoch = config.epoch_id_for_time(now);
@@ -131,14 +408,32 @@ pub async fn consensus_proposal(
     // propose `epoch_end` with `epoch_id = last_ended_epoch + 1`
     if is_settled && epoch_state.latest_ended < Some(expected_epoch) {
         // if is_settled && epoch_state.requires_ending(expected_epoch) {
-        let epoch_id = epoch_state.latest_ended.map_or(0, |id| id + 1);
-        let price = oracle.price_at_epoch_start(config, epoch_id).await.ok();
-        if price.is_none() {
-            backoff.record_failure(now);
+        let epoch_id = epoch_state.latest_ended.map_or(EpochId(0), EpochId::next);
+        // end the epoch right away, but hold back the price until the
+        // settlement delay has passed
+        let price = if now < config.settlement_time(epoch_id) {
+            None
         } else {
-            backoff.reset();
+            // a failing oracle must not stop the epoch from ending, the price can
+            // still be provided once the oracle is back
+            let fetch = || oracle.price_at_epoch_start(config, epoch_id);
+            match backoff.retry(now, fetch).await {
+                Ok(price) => Some(price),
+                Err(err) => {
+                    tracing::warn!(
+                        error = err.to_string(),
+                        "failed to fetch price, ending epoch without one"
+                    );
+                    None
+                }
+            }
+        };
+        return vec![EpochEnd {
+            price,
+            epoch_id,
+            time,
         }
-        return vec![EpochEnd { price, epoch_id }.into()];
+        .into()];
     }
 
     // if we are not in a settled state, and time requires a settlement
@@ -146,15 +441,22 @@ pub async fn consensus_proposal(
     // ensure that we have a price!
     if !is_settled && epoch_state.latest_settled < Some(expected_epoch) {
         // if !is_settled && epoch_state.requires_settlement(expected_epoch) {
-        let epoch_id = epoch_state.latest_settled.map_or(0, |id| id + 1);
-        match oracle.price_at_epoch_start(config, epoch_id).await {
+        let epoch_id = epoch_state.latest_settled.map_or(EpochId(0), EpochId::next);
+        if now < config.settlement_time(epoch_id) {
+            return vec![];
+        }
+        let fetch = || oracle.price_at_epoch_start(config, epoch_id);
+        match backoff.retry(now, fetch).await {
             Ok(price) => {
-                backoff.reset();
                 let price = Some(price);
-                return vec![EpochEnd { price, epoch_id }.into()];
+                return vec![EpochEnd {
+                    price,
+                    epoch_id,
+                    time,
+                }
+                .into()];
             }
             Err(err) => {
-                backoff.record_failure(now);
                 tracing::warn!(
                     error = err.to_string(),
                     "failed to fetch price and settle epoch, will try again"
@@ -166,6 +468,111 @@ pub async fn consensus_proposal(
     vec![]
 }
 
+/// Count a new consensus session. Sessions go on while an epoch cannot end
+/// for lack of votes, as the peers still online keep proposing theirs, so they
+/// measure how long peers have been offline. Peers that just started counting
+/// as offline may lower the quorum enough for the votes already recorded,
+/// which are not repeated, so they are tallied again.
+pub async fn begin_session(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &EpochConfig,
+) {
+    let session = db::get(dbtx, &db::ConsensusSessionsKey).await.unwrap_or(0) + 1;
+    db::set(dbtx, &db::ConsensusSessionsKey, &session).await;
+    tally_votes(dbtx, config).await;
+}
+
+/// Note that `peer_id` submitted a consensus item in the current session.
+pub async fn record_peer_seen(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    peer_id: PeerId,
+) {
+    let session = db::get(dbtx, &db::ConsensusSessionsKey).await.unwrap_or(0);
+    db::set(dbtx, &db::PeerLastSeenKey(peer_id), &session).await;
+}
+
+/// Time the federation agrees `epoch_id` ended at: the median of the clocks
+/// of the recorded votes for it. Every peer derives the same time from the
+/// same votes, and a minority of skewed clocks cannot move it outside the
+/// range of the honest ones.
+pub async fn agreed_time(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    epoch_id: EpochId,
+) -> Option<u64> {
+    median_vote_time(dbtx, epoch_id, false).await
+}
+
+/// Time the votes carrying a price for `epoch_id` agree on, like
+/// [`agreed_time`]. These are the votes that settle the epoch, so this is the
+/// time it settles at.
+pub async fn agreed_settlement_time(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    epoch_id: EpochId,
+) -> Option<u64> {
+    median_vote_time(dbtx, epoch_id, true).await
+}
+
+async fn median_vote_time(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    epoch_id: EpochId,
+    priced_only: bool,
+) -> Option<u64> {
+    let mut times = dbtx
+        .find_by_prefix(&db::EpochEndKeyPrefix)
+        .await
+        .map(|(_, vote)| vote)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .filter(|vote| vote.epoch_id == epoch_id)
+        .filter(|vote| !priced_only || vote.price.is_some())
+        .map(|vote| vote.time)
+        .collect::<Vec<_>>();
+    times.sort_unstable();
+    times.get(times.len().checked_sub(1)? / 2).copied()
+}
+
+/// The votes needed out of `quorum` to end or settle an epoch if the
+/// [`governance::DegradedQuorumPolicy`] lowers it: each peer that has been
+/// offline for the policy's `offline_sessions` takes one off, down to its
+/// `min_quorum`. A peer that was never seen counts as seen before the first
+/// session, so peers that are offline from the start are noticed too. Without
+/// a configured number of peers only the peers seen at all are known.
+async fn degraded_quorum(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &EpochConfig,
+    quorum: u32,
+) -> Option<u32> {
+    let policy = db::get(dbtx, &db::DegradedQuorumPolicyKey).await?;
+    let session = db::get(dbtx, &db::ConsensusSessionsKey).await.unwrap_or(0);
+    let mut last_seen = dbtx
+        .find_by_prefix(&db::PeerLastSeenKeyPrefix)
+        .await
+        .map(|(key, session)| (key.0, session))
+        .collect::<BTreeMap<_, _>>()
+        .await;
+    if let Some(num_peers) = config.num_peers {
+        for peer_id in (0..num_peers).map(|peer| PeerId::from(peer as u16)) {
+            last_seen.entry(peer_id).or_insert(0);
+        }
+    }
+    let offline = last_seen
+        .values()
+        .filter(|last_seen| last_seen.saturating_add(policy.offline_sessions) < session)
+        .count() as u32;
+
+    let degraded = quorum.saturating_sub(offline).max(policy.min_quorum);
+    (degraded < quorum).then(|| degraded)
+}
+
+/// Record a peer's vote to end the epoch we are waiting on.
+///
+/// The epoch ends once `price_threshold` peers have voted for it, and settles
+/// once `oracle_quorum` of those votes carry a price and agree that the
+/// settlement delay has passed, see [`agreed_settlement_time`]. The settled
+/// price combines the submitted prices according to `price_aggregation`. Both
+/// numbers are lowered while peers are offline if governance has set a
+/// [`governance::DegradedQuorumPolicy`].
 pub async fn process_consensus_item(
     dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
     config: &EpochConfig,
@@ -181,14 +588,35 @@ pub async fn process_consensus_item(
                 prev_epoch_end.epoch_id, epoch_end.epoch_id
             ));
         }
-        // we don't mark backtracking of price proposals
-        if epoch_end.epoch_id == prev_epoch_end.epoch_id
-            && epoch_end.price.is_none()
-            && prev_epoch_end.price.is_some()
-        {
-            return ConsensusItemOutcome::Ignored(format!(
-                "epoch: ignore backtrack of price proposal"
-            ));
+        // a peer only gets one vote per epoch: repeating it is ignored. A
+        // price can be supplied after having ended the epoch without one, and
+        // a different price replaces the earlier one, as oracles report the
+        // current price and peers re-propose it until the epoch settles
+        if epoch_end.epoch_id == prev_epoch_end.epoch_id {
+            match (prev_epoch_end.price, epoch_end.price) {
+                (None, Some(_)) => {}
+                (Some(prev_price), Some(price)) if prev_price != price => {
+                    tracing::debug!(
+                        epoch_id = epoch_end.epoch_id.0,
+                        ?peer_id,
+                        prev_price,
+                        price,
+                        "peer replaced its epoch_end price"
+                    );
+                }
+                (Some(_), None) => {
+                    // we don't mark backtracking of price proposals
+                    return ConsensusItemOutcome::Ignored(format!(
+                        "epoch: ignore backtrack of price proposal"
+                    ));
+                }
+                _ => {
+                    return ConsensusItemOutcome::Ignored(format!(
+                        "epoch: peer already submitted epoch_end for epoch {}",
+                        epoch_end.epoch_id
+                    ));
+                }
+            }
         }
     }
 
@@ -217,11 +645,24 @@ pub async fn process_consensus_item(
 
     // update epoch_end
     db::set(dbtx, &db::EpochEndKey(peer_id), &epoch_end).await;
+    tally_votes(dbtx, config).await;
+
+    ConsensusItemOutcome::Applied
+}
 
-    let threshold = config.price_threshold as usize;
+/// End and settle the epoch we are waiting on if the recorded votes allow it.
+async fn tally_votes(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &EpochConfig,
+) {
+    let epoch_state = EpochState::from_db(dbtx).await;
+    let (expected_epoch_end_id, _) = epoch_state.expected_epoch_end_id();
 
     // see if we can update `last_ended_epoch`
     if epoch_state.is_settled() {
+        let threshold = degraded_quorum(dbtx, config, config.price_threshold)
+            .await
+            .unwrap_or(config.price_threshold) as usize;
         let count = dbtx
             .find_by_prefix(&db::EpochEndKeyPrefix)
             .await
@@ -233,144 +674,483 @@ pub async fn process_consensus_item(
             .count();
 
         if count < threshold {
-            return ConsensusItemOutcome::Applied;
+            return;
+        }
+        // the votes must also agree the epoch is due, however early a peer
+        // voted for it
+        let agreed = agreed_time(dbtx, expected_epoch_end_id)
+            .await
+            .expect("votes were counted");
+        if config.epoch_id_for_time(unix_time(agreed)) < expected_epoch_end_id {
+            tracing::debug!(
+                epoch_id = expected_epoch_end_id.0,
+                agreed,
+                "epoch: votes do not agree the epoch is due"
+            );
+            return;
         }
         db::set(dbtx, &db::LastEpochEndedKey, &expected_epoch_end_id).await;
     }
 
-    // see if we can update `last_settled_epoch` with price tally: Map<price,
-    // occurences>
-    let price_tally = dbtx
+    // see if we can update `last_settled_epoch`, which requires
+    // `oracle_quorum` prices. Peers that have not voted on this epoch yet still
+    // hold their vote for the previous one, which must not count
+    let prices = dbtx
         .find_by_prefix(&db::EpochEndKeyPrefix)
         .await
         .collect::<Vec<_>>()
         .await
         .into_iter()
+        .filter(|(_, peer_epoch_end)| peer_epoch_end.epoch_id == expected_epoch_end_id)
         .filter_map(|(_, peer_epoch_end)| peer_epoch_end.price)
-        .fold(BTreeMap::new(), |mut tally, price| {
-            *tally.entry(price).or_insert(0_usize) += 1;
-            tally
-        });
+        .collect::<Vec<_>>();
+
+    // once enough prices are in, we can settle balances and start next epoch.
+    // A degraded quorum may be 0, but there is no price to settle at without
+    // at least one
+    let degraded_oracle_quorum = degraded_quorum(dbtx, config, config.oracle_quorum()).await;
+    let quorum = degraded_oracle_quorum.unwrap_or(config.oracle_quorum()) as usize;
+    if !prices.is_empty() && prices.len() >= quorum {
+        // a peer whose clock runs ahead may vote a price before the delay is
+        // over, which must not settle the epoch early
+        let settled_at = agreed_settlement_time(dbtx, expected_epoch_end_id)
+            .await
+            .expect("prices are only recorded with votes");
+        let settlement_time = config.settlement_time(expected_epoch_end_id);
+        if i64::try_from(settled_at).unwrap_or(i64::MAX) < settlement_time.unix_timestamp() {
+            tracing::debug!(
+                epoch_id = expected_epoch_end_id.0,
+                settled_at,
+                "epoch: votes do not agree the settlement delay has passed"
+            );
+            return;
+        }
 
-    // if we get a price that reaches agreement threshold, we can settle balances
-    // and start next epoch
-    if let Some((price, _)) = price_tally.iter().find(|(_, &count)| count >= threshold) {
+        let price = config.price_aggregation.aggregate(&prices);
+        if let Some(quorum) = degraded_oracle_quorum {
+            tracing::warn!(
+                epoch_id = expected_epoch_end_id.0,
+                quorum,
+                "settling epoch with degraded quorum as peers are offline"
+            );
+        }
         // the `expected_epoch_end_id` is now the actual `epoch_end_id`
-        let epoch_end_id = expected_epoch_end_id;
+        settle_epoch(
+            dbtx,
+            config,
+            expected_epoch_end_id,
+            price,
+            false,
+            degraded_oracle_quorum,
+            settled_at,
+        )
+        .await;
+    }
+}
 
-        db::set(dbtx, &db::LastEpochSettledKey, &epoch_end_id).await;
+/// Provide our operator's force settlement while it is still applicable and we
+/// have not yet voted for it.
+pub async fn force_settle_proposal(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    proposal: &ForceSettleProposal,
+    our_peer_id: PeerId,
+) -> Vec<PoolConsensusItem> {
+    let force_settle = match proposal.get() {
+        Some(force_settle) => force_settle,
+        None => return vec![],
+    };
 
-        // save price in epoch outcome and return epoch outcome
-        let epoch_outcome = {
-            let mut epoch_outcome = db::get(dbtx, &db::EpochOutcomeKey(epoch_end_id))
-                .await
-                // return dummy outcome for the very first epoch
-                .unwrap_or(EpochOutcome {
-                    feerate: EpochFeerate::from_ppm_feerate(0),
-                    total_seeker_locked: 0,
-                    total_provider_locked: 0,
-                    settled_price: None,
-                });
-            let _old_price = epoch_outcome.settled_price.replace(*price);
-            assert_eq!(_old_price, None);
-            db::set(dbtx, &db::EpochOutcomeKey(epoch_end_id), &epoch_outcome).await;
-            epoch_outcome
-        };
-
-        let previous_seeker_payouts =
-            settle_locked_balances(dbtx, epoch_end_id, epoch_outcome).await;
-
-        let current_balances = dbtx
-            .find_by_prefix(&db::AccountBalanceKeyPrefix)
-            .await
-            .map(|(k, v)| (k.0, v.unlocked.msats))
-            .collect::<BTreeMap<_, _>>()
-            .await;
+    let epoch_state = EpochState::from_db(dbtx).await;
+    if epoch_state.is_settled() || epoch_state.latest_ended != Some(force_settle.epoch_id) {
+        proposal.clear();
+        return vec![];
+    }
 
-        let mut seeker_actions = Vec::<Action<SeekerAction>>::new();
-        let mut provider_actions = Vec::<Action<ProviderBid>>::new();
-        dbtx.find_by_prefix(&db::ActionStagedKeyPrefix)
-            .await
-            .collect::<Vec<_>>()
-            .await
-            .into_iter()
-            .for_each(|(_, action)| match action {
-                ActionStaged::Seeker(a) => seeker_actions.push(a),
-                ActionStaged::Provider(a) => provider_actions.push(a),
-            });
-
-        // Seeker actions are applied once and removed, whereas provider actions are
-        // reused.
-        for seeker_id in seeker_actions.iter().map(|a| a.account_id) {
-            db::pop(dbtx, &db::ActionStagedKey(seeker_id)).await;
-        }
-
-        let (seeker_locks, provider_bids) = stability_core::compute_desired_positions(
-            &current_balances,
-            previous_seeker_payouts,
-            seeker_actions,
-            provider_actions,
+    if db::get(dbtx, &db::ForceSettleKey(our_peer_id)).await == Some(force_settle) {
+        return vec![];
+    }
+
+    vec![force_settle.into()]
+}
+
+pub async fn process_force_settle(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &EpochConfig,
+    peer_id: PeerId,
+    force_settle: ForceSettle,
+) -> ConsensusItemOutcome {
+    // only an epoch that has ended but is waiting on a price can be force settled
+    let epoch_state = EpochState::from_db(dbtx).await;
+    if epoch_state.is_settled() || epoch_state.latest_ended != Some(force_settle.epoch_id) {
+        return ConsensusItemOutcome::Ignored(format!(
+            "force_settle: epoch {} is not awaiting settlement",
+            force_settle.epoch_id
+        ));
+    }
+
+    db::set(dbtx, &db::ForceSettleKey(peer_id), &force_settle).await;
+
+    let count = dbtx
+        .find_by_prefix(&db::ForceSettleKeyPrefix)
+        .await
+        .map(|res| res.1)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .filter(|peer_force_settle| *peer_force_settle == force_settle)
+        .count();
+
+    if count >= config.price_threshold as usize {
+        tracing::warn!(
+            epoch_id = %force_settle.epoch_id,
+            price = force_settle.price,
+            "force settling epoch with operator supplied price"
         );
+        // the votes that ended the epoch agree on its time
+        let settled_at = match agreed_time(dbtx, force_settle.epoch_id).await {
+            Some(time) => time,
+            None => config
+                .settlement_time(force_settle.epoch_id)
+                .unix_timestamp() as u64,
+        };
+        settle_epoch(
+            dbtx,
+            config,
+            force_settle.epoch_id,
+            force_settle.price,
+            true,
+            None,
+            settled_at,
+        )
+        .await;
+        dbtx.remove_by_prefix(&db::ForceSettleKeyPrefix).await;
+    }
 
-        let (feerate, seeker_locked_balances, provider_locked_balances) =
-            stability_core::match_locks_and_bids(
-                seeker_locks.collect(),
-                provider_bids
-                    .filter(|bid| bid.min_feerate <= config.max_feerate_ppm)
-                    .collect(),
-                config.collateral_ratio,
-            );
+    ConsensusItemOutcome::Applied
+}
 
-        let mut total_seeker_locked = 0;
-        let mut total_provider_locked = 0;
+/// Digest of the state settling `epoch_id` left behind: its settled price, the
+/// outcome of the epoch it started and every account balance.
+pub async fn settlement_digest(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    epoch_id: EpochId,
+) -> SettlementDigest {
+    fn encode(bytes: &mut Vec<u8>, item: &impl Encodable) {
+        item.consensus_encode(bytes)
+            .expect("encoding to vec can't fail");
+    }
 
-        let locked_amounts_on_sides = seeker_locked_balances
-            .into_iter()
-            .map(|(k, a)| (k, LockedBalance::Seeker(fedimint_core::msats(a))))
-            .chain(
-                provider_locked_balances
-                    .into_iter()
-                    .map(|(k, a)| (k, LockedBalance::Provider(fedimint_core::msats(a)))),
-            );
+    let mut bytes = vec![];
+    encode(&mut bytes, &epoch_id);
+    // of the settled epoch's outcome only the price is hashed, as digests
+    // predate the agreed settlement time
+    let settled_price = db::get(dbtx, &db::EpochOutcomeKey(epoch_id))
+        .await
+        .and_then(|outcome| outcome.settled_price);
+    encode(&mut bytes, &settled_price);
+    let next_outcome = db::get(dbtx, &db::EpochOutcomeKey(epoch_id.next())).await;
+    encode(&mut bytes, &next_outcome);
+    let accounts = dbtx
+        .find_by_prefix(&db::AccountBalanceKeyPrefix)
+        .await
+        .collect::<Vec<_>>()
+        .await;
+    for (key, account) in accounts {
+        encode(&mut bytes, &key);
+        encode(&mut bytes, &account);
+    }
 
-        for (account_id, locked_amount) in locked_amounts_on_sides {
-            let current_balance = *current_balances
-                .get(&account_id)
-                .expect("there can not be a position if they have no balance");
-            assert!(locked_amount.amount().msats <= current_balance);
-
-            db::set(
-                dbtx,
-                &db::AccountBalanceKey(account_id),
-                &AccountBalance {
-                    unlocked: fedimint_core::msats(current_balance - locked_amount.amount().msats),
-                    locked: locked_amount,
-                },
-            )
-            .await;
+    SettlementDigest {
+        epoch_id,
+        digest: sha256::Hash::hash(&bytes),
+    }
+}
 
-            match locked_amount {
-                LockedBalance::Seeker(a) => total_seeker_locked += a.msats,
-                LockedBalance::Provider(a) => total_provider_locked += a.msats,
-                LockedBalance::None => unreachable!("this is not possible"),
-            }
+/// Our digest of the latest settlement, proposed until our own submission of
+/// it has been processed.
+pub async fn settlement_digest_proposal(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &EpochConfig,
+    peer_id: PeerId,
+) -> Vec<PoolConsensusItem> {
+    if !config.settlement_digests {
+        return vec![];
+    }
+
+    let digest = match db::get(dbtx, &db::SettlementDigestKey).await {
+        Some(digest) => digest,
+        None => return vec![],
+    };
+    if db::get(dbtx, &db::PeerSettlementDigestKey(peer_id)).await == Some(digest) {
+        return vec![];
+    }
+    vec![PoolConsensusItem::SettlementDigest(digest)]
+}
+
+pub async fn process_settlement_digest(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    peer_id: PeerId,
+    digest: SettlementDigest,
+) -> ConsensusItemOutcome {
+    let latest_settled = EpochState::from_db(dbtx).await.latest_settled;
+    if latest_settled != Some(digest.epoch_id) {
+        return ConsensusItemOutcome::Ignored(format!(
+            "settlement_digest: epoch {} is not the latest settled epoch",
+            digest.epoch_id
+        ));
+    }
+
+    db::set(dbtx, &db::PeerSettlementDigestKey(peer_id), &digest).await;
+
+    if let Some(ours) = db::get(dbtx, &db::SettlementDigestKey).await {
+        if ours.epoch_id == digest.epoch_id && ours.digest != digest.digest {
+            tracing::error!(
+                peer = peer_id.to_usize(),
+                epoch_id = %digest.epoch_id,
+                ours = %ours.digest,
+                theirs = %digest.digest,
+                "SETTLEMENT DIVERGENCE: peer settled the epoch to a different state"
+            );
         }
+    }
 
-        // START EPOCH
+    ConsensusItemOutcome::Applied
+}
+
+/// Unlocked balance (msats) of every account.
+pub async fn unlocked_balances(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+) -> BTreeMap<XOnlyPublicKey, u64> {
+    dbtx.find_by_prefix(&db::AccountBalanceKeyPrefix)
+        .await
+        .map(|(k, v)| (k.0, v.unlocked.msats))
+        .collect::<BTreeMap<_, _>>()
+        .await
+}
+
+/// Staged actions split by side, which are matched when the current epoch
+/// settles.
+pub async fn staged_actions(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+) -> (Vec<Action<SeekerAction>>, Vec<Action<ProviderBid>>) {
+    let mut seeker_actions = Vec::<Action<SeekerAction>>::new();
+    let mut provider_actions = Vec::<Action<ProviderBid>>::new();
+    dbtx.find_by_prefix(&db::ActionStagedKeyPrefix)
+        .await
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .for_each(|(_, action)| match action {
+            ActionStaged::Seeker(a) => seeker_actions.push(a),
+            ActionStaged::Provider(a) => provider_actions.push(a),
+        });
+    (seeker_actions, provider_actions)
+}
+
+/// Settle the epoch `epoch_end_id` at `price`, pay out its locked balances and
+/// start the next epoch by matching staged actions. `forced` records whether
+/// the price was supplied by operators through [`ForceSettle`], and
+/// `degraded_quorum` whether it was agreed by fewer peers than usual.
+async fn settle_epoch(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &EpochConfig,
+    epoch_end_id: EpochId,
+    price: u64,
+    forced: bool,
+    degraded_quorum: Option<u32>,
+    settled_at: u64,
+) {
+    db::set(dbtx, &db::LastEpochSettledKey, &epoch_end_id).await;
+
+    // save price in epoch outcome and return epoch outcome
+    let epoch_outcome = {
+        let mut epoch_outcome = db::get(dbtx, &db::EpochOutcomeKey(epoch_end_id))
+            .await
+            // return dummy outcome for the very first epoch
+            .unwrap_or_default();
+        let _old_price = epoch_outcome.settled_price.replace(price);
+        assert_eq!(_old_price, None);
+        epoch_outcome.forced = forced;
+        epoch_outcome.degraded_quorum = degraded_quorum;
+        // votes carry unsigned times, which only exceed an `i64` if a
+        // majority of clocks is billions of years off
+        epoch_outcome.settled_at = Some(i64::try_from(settled_at).unwrap_or(i64::MAX));
+        db::set(dbtx, &db::EpochOutcomeKey(epoch_end_id), &epoch_outcome).await;
+        epoch_outcome
+    };
+
+    let previous_seeker_payouts =
+        settle_locked_balances(dbtx, config, epoch_end_id, epoch_outcome).await;
+
+    // once the pool is closed this is the final settlement: payouts are not
+    // relocked and staged actions, standing bids included, are dropped
+    let previous_seeker_payouts = if governance::is_closed(dbtx).await {
+        dbtx.remove_by_prefix(&db::ActionStagedKeyPrefix).await;
+        dbtx.remove_by_prefix(&db::ActionSignedKeyPrefix).await;
+        BTreeMap::new()
+    } else {
+        previous_seeker_payouts
+    };
+
+    let current_balances = unlocked_balances(dbtx).await;
+    let (seeker_actions, provider_actions) = staged_actions(dbtx).await;
+
+    // Seeker actions are applied once and removed, whereas provider actions are
+    // reused.
+    for seeker_id in seeker_actions.iter().map(|a| a.account_id) {
+        db::pop(dbtx, &db::ActionStagedKey(seeker_id)).await;
+        db::pop(dbtx, &db::ActionSignedKey(seeker_id)).await;
+    }
+    let seeker_actions = reject_early_unlocks(dbtx, config, epoch_end_id, seeker_actions).await;
+
+    let (seeker_locks, provider_bids) = stability_core::compute_desired_positions(
+        &current_balances,
+        previous_seeker_payouts,
+        seeker_actions,
+        provider_actions,
+    );
+
+    let seeker_locks = seeker_locks.collect();
+    let provider_bids = provider_bids.collect::<Vec<_>>();
+    let provider_bids = governance::bids_within_feerate_cap(dbtx, config, provider_bids).await;
+    let provider_bids =
+        stability_core::bids_above_feerate_floor(provider_bids, config.min_feerate_ppm);
+
+    let (feerate, seeker_locked_balances, provider_locked_balances) =
+        stability_core::match_locks_and_bids(seeker_locks, provider_bids, config.collateral_ratio);
+
+    let mut total_seeker_locked = 0;
+    let mut total_provider_locked = 0;
+    let mut locked_seekers = BTreeSet::new();
+    let num_seekers = seeker_locked_balances.len() as u64;
+    let num_providers = provider_locked_balances.len() as u64;
+
+    let locked_amounts_on_sides = seeker_locked_balances
+        .into_iter()
+        .map(|(k, a)| (k, LockedBalance::Seeker(fedimint_core::msats(a))))
+        .chain(
+            provider_locked_balances
+                .into_iter()
+                .map(|(k, a)| (k, LockedBalance::Provider(fedimint_core::msats(a)))),
+        );
+
+    for (account_id, locked_amount) in locked_amounts_on_sides {
+        let current_balance = *current_balances
+            .get(&account_id)
+            .expect("there can not be a position if they have no balance");
+        assert!(locked_amount.amount().msats <= current_balance);
+
+        let account = db::get(dbtx, &db::AccountBalanceKey(account_id))
+            .await
+            .unwrap_or_default();
         db::set(
             dbtx,
-            &db::EpochOutcomeKey(epoch_end_id + 1),
-            &EpochOutcome {
-                feerate,
-                settled_price: None,
-                total_seeker_locked,
-                total_provider_locked,
+            &db::AccountBalanceKey(account_id),
+            &AccountBalance {
+                unlocked: fedimint_core::msats(current_balance - locked_amount.amount().msats),
+                locked: locked_amount,
+                ..account
             },
         )
         .await;
+        db::set(
+            dbtx,
+            &db::AccountEpochKey(account_id, epoch_end_id.next()),
+            &locked_amount,
+        )
+        .await;
+
+        match locked_amount {
+            LockedBalance::Seeker(a) => {
+                total_seeker_locked += a.msats;
+                locked_seekers.insert(account_id);
+            }
+            LockedBalance::Provider(a) => {
+                total_provider_locked += a.msats;
+                let key = db::ProviderLockedKey(epoch_end_id.next(), account_id);
+                db::set(dbtx, &key, &a.msats).await;
+            }
+            LockedBalance::None => unreachable!("this is not possible"),
+        }
+    }
+
+    // positions that stay locked keep the epoch they were first locked in
+    let locked_since = dbtx
+        .find_by_prefix(&db::SeekerLockedSinceKeyPrefix)
+        .await
+        .map(|(key, epoch_id)| (key.0, epoch_id))
+        .collect::<BTreeMap<_, _>>()
+        .await;
+    for account_id in locked_since.keys() {
+        if !locked_seekers.contains(account_id) {
+            db::pop(dbtx, &db::SeekerLockedSinceKey(*account_id)).await;
+        }
+    }
+    for account_id in locked_seekers {
+        if !locked_since.contains_key(&account_id) {
+            let key = db::SeekerLockedSinceKey(account_id);
+            db::set(dbtx, &key, &epoch_end_id.next()).await;
+        }
     }
 
-    ConsensusItemOutcome::Applied
+    // START EPOCH
+    db::set(
+        dbtx,
+        &db::EpochOutcomeKey(epoch_end_id.next()),
+        &EpochOutcome {
+            feerate,
+            settled_price: None,
+            total_seeker_locked,
+            total_provider_locked,
+            forced: false,
+            num_seekers,
+            num_providers,
+            settled_at: None,
+            degraded_quorum: None,
+        },
+    )
+    .await;
+
+    if config.settlement_digests {
+        let digest = settlement_digest(dbtx, epoch_end_id).await;
+        db::set(dbtx, &db::SettlementDigestKey, &digest).await;
+    }
+}
+
+/// Drop the unlocks of seeker positions that have not been locked for
+/// `min_lock_epochs` epochs once `epoch_id` settles, so that they stay locked.
+async fn reject_early_unlocks(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &EpochConfig,
+    epoch_id: EpochId,
+    seeker_actions: Vec<Action<SeekerAction>>,
+) -> Vec<Action<SeekerAction>> {
+    let min_lock_epochs = match config.min_lock_epochs {
+        Some(min_lock_epochs) => min_lock_epochs,
+        None => return seeker_actions,
+    };
+
+    let mut accepted = Vec::with_capacity(seeker_actions.len());
+    for action in seeker_actions {
+        if let SeekerAction::Unlock { .. } = action.body {
+            let key = db::SeekerLockedSinceKey(action.account_id);
+            if let Some(locked_since) = db::get(dbtx, &key).await {
+                // the position was locked up to and including `epoch_id`
+                let locked_epochs = (epoch_id.0 + 1).saturating_sub(locked_since.0);
+                if locked_epochs < min_lock_epochs {
+                    tracing::info!(
+                        account = %action.account_id,
+                        locked_epochs,
+                        min_lock_epochs,
+                        "rejecting unlock of a position locked too recently"
+                    );
+                    continue;
+                }
+            }
+        }
+        accepted.push(action);
+    }
+    accepted
 }
 
 /// Calculate payouts from this epoch's positions and unlock these payouts into
@@ -378,10 +1158,11 @@ pub async fn process_consensus_item(
 /// relock in the next epoch.
 async fn settle_locked_balances(
     dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
-    epoch_id: u64,
+    config: &EpochConfig,
+    epoch_id: EpochId,
     epoch_outcome: EpochOutcome,
 ) -> BTreeMap<XOnlyPublicKey, u64> {
-    let prev_epoch_id = match epoch_id.checked_sub(1) {
+    let prev_epoch_id = match epoch_id.prev() {
         Some(prev_epoch_id) => prev_epoch_id,
         // there is no previous epoch, so no start_price and nothing to settle
         None => return BTreeMap::new(),
@@ -422,6 +1203,20 @@ async fn settle_locked_balances(
         .collect::<Vec<_>>()
         .await;
 
+    let num_seekers = seeker_entries.len();
+    let num_providers = provider_entries.len();
+    let total_seeker_locked = seeker_entries.values().sum::<u64>();
+    let total_provider_locked = provider_entries.values().sum::<u64>();
+
+    let fees = EpochFees {
+        seeker_fees_msat: seeker_entries
+            .values()
+            .map(|locked| stability_core::seeker_fee(feerate, *locked))
+            .sum(),
+        provider_fees_msat: stability_core::seeker_fee(feerate, total_seeker_locked),
+    };
+    db::set(dbtx, &db::EpochFeesKey(epoch_id), &fees).await;
+
     // calculate payouts from account positions (entries) and price change
     let (seeker_payouts, provider_payouts) = stability_core::calculate_payouts(
         feerate,
@@ -438,9 +1233,972 @@ async fn settle_locked_balances(
         let new_balance = AccountBalance {
             locked: LockedBalance::None,
             unlocked: old_balance.unlocked + fedimint_core::msats(*payout_amount),
+            ..old_balance
         };
         db::set(dbtx, &db_key, &new_balance).await;
+
+        let change = BalanceChange {
+            account: *account_id,
+            reason: BalanceChangeReason::Settlement,
+            epoch_id,
+            credit: fedimint_core::msats(*payout_amount),
+            debit: old_balance.locked.amount(),
+        };
+        log_balance_change(dbtx, config.balance_change_retention, change).await;
     }
 
+    // one line for operators to follow how value moved between the sides
+    let pnl = |payouts: &BTreeMap<XOnlyPublicKey, u64>, locked: u64| {
+        payouts.values().sum::<u64>() as i64 - locked as i64
+    };
+    tracing::info!(
+        epoch_id = epoch_id.0,
+        seeker_pnl_msat = pnl(&seeker_payouts, total_seeker_locked),
+        provider_pnl_msat = pnl(&provider_payouts, total_provider_locked),
+        fees_msat = fees.provider_fees_msat,
+        feerate_ppm = %feerate.ppm_feerate_decimal(),
+        num_seekers,
+        num_providers,
+        "epoch settled"
+    );
+
     seeker_payouts
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balance_changes;
+    use crate::config::PriceAggregation;
+
+    fn random_pubkey() -> XOnlyPublicKey {
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        let (_, public_key) = secp.generate_keypair(&mut rand::thread_rng());
+        public_key.x_only_public_key().0
+    }
+
+    async fn fund_account(
+        dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+        account_id: XOnlyPublicKey,
+        msats: u64,
+    ) {
+        let balance = AccountBalance {
+            unlocked: fedimint_core::msats(msats),
+            locked: LockedBalance::None,
+            ..Default::default()
+        };
+        db::set(dbtx, &db::AccountBalanceKey(account_id), &balance).await;
+    }
+
+    async fn stage(
+        dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+        action: ActionStaged,
+    ) {
+        db::set(dbtx, &db::ActionStagedKey(action.account_id()), &action).await;
+    }
+
+    fn epoch_config(price_threshold: u32) -> EpochConfig {
+        EpochConfig {
+            price_threshold,
+            ..Default::default()
+        }
+    }
+
+    #[derive(Debug)]
+    struct FixedOracle(u64);
+
+    #[async_trait::async_trait]
+    impl OracleClient for FixedOracle {
+        async fn price_at_time(&self, _datetime: OffsetDateTime) -> anyhow::Result<u64> {
+            Ok(self.0)
+        }
+    }
+
+    #[test]
+    fn history_entries_sort_by_epoch() {
+        use rand::seq::SliceRandom;
+
+        let entries: Vec<_> = (0..20)
+            .map(|epoch| EpochHistoryEntry {
+                epoch_id: EpochId(epoch),
+                outcome: EpochOutcome {
+                    // prices against epoch order so sorting by them would fail
+                    settled_price: Some(3_000_000 - epoch * 1_000),
+                    ..Default::default()
+                },
+            })
+            .collect();
+        let mut shuffled = entries.clone();
+        shuffled.shuffle(&mut rand::thread_rng());
+        shuffled.sort();
+        assert_eq!(shuffled, entries);
+    }
+
+    #[test]
+    fn epoch_id_is_a_plain_number() {
+        assert_eq!(serde_json::to_string(&EpochId(7)).unwrap(), "7");
+        assert_eq!(serde_json::from_str::<EpochId>("7").unwrap(), EpochId(7));
+        assert_eq!(
+            EpochId(7).consensus_encode_to_vec().unwrap(),
+            7_u64.consensus_encode_to_vec().unwrap()
+        );
+
+        assert_eq!(EpochId(7).next(), EpochId(8));
+        assert_eq!(EpochId(7).prev(), Some(EpochId(6)));
+        assert_eq!(EpochId(0).prev(), None);
+        assert_eq!(EpochId(0).saturating_prev(), EpochId(0));
+    }
+
+    #[tokio::test]
+    async fn force_settle_requires_quorum() {
+        test_dbtx!(dbtx);
+        let config = epoch_config(2);
+
+        // epoch 0 ended without a price
+        db::set(&mut dbtx, &db::LastEpochEndedKey, &EpochId(0)).await;
+
+        let force_settle = ForceSettle {
+            epoch_id: EpochId(0),
+            price: 2_000_000,
+        };
+        let outcome = process_force_settle(&mut dbtx, &config, PeerId::from(0), force_settle).await;
+        assert_eq!(outcome, ConsensusItemOutcome::Applied);
+        assert!(!EpochState::from_db(&mut dbtx).await.is_settled());
+
+        // a different price does not count towards the quorum
+        let other = ForceSettle {
+            price: 1_000_000,
+            ..force_settle
+        };
+        process_force_settle(&mut dbtx, &config, PeerId::from(1), other).await;
+        assert!(!EpochState::from_db(&mut dbtx).await.is_settled());
+
+        process_force_settle(&mut dbtx, &config, PeerId::from(1), force_settle).await;
+        assert!(EpochState::from_db(&mut dbtx).await.is_settled());
+
+        let epoch_outcome = db::get(&mut dbtx, &db::EpochOutcomeKey(EpochId(0)))
+            .await
+            .unwrap();
+        assert_eq!(epoch_outcome.settled_price, Some(2_000_000));
+        assert!(epoch_outcome.forced);
+
+        // the epoch is no longer stuck so further votes are ignored
+        let outcome = process_force_settle(&mut dbtx, &config, PeerId::from(2), force_settle).await;
+        assert!(matches!(outcome, ConsensusItemOutcome::Ignored(_)));
+    }
+
+    #[tokio::test]
+    async fn settlement_counts_participants() {
+        test_dbtx!(dbtx);
+        let config = epoch_config(1);
+        db::set(&mut dbtx, &db::LastEpochEndedKey, &EpochId(0)).await;
+
+        for _ in 0..3 {
+            let account_id = random_pubkey();
+            fund_account(&mut dbtx, account_id, 1_000).await;
+            let lock = Action {
+                epoch_id: EpochId(1),
+                sequence: 0,
+                account_id,
+                body: SeekerAction::Lock {
+                    amount: fedimint_core::msats(1_000),
+                },
+            };
+            stage(&mut dbtx, ActionStaged::Seeker(lock)).await;
+        }
+        for _ in 0..2 {
+            let account_id = random_pubkey();
+            fund_account(&mut dbtx, account_id, 10_000).await;
+            let bid = Action {
+                epoch_id: EpochId(1),
+                sequence: 0,
+                account_id,
+                body: ProviderBid {
+                    min_feerate: 0,
+                    max_amount: fedimint_core::msats(10_000),
+                },
+            };
+            stage(&mut dbtx, ActionStaged::Provider(bid)).await;
+        }
+
+        let now = OffsetDateTime::now_utc().unix_timestamp() as u64;
+        settle_epoch(&mut dbtx, &config, EpochId(0), 2_000_000, false, None, now).await;
+
+        let epoch_outcome = db::get(&mut dbtx, &db::EpochOutcomeKey(EpochId(1)))
+            .await
+            .unwrap();
+        assert_eq!(epoch_outcome.num_seekers, 3);
+        assert_eq!(epoch_outcome.num_providers, 1);
+    }
+
+    #[tokio::test]
+    async fn settlement_applies_feerate_floor() {
+        test_dbtx!(dbtx);
+        let config = EpochConfig {
+            min_feerate_ppm: 5_000,
+            ..epoch_config(1)
+        };
+        db::set(&mut dbtx, &db::LastEpochEndedKey, &EpochId(0)).await;
+
+        let seeker = random_pubkey();
+        fund_account(&mut dbtx, seeker, 1_000_000).await;
+        let lock = Action {
+            epoch_id: EpochId(1),
+            sequence: 0,
+            account_id: seeker,
+            body: SeekerAction::Lock {
+                amount: fedimint_core::msats(1_000_000),
+            },
+        };
+        stage(&mut dbtx, ActionStaged::Seeker(lock)).await;
+        // far more collateral than needed, offered for free
+        let provider = random_pubkey();
+        fund_account(&mut dbtx, provider, 10_000_000).await;
+        let bid = Action {
+            epoch_id: EpochId(1),
+            sequence: 0,
+            account_id: provider,
+            body: ProviderBid {
+                min_feerate: 0,
+                max_amount: fedimint_core::msats(10_000_000),
+            },
+        };
+        stage(&mut dbtx, ActionStaged::Provider(bid)).await;
+
+        let now = OffsetDateTime::now_utc().unix_timestamp() as u64;
+        settle_epoch(&mut dbtx, &config, EpochId(0), 2_000_000, false, None, now).await;
+
+        let outcome = db::get(&mut dbtx, &db::EpochOutcomeKey(EpochId(1)))
+            .await
+            .unwrap();
+        assert_eq!(outcome.feerate.approx_ppm_feerate(), 5_000);
+        assert_eq!(outcome.num_seekers, 1);
+        assert_eq!(outcome.num_providers, 1);
+    }
+
+    #[tokio::test]
+    async fn settlement_records_timestamp() {
+        test_dbtx!(dbtx);
+        let config = EpochConfig {
+            start_epoch_at: 1_000,
+            ..epoch_config(1)
+        };
+        db::set(&mut dbtx, &db::LastEpochEndedKey, &EpochId(2)).await;
+        db::set(&mut dbtx, &db::LastEpochSettledKey, &EpochId(1)).await;
+
+        let epoch_end = EpochEnd {
+            price: Some(2_000_000),
+            epoch_id: EpochId(2),
+            time: 1_700_000_000,
+        };
+        process_consensus_item(&mut dbtx, &config, PeerId::from(0), epoch_end).await;
+
+        // the epoch settles at the time of the vote that settled it
+        let settled_at = db::get(&mut dbtx, &db::EpochOutcomeKey(EpochId(2)))
+            .await
+            .unwrap()
+            .settled_at;
+        assert_eq!(settled_at, Some(1_700_000_000));
+
+        // the next epoch is only started, not settled
+        let next = db::get(&mut dbtx, &db::EpochOutcomeKey(EpochId(3)))
+            .await
+            .unwrap();
+        assert_eq!(next.settled_at, None);
+    }
+
+    #[tokio::test]
+    async fn skewed_clocks_agree_on_settlement() {
+        let config = EpochConfig {
+            start_epoch_at: 10_000,
+            ..epoch_config(3)
+        };
+        let backoff = BackOff::default();
+        let oracle = FixedOracle(2_000_000);
+        let clock = |time| OffsetDateTime::from_unix_timestamp(time).unwrap();
+
+        // epoch 2 is due at 10_080. Peer 0 runs an hour ahead and votes while
+        // a lagging clock still says epoch 1 is running
+        let mut votes = vec![];
+        for (peer, time) in [(0, 13_600), (1, 10_080), (2, 10_084)] {
+            test_dbtx!(dbtx);
+            db::set(&mut dbtx, &db::LastEpochEndedKey, &EpochId(1)).await;
+            db::set(&mut dbtx, &db::LastEpochSettledKey, &EpochId(1)).await;
+            assert!(!can_propose(&mut dbtx, &backoff, &config, clock(10_039)).await);
+            assert!(can_propose(&mut dbtx, &backoff, &config, clock(time)).await);
+            let proposal =
+                consensus_proposal(&mut dbtx, &backoff, &config, &oracle, clock(time)).await;
+            match proposal.as_slice() {
+                [PoolConsensusItem::EpochEnd(epoch_end)] => votes.push((peer, *epoch_end)),
+                _ => panic!("expected an epoch end vote, got {:?}", proposal),
+            }
+        }
+
+        // every peer sees the same votes and settles at the same time, whatever
+        // its own clock says
+        let mut outcomes = vec![];
+        for _ in 0..2 {
+            test_dbtx!(dbtx);
+            db::set(&mut dbtx, &db::LastEpochEndedKey, &EpochId(1)).await;
+            db::set(&mut dbtx, &db::LastEpochSettledKey, &EpochId(1)).await;
+            for &(peer, epoch_end) in &votes {
+                assert_eq!(
+                    db::get(&mut dbtx, &db::LastEpochSettledKey).await,
+                    Some(EpochId(1)),
+                    "epoch settled before the threshold of votes"
+                );
+                process_consensus_item(&mut dbtx, &config, PeerId::from(peer), epoch_end).await;
+            }
+            outcomes.push(db::get(&mut dbtx, &db::EpochOutcomeKey(EpochId(2))).await);
+        }
+        assert_eq!(outcomes[0], outcomes[1]);
+        assert_eq!(outcomes[0].as_ref().unwrap().settled_at, Some(10_084));
+    }
+
+    #[tokio::test]
+    async fn epoch_ends_once_votes_agree_it_is_due() {
+        test_dbtx!(dbtx);
+        let config = EpochConfig {
+            start_epoch_at: 10_000,
+            ..epoch_config(2)
+        };
+        db::set(&mut dbtx, &db::LastEpochEndedKey, &EpochId(1)).await;
+        db::set(&mut dbtx, &db::LastEpochSettledKey, &EpochId(1)).await;
+        let vote = |time| EpochEnd {
+            price: None,
+            epoch_id: EpochId(2),
+            time,
+        };
+
+        // epoch 2 is due at 10_040. The threshold is met by a peer running an
+        // hour ahead and a vote from before the epoch was due
+        process_consensus_item(&mut dbtx, &config, PeerId::from(0), vote(13_600)).await;
+        process_consensus_item(&mut dbtx, &config, PeerId::from(1), vote(10_039)).await;
+        assert_eq!(
+            db::get(&mut dbtx, &db::LastEpochEndedKey).await,
+            Some(EpochId(1))
+        );
+
+        process_consensus_item(&mut dbtx, &config, PeerId::from(2), vote(10_041)).await;
+        assert_eq!(
+            db::get(&mut dbtx, &db::LastEpochEndedKey).await,
+            Some(EpochId(2))
+        );
+    }
+
+    #[tokio::test]
+    async fn lagging_clock_follows_agreed_time() {
+        test_dbtx!(dbtx);
+        // four peers tolerate one faulty one, so three votes are followed
+        let config = EpochConfig {
+            start_epoch_at: 10_000,
+            num_peers: Some(4),
+            ..epoch_config(4)
+        };
+        let backoff = BackOff::default();
+        let oracle = FixedOracle(2_000_000);
+        let clock = |time| OffsetDateTime::from_unix_timestamp(time).unwrap();
+        db::set(&mut dbtx, &db::LastEpochEndedKey, &EpochId(1)).await;
+        db::set(&mut dbtx, &db::LastEpochSettledKey, &EpochId(1)).await;
+
+        // our clock lags behind the other peers, which voted for epoch 2
+        for (peer, time) in [(1, 10_050), (2, 10_060)] {
+            let vote = EpochEnd {
+                price: None,
+                epoch_id: EpochId(2),
+                time,
+            };
+            process_consensus_item(&mut dbtx, &config, PeerId::from(peer), vote).await;
+        }
+        assert!(!can_propose(&mut dbtx, &backoff, &config, clock(10_030)).await);
+
+        let vote = EpochEnd {
+            price: None,
+            epoch_id: EpochId(2),
+            time: 10_070,
+        };
+        process_consensus_item(&mut dbtx, &config, PeerId::from(3), vote).await;
+        assert!(can_propose(&mut dbtx, &backoff, &config, clock(10_030)).await);
+        let proposal =
+            consensus_proposal(&mut dbtx, &backoff, &config, &oracle, clock(10_030)).await;
+        assert_eq!(
+            proposal,
+            vec![EpochEnd {
+                price: None,
+                epoch_id: EpochId(2),
+                time: 10_030,
+            }
+            .into()]
+        );
+    }
+
+    #[tokio::test]
+    async fn repeated_epoch_end_is_ignored_and_new_price_replaces_vote() {
+        test_dbtx!(dbtx);
+        let config = epoch_config(2);
+        let peer = PeerId::from(0);
+
+        // ending the epoch without a price, then supplying one, is accepted
+        let no_price = EpochEnd {
+            price: None,
+            epoch_id: EpochId(0),
+            time: 0,
+        };
+        let outcome = process_consensus_item(&mut dbtx, &config, peer, no_price).await;
+        assert!(matches!(outcome, ConsensusItemOutcome::Applied));
+        let outcome = process_consensus_item(&mut dbtx, &config, peer, no_price).await;
+        assert!(matches!(outcome, ConsensusItemOutcome::Ignored(_)));
+
+        let priced = EpochEnd {
+            price: Some(2_000_000),
+            epoch_id: EpochId(0),
+            time: 0,
+        };
+        let outcome = process_consensus_item(&mut dbtx, &config, peer, priced).await;
+        assert!(matches!(outcome, ConsensusItemOutcome::Applied));
+
+        // the same vote again is ignored
+        let outcome = process_consensus_item(&mut dbtx, &config, peer, priced).await;
+        assert!(matches!(outcome, ConsensusItemOutcome::Ignored(_)));
+
+        // the oracle moved while waiting for quorum: the new price replaces
+        // the vote rather than getting the peer banned
+        let repriced = EpochEnd {
+            price: Some(2_100_000),
+            epoch_id: EpochId(0),
+            time: 1,
+        };
+        let outcome = process_consensus_item(&mut dbtx, &config, peer, repriced).await;
+        assert!(matches!(outcome, ConsensusItemOutcome::Applied));
+        let vote = db::get(&mut dbtx, &db::EpochEndKey(peer)).await.unwrap();
+        assert_eq!(vote, repriced);
+        assert_eq!(
+            db::get(&mut dbtx, &db::LastEpochSettledKey).await,
+            None,
+            "a replaced vote is still one vote"
+        );
+    }
+
+    async fn end_epoch(
+        dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+        config: &EpochConfig,
+        peer: u16,
+        price: u64,
+    ) {
+        let epoch_end = EpochEnd {
+            price: Some(price),
+            epoch_id: EpochId(0),
+            time: 0,
+        };
+        let outcome = process_consensus_item(dbtx, config, PeerId::from(peer), epoch_end).await;
+        assert!(matches!(outcome, ConsensusItemOutcome::Applied));
+    }
+
+    #[tokio::test]
+    async fn oracle_quorum_defaults_to_price_threshold() {
+        test_dbtx!(dbtx);
+        let config = epoch_config(2);
+        assert_eq!(config.oracle_quorum(), 2);
+
+        end_epoch(&mut dbtx, &config, 0, 2_000_000).await;
+        end_epoch(&mut dbtx, &config, 1, 2_000_000).await;
+        assert_eq!(
+            db::get(&mut dbtx, &db::LastEpochSettledKey).await,
+            Some(EpochId(0))
+        );
+    }
+
+    #[tokio::test]
+    async fn oracle_quorum_above_price_threshold_delays_settlement() {
+        test_dbtx!(dbtx);
+        let config = EpochConfig {
+            oracle_quorum: Some(3),
+            ..epoch_config(2)
+        };
+
+        // two peers are enough to end the epoch but not to settle it
+        end_epoch(&mut dbtx, &config, 0, 2_000_000).await;
+        end_epoch(&mut dbtx, &config, 1, 2_000_000).await;
+        assert_eq!(
+            db::get(&mut dbtx, &db::LastEpochEndedKey).await,
+            Some(EpochId(0))
+        );
+        assert_eq!(db::get(&mut dbtx, &db::LastEpochSettledKey).await, None);
+
+        // the third price reaches the quorum, and the median leaves out the
+        // outlier
+        end_epoch(&mut dbtx, &config, 2, 2_100_000).await;
+        assert_eq!(
+            db::get(&mut dbtx, &db::LastEpochSettledKey).await,
+            Some(EpochId(0))
+        );
+        let outcome = db::get(&mut dbtx, &db::EpochOutcomeKey(EpochId(0)))
+            .await
+            .unwrap();
+        assert_eq!(outcome.settled_price, Some(2_000_000));
+    }
+
+    #[tokio::test]
+    async fn settled_price_follows_aggregation_policy() {
+        let prices = [1_000, 2_000_000, 2_000_100, 2_000_500, 9_000_000];
+        for (price_aggregation, settled_price) in [
+            (PriceAggregation::Median, 2_000_100),
+            (PriceAggregation::TrimmedMean, 2_000_200),
+            (PriceAggregation::Mean, 3_000_320),
+        ] {
+            test_dbtx!(dbtx);
+            let config = EpochConfig {
+                price_aggregation,
+                ..epoch_config(5)
+            };
+
+            for (peer, &price) in prices.iter().enumerate() {
+                end_epoch(&mut dbtx, &config, peer as u16, price).await;
+            }
+            let outcome = db::get(&mut dbtx, &db::EpochOutcomeKey(EpochId(0)))
+                .await
+                .unwrap();
+            assert_eq!(
+                outcome.settled_price,
+                Some(settled_price),
+                "{:?}",
+                price_aggregation
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn offline_peers_degrade_quorum() {
+        test_dbtx!(dbtx);
+        let config = epoch_config(3);
+
+        // epoch 4 is settled, so we are waiting on votes for epoch 5
+        db::set(&mut dbtx, &db::LastEpochEndedKey, &EpochId(4)).await;
+        db::set(&mut dbtx, &db::LastEpochSettledKey, &EpochId(4)).await;
+        let outcome = EpochOutcome {
+            settled_price: Some(2_000_000),
+            ..Default::default()
+        };
+        db::set(&mut dbtx, &db::EpochOutcomeKey(EpochId(4)), &outcome).await;
+
+        let policy = governance::DegradedQuorumPolicy {
+            offline_sessions: 2,
+            min_quorum: 1,
+        };
+        db::set(&mut dbtx, &db::DegradedQuorumPolicyKey, &policy).await;
+        // peer 2 was seen recently enough, only peer 3 counts as offline
+        db::set(&mut dbtx, &db::ConsensusSessionsKey, &5).await;
+        for (peer, last_seen) in [(0, 5), (1, 5), (2, 3), (3, 1)] {
+            let key = db::PeerLastSeenKey(PeerId::from(peer));
+            db::set(&mut dbtx, &key, &last_seen).await;
+        }
+
+        let epoch_end = EpochEnd {
+            epoch_id: EpochId(5),
+            price: Some(2_000_000),
+            time: config.settlement_time(EpochId(5)).unix_timestamp() as u64,
+        };
+        process_consensus_item(&mut dbtx, &config, PeerId::from(0), epoch_end).await;
+        assert_eq!(
+            db::get(&mut dbtx, &db::LastEpochEndedKey).await,
+            Some(EpochId(4))
+        );
+
+        process_consensus_item(&mut dbtx, &config, PeerId::from(1), epoch_end).await;
+        assert_eq!(
+            db::get(&mut dbtx, &db::LastEpochSettledKey).await,
+            Some(EpochId(5))
+        );
+        let outcome = db::get(&mut dbtx, &db::EpochOutcomeKey(EpochId(5)))
+            .await
+            .unwrap();
+        assert_eq!(outcome.degraded_quorum, Some(2));
+    }
+
+    #[tokio::test]
+    async fn settlement_price_waits_for_delay() {
+        test_dbtx!(dbtx);
+        let backoff = BackOff::default();
+        let oracle = FixedOracle(2_000_000);
+
+        // epoch 0 ended 100s ago
+        let now = OffsetDateTime::now_utc();
+        let time = now.unix_timestamp() as u64;
+        let delayed = EpochConfig {
+            start_epoch_at: time - 100,
+            settlement_delay_secs: 3_600,
+            ..epoch_config(1)
+        };
+        let undelayed = EpochConfig {
+            settlement_delay_secs: 0,
+            ..delayed.clone()
+        };
+
+        // the epoch is ended without a price while the delay runs
+        let proposal = consensus_proposal(&mut dbtx, &backoff, &delayed, &oracle, now).await;
+        assert_eq!(
+            proposal,
+            vec![EpochEnd {
+                price: None,
+                epoch_id: EpochId(0),
+                time,
+            }
+            .into()]
+        );
+        let proposal = consensus_proposal(&mut dbtx, &backoff, &undelayed, &oracle, now).await;
+        assert_eq!(
+            proposal,
+            vec![EpochEnd {
+                price: Some(2_000_000),
+                epoch_id: EpochId(0),
+                time,
+            }
+            .into()]
+        );
+
+        // once ended, nothing is proposed until the delay has elapsed
+        db::set(&mut dbtx, &db::LastEpochEndedKey, &EpochId(0)).await;
+        assert!(!can_propose(&mut dbtx, &backoff, &delayed, now).await);
+        assert!(
+            consensus_proposal(&mut dbtx, &backoff, &delayed, &oracle, now)
+                .await
+                .is_empty()
+        );
+
+        assert!(can_propose(&mut dbtx, &backoff, &undelayed, now).await);
+        let proposal = consensus_proposal(&mut dbtx, &backoff, &undelayed, &oracle, now).await;
+        assert_eq!(
+            proposal,
+            vec![EpochEnd {
+                price: Some(2_000_000),
+                epoch_id: EpochId(0),
+                time,
+            }
+            .into()]
+        );
+    }
+
+    #[tokio::test]
+    async fn early_votes_wait_out_settlement_delay() {
+        test_dbtx!(dbtx);
+        let config = EpochConfig {
+            settlement_delay_secs: 3_600,
+            ..epoch_config(2)
+        };
+        let settlement_time = config.settlement_time(EpochId(0)).unix_timestamp() as u64;
+        let vote = |time| EpochEnd {
+            price: Some(2_000_000),
+            epoch_id: EpochId(0),
+            time,
+        };
+
+        // peer 1's clock runs ahead, so it votes a price before the delay has
+        // passed. The threshold is met, but the votes do not agree the delay is
+        // over
+        process_consensus_item(&mut dbtx, &config, PeerId::from(0), vote(settlement_time)).await;
+        process_consensus_item(&mut dbtx, &config, PeerId::from(1), vote(60)).await;
+        assert_eq!(
+            db::get(&mut dbtx, &db::LastEpochEndedKey).await,
+            Some(EpochId(0))
+        );
+        assert_eq!(db::get(&mut dbtx, &db::LastEpochSettledKey).await, None);
+
+        // another vote after the delay settles the epoch at the agreed time
+        process_consensus_item(
+            &mut dbtx,
+            &config,
+            PeerId::from(2),
+            vote(settlement_time + 5),
+        )
+        .await;
+        assert_eq!(
+            db::get(&mut dbtx, &db::LastEpochSettledKey).await,
+            Some(EpochId(0))
+        );
+        let outcome = db::get(&mut dbtx, &db::EpochOutcomeKey(EpochId(0)))
+            .await
+            .unwrap();
+        assert_eq!(outcome.settled_at, Some(settlement_time as i64));
+    }
+
+    #[tokio::test]
+    async fn settlement_logs_balance_changes() {
+        test_dbtx!(dbtx);
+        let config = epoch_config(1);
+        let seeker = random_pubkey();
+
+        // epoch 1 started at 2_000_000 with a locked seeker and no fees
+        for (epoch_id, settled_price) in [(0, Some(2_000_000)), (1, None)] {
+            let outcome = EpochOutcome {
+                total_seeker_locked: 1_000,
+                settled_price,
+                num_seekers: 1,
+                ..Default::default()
+            };
+            db::set(&mut dbtx, &db::EpochOutcomeKey(EpochId(epoch_id)), &outcome).await;
+        }
+        db::set(
+            &mut dbtx,
+            &db::AccountBalanceKey(seeker),
+            &AccountBalance {
+                unlocked: fedimint_core::Amount::ZERO,
+                locked: LockedBalance::Seeker(fedimint_core::msats(1_000)),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let now = OffsetDateTime::now_utc().unix_timestamp() as u64;
+        settle_epoch(&mut dbtx, &config, EpochId(1), 2_000_000, false, None, now).await;
+
+        let changes = balance_changes(&mut dbtx, None, None, 10).await;
+        assert_eq!(changes.len(), 1);
+        let (seq, change) = changes[0];
+        assert_eq!(seq, 0);
+        assert_eq!(change.account, seeker);
+        assert_eq!(change.reason, BalanceChangeReason::Settlement);
+        assert_eq!(change.epoch_id, EpochId(1));
+        assert_eq!(change.debit, fedimint_core::msats(1_000));
+        // unchanged price without fees pays out the locked value
+        assert_eq!(change.delta_msats(), 0);
+    }
+
+    #[tokio::test]
+    async fn settlement_logs_summary() {
+        let logs = std::sync::Arc::new(Mutex::new(Vec::<u8>::new()));
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(logs.clone())
+            .with_ansi(false)
+            .without_time()
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        test_dbtx!(dbtx);
+        let config = epoch_config(1);
+
+        // epoch 1 started at 2_000_000 with a seeker and a provider locked
+        for (epoch_id, settled_price) in [(0, Some(2_000_000)), (1, None)] {
+            let outcome = EpochOutcome {
+                total_seeker_locked: 100_000,
+                total_provider_locked: 100_000,
+                settled_price,
+                feerate: EpochFeerate::from_ppm_feerate(10_000),
+                num_seekers: 1,
+                num_providers: 1,
+                ..Default::default()
+            };
+            db::set(&mut dbtx, &db::EpochOutcomeKey(EpochId(epoch_id)), &outcome).await;
+        }
+        for locked in [
+            LockedBalance::Seeker(fedimint_core::msats(100_000)),
+            LockedBalance::Provider(fedimint_core::msats(100_000)),
+        ] {
+            let balance = AccountBalance {
+                unlocked: fedimint_core::Amount::ZERO,
+                locked,
+                ..Default::default()
+            };
+            db::set(&mut dbtx, &db::AccountBalanceKey(random_pubkey()), &balance).await;
+        }
+
+        // the price drops, so value moves from the provider to the seeker
+        let now = OffsetDateTime::now_utc().unix_timestamp() as u64;
+        settle_epoch(&mut dbtx, &config, EpochId(1), 1_600_000, false, None, now).await;
+
+        let logs = String::from_utf8(logs.lock().unwrap().clone()).unwrap();
+        let summaries: Vec<_> = logs
+            .lines()
+            .filter(|line| line.contains("epoch settled"))
+            .collect();
+        assert_eq!(summaries.len(), 1);
+        let field = |name: &str| {
+            summaries[0]
+                .split_whitespace()
+                .find_map(|field| field.strip_prefix(name)?.strip_prefix('='))
+                .unwrap()
+                .parse::<i64>()
+                .unwrap()
+        };
+        assert_eq!(field("epoch_id"), 1);
+        assert!(field("seeker_pnl_msat") > 0);
+        assert_eq!(field("seeker_pnl_msat"), -field("provider_pnl_msat"));
+        assert_eq!(field("fees_msat"), 990);
+        assert_eq!(field("feerate_ppm"), 10_000);
+        assert_eq!(field("num_seekers"), 1);
+        assert_eq!(field("num_providers"), 1);
+    }
+
+    #[tokio::test]
+    async fn epoch_fees_sum_position_fees() {
+        test_dbtx!(dbtx);
+        let config = epoch_config(1);
+        let feerate = EpochFeerate::from_ppm_feerate(10_000);
+
+        for (epoch_id, settled_price) in [(0, Some(2_000_000)), (1, None)] {
+            let outcome = EpochOutcome {
+                total_seeker_locked: 100_000,
+                total_provider_locked: 100_000,
+                settled_price,
+                feerate,
+                num_seekers: 3,
+                num_providers: 1,
+                ..Default::default()
+            };
+            db::set(&mut dbtx, &db::EpochOutcomeKey(EpochId(epoch_id)), &outcome).await;
+        }
+        let seeker_locks = [33_333, 33_333, 33_334];
+        for locked in seeker_locks
+            .iter()
+            .map(|msats| LockedBalance::Seeker(fedimint_core::msats(*msats)))
+            .chain([LockedBalance::Provider(fedimint_core::msats(100_000))])
+        {
+            let balance = AccountBalance {
+                unlocked: fedimint_core::Amount::ZERO,
+                locked,
+                ..Default::default()
+            };
+            db::set(&mut dbtx, &db::AccountBalanceKey(random_pubkey()), &balance).await;
+        }
+
+        assert_eq!(
+            db::get(&mut dbtx, &db::EpochFeesKey(EpochId(1))).await,
+            None
+        );
+        let now = OffsetDateTime::now_utc().unix_timestamp() as u64;
+        settle_epoch(&mut dbtx, &config, EpochId(1), 2_000_000, false, None, now).await;
+
+        let fees = db::get(&mut dbtx, &db::EpochFeesKey(EpochId(1)))
+            .await
+            .unwrap();
+        let position_fees = seeker_locks
+            .iter()
+            .map(|locked| stability_core::seeker_fee(feerate, *locked))
+            .sum::<u64>();
+        assert_eq!(fees.seeker_fees_msat, position_fees);
+        assert_eq!(
+            fees.provider_fees_msat,
+            stability_core::seeker_fee(feerate, 100_000)
+        );
+        // each seeker fee rounds down by less than a msat
+        assert!(fees.provider_fees_msat - fees.seeker_fees_msat < seeker_locks.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn identical_settlements_have_identical_digests() {
+        let mut config = epoch_config(1);
+        config.settlement_digests = true;
+        let accounts = [
+            (
+                random_pubkey(),
+                LockedBalance::Seeker(fedimint_core::msats(100_000)),
+            ),
+            (
+                random_pubkey(),
+                LockedBalance::Provider(fedimint_core::msats(100_000)),
+            ),
+        ];
+
+        let mut digests = vec![];
+        for settled_at_offset in [0, 5] {
+            test_dbtx!(dbtx);
+            for (epoch_id, settled_price) in [(0, Some(2_000_000)), (1, None)] {
+                let outcome = EpochOutcome {
+                    total_seeker_locked: 100_000,
+                    total_provider_locked: 100_000,
+                    settled_price,
+                    feerate: EpochFeerate::from_ppm_feerate(10_000),
+                    num_seekers: 1,
+                    num_providers: 1,
+                    ..Default::default()
+                };
+                db::set(&mut dbtx, &db::EpochOutcomeKey(EpochId(epoch_id)), &outcome).await;
+            }
+            for (account_id, locked) in accounts {
+                let balance = AccountBalance {
+                    unlocked: fedimint_core::msats(1_000),
+                    locked,
+                    ..Default::default()
+                };
+                db::set(&mut dbtx, &db::AccountBalanceKey(account_id), &balance).await;
+            }
+
+            // the settlement time is left out of the digest
+            let now = OffsetDateTime::now_utc().unix_timestamp() as u64 + settled_at_offset;
+            settle_epoch(&mut dbtx, &config, EpochId(1), 1_600_000, false, None, now).await;
+            let digest = db::get(&mut dbtx, &db::SettlementDigestKey).await.unwrap();
+            assert_eq!(digest, settlement_digest(&mut dbtx, EpochId(1)).await);
+
+            // once our digest is in, we stop proposing it
+            let peer_id = PeerId::from(0);
+            assert_eq!(
+                settlement_digest_proposal(&mut dbtx, &config, peer_id).await,
+                vec![PoolConsensusItem::SettlementDigest(digest)]
+            );
+            let outcome = process_settlement_digest(&mut dbtx, peer_id, digest).await;
+            assert_eq!(outcome, ConsensusItemOutcome::Applied);
+            assert!(settlement_digest_proposal(&mut dbtx, &config, peer_id)
+                .await
+                .is_empty());
+
+            // a different balance changes the digest
+            let balance = AccountBalance {
+                unlocked: fedimint_core::msats(1),
+                ..Default::default()
+            };
+            db::set(&mut dbtx, &db::AccountBalanceKey(random_pubkey()), &balance).await;
+            assert_ne!(settlement_digest(&mut dbtx, EpochId(1)).await, digest);
+
+            digests.push(digest);
+        }
+        assert_eq!(digests[0], digests[1]);
+    }
+
+    #[tokio::test]
+    async fn balance_change_log_is_pruned() {
+        test_dbtx!(dbtx);
+        let account = random_pubkey();
+
+        for epoch_id in 0..5 {
+            let change = BalanceChange {
+                account,
+                reason: BalanceChangeReason::Deposit,
+                epoch_id: EpochId(epoch_id),
+                credit: fedimint_core::msats(1),
+                debit: fedimint_core::Amount::ZERO,
+            };
+            log_balance_change(&mut dbtx, Some(2), change).await;
+        }
+
+        let seqs = |changes: Vec<(u64, BalanceChange)>| {
+            changes.into_iter().map(|(seq, _)| seq).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            seqs(balance_changes(&mut dbtx, Some(2), None, 10).await),
+            vec![3, 4]
+        );
+        assert_eq!(
+            seqs(balance_changes(&mut dbtx, Some(2), Some(3), 10).await),
+            vec![4]
+        );
+        assert_eq!(
+            seqs(balance_changes(&mut dbtx, Some(2), None, 1).await),
+            vec![3]
+        );
+    }
+
+    #[tokio::test]
+    async fn staging_epoch_follows_settled_epoch() {
+        test_dbtx!(dbtx);
+
+        db::set(&mut dbtx, &db::LastEpochEndedKey, &EpochId(3)).await;
+        db::set(&mut dbtx, &db::LastEpochSettledKey, &EpochId(5)).await;
+        let epoch_state = EpochState::from_db(&mut dbtx).await;
+        assert!(epoch_state.is_settled());
+        assert_eq!(epoch_state.current_epoch_id(), EpochId(6));
+        assert_eq!(epoch_state.staging_epoch_id(), EpochId(7));
+
+        db::pop(&mut dbtx, &db::LastEpochEndedKey).await;
+        let epoch_state = EpochState::from_db(&mut dbtx).await;
+        assert!(epoch_state.staging_epoch_id() > EpochId(5));
+    }
+}
@@ -0,0 +1,850 @@
+use std::sync::Mutex;
+
+use bitcoin::XOnlyPublicKey;
+use fedimint_core::core::ModuleInstanceId;
+use fedimint_core::db::ModuleDatabaseTransaction;
+use fedimint_core::encoding::{Decodable, Encodable};
+use fedimint_core::PeerId;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::account::{
+    account_totals, log_balance_change, AccountBalance, BalanceChange, BalanceChangeReason,
+    LockedBalance,
+};
+use crate::config::{EpochConfig, PoolConfigConsensus};
+use crate::epoch::EpochState;
+use crate::{db, stability_core, ConsensusItemOutcome, PoolConsensusItem};
+
+/// Change to module parameters voted on by operators. An action takes effect
+/// once `price_threshold` peers have proposed the same item.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize, Encodable, Decodable)]
+pub enum GovernanceAction {
+    /// Raise the maximum total value (msats) the pool accepts
+    RaiseTvlCap { max_tvl_msat: u64 },
+    /// Block deposits to and withdrawals from an account
+    FreezeAccount { account: XOnlyPublicKey },
+    /// Lift a previous [`GovernanceAction::FreezeAccount`]
+    UnfreezeAccount { account: XOnlyPublicKey },
+    /// Cap the feerate (ppm) a provider can bid at instead of the global
+    /// `max_feerate_ppm`, or go back to the global cap if [`None`]
+    SetFeerateCap {
+        account: XOnlyPublicKey,
+        max_feerate_ppm: Option<u64>,
+    },
+    /// Let epochs end and settle with fewer votes while peers are offline, or
+    /// always require the full quorum if [`None`]. This has to be set while
+    /// enough peers are online to vote for it.
+    SetDegradedQuorum {
+        policy: Option<DegradedQuorumPolicy>,
+    },
+    /// Wind down the pool: the settlement of the current epoch is the final
+    /// one, after which all balances stay unlocked. Deposits and actions are
+    /// refused from now on, withdrawals keep working.
+    ClosePool,
+    /// Move the balance of `from`, unlocked and locked, to `to`, for users who
+    /// lost their key but proved who they are to operators. Actions `from`
+    /// has staged are dropped, while a freeze or feerate cap of `from`
+    /// carries over to `to`. If `to` already has an account the balances are
+    /// only combined when `merge` is set.
+    ReassignAccount {
+        from: XOnlyPublicKey,
+        to: XOnlyPublicKey,
+        merge: bool,
+    },
+    /// Recompute the running total balance from the account balances, to
+    /// recover from it going out of sync with them
+    ReconstructDerived,
+    /// Create an empty record for `account`, so it can receive deposits when
+    /// the federation does not create accounts on their first deposit
+    RegisterAccount { account: XOnlyPublicKey },
+}
+
+/// When peers count as offline and how far their absence can lower the
+/// number of votes needed to end and settle an epoch.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize, Encodable, Decodable)]
+pub struct DegradedQuorumPolicy {
+    /// Number of consensus sessions without any consensus item from a peer
+    /// after which it counts as offline
+    pub offline_sessions: u64,
+    /// Fewest votes an epoch can end and settle with
+    pub min_quorum: u32,
+}
+
+impl std::fmt::Display for GovernanceAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::RaiseTvlCap { max_tvl_msat } => write!(f, "raise_tvl_cap:{}", max_tvl_msat),
+            Self::FreezeAccount { account } => write!(f, "freeze_account:{}", account),
+            Self::UnfreezeAccount { account } => write!(f, "unfreeze_account:{}", account),
+            Self::SetFeerateCap {
+                account,
+                max_feerate_ppm: Some(max_feerate_ppm),
+            } => write!(f, "set_feerate_cap:{}:{}", account, max_feerate_ppm),
+            Self::SetFeerateCap {
+                account,
+                max_feerate_ppm: None,
+            } => write!(f, "set_feerate_cap:{}:global", account),
+            Self::SetDegradedQuorum {
+                policy: Some(policy),
+            } => write!(
+                f,
+                "set_degraded_quorum:{}:{}",
+                policy.offline_sessions, policy.min_quorum
+            ),
+            Self::SetDegradedQuorum { policy: None } => write!(f, "set_degraded_quorum:none"),
+            Self::ClosePool => write!(f, "close_pool"),
+            Self::ReassignAccount { from, to, merge } => write!(
+                f,
+                "reassign_account:{}:{}{}",
+                from,
+                to,
+                if *merge { ":merge" } else { "" }
+            ),
+            Self::ReconstructDerived => write!(f, "reconstruct_derived"),
+            Self::RegisterAccount { account } => write!(f, "register_account:{}", account),
+        }
+    }
+}
+
+/// Governance action submitted by our operator, proposed until it is applied
+/// or no longer applicable.
+#[derive(Debug, Default)]
+pub struct GovernanceProposal {
+    item: Mutex<Option<GovernanceAction>>,
+}
+
+impl GovernanceProposal {
+    pub fn get(&self) -> Option<GovernanceAction> {
+        *self.item.lock().unwrap()
+    }
+
+    pub fn set(&self, item: GovernanceAction) {
+        self.item.lock().unwrap().replace(item);
+    }
+
+    pub fn clear(&self) {
+        self.item.lock().unwrap().take();
+    }
+}
+
+/// The TVL cap (msats) in effect: the last one raised through governance, or
+/// the configured one. [`None`] means the pool is uncapped.
+pub async fn max_tvl(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &PoolConfigConsensus,
+) -> Option<u64> {
+    db::get(dbtx, &db::TvlCapKey).await.or(config.max_tvl_msat)
+}
+
+/// Whether deposits and withdrawals of `account` are blocked.
+pub async fn is_frozen(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    account: XOnlyPublicKey,
+) -> bool {
+    db::get(dbtx, &db::FrozenAccountKey(account))
+        .await
+        .is_some()
+}
+
+/// Whether the pool was closed through [`GovernanceAction::ClosePool`].
+pub async fn is_closed(dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>) -> bool {
+    db::get(dbtx, &db::PoolClosedKey).await.is_some()
+}
+
+/// The highest feerate (ppm) `account` can bid at: its own cap if one was set
+/// through governance, the global one otherwise.
+pub async fn max_feerate_ppm(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &EpochConfig,
+    account: XOnlyPublicKey,
+) -> u64 {
+    db::get(dbtx, &db::FeerateCapKey(account))
+        .await
+        .unwrap_or(config.max_feerate_ppm)
+}
+
+/// The `bids` at or below the feerate cap of their provider.
+pub async fn bids_within_feerate_cap(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &EpochConfig,
+    bids: impl IntoIterator<Item = stability_core::ProviderBid>,
+) -> Vec<stability_core::ProviderBid> {
+    let mut capped = Vec::new();
+    for bid in bids {
+        if bid.min_feerate <= max_feerate_ppm(dbtx, config, bid.account_id).await {
+            capped.push(bid);
+        }
+    }
+    capped
+}
+
+/// Why `action` cannot be applied to the current state, if at all.
+async fn rejection(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &PoolConfigConsensus,
+    action: GovernanceAction,
+) -> Option<String> {
+    match action {
+        GovernanceAction::RaiseTvlCap { max_tvl_msat } => match max_tvl(dbtx, config).await {
+            None => Some("pool has no tvl cap to raise".to_string()),
+            Some(current) if max_tvl_msat <= current => Some(format!(
+                "tvl cap {} does not raise the current cap {}",
+                max_tvl_msat, current
+            )),
+            Some(_) => None,
+        },
+        GovernanceAction::FreezeAccount { account } => is_frozen(dbtx, account)
+            .await
+            .then(|| format!("account {} is already frozen", account)),
+        GovernanceAction::UnfreezeAccount { account } => {
+            (!is_frozen(dbtx, account).await).then(|| format!("account {} is not frozen", account))
+        }
+        GovernanceAction::SetFeerateCap {
+            account,
+            max_feerate_ppm,
+        } => (db::get(dbtx, &db::FeerateCapKey(account)).await == max_feerate_ppm)
+            .then(|| format!("account {} already has this feerate cap", account)),
+        GovernanceAction::SetDegradedQuorum {
+            policy: Some(policy),
+        } if policy.offline_sessions == 0 => {
+            Some("degraded quorum policy needs at least one offline session".to_string())
+        }
+        // fewer votes could all come from faulty peers
+        GovernanceAction::SetDegradedQuorum {
+            policy: Some(policy),
+        } if policy.min_quorum < config.epoch.min_honest_quorum() => Some(format!(
+            "degraded quorum of {} votes is below the {} needed to include an honest peer",
+            policy.min_quorum,
+            config.epoch.min_honest_quorum()
+        )),
+        GovernanceAction::SetDegradedQuorum { policy } => {
+            (db::get(dbtx, &db::DegradedQuorumPolicyKey).await == policy)
+                .then(|| "degraded quorum policy is already in place".to_string())
+        }
+        GovernanceAction::ClosePool => is_closed(dbtx)
+            .await
+            .then(|| "pool is already closed".to_string()),
+        GovernanceAction::ReassignAccount { from, to, merge } => {
+            reassignment_rejection(dbtx, from, to, merge).await
+        }
+        GovernanceAction::ReconstructDerived => {
+            let total = account_totals(dbtx).await.into_iter().sum::<u64>();
+            (db::get(dbtx, &db::TotalBalanceKey).await == Some(total))
+                .then(|| "total balance is already in sync with the accounts".to_string())
+        }
+        GovernanceAction::RegisterAccount { account } => {
+            db::get(dbtx, &db::AccountBalanceKey(account))
+                .await
+                .map(|_| format!("account {} is already registered", account))
+        }
+    }
+}
+
+async fn reassignment_rejection(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    from: XOnlyPublicKey,
+    to: XOnlyPublicKey,
+    merge: bool,
+) -> Option<String> {
+    if from == to {
+        return Some(format!("account {} cannot be reassigned to itself", from));
+    }
+    let source = match db::get(dbtx, &db::AccountBalanceKey(from)).await {
+        Some(source) => source,
+        None => return Some(format!("account {} does not exist", from)),
+    };
+    let target = match db::get(dbtx, &db::AccountBalanceKey(to)).await {
+        Some(target) => target,
+        None => return None,
+    };
+    if !merge {
+        return Some(format!("account {} already exists", to));
+    }
+    match merged_balance(&source, &target) {
+        Some(_) => None,
+        None => Some(format!("accounts {} and {} cannot be merged", from, to)),
+    }
+}
+
+/// `target` with the unlocked and locked balance of `source` added, if both
+/// add up without overflow and neither has a locked position on the other
+/// side of the pool.
+fn merged_balance(source: &AccountBalance, target: &AccountBalance) -> Option<AccountBalance> {
+    let unlocked = source.unlocked.msats.checked_add(target.unlocked.msats)?;
+    let locked = match (source.locked, target.locked) {
+        (locked, LockedBalance::None) | (LockedBalance::None, locked) => locked,
+        (LockedBalance::Seeker(a), LockedBalance::Seeker(b)) => {
+            LockedBalance::Seeker(fedimint_core::msats(a.msats.checked_add(b.msats)?))
+        }
+        (LockedBalance::Provider(a), LockedBalance::Provider(b)) => {
+            LockedBalance::Provider(fedimint_core::msats(a.msats.checked_add(b.msats)?))
+        }
+        _ => return None,
+    };
+    let merged = AccountBalance {
+        unlocked: fedimint_core::msats(unlocked),
+        locked,
+        ..target.clone()
+    };
+    merged.total_balance().map(|_| merged)
+}
+
+/// Apply a [`GovernanceAction::ReassignAccount`] that passed
+/// [`reassignment_rejection`].
+async fn reassign_account(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &EpochConfig,
+    from: XOnlyPublicKey,
+    to: XOnlyPublicKey,
+) {
+    let source = db::pop(dbtx, &db::AccountBalanceKey(from))
+        .await
+        .expect("checked by reassignment_rejection");
+    let balance = match db::get(dbtx, &db::AccountBalanceKey(to)).await {
+        Some(target) => {
+            merged_balance(&source, &target).expect("checked by reassignment_rejection")
+        }
+        // a new key starts its own sequence of actions
+        None => AccountBalance {
+            unlocked: source.unlocked,
+            locked: source.locked,
+            ..Default::default()
+        },
+    };
+    db::set(dbtx, &db::AccountBalanceKey(to), &balance).await;
+
+    // a merged position counts as locked since the later of the two
+    if let Some(since) = db::pop(dbtx, &db::SeekerLockedSinceKey(from)).await {
+        let since = match db::get(dbtx, &db::SeekerLockedSinceKey(to)).await {
+            Some(target_since) => since.max(target_since),
+            None => since,
+        };
+        db::set(dbtx, &db::SeekerLockedSinceKey(to), &since).await;
+    }
+
+    // actions signed by the old key are not carried over
+    db::pop(dbtx, &db::ActionStagedKey(from)).await;
+    db::pop(dbtx, &db::ActionSignedKey(from)).await;
+
+    // the freeze and feerate cap of the old key move to the new one, keeping
+    // the stricter cap when merging
+    if let Some(frozen_at) = db::pop(dbtx, &db::FrozenAccountKey(from)).await {
+        if !is_frozen(dbtx, to).await {
+            db::set(dbtx, &db::FrozenAccountKey(to), &frozen_at).await;
+        }
+    }
+    if let Some(cap) = db::pop(dbtx, &db::FeerateCapKey(from)).await {
+        let cap = match db::get(dbtx, &db::FeerateCapKey(to)).await {
+            Some(target_cap) => cap.min(target_cap),
+            None => cap,
+        };
+        db::set(dbtx, &db::FeerateCapKey(to), &cap).await;
+    }
+
+    let epoch_id = EpochState::from_db(dbtx).await.current_epoch_id();
+    let moved = source
+        .total_balance()
+        .expect("stored balances don't overflow");
+    for (account, credit, debit) in [
+        (from, fedimint_core::Amount::ZERO, moved),
+        (to, moved, fedimint_core::Amount::ZERO),
+    ] {
+        let change = BalanceChange {
+            account,
+            reason: BalanceChangeReason::Reassignment,
+            epoch_id,
+            credit,
+            debit,
+        };
+        log_balance_change(dbtx, config.balance_change_retention, change).await;
+    }
+
+    tracing::warn!(
+        %from,
+        %to,
+        unlocked = source.unlocked.msats,
+        locked = source.locked.amount().msats,
+        "ACCOUNT REASSIGNED: balance moved to a new key by governance"
+    );
+}
+
+/// Apply a [`GovernanceAction::ReconstructDerived`]. The collected fees are
+/// not derived from any other record and are left as they are.
+async fn reconstruct_derived(dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>) {
+    let previous_total_balance_msat = db::get(dbtx, &db::TotalBalanceKey).await;
+    let totals = account_totals(dbtx).await;
+    let total_balance_msat = totals.iter().sum::<u64>();
+    db::set(dbtx, &db::TotalBalanceKey, &total_balance_msat).await;
+
+    tracing::warn!(
+        accounts = totals.len(),
+        ?previous_total_balance_msat,
+        total_balance_msat,
+        "TOTAL BALANCE RECONSTRUCTED: recomputed from the accounts by governance"
+    );
+}
+
+/// Provide our operator's governance action while it is still applicable and
+/// we have not yet voted for it.
+pub async fn consensus_proposal(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &PoolConfigConsensus,
+    proposal: &GovernanceProposal,
+    our_peer_id: PeerId,
+) -> Vec<PoolConsensusItem> {
+    let action = match proposal.get() {
+        Some(action) => action,
+        None => return vec![],
+    };
+
+    if rejection(dbtx, config, action).await.is_some() {
+        proposal.clear();
+        return vec![];
+    }
+
+    if db::get(dbtx, &db::GovernanceVoteKey(our_peer_id)).await == Some(action) {
+        return vec![];
+    }
+
+    vec![action.into()]
+}
+
+pub async fn process_consensus_item(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &PoolConfigConsensus,
+    peer_id: PeerId,
+    action: GovernanceAction,
+) -> ConsensusItemOutcome {
+    if let Some(reason) = rejection(dbtx, config, action).await {
+        return ConsensusItemOutcome::Ignored(format!("governance: {}", reason));
+    }
+
+    db::set(dbtx, &db::GovernanceVoteKey(peer_id), &action).await;
+
+    let count = dbtx
+        .find_by_prefix(&db::GovernanceVoteKeyPrefix)
+        .await
+        .map(|res| res.1)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .filter(|peer_action| *peer_action == action)
+        .count();
+
+    if count >= config.epoch.price_threshold as usize {
+        tracing::info!(%action, "applying governance action");
+        match action {
+            GovernanceAction::RaiseTvlCap { max_tvl_msat } => {
+                db::set(dbtx, &db::TvlCapKey, &max_tvl_msat).await;
+            }
+            GovernanceAction::FreezeAccount { account } => {
+                let epoch_id = EpochState::from_db(dbtx).await.current_epoch_id();
+                db::set(dbtx, &db::FrozenAccountKey(account), &epoch_id).await;
+            }
+            GovernanceAction::UnfreezeAccount { account } => {
+                db::pop(dbtx, &db::FrozenAccountKey(account)).await;
+            }
+            GovernanceAction::SetFeerateCap {
+                account,
+                max_feerate_ppm: Some(max_feerate_ppm),
+            } => {
+                db::set(dbtx, &db::FeerateCapKey(account), &max_feerate_ppm).await;
+            }
+            GovernanceAction::SetFeerateCap {
+                account,
+                max_feerate_ppm: None,
+            } => {
+                db::pop(dbtx, &db::FeerateCapKey(account)).await;
+            }
+            GovernanceAction::SetDegradedQuorum {
+                policy: Some(policy),
+            } => {
+                db::set(dbtx, &db::DegradedQuorumPolicyKey, &policy).await;
+            }
+            GovernanceAction::SetDegradedQuorum { policy: None } => {
+                db::pop(dbtx, &db::DegradedQuorumPolicyKey).await;
+            }
+            GovernanceAction::ClosePool => {
+                let epoch_id = EpochState::from_db(dbtx).await.current_epoch_id();
+                db::set(dbtx, &db::PoolClosedKey, &epoch_id).await;
+            }
+            GovernanceAction::ReassignAccount { from, to, .. } => {
+                reassign_account(dbtx, &config.epoch, from, to).await;
+            }
+            GovernanceAction::ReconstructDerived => {
+                reconstruct_derived(dbtx).await;
+            }
+            GovernanceAction::RegisterAccount { account } => {
+                db::set(
+                    dbtx,
+                    &db::AccountBalanceKey(account),
+                    &AccountBalance::default(),
+                )
+                .await;
+            }
+        }
+        dbtx.remove_by_prefix(&db::GovernanceVoteKeyPrefix).await;
+    }
+
+    ConsensusItemOutcome::Applied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::action::{Action, ActionStaged, SeekerAction};
+    use crate::epoch::EpochId;
+
+    fn pool_config(max_tvl_msat: Option<u64>) -> PoolConfigConsensus {
+        PoolConfigConsensus {
+            epoch: EpochConfig {
+                price_threshold: 2,
+                ..Default::default()
+            },
+            max_tvl_msat,
+            ..Default::default()
+        }
+    }
+
+    fn random_account() -> XOnlyPublicKey {
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        let (_, public_key) = secp.generate_keypair(&mut rand::thread_rng());
+        public_key.x_only_public_key().0
+    }
+
+    #[tokio::test]
+    async fn tvl_cap_is_raised_by_quorum() {
+        test_dbtx!(dbtx);
+        let config = pool_config(Some(1_000));
+        let raise = GovernanceAction::RaiseTvlCap {
+            max_tvl_msat: 2_000,
+        };
+
+        let outcome = process_consensus_item(&mut dbtx, &config, PeerId::from(0), raise).await;
+        assert_eq!(outcome, ConsensusItemOutcome::Applied);
+        assert_eq!(max_tvl(&mut dbtx, &config).await, Some(1_000));
+
+        let outcome = process_consensus_item(&mut dbtx, &config, PeerId::from(1), raise).await;
+        assert_eq!(outcome, ConsensusItemOutcome::Applied);
+        assert_eq!(max_tvl(&mut dbtx, &config).await, Some(2_000));
+
+        // lowering, or raising to the current cap, is not accepted
+        for max_tvl_msat in [1_500, 2_000] {
+            let lower = GovernanceAction::RaiseTvlCap { max_tvl_msat };
+            let outcome = process_consensus_item(&mut dbtx, &config, PeerId::from(0), lower).await;
+            assert!(matches!(outcome, ConsensusItemOutcome::Ignored(_)));
+        }
+    }
+
+    #[tokio::test]
+    async fn reconstruct_derived_fixes_corrupted_total() {
+        test_dbtx!(dbtx);
+        let config = pool_config(None);
+        for msats in [1_000, 500] {
+            let account = AccountBalance {
+                unlocked: fedimint_core::msats(msats),
+                ..Default::default()
+            };
+            db::set(
+                &mut dbtx,
+                &db::AccountBalanceKey(random_account()),
+                &account,
+            )
+            .await;
+        }
+        db::set(&mut dbtx, &db::TotalBalanceKey, &1_500).await;
+
+        // nothing to reconstruct while the total is in sync
+        let reconstruct = GovernanceAction::ReconstructDerived;
+        let outcome =
+            process_consensus_item(&mut dbtx, &config, PeerId::from(0), reconstruct).await;
+        assert!(matches!(outcome, ConsensusItemOutcome::Ignored(_)));
+
+        db::set(&mut dbtx, &db::TotalBalanceKey, &42).await;
+        for peer in 0..2 {
+            let outcome =
+                process_consensus_item(&mut dbtx, &config, PeerId::from(peer), reconstruct).await;
+            assert_eq!(outcome, ConsensusItemOutcome::Applied);
+        }
+        assert_eq!(db::get(&mut dbtx, &db::TotalBalanceKey).await, Some(1_500));
+    }
+
+    #[tokio::test]
+    async fn registering_creates_an_empty_account_once() {
+        test_dbtx!(dbtx);
+        let config = pool_config(None);
+        let account = random_account();
+        let register = GovernanceAction::RegisterAccount { account };
+
+        for peer in 0..2 {
+            let outcome =
+                process_consensus_item(&mut dbtx, &config, PeerId::from(peer), register).await;
+            assert_eq!(outcome, ConsensusItemOutcome::Applied);
+        }
+        assert_eq!(
+            db::get(&mut dbtx, &db::AccountBalanceKey(account)).await,
+            Some(AccountBalance::default())
+        );
+
+        let outcome = process_consensus_item(&mut dbtx, &config, PeerId::from(0), register).await;
+        assert!(matches!(outcome, ConsensusItemOutcome::Ignored(_)));
+    }
+
+    #[tokio::test]
+    async fn uncapped_pool_cannot_be_raised() {
+        test_dbtx!(dbtx);
+        let config = pool_config(None);
+        let raise = GovernanceAction::RaiseTvlCap {
+            max_tvl_msat: 2_000,
+        };
+
+        let proposal = GovernanceProposal::default();
+        proposal.set(raise);
+        assert!(
+            consensus_proposal(&mut dbtx, &config, &proposal, PeerId::from(0))
+                .await
+                .is_empty()
+        );
+        assert_eq!(proposal.get(), None);
+
+        let outcome = process_consensus_item(&mut dbtx, &config, PeerId::from(0), raise).await;
+        assert!(matches!(outcome, ConsensusItemOutcome::Ignored(_)));
+    }
+
+    #[tokio::test]
+    async fn accounts_are_frozen_and_unfrozen_by_quorum() {
+        test_dbtx!(dbtx);
+        let config = pool_config(None);
+        let account = random_account();
+
+        let unfreeze = GovernanceAction::UnfreezeAccount { account };
+        let outcome = process_consensus_item(&mut dbtx, &config, PeerId::from(0), unfreeze).await;
+        assert!(matches!(outcome, ConsensusItemOutcome::Ignored(_)));
+
+        let freeze = GovernanceAction::FreezeAccount { account };
+        for peer in 0..2 {
+            process_consensus_item(&mut dbtx, &config, PeerId::from(peer), freeze).await;
+        }
+        assert!(is_frozen(&mut dbtx, account).await);
+
+        for peer in 0..2 {
+            process_consensus_item(&mut dbtx, &config, PeerId::from(peer), unfreeze).await;
+        }
+        assert!(!is_frozen(&mut dbtx, account).await);
+    }
+
+    #[tokio::test]
+    async fn feerate_caps_override_the_global_cap() {
+        test_dbtx!(dbtx);
+        let config = pool_config(None);
+        let (capped, uncapped) = (random_account(), random_account());
+
+        let set_cap = |max_feerate_ppm| GovernanceAction::SetFeerateCap {
+            account: capped,
+            max_feerate_ppm,
+        };
+        for peer in 0..2 {
+            process_consensus_item(&mut dbtx, &config, PeerId::from(peer), set_cap(Some(500)))
+                .await;
+        }
+        assert_eq!(max_feerate_ppm(&mut dbtx, &config.epoch, capped).await, 500);
+        assert_eq!(
+            max_feerate_ppm(&mut dbtx, &config.epoch, uncapped).await,
+            config.epoch.max_feerate_ppm
+        );
+
+        // setting the same cap again is not accepted
+        let outcome =
+            process_consensus_item(&mut dbtx, &config, PeerId::from(0), set_cap(Some(500))).await;
+        assert!(matches!(outcome, ConsensusItemOutcome::Ignored(_)));
+
+        for peer in 0..2 {
+            process_consensus_item(&mut dbtx, &config, PeerId::from(peer), set_cap(None)).await;
+        }
+        assert_eq!(
+            max_feerate_ppm(&mut dbtx, &config.epoch, capped).await,
+            config.epoch.max_feerate_ppm
+        );
+    }
+
+    #[tokio::test]
+    async fn degraded_quorum_policy_needs_a_minimum() {
+        test_dbtx!(dbtx);
+        let mut config = pool_config(None);
+        // a federation of four tolerates one faulty peer
+        config.epoch.num_peers = Some(4);
+
+        for (offline_sessions, min_quorum) in [(0, 2), (10, 0), (10, 1)] {
+            let unsafe_policy = GovernanceAction::SetDegradedQuorum {
+                policy: Some(DegradedQuorumPolicy {
+                    offline_sessions,
+                    min_quorum,
+                }),
+            };
+            let outcome =
+                process_consensus_item(&mut dbtx, &config, PeerId::from(0), unsafe_policy).await;
+            assert!(matches!(outcome, ConsensusItemOutcome::Ignored(_)));
+        }
+
+        let policy = DegradedQuorumPolicy {
+            offline_sessions: 10,
+            min_quorum: 2,
+        };
+        let set = GovernanceAction::SetDegradedQuorum {
+            policy: Some(policy),
+        };
+        for peer in 0..2 {
+            process_consensus_item(&mut dbtx, &config, PeerId::from(peer), set).await;
+        }
+        assert_eq!(
+            db::get(&mut dbtx, &db::DegradedQuorumPolicyKey).await,
+            Some(policy)
+        );
+    }
+
+    async fn set_balance(
+        dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+        account: XOnlyPublicKey,
+        unlocked: u64,
+        locked: LockedBalance,
+    ) {
+        let balance = AccountBalance {
+            unlocked: fedimint_core::msats(unlocked),
+            locked,
+            ..Default::default()
+        };
+        db::set(dbtx, &db::AccountBalanceKey(account), &balance).await;
+    }
+
+    #[tokio::test]
+    async fn accounts_are_reassigned_by_quorum() {
+        test_dbtx!(dbtx);
+        let config = pool_config(None);
+        let (from, to) = (random_account(), random_account());
+
+        let reassign = GovernanceAction::ReassignAccount {
+            from,
+            to,
+            merge: false,
+        };
+        let outcome = process_consensus_item(&mut dbtx, &config, PeerId::from(0), reassign).await;
+        assert!(matches!(outcome, ConsensusItemOutcome::Ignored(_)));
+
+        let locked = LockedBalance::Seeker(fedimint_core::msats(2_000));
+        set_balance(&mut dbtx, from, 1_000, locked).await;
+        db::set(&mut dbtx, &db::SeekerLockedSinceKey(from), &EpochId(3)).await;
+        let lock = Action {
+            epoch_id: EpochId(4),
+            sequence: 0,
+            account_id: from,
+            body: SeekerAction::Lock {
+                amount: fedimint_core::msats(1_000),
+            },
+        };
+        db::set(
+            &mut dbtx,
+            &db::ActionStagedKey(from),
+            &ActionStaged::Seeker(lock),
+        )
+        .await;
+        db::set(&mut dbtx, &db::FrozenAccountKey(from), &EpochId(2)).await;
+        db::set(&mut dbtx, &db::FeerateCapKey(from), &500).await;
+
+        process_consensus_item(&mut dbtx, &config, PeerId::from(0), reassign).await;
+        assert!(db::get(&mut dbtx, &db::AccountBalanceKey(to))
+            .await
+            .is_none());
+        process_consensus_item(&mut dbtx, &config, PeerId::from(1), reassign).await;
+
+        assert!(db::get(&mut dbtx, &db::AccountBalanceKey(from))
+            .await
+            .is_none());
+        let balance = db::get(&mut dbtx, &db::AccountBalanceKey(to))
+            .await
+            .unwrap();
+        assert_eq!(balance.unlocked, fedimint_core::msats(1_000));
+        assert_eq!(balance.locked, locked);
+        assert_eq!(
+            db::get(&mut dbtx, &db::SeekerLockedSinceKey(to)).await,
+            Some(EpochId(3))
+        );
+        assert!(db::get(&mut dbtx, &db::ActionStagedKey(from))
+            .await
+            .is_none());
+
+        // restrictions follow the balance
+        assert!(!is_frozen(&mut dbtx, from).await);
+        assert!(is_frozen(&mut dbtx, to).await);
+        assert_eq!(db::get(&mut dbtx, &db::FeerateCapKey(from)).await, None);
+        assert_eq!(db::get(&mut dbtx, &db::FeerateCapKey(to)).await, Some(500));
+
+        // the move is logged as a debit of the old key and a credit of the new
+        let changes = crate::balance_changes(&mut dbtx, None, None, 10).await;
+        let logged = changes
+            .iter()
+            .map(|(_, change)| (change.account, change.reason, change.delta_msats()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            logged,
+            vec![
+                (from, BalanceChangeReason::Reassignment, -3_000),
+                (to, BalanceChangeReason::Reassignment, 3_000),
+            ]
+        );
+
+        // the old key has nothing left to move
+        let back = GovernanceAction::ReassignAccount {
+            from,
+            to: random_account(),
+            merge: false,
+        };
+        let outcome = process_consensus_item(&mut dbtx, &config, PeerId::from(0), back).await;
+        assert!(matches!(outcome, ConsensusItemOutcome::Ignored(_)));
+    }
+
+    #[tokio::test]
+    async fn existing_accounts_are_only_merged_on_request() {
+        test_dbtx!(dbtx);
+        let config = pool_config(None);
+        let (from, to) = (random_account(), random_account());
+        let provider = |msats| LockedBalance::Provider(fedimint_core::msats(msats));
+        set_balance(&mut dbtx, from, 1_000, provider(2_000)).await;
+        set_balance(&mut dbtx, to, 500, LockedBalance::None).await;
+
+        let reassign = |merge| GovernanceAction::ReassignAccount { from, to, merge };
+        let outcome =
+            process_consensus_item(&mut dbtx, &config, PeerId::from(0), reassign(false)).await;
+        assert!(matches!(outcome, ConsensusItemOutcome::Ignored(_)));
+
+        for peer in 0..2 {
+            process_consensus_item(&mut dbtx, &config, PeerId::from(peer), reassign(true)).await;
+        }
+        assert!(db::get(&mut dbtx, &db::AccountBalanceKey(from))
+            .await
+            .is_none());
+        let balance = db::get(&mut dbtx, &db::AccountBalanceKey(to))
+            .await
+            .unwrap();
+        assert_eq!(balance.unlocked, fedimint_core::msats(1_500));
+        assert_eq!(balance.locked, provider(2_000));
+
+        // positions on opposite sides of the pool don't add up
+        let seeker = random_account();
+        set_balance(
+            &mut dbtx,
+            seeker,
+            0,
+            LockedBalance::Seeker(fedimint_core::msats(1_000)),
+        )
+        .await;
+        let conflicting = GovernanceAction::ReassignAccount {
+            from: seeker,
+            to,
+            merge: true,
+        };
+        let outcome =
+            process_consensus_item(&mut dbtx, &config, PeerId::from(0), conflicting).await;
+        assert!(matches!(outcome, ConsensusItemOutcome::Ignored(_)));
+    }
+}
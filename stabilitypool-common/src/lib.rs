@@ -6,6 +6,7 @@ use config::PoolConfigClient;
 use fedimint_core::core::{Decoder, ModuleInstanceId, ModuleKind};
 use fedimint_core::encoding::{Decodable, Encodable};
 use fedimint_core::module::{CommonModuleGen, ModuleCommon};
+use governance::GovernanceAction;
 use serde::{Deserialize, Serialize};
 
 pub use crate::account::*;
@@ -13,12 +14,29 @@ pub use crate::action::*;
 pub use crate::epoch::*;
 pub use crate::price::*;
 
+/// Opens a transaction on a fresh in-memory database, scoped to the module
+/// prefix, and binds it to `$dbtx`.
+#[cfg(any(test, feature = "test-utils"))]
+#[macro_export]
+macro_rules! test_dbtx {
+    ($dbtx:ident) => {
+        let db = fedimint_core::db::Database::new(
+            fedimint_core::db::mem_impl::MemDatabase::new(),
+            Default::default(),
+        );
+        let mut $dbtx = db.begin_transaction().await;
+        let mut $dbtx = $dbtx.with_module_prefix(0);
+    };
+}
+
 pub mod account;
 pub mod action;
+pub mod clock;
 pub mod common;
 pub mod config;
 pub mod db;
 pub mod epoch;
+pub mod governance;
 pub mod price;
 pub mod stability_core;
 
@@ -58,6 +76,9 @@ impl fmt::Display for PoolOutputOutcome {
 pub enum PoolConsensusItem {
     ActionProposed(ActionProposed),
     EpochEnd(EpochEnd),
+    ForceSettle(ForceSettle),
+    Governance(GovernanceAction),
+    SettlementDigest(SettlementDigest),
 }
 
 impl fmt::Display for PoolConsensusItem {
@@ -74,6 +95,17 @@ impl fmt::Display for PoolConsensusItem {
                 "[epoch_end] epoch_id:{} with price:{:?}",
                 end.epoch_id, end.price
             ),
+            Self::ForceSettle(force_settle) => write!(
+                f,
+                "[force_settle] epoch_id:{} with price:{}",
+                force_settle.epoch_id, force_settle.price
+            ),
+            Self::Governance(action) => write!(f, "[governance] {}", action),
+            Self::SettlementDigest(digest) => write!(
+                f,
+                "[settlement_digest] epoch_id:{} with digest:{}",
+                digest.epoch_id, digest.digest
+            ),
         }
     }
 }
@@ -90,6 +122,18 @@ impl From<EpochEnd> for PoolConsensusItem {
     }
 }
 
+impl From<ForceSettle> for PoolConsensusItem {
+    fn from(value: ForceSettle) -> Self {
+        Self::ForceSettle(value)
+    }
+}
+
+impl From<GovernanceAction> for PoolConsensusItem {
+    fn from(value: GovernanceAction) -> Self {
+        Self::Governance(value)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ConsensusItemOutcome {
     Applied,
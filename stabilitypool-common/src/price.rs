@@ -1,38 +1,84 @@
 use std::{
     fs::File,
+    future::Future,
     io::Read,
     sync::atomic::{self, AtomicU32, AtomicU64},
 };
 
 use anyhow::Result;
 use async_trait::async_trait;
+use bitcoin::secp256k1::rand::{self, Rng};
+use serde::{Deserialize, Serialize};
+use time::format_description::well_known::Rfc3339;
 use time::{Duration, OffsetDateTime};
 
 use crate::config::EpochConfig;
+use crate::epoch::EpochId;
 
-/// Oracle Client that returns prices in cents/BTC
+/// Oracle Client that returns prices in cents/BTC of the pool's quote currency
 #[async_trait]
 pub trait OracleClient: Sync + Send + core::fmt::Debug {
     async fn price_at_epoch_start(
         &self,
         config: &EpochConfig,
-        epoch_id: u64,
+        epoch_id: EpochId,
     ) -> anyhow::Result<u64> {
         let epoch_time =
-            config.start_epoch_at() + Duration::new((epoch_id * config.epoch_length) as _, 0);
-        self.price_at_time(epoch_time).await
+            config.start_epoch_at() + Duration::new((epoch_id.0 * config.epoch_length) as _, 0);
+        let TimestampedPrice { price, timestamp } =
+            self.timestamped_price_at_time(epoch_time).await?;
+        if let Some(max_staleness_secs) = config.max_price_staleness_secs {
+            if epoch_time - timestamp > Duration::new(max_staleness_secs as _, 0) {
+                return Err(OracleError::Stale {
+                    timestamp,
+                    requested_for: epoch_time,
+                }
+                .into());
+            }
+        }
+        Ok(price)
     }
 
     async fn price_at_time(&self, datetime: OffsetDateTime) -> Result<u64>;
 
+    /// The price at `datetime` along with when the oracle's source last
+    /// updated it. Oracles that can not tell report `datetime` itself.
+    async fn timestamped_price_at_time(
+        &self,
+        datetime: OffsetDateTime,
+    ) -> Result<TimestampedPrice> {
+        Ok(TimestampedPrice {
+            price: self.price_at_time(datetime).await?,
+            timestamp: datetime,
+        })
+    }
+
     async fn price_now(&self) -> Result<u64> {
         self.price_at_time(OffsetDateTime::now_utc()).await
     }
 }
 
+/// A price in cents/BTC and the time its source last updated it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct TimestampedPrice {
+    pub price: u64,
+    pub timestamp: OffsetDateTime,
+}
+
 #[derive(Debug)]
 pub struct MockOracle {
     pub url: reqwest::Url,
+    pub quote_currency: String,
+}
+
+impl MockOracle {
+    /// The configured url with the requested `currency` added to the query.
+    pub fn request_url(&self) -> reqwest::Url {
+        let mut url = self.url.clone();
+        url.query_pairs_mut()
+            .append_pair("currency", &self.quote_currency);
+        url
+    }
 }
 
 #[async_trait]
@@ -42,7 +88,7 @@ impl OracleClient for MockOracle {
         struct Response {
             price: f32,
         }
-        let price_at_time = reqwest::get(self.url.clone())
+        let price_at_time = reqwest::get(self.request_url())
             .await?
             .json::<Response>()
             .await?
@@ -58,26 +104,156 @@ pub struct FileOracle {
 
 #[async_trait]
 impl OracleClient for FileOracle {
-    async fn price_at_time(&self, _datetime: OffsetDateTime) -> Result<u64> {
-        let price = match File::open(&self.path.to_path_buf()) {
+    async fn price_at_time(&self, datetime: OffsetDateTime) -> Result<u64> {
+        Ok(self.timestamped_price_at_time(datetime).await?.price)
+    }
+
+    /// The price in the file, timestamped with the file's modification time.
+    async fn timestamped_price_at_time(
+        &self,
+        datetime: OffsetDateTime,
+    ) -> Result<TimestampedPrice> {
+        match File::open(&self.path.to_path_buf()) {
             Ok(mut f) => {
                 let mut buf = String::new();
                 f.read_to_string(&mut buf)?;
-                buf.trim().parse::<f64>()?
+                Ok(TimestampedPrice {
+                    price: parse_file_price(&buf)?,
+                    timestamp: f.metadata()?.modified()?.into(),
+                })
             }
-            // default price of $10,000 (or 1,000,000 cents) if file does not exist
-            Err(_) => 1_000_000_f64,
+            // default price of $1,000,000 (or 100,000,000 cents) if file does not exist
+            Err(_) => Ok(TimestampedPrice {
+                price: 100_000_000,
+                timestamp: datetime,
+            }),
+        }
+    }
+}
+
+/// Parse the content of a [`FileOracle`] file, surrounding whitespace
+/// ignored. A whole number is a price in cents, e.g. `5000000`, while a
+/// decimal number is in dollars, e.g. `50000.00`, and is rounded down to
+/// whole cents.
+pub fn parse_file_price(content: &str) -> std::result::Result<u64, OracleError> {
+    let parse_error = || OracleError::Parse {
+        content: content.to_string(),
+    };
+    let price = content.trim();
+    let is_number = |digits: &str| !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit());
+
+    match price.split_once('.') {
+        None if is_number(price) => price.parse::<u64>().map_err(|_| parse_error()),
+        Some((dollars, cents)) if is_number(dollars) && is_number(cents) => {
+            let cents = format!("{:0<2}", &cents[..cents.len().min(2)]);
+            dollars
+                .parse::<u64>()
+                .ok()
+                .and_then(|dollars| dollars.checked_mul(100))
+                .and_then(|price| price.checked_add(cents.parse().ok()?))
+                .ok_or_else(parse_error)
+        }
+        _ => Err(parse_error()),
+    }
+}
+
+/// How long a [`UnixSocketOracle`] waits for the price feed.
+pub const UNIX_SOCKET_ORACLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Price feed running next to the peer, which writes a price in the format of
+/// [`parse_file_price`] followed by a newline to every connection to the unix
+/// socket at `path`. Only available on unix.
+#[derive(Debug)]
+pub struct UnixSocketOracle {
+    pub path: std::path::PathBuf,
+    pub timeout: std::time::Duration,
+}
+
+impl UnixSocketOracle {
+    #[cfg(unix)]
+    async fn read_price(&self) -> Result<u64> {
+        use tokio::io::{AsyncBufReadExt, BufReader};
+        use tokio::net::UnixStream;
+
+        let read_line = async {
+            let stream = UnixStream::connect(&self.path).await?;
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line).await?;
+            Ok::<_, std::io::Error>(line)
         };
-        Ok((price * 100.0).floor() as u64)
+        match tokio::time::timeout(self.timeout, read_line).await {
+            Ok(line) => Ok(parse_file_price(&line?)?),
+            Err(_) => Err(OracleError::Timeout {
+                timeout: self.timeout,
+            }
+            .into()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    async fn read_price(&self) -> Result<u64> {
+        anyhow::bail!("unix socket oracles are only available on unix")
     }
 }
 
+#[async_trait]
+impl OracleClient for UnixSocketOracle {
+    async fn price_at_time(&self, _datetime: OffsetDateTime) -> Result<u64> {
+        self.read_price().await
+    }
+}
+
+/// Errors of oracles that can not provide a price.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum OracleError {
+    /// The oracle's price source holds something other than a price
+    Parse { content: String },
+    /// The price was last updated at `timestamp`, too long before the time
+    /// `requested_for` it was requested for
+    Stale {
+        timestamp: OffsetDateTime,
+        requested_for: OffsetDateTime,
+    },
+    /// The oracle's price source did not answer within `timeout`
+    Timeout { timeout: std::time::Duration },
+}
+
+impl std::fmt::Display for OracleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Parse { content } => write!(f, "failed to parse price from {:?}", content),
+            Self::Stale {
+                timestamp,
+                requested_for,
+            } => write!(
+                f,
+                "price from {} is too stale for {}",
+                timestamp, requested_for
+            ),
+            Self::Timeout { timeout } => {
+                write!(f, "no price from the price feed within {:?}", timeout)
+            }
+        }
+    }
+}
+
+impl std::error::Error for OracleError {}
+
+/// BitMex `.BXBT` index, only available in USD.
 #[derive(Debug)]
 pub struct BitMexOracle {}
 
 #[async_trait]
 impl OracleClient for BitMexOracle {
     async fn price_at_time(&self, datetime: OffsetDateTime) -> Result<u64> {
+        Ok(self.timestamped_price_at_time(datetime).await?.price)
+    }
+
+    /// The index price, timestamped with the time BitMex recorded it at.
+    async fn timestamped_price_at_time(
+        &self,
+        datetime: OffsetDateTime,
+    ) -> Result<TimestampedPrice> {
         let mut url =
             reqwest::Url::parse("https://www.bitmex.com/api/v1/instrument/compositeIndex").unwrap();
         let symbol = ".BXBT";
@@ -94,10 +270,11 @@ impl OracleClient for BitMexOracle {
             timestamp_second: u8,
         }
 
-        #[derive(serde::Deserialize, Debug, Clone, Copy)]
+        #[derive(serde::Deserialize, Debug, Clone)]
         #[serde(rename_all = "camelCase")]
         struct Price {
             last_price: f64,
+            timestamp: String,
         }
         let filter = serde_json::to_string(&Filter {
             timestamp_date: datetime.date(),
@@ -112,23 +289,74 @@ impl OracleClient for BitMexOracle {
             .append_pair("filter", &filter)
             .append_pair("columns", "lastPrice,timestamp"); // only necessary fields
 
-        let price_at_time = reqwest::get(url).await?.json::<[Price; 1]>().await?[0];
+        let [price_at_time] = reqwest::get(url).await?.json::<[Price; 1]>().await?;
 
-        Ok((price_at_time.last_price * 100.0).floor() as u64)
+        Ok(TimestampedPrice {
+            price: (price_at_time.last_price * 100.0).floor() as u64,
+            timestamp: OffsetDateTime::parse(&price_at_time.timestamp, &Rfc3339)?,
+        })
     }
 }
 
-#[derive(Debug)]
-pub struct BackOff {
+/// Schedule of [`BackOff`]: after `n` consecutive failed rounds we wait
+/// `base_delay * multiplier^n` seconds, up to `max_delay`.
+///
+/// Within a round a fetch is attempted up to `attempts_per_round` times. The
+/// `n`th retry waits `retry_delay_ms * multiplier^(n - 1)` milliseconds, up
+/// to `max_delay`, of which a random part between half and all is used so
+/// that peers do not retry in lockstep.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct BackOffConfig {
+    /// Delay in seconds the schedule starts from
+    pub base_delay: u64,
     /// Max delay in seconds
     pub max_delay: u64,
-    pub base: u64,
+    pub multiplier: u64,
+    /// Attempts at a fetch within one round
+    #[serde(default = "default_attempts_per_round")]
+    pub attempts_per_round: u32,
+    /// Delay in milliseconds before the first retry within a round
+    #[serde(default = "default_retry_delay_ms")]
+    pub retry_delay_ms: u64,
+}
+
+fn default_attempts_per_round() -> u32 {
+    3
+}
+
+fn default_retry_delay_ms() -> u64 {
+    250
+}
+
+impl Default for BackOffConfig {
+    fn default() -> Self {
+        Self {
+            base_delay: 1,
+            max_delay: 20,
+            multiplier: 2,
+            attempts_per_round: default_attempts_per_round(),
+            retry_delay_ms: default_retry_delay_ms(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BackOff {
+    pub config: BackOffConfig,
 
     failures: AtomicU32,
     last_failure: AtomicU64,
 }
 
 impl BackOff {
+    pub fn new(config: BackOffConfig) -> Self {
+        Self {
+            config,
+            failures: AtomicU32::new(0),
+            last_failure: AtomicU64::new(0),
+        }
+    }
+
     pub fn record_failure(&self, failure_time: OffsetDateTime) {
         let timestamp = failure_time.unix_timestamp() as u64;
         self.last_failure.swap(timestamp, atomic::Ordering::Relaxed);
@@ -143,9 +371,59 @@ impl BackOff {
         now >= self.earliest_retry()
     }
 
+    /// Run `fetch` for the round at `now`, retrying it up to
+    /// `attempts_per_round` times in all with a jittered delay in between.
+    /// Success resets the backoff, and the round only counts as a failure if
+    /// the last attempt fails too.
+    pub async fn retry<T, F, Fut>(&self, now: OffsetDateTime, mut fetch: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let attempts = self.config.attempts_per_round.max(1);
+        let mut attempt = 1;
+        loop {
+            match fetch().await {
+                Ok(value) => {
+                    self.reset();
+                    return Ok(value);
+                }
+                Err(err) if attempt >= attempts => {
+                    self.record_failure(now);
+                    return Err(err);
+                }
+                Err(err) => {
+                    tracing::debug!(attempt, attempts, error = %err, "fetch failed, retrying");
+                    fedimint_core::task::sleep(self.retry_delay(attempt)).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Delay before retry number `retry` of a round, see [`BackOffConfig`].
+    fn retry_delay(&self, retry: u32) -> std::time::Duration {
+        let max_delay_ms = self.config.max_delay.saturating_mul(1_000);
+        let delay_ms = self
+            .config
+            .multiplier
+            .checked_pow(retry - 1)
+            .and_then(|factor| factor.checked_mul(self.config.retry_delay_ms))
+            .map_or(max_delay_ms, |delay| delay.min(max_delay_ms));
+        let jittered_ms = rand::thread_rng().gen_range(delay_ms / 2..=delay_ms);
+        std::time::Duration::from_millis(jittered_ms)
+    }
+
     fn delay(&self) -> Duration {
         let failures = self.failures.load(atomic::Ordering::Relaxed);
-        let delay = self.base.pow(failures).min(self.max_delay);
+        let delay = self
+            .config
+            .multiplier
+            .checked_pow(failures)
+            .and_then(|factor| factor.checked_mul(self.config.base_delay))
+            .map_or(self.config.max_delay, |delay| {
+                delay.min(self.config.max_delay)
+            });
         Duration::new(delay as _, 0)
     }
 
@@ -160,21 +438,152 @@ impl BackOff {
 
 impl Default for BackOff {
     fn default() -> Self {
-        Self {
-            max_delay: 20,
-            base: 2,
-            failures: AtomicU32::new(0),
-            last_failure: AtomicU64::new(0),
-        }
+        Self::new(BackOffConfig::default())
     }
 }
 
 #[cfg(test)]
 mod test {
-    use time::OffsetDateTime;
+    use super::*;
 
-    use crate::BitMexOracle;
-    use crate::OracleClient;
+    #[test]
+    fn backoff_follows_configured_schedule() {
+        let backoff = BackOff::new(BackOffConfig {
+            base_delay: 3,
+            max_delay: 50,
+            multiplier: 3,
+            attempts_per_round: 1,
+            retry_delay_ms: 0,
+        });
+        let start = OffsetDateTime::from_unix_timestamp(1_000_000).unwrap();
+
+        // 9s, 27s, then capped at 50s
+        let mut delays = vec![];
+        for _ in 0..4 {
+            backoff.record_failure(start);
+            let delay = (1..)
+                .find(|&secs| backoff.can_retry(start + time::Duration::seconds(secs)))
+                .unwrap();
+            delays.push(delay);
+        }
+        assert_eq!(delays, vec![9, 27, 50, 50]);
+
+        backoff.reset();
+        assert!(backoff.can_retry(start + time::Duration::seconds(3)));
+        assert!(!backoff.can_retry(start + time::Duration::seconds(2)));
+    }
+
+    /// Fails the first `failures` requests, then returns a price.
+    #[derive(Debug)]
+    struct FlakyOracle {
+        failures: u32,
+        requests: std::sync::atomic::AtomicU32,
+    }
+
+    #[async_trait::async_trait]
+    impl OracleClient for FlakyOracle {
+        async fn price_at_time(&self, _datetime: OffsetDateTime) -> anyhow::Result<u64> {
+            let request = self
+                .requests
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            if request < self.failures {
+                anyhow::bail!("oracle is flaky");
+            }
+            Ok(2_000_000)
+        }
+    }
+
+    #[tokio::test]
+    async fn oracle_fetches_are_retried_within_bounds() {
+        let backoff = BackOff::new(BackOffConfig {
+            attempts_per_round: 3,
+            retry_delay_ms: 1,
+            ..BackOffConfig::default()
+        });
+        let now = OffsetDateTime::from_unix_timestamp(1_000_000).unwrap();
+        let flaky = |failures| FlakyOracle {
+            failures,
+            requests: Default::default(),
+        };
+        let requests =
+            |oracle: &FlakyOracle| oracle.requests.load(std::sync::atomic::Ordering::Relaxed);
+
+        // transient failures are retried away within the round
+        let oracle = flaky(2);
+        let price = backoff.retry(now, || oracle.price_at_time(now)).await;
+        assert_eq!(price.unwrap(), 2_000_000);
+        assert_eq!(requests(&oracle), 3);
+        assert!(backoff.can_retry(now));
+
+        // but a round stops after its attempts and backs off
+        let oracle = flaky(10);
+        assert!(backoff
+            .retry(now, || oracle.price_at_time(now))
+            .await
+            .is_err());
+        assert_eq!(requests(&oracle), 3);
+        assert!(!backoff.can_retry(now));
+    }
+
+    #[test]
+    fn retry_delays_are_jittered_and_capped() {
+        let backoff = BackOff::new(BackOffConfig {
+            max_delay: 1,
+            multiplier: 2,
+            retry_delay_ms: 300,
+            ..BackOffConfig::default()
+        });
+        for _ in 0..100 {
+            let first = backoff.retry_delay(1).as_millis();
+            assert!((150..=300).contains(&first), "{}", first);
+            let second = backoff.retry_delay(2).as_millis();
+            assert!((300..=600).contains(&second), "{}", second);
+            // capped at `max_delay`
+            let fourth = backoff.retry_delay(4).as_millis();
+            assert!((500..=1_000).contains(&fourth), "{}", fourth);
+        }
+    }
+
+    #[tokio::test]
+    async fn mock_oracle_requests_quote_currency() {
+        use std::io::Write;
+
+        let oracle = MockOracle {
+            url: reqwest::Url::parse("http://127.0.0.1:8080/price").unwrap(),
+            quote_currency: "EUR".to_string(),
+        };
+        assert_eq!(
+            oracle.request_url().as_str(),
+            "http://127.0.0.1:8080/price?currency=EUR"
+        );
+
+        // serve a single EUR price and check it is the one returned
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let len = stream.read(&mut request).unwrap();
+            let body = r#"{"price":19000.5}"#;
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+            String::from_utf8_lossy(&request[..len]).to_string()
+        });
+        let oracle = MockOracle {
+            url: reqwest::Url::parse(&format!("http://{}/price", addr)).unwrap(),
+            quote_currency: "EUR".to_string(),
+        };
+        assert_eq!(oracle.price_now().await.unwrap(), 1_900_050);
+        assert!(server
+            .join()
+            .unwrap()
+            .starts_with("GET /price?currency=EUR "));
+    }
 
     #[tokio::test]
     async fn get_price_at_time() {
@@ -183,4 +592,138 @@ mod test {
         let client = BitMexOracle {};
         assert_eq!(client.price_at_time(time).await.unwrap(), 2118721);
     }
+
+    #[test]
+    fn file_prices_in_cents_or_dollars() {
+        assert_eq!(parse_file_price("5000000"), Ok(5_000_000));
+        assert_eq!(parse_file_price("5000000\n"), Ok(5_000_000));
+        assert_eq!(parse_file_price("  50000.00 \n"), Ok(5_000_000));
+        assert_eq!(parse_file_price("50000.5"), Ok(5_000_050));
+        // fractions of a cent are rounded down
+        assert_eq!(parse_file_price("50000.129"), Ok(5_000_012));
+
+        for content in [
+            "", "\n", "abc", "50,000", "-5", "5e6", "50000.", ".5", "1.2.3",
+        ] {
+            assert_eq!(
+                parse_file_price(content),
+                Err(OracleError::Parse {
+                    content: content.to_string()
+                }),
+                "{:?}",
+                content
+            );
+        }
+        assert!(parse_file_price("184467440737095516.16").is_err());
+    }
+
+    #[tokio::test]
+    async fn malformed_file_reports_its_content() {
+        let path =
+            std::env::temp_dir().join(format!("stabilitypool-oracle-{}", rand::random::<u64>()));
+        std::fs::write(&path, "not a price\n").unwrap();
+        let oracle = FileOracle { path: path.clone() };
+
+        let err = oracle.price_now().await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<OracleError>(),
+            Some(&OracleError::Parse {
+                content: "not a price\n".to_string()
+            })
+        );
+
+        std::fs::write(&path, "50000.00").unwrap();
+        assert_eq!(oracle.price_now().await.unwrap(), 5_000_000);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket_prices_are_read_line_by_line() {
+        use std::io::Write;
+        use std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!(
+            "stabilitypool-oracle-{}.sock",
+            rand::random::<u64>()
+        ));
+        let listener = UnixListener::bind(&path).unwrap();
+        let oracle = UnixSocketOracle {
+            path: path.clone(),
+            timeout: std::time::Duration::from_millis(200),
+        };
+
+        // the feed answers the first connection and then goes quiet
+        let feed = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"50000.00\n").unwrap();
+            drop(stream);
+            let (stream, _) = listener.accept().unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(500));
+            drop(stream);
+        });
+        assert_eq!(oracle.price_now().await.unwrap(), 5_000_000);
+        let err = oracle.price_now().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<OracleError>(),
+            Some(OracleError::Timeout { .. })
+        ));
+        feed.join().unwrap();
+
+        // nothing listens anymore
+        std::fs::remove_file(&path).unwrap();
+        assert!(oracle.price_now().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn stale_file_price_is_rejected() {
+        use crate::config::EpochConfig;
+
+        let path =
+            std::env::temp_dir().join(format!("stabilitypool-oracle-{}", rand::random::<u64>()));
+        std::fs::write(&path, "5000000").unwrap();
+        let oracle = FileOracle { path: path.clone() };
+        let config = |start_epoch_at: OffsetDateTime| EpochConfig {
+            start_epoch_at: start_epoch_at.unix_timestamp() as _,
+            epoch_length: 3_600,
+            max_price_staleness_secs: Some(600),
+            ..Default::default()
+        };
+        let now = OffsetDateTime::now_utc();
+
+        // the file was just written, so it is fresh for an epoch starting now
+        let fresh = config(now);
+        assert_eq!(
+            oracle
+                .price_at_epoch_start(&fresh, EpochId(0))
+                .await
+                .unwrap(),
+            5_000_000
+        );
+
+        // but a day too old for one starting tomorrow
+        let stale = config(now + Duration::days(1));
+        let err = oracle
+            .price_at_epoch_start(&stale, EpochId(0))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<OracleError>(),
+            Some(OracleError::Stale { .. })
+        ));
+
+        // unless staleness is unbounded
+        let unbounded = EpochConfig {
+            max_price_staleness_secs: None,
+            ..stale
+        };
+        assert_eq!(
+            oracle
+                .price_at_epoch_start(&unbounded, EpochId(0))
+                .await
+                .unwrap(),
+            5_000_000
+        );
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -18,6 +18,7 @@ const PPM_TO_INTERNAL: u64 = FEERATE_UNIT_DENOMINATOR / 1_000_000;
     Clone,
     Debug,
     Copy,
+    Default,
     PartialEq,
     Eq,
     Hash,
@@ -35,14 +36,29 @@ impl EpochFeerate {
         Self(0)
     }
 
-    /// Approximate feerate in parts per million
+    /// Feerate in parts per million rounded down, so less than 1 ppm below
+    /// [`Self::ppm_feerate_decimal`]
     pub fn approx_ppm_feerate(&self) -> u64 {
         self.0 / PPM_TO_INTERNAL
     }
 
+    /// The exact feerate in parts per million as a decimal number, e.g.
+    /// `"12.5"`. Every feerate has one since the internal unit is a power of
+    /// ten fraction of a ppm.
+    pub fn ppm_feerate_decimal(&self) -> String {
+        let whole = self.0 / PPM_TO_INTERNAL;
+        let fraction = self.0 % PPM_TO_INTERNAL;
+        if fraction == 0 {
+            return whole.to_string();
+        }
+        // PPM_TO_INTERNAL is 10^12, so the fraction has 12 decimal digits
+        let fraction = format!("{:012}", fraction);
+        format!("{}.{}", whole, fraction.trim_end_matches('0'))
+    }
+
     /// Convert a parts per million feerate to an epoch feerate
     pub fn from_ppm_feerate(rate: FeeratePPM) -> Self {
-        Self(rate * PPM_TO_INTERNAL)
+        Self(rate.saturating_mul(PPM_TO_INTERNAL))
     }
 }
 
@@ -64,14 +80,71 @@ pub struct ProviderBid {
     pub account_id: XOnlyPublicKey,
 }
 
-/// Ratio of seeker position to provider collateral.
+/// Ratio of seeker position to provider collateral. Neither side can be 0,
+/// which [`CollateralRatio::new`] and deserialization check, so the ratio is
+/// always between 1:255 and 255:1.
 #[derive(Clone, Copy, Debug, serde::Serialize, serde::Deserialize, PartialEq, Eq, Encodable)]
+#[serde(try_from = "RawCollateralRatio")]
 pub struct CollateralRatio {
-    pub seeker: u8,
-    pub provider: u8,
+    seeker: u8,
+    provider: u8,
+}
+
+/// [`CollateralRatio`] as serialized, before it is checked.
+#[derive(serde::Deserialize)]
+struct RawCollateralRatio {
+    seeker: u8,
+    provider: u8,
+}
+
+impl TryFrom<RawCollateralRatio> for CollateralRatio {
+    type Error = CollateralRatioError;
+
+    fn try_from(raw: RawCollateralRatio) -> Result<Self, Self::Error> {
+        Self::new(raw.seeker, raw.provider)
+    }
 }
 
+/// Errors of ratios that can not be a [`CollateralRatio`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CollateralRatioError {
+    ZeroSeeker,
+    ZeroProvider,
+}
+
+impl std::fmt::Display for CollateralRatioError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ZeroSeeker => write!(f, "collateral ratio has no seeker side"),
+            Self::ZeroProvider => write!(f, "collateral ratio has no provider side"),
+        }
+    }
+}
+
+impl std::error::Error for CollateralRatioError {}
+
 impl CollateralRatio {
+    /// The ratio of `seeker` position to `provider` collateral.
+    pub fn new(seeker: u8, provider: u8) -> Result<Self, CollateralRatioError> {
+        if seeker == 0 {
+            return Err(CollateralRatioError::ZeroSeeker);
+        }
+        if provider == 0 {
+            return Err(CollateralRatioError::ZeroProvider);
+        }
+        Ok(Self { seeker, provider })
+    }
+
+    /// The seeker position side of the ratio.
+    pub fn seeker(&self) -> u8 {
+        self.seeker
+    }
+
+    /// The provider collateral side of the ratio.
+    pub fn provider(&self) -> u8 {
+        self.provider
+    }
+
     /// The collateral needed to satisfy `position`.
     pub fn collateral_for_provider_position(&self, position: u64) -> u64 {
         ceiling_div(
@@ -80,9 +153,11 @@ impl CollateralRatio {
         )
         .expect("collateral for position is ridiculously high")
     }
-    /// The amount long position a provider gets with `collateral`.
+    /// The amount long position a provider gets with `collateral`. Saturates
+    /// at [`u64::MAX`] when leveraged seekers push it past that.
     pub fn position_for_provider_collateral(&self, collateral: u64) -> u64 {
-        (collateral as u128 * self.seeker as u128 / self.provider as u128) as u64
+        u64::try_from(collateral as u128 * self.seeker as u128 / self.provider as u128)
+            .unwrap_or(u64::MAX)
     }
 
     /// How low does end_price/start_price have to go before the provider
@@ -91,27 +166,21 @@ impl CollateralRatio {
     /// ```
     /// use stabilitypool::stability_core::CollateralRatio;
     /// assert_eq!(
-    ///     CollateralRatio {
-    ///         seeker: 1,
-    ///         provider: 1
-    ///     }
-    ///     .seeker_protection_threshold(),
+    ///     CollateralRatio::new(1, 1)
+    ///         .unwrap()
+    ///         .seeker_protection_threshold(),
     ///     0.50
     /// );
     /// assert_eq!(
-    ///     CollateralRatio {
-    ///         seeker: 1,
-    ///         provider: 3
-    ///     }
-    ///     .seeker_protection_threshold(),
+    ///     CollateralRatio::new(1, 3)
+    ///         .unwrap()
+    ///         .seeker_protection_threshold(),
     ///     0.25
     /// );
     /// assert_eq!(
-    ///     CollateralRatio {
-    ///         seeker: 3,
-    ///         provider: 2
-    ///     }
-    ///     .seeker_protection_threshold(),
+    ///     CollateralRatio::new(3, 2)
+    ///         .unwrap()
+    ///         .seeker_protection_threshold(),
     ///     0.60
     /// );
     /// ```
@@ -130,7 +199,7 @@ impl CollateralRatio {
     }
 
     pub fn provider_leverage(&self) -> f64 {
-        self.provider as f64 / (self.seeker + self.provider) as f64
+        self.provider as f64 / (self.seeker as f64 + self.provider as f64)
     }
 }
 
@@ -223,6 +292,22 @@ pub fn compute_desired_positions(
     (seeker_locks.into_values(), provider_bids)
 }
 
+/// The `bids` with their feerate raised to at least `min_feerate_ppm`.
+///
+/// Matching never settles below the feerate of the providers it selects, so
+/// flooring every bid puts a floor under the epoch feerate.
+pub fn bids_above_feerate_floor(
+    bids: impl IntoIterator<Item = ProviderBid>,
+    min_feerate_ppm: u64,
+) -> Vec<ProviderBid> {
+    bids.into_iter()
+        .map(|bid| ProviderBid {
+            min_feerate: bid.min_feerate.max(min_feerate_ppm),
+            ..bid
+        })
+        .collect()
+}
+
 const TOTAL_MSAT: u64 = 21_000_000 * 100_000_000 * 1_000;
 pub const MAX_TOTAL_SEEKER_VALUE: u64 = TOTAL_MSAT / 10;
 
@@ -370,8 +455,9 @@ pub fn match_locks_and_bids(
     let total_fee = provider_fee(feerate, selected_provider_collateral, pool_ratio);
     // The total value we will lock from the seekers is the provider position +
     // total fee.
-    let mut seeker_locked_remaining =
-        pool_ratio.position_for_provider_collateral(selected_provider_collateral) + total_fee;
+    let mut seeker_locked_remaining = pool_ratio
+        .position_for_provider_collateral(selected_provider_collateral)
+        .saturating_add(total_fee);
 
     for seeker in seeker_locks.iter() {
         let locked_value = seeker_locked_remaining.min(seeker.value);
@@ -383,9 +469,64 @@ pub fn match_locks_and_bids(
         }
     }
 
+    let total_provider_locked = provider_entries.values().sum();
+    cap_seekers_to_collateral(
+        feerate,
+        &mut seeker_entries,
+        total_provider_locked,
+        pool_ratio,
+    );
+
     (feerate, seeker_entries, provider_entries)
 }
 
+/// Whether `total_provider_locked` collateral covers the worst-case payout to
+/// seekers who locked `total_seeker_locked` at `feerate`, that is whether it
+/// is at least `ratio` times their position.
+pub fn is_collateralized(
+    feerate: EpochFeerate,
+    total_seeker_locked: u64,
+    total_provider_locked: u64,
+    ratio: CollateralRatio,
+) -> bool {
+    let position = seeker_position(feerate, total_seeker_locked);
+    ceiling_div(
+        position as u128 * ratio.provider() as u128,
+        ratio.seeker() as u128,
+    )
+    .map_or(false, |needed| needed <= total_provider_locked)
+}
+
+/// Reduce `seeker_entries`, last account first, until `total_provider_locked`
+/// collateral covers them (see [`is_collateralized`]). Seekers left with
+/// nothing locked are removed.
+pub fn cap_seekers_to_collateral(
+    feerate: EpochFeerate,
+    seeker_entries: &mut BTreeMap<XOnlyPublicKey, u64>,
+    total_provider_locked: u64,
+    ratio: CollateralRatio,
+) {
+    // the most seekers can lock is the position the collateral backs plus the
+    // fee paid on it, as in `match_locks_and_bids`
+    let max_seeker_locked = ratio
+        .position_for_provider_collateral(total_provider_locked)
+        .saturating_add(provider_fee(feerate, total_provider_locked, ratio));
+    let mut excess = seeker_entries
+        .values()
+        .sum::<u64>()
+        .saturating_sub(max_seeker_locked);
+
+    for locked in seeker_entries.values_mut().rev() {
+        if excess == 0 {
+            break;
+        }
+        let trimmed = excess.min(*locked);
+        *locked -= trimmed;
+        excess -= trimmed;
+    }
+    seeker_entries.retain(|_, locked| *locked > 0);
+}
+
 /// Calculate the payouts from the entries into the lockbox using the start and
 /// end prices of the epoch. Payouts are not *profits* they are simply what is
 /// returned to that account from the pool. This is called with the output of
@@ -503,6 +644,8 @@ pub fn seeker_fee(feerate: EpochFeerate, locked_value: u64) -> u64 {
     // NOTE: the formula here is derived from
     // 1. fee = feerate * position
     // 2. position = locked_value - fee
+    // Neither the product nor the sum can overflow u128, and the fee is always
+    // below locked_value so it fits back into u64.
     ((locked_value as u128 * feerate as u128)
         / (FEERATE_UNIT_DENOMINATOR as u128 + feerate as u128)) as u64
 }
@@ -515,10 +658,17 @@ pub fn seeker_position(feerate: EpochFeerate, locked_value: u64) -> u64 {
 
 /// The fee the provider will receive for locking in `locked_value`. The fee is
 /// paid per point of position so we need to know the collateral `ratio`.
+/// Saturates at [`u64::MAX`] rather than overflowing for absurd feerates.
 pub fn provider_fee(feerate: EpochFeerate, locked_value: u64, ratio: CollateralRatio) -> u64 {
     let feerate = feerate.0;
-    let position = ratio.position_for_provider_collateral(locked_value);
-    ((position as u128 * feerate as u128) / FEERATE_UNIT_DENOMINATOR as u128) as u64
+    // NOTE: the position is kept in u128 as it can exceed locked_value (and
+    // u64) when seekers are leveraged
+    let position = locked_value as u128 * ratio.seeker() as u128 / ratio.provider() as u128;
+    position
+        .checked_mul(feerate as u128)
+        .map(|fee| fee / FEERATE_UNIT_DENOMINATOR as u128)
+        .and_then(|fee| u64::try_from(fee).ok())
+        .unwrap_or(u64::MAX)
 }
 
 /// The payout in msats (not including fee) for a provider who locked in
@@ -530,13 +680,13 @@ pub fn provider_price_payout(
     ratio: CollateralRatio,
 ) -> u64 {
     let position = ratio.position_for_provider_collateral(locked_value);
-    let pnl = pnl_for_price(position as i64, start_price, end_price);
-    let payout = locked_value as i64 + pnl;
-    if payout < 0 {
-        0
-    } else {
-        payout as u64
-    }
+    let pnl = pnl_for_price(
+        i64::try_from(position).unwrap_or(i64::MAX),
+        start_price,
+        end_price,
+    );
+    let payout = locked_value as i128 + pnl as i128;
+    u64::try_from(payout.max(0)).unwrap_or(u64::MAX)
 }
 
 /// The payout in msats for a provider who locked in `locked_value` into an
@@ -550,7 +700,7 @@ pub fn provider_payout(
 ) -> u64 {
     let fee = provider_fee(feerate, locked_value, ratio);
     let price_payout = provider_price_payout(locked_value, start_price, end_price, ratio);
-    fee + price_payout
+    fee.saturating_add(price_payout)
 }
 
 /// The payout in msats for a seeker who locked in `locked_value` into an
@@ -682,6 +832,59 @@ mod tests {
         assert_eq!(feerate.0, 0);
     }
 
+    #[test]
+    fn feerate_floor_applies_below_natural_clearing_rate() {
+        let seeker = SeekerLock {
+            value: 1_000_000,
+            account_id: random_pubkey(&mut rand::thread_rng()),
+        };
+        let provider = ProviderBid {
+            max_value: 10_000_000,
+            min_feerate: 0,
+            account_id: random_pubkey(&mut rand::thread_rng()),
+        };
+        let ratio = CollateralRatio::default();
+
+        // plenty of free collateral clears at no fee at all
+        let (feerate, ..) = match_locks_and_bids(
+            vec![seeker.clone()],
+            bids_above_feerate_floor(vec![provider.clone()], 0),
+            ratio,
+        );
+        assert_eq!(feerate.0, 0);
+
+        let floor = HUNDRED_PERCENT_FEE / 100; // A fee rate of 1%
+        let (feerate, seeker_entries, provider_entries) = match_locks_and_bids(
+            vec![seeker.clone()],
+            bids_above_feerate_floor(vec![provider.clone()], floor),
+            ratio,
+        );
+        assert_eq!(feerate.approx_ppm_feerate(), floor);
+        assert_almost_eq!(seeker_entries[&seeker.account_id], seeker.value, 1);
+        let total_provider_locked = provider_entries[&provider.account_id];
+        assert!(total_provider_locked < provider.max_value);
+        assert!(is_collateralized(
+            feerate,
+            seeker_entries[&seeker.account_id],
+            total_provider_locked,
+            ratio
+        ));
+
+        // bids above the floor keep their own feerate
+        let (feerate, ..) = match_locks_and_bids(
+            vec![seeker],
+            bids_above_feerate_floor(
+                vec![ProviderBid {
+                    min_feerate: floor * 2,
+                    ..provider
+                }],
+                floor,
+            ),
+            ratio,
+        );
+        assert_eq!(feerate.approx_ppm_feerate(), floor * 2);
+    }
+
     #[test]
     fn begin_bigger_fee_than_min() {
         let seeker = SeekerLock {
@@ -729,6 +932,59 @@ mod tests {
         assert_eq!(feerate.approx_ppm_feerate(), HUNDRED_PERCENT_FEE / 5);
     }
 
+    #[test]
+    fn fully_covered_match_is_kept() {
+        let seeker = SeekerLock {
+            value: 1_000_000,
+            account_id: random_pubkey(&mut rand::thread_rng()),
+        };
+        let provider = ProviderBid {
+            max_value: 1_000_000,
+            min_feerate: HUNDRED_PERCENT_FEE / 100,
+            account_id: random_pubkey(&mut rand::thread_rng()),
+        };
+        let ratio = CollateralRatio::default();
+
+        let (feerate, mut seeker_entries, provider_entries) =
+            match_locks_and_bids(vec![seeker], vec![provider], ratio);
+        let total_provider_locked = provider_entries.values().sum();
+        assert!(is_collateralized(
+            feerate,
+            seeker_entries.values().sum(),
+            total_provider_locked,
+            ratio
+        ));
+
+        let matched = seeker_entries.clone();
+        cap_seekers_to_collateral(feerate, &mut seeker_entries, total_provider_locked, ratio);
+        assert_eq!(seeker_entries, matched);
+    }
+
+    #[test]
+    fn under_collateralized_match_is_capped() {
+        let mut accounts = [
+            random_pubkey(&mut rand::thread_rng()),
+            random_pubkey(&mut rand::thread_rng()),
+        ];
+        accounts.sort();
+        // 2:1 leverage, so 500 msats of collateral back 1_000 msats of seekers
+        let ratio = CollateralRatio::new(2, 1).unwrap();
+        let feerate = EpochFeerate::zero();
+        let mut seeker_entries = BTreeMap::from([(accounts[0], 600), (accounts[1], 600)]);
+        assert!(!is_collateralized(feerate, 1_200, 500, ratio));
+
+        cap_seekers_to_collateral(feerate, &mut seeker_entries, 500, ratio);
+        assert_eq!(
+            seeker_entries,
+            BTreeMap::from([(accounts[0], 600), (accounts[1], 400)])
+        );
+        assert!(is_collateralized(feerate, 1_000, 500, ratio));
+
+        // seekers left with nothing are dropped
+        cap_seekers_to_collateral(feerate, &mut seeker_entries, 200, ratio);
+        assert_eq!(seeker_entries, BTreeMap::from([(accounts[0], 400)]));
+    }
+
     #[test]
     fn partial_fill_one_seeker() {
         let seekers = vec![
@@ -857,6 +1113,15 @@ mod tests {
 
         let total_seeker_entered = seeker_entries.iter().map(|pos| pos.1).sum::<u64>();
         let total_provider_entered = provider_entries.iter().map(|pos| pos.1).sum::<u64>();
+        assert!(
+            is_collateralized(
+                feerate,
+                total_seeker_entered,
+                total_provider_entered,
+                pool_ratio
+            ),
+            "matched seekers must be covered by provider collateral"
+        );
         if pool_ratio.position_for_provider_collateral(total_provider_desired)
             >= total_seeker_desired
             && total_seeker_desired < MAX_TOTAL_SEEKER_VALUE
@@ -988,10 +1253,7 @@ mod tests {
             // price change as a percentage of start price
             price_change in -100i64..300,
         ) {
-            let pool_ratio = CollateralRatio {
-                seeker: 1,
-                provider: 1
-            };
+            let pool_ratio = CollateralRatio::default();
             let end_price = (start_price as i64 + (start_price as i64 * price_change / 100)) as u64;
             let seekers = CACHED_TEST_DATA.0.iter().cloned().take(n_seekers).collect();
             let providers = CACHED_TEST_DATA.1.iter().cloned().take(n_providers).collect();
@@ -1008,17 +1270,188 @@ mod tests {
             price_change in -100i64..300,
             pool_ratio in ((1u8..10), (1u8..10))
         ) {
-            let pool_ratio = CollateralRatio {
-                seeker: pool_ratio.0,
-                provider: pool_ratio.1,
-            };
+            let pool_ratio = CollateralRatio::new(pool_ratio.0, pool_ratio.1).unwrap();
             let end_price = (start_price as i64 + (start_price as i64 * price_change / 100)) as u64;
 
             let seekers = CACHED_TEST_DATA.0.iter().cloned().take(n_seekers).collect();
             let providers = CACHED_TEST_DATA.1.iter().cloned().take(n_providers).collect();
             // NOTE: Errors are larger with more drastic leverage ratios -- this is an empirically discovered bound
-            let fudge = pool_ratio.seeker.max(pool_ratio.provider) as u64 * 2;
+            let fudge = pool_ratio.seeker().max(pool_ratio.provider()) as u64 * 2;
             test_guarantees(seekers, providers, start_price, end_price, pool_ratio, fudge);
         }
+
+        #[test]
+        fn fees_do_not_overflow_proptest(
+            locked_value in any::<u64>(),
+            feerate_ppm in 0_u64..=HUNDRED_PERCENT_FEE,
+            pool_ratio in ((1u8..=u8::MAX), (1u8..=u8::MAX))
+        ) {
+            let pool_ratio = CollateralRatio::new(pool_ratio.0, pool_ratio.1).unwrap();
+            let feerate = EpochFeerate::from_ppm_feerate(feerate_ppm);
+
+            // the seeker pays at most the whole locked value, roughly
+            // feerate / (1 + feerate) of it
+            let fee = seeker_fee(feerate, locked_value);
+            prop_assert!(fee <= locked_value);
+            let expected = locked_value as u128 * feerate_ppm as u128
+                / (1_000_000 + feerate_ppm as u128);
+            prop_assert!(expected.abs_diff(fee as u128) <= 1);
+
+            // the provider earns feerate on its position, which can exceed
+            // u64 for leveraged seekers
+            let fee = provider_fee(feerate, locked_value, pool_ratio);
+            let position =
+                locked_value as u128 * pool_ratio.seeker() as u128 / pool_ratio.provider() as u128;
+            let expected = (position * feerate_ppm as u128 / 1_000_000).min(u64::MAX as u128);
+            prop_assert!(expected.abs_diff(fee as u128) <= 1);
+        }
+    }
+
+    #[test]
+    fn absurd_feerates_saturate() {
+        let feerate = EpochFeerate::from_ppm_feerate(u64::MAX);
+        assert_eq!(feerate, EpochFeerate(u64::MAX));
+        let ratio = CollateralRatio::new(u8::MAX, 1).unwrap();
+        assert_eq!(provider_fee(feerate, u64::MAX, ratio), u64::MAX);
+        assert_eq!(provider_payout(u64::MAX, feerate, 1, 1, ratio), u64::MAX);
+    }
+
+    #[test]
+    fn leveraged_positions_saturate() {
+        let ratio = CollateralRatio {
+            seeker: u8::MAX,
+            provider: 1,
+        };
+        assert_eq!(ratio.position_for_provider_collateral(u64::MAX), u64::MAX);
+        // a provider's gain on a position past u64 is capped rather than
+        // wrapping around to a loss
+        assert!(provider_price_payout(u64::MAX / 2, 1, 2, ratio) > u64::MAX / 2);
+    }
+
+    #[test]
+    fn approx_ppm_feerate_is_within_one_ppm() {
+        let cases = [
+            (0, "0", 0),
+            (1, "0.000000000001", 0),
+            (PPM_TO_INTERNAL - 1, "0.999999999999", 0),
+            (PPM_TO_INTERNAL, "1", 1),
+            (12 * PPM_TO_INTERNAL + PPM_TO_INTERNAL / 2, "12.5", 12),
+            (
+                100_000 * PPM_TO_INTERNAL + 250,
+                "100000.00000000025",
+                100_000,
+            ),
+            (u64::MAX, "18446744.073709551615", 18_446_744),
+        ];
+        for (internal, exact, approx) in cases {
+            let feerate = EpochFeerate(internal);
+            assert_eq!(feerate.ppm_feerate_decimal(), exact);
+            assert_eq!(feerate.approx_ppm_feerate(), approx);
+        }
+
+        for rate in [0, 1, 999, 10_000, HUNDRED_PERCENT_FEE] {
+            let feerate = EpochFeerate::from_ppm_feerate(rate);
+            assert_eq!(feerate.ppm_feerate_decimal(), rate.to_string());
+            assert_eq!(feerate.approx_ppm_feerate(), rate);
+        }
+    }
+
+    #[test]
+    fn collateral_ratios_need_both_sides() {
+        let ratio = CollateralRatio::new(3, 2).unwrap();
+        assert_eq!((ratio.seeker(), ratio.provider()), (3, 2));
+        assert_eq!(
+            CollateralRatio::new(0, 1),
+            Err(CollateralRatioError::ZeroSeeker)
+        );
+        assert_eq!(
+            CollateralRatio::new(1, 0),
+            Err(CollateralRatioError::ZeroProvider)
+        );
+
+        let parse = |json| serde_json::from_str::<CollateralRatio>(json);
+        assert_eq!(parse(r#"{"seeker":3,"provider":2}"#).unwrap(), ratio);
+        assert!(parse(r#"{"seeker":1,"provider":0}"#).is_err());
+        assert!(parse(r#"{"seeker":256,"provider":1}"#).is_err());
+    }
+
+    #[test]
+    fn payouts_are_bounded_across_ratios() {
+        let feerate = EpochFeerate::from_ppm_feerate(10_000);
+        let locked_value = 1_000_000;
+        for (seeker, provider) in [(1, 1), (1, u8::MAX), (u8::MAX, 1), (u8::MAX, u8::MAX)] {
+            let ratio = CollateralRatio::new(seeker, provider).unwrap();
+            assert!(ratio.provider_leverage() > 0.0 && ratio.provider_leverage() < 1.0);
+            for end_price in [1, 500, 1_000, 2_000, 1_000_000] {
+                // the seeker never gets more than its position and the
+                // collateral backing it
+                let position = seeker_position(feerate, locked_value);
+                let payout = seeker_payout(locked_value, feerate, 1_000, end_price, ratio);
+                assert!(payout <= position + ratio.collateral_for_provider_position(position));
+
+                // the provider never loses more than its collateral
+                let payout = provider_payout(locked_value, feerate, 1_000, end_price, ratio);
+                assert!(payout >= provider_fee(feerate, locked_value, ratio));
+            }
+            assert!(!ratio.does_price_change_break_protection_threshold(1_000, 1_000));
+        }
+    }
+
+    /// A row of `test_vectors/payouts.json`. Seeker and provider are evaluated
+    /// separately, each having locked `locked_value`.
+    #[derive(Debug, serde::Deserialize)]
+    struct PayoutVector {
+        scenario: String,
+        locked_value: u64,
+        feerate_ppm: u64,
+        start_price: u64,
+        end_price: u64,
+        collateral_ratio: CollateralRatio,
+        seeker_payout: u64,
+        provider_payout: u64,
+        seeker_fee: u64,
+        provider_fee: u64,
+    }
+
+    /// The payout math other implementations have to match, exported as JSON
+    /// so they can run the same vectors.
+    #[test]
+    fn payout_test_vectors() {
+        let vectors: Vec<PayoutVector> =
+            serde_json::from_str(include_str!("../test_vectors/payouts.json")).unwrap();
+        assert!(!vectors.is_empty());
+
+        for vector in vectors {
+            let feerate = EpochFeerate::from_ppm_feerate(vector.feerate_ppm);
+            let ratio = vector.collateral_ratio;
+            assert_eq!(
+                (
+                    seeker_payout(
+                        vector.locked_value,
+                        feerate,
+                        vector.start_price,
+                        vector.end_price,
+                        ratio
+                    ),
+                    provider_payout(
+                        vector.locked_value,
+                        feerate,
+                        vector.start_price,
+                        vector.end_price,
+                        ratio
+                    ),
+                    seeker_fee(feerate, vector.locked_value),
+                    provider_fee(feerate, vector.locked_value, ratio),
+                ),
+                (
+                    vector.seeker_payout,
+                    vector.provider_payout,
+                    vector.seeker_fee,
+                    vector.provider_fee,
+                ),
+                "{}",
+                vector.scenario
+            );
+        }
     }
 }
@@ -1,42 +1,63 @@
 use std::collections::BTreeMap;
 
+use base64::Engine;
+use bitcoin::hashes::hex::{FromHex, ToHex};
 use fedimint_core::core::ModuleInstanceId;
 use fedimint_core::db::ModuleDatabaseTransaction;
+use fedimint_core::encoding::Encodable;
 use fedimint_core::module::{api_endpoint, ApiEndpoint, ApiError};
-use futures::StreamExt;
-use stabilitypool::LockedBalance;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::{future, StreamExt};
+use stabilitypool::{balance_changes, stability_core, BalanceChange, LockedBalance};
 
-use crate::action::{ActionProposed, ActionProposedDb, ActionStaged};
-use crate::epoch::{self, EpochOutcome, EpochState};
-use crate::{db, StabilityPool};
+use crate::action::{self, ActionBody, ActionProposed, ActionProposedDb, ActionStaged};
+use crate::epoch::{self, EpochId, EpochOutcome, EpochState, ForceSettle};
+use crate::{
+    db, EpochConfig, Solvency, StabilityPool, MODULE_CONSENSUS_VERSION, SUPPORTED_API_VERSIONS,
+};
 use stabilitypool::account::AccountBalance;
+use stabilitypool::clock::Clock;
+use stabilitypool::governance::{self, GovernanceAction};
 
 pub fn endpoints() -> Vec<ApiEndpoint<StabilityPool>> {
     vec![
         // Get outcome of given `epoch_id`.
         api_endpoint! {
             "/epoch",
-            async |_module: &StabilityPool, context, epoch_id: u64| -> EpochOutcome {
+            async |_module: &StabilityPool, context, epoch_id: EpochId| -> EpochOutcome {
                 epoch_outcome(context.dbtx(), epoch_id).await
             }
         },
         // Get the `epoch_id` that the federation will accept user actions for.
         api_endpoint! {
             "/epoch_next",
-            async |_module: &StabilityPool, context, _request: ()| -> u64 {
+            async |_module: &StabilityPool, context, _request: ()| -> EpochId {
                 Ok(epoch::EpochState::from_db(context.dbtx()).await.staging_epoch_id())
             }
         },
         api_endpoint! {
             "/epoch_last_settled",
-            async |_module: &StabilityPool, context, _request: ()| -> Option<u64> {
+            async |_module: &StabilityPool, context, _request: ()| -> Option<EpochId> {
                 Ok(epoch::EpochState::from_db(context.dbtx()).await.latest_settled)
             }
         },
         api_endpoint! {
             "/account",
-            async |_module: &StabilityPool, context, request: secp256k1_zkp::XOnlyPublicKey| -> BalanceResponse {
-                Ok(account(context.dbtx(), request).await)
+            async |module: &StabilityPool, context, request: secp256k1_zkp::XOnlyPublicKey| -> BalanceResponse {
+                // only a locked position is valued at the current price, which
+                // is advisory: the balance is still served while the oracle is
+                // unreachable
+                let locked = db::get(context.dbtx(), &db::AccountBalanceKey(request))
+                    .await
+                    .map_or(false, |account| account.locked != LockedBalance::None);
+                let current_price = if locked {
+                    module.oracle().price_at_time(module.clock().now()).await.ok()
+                } else {
+                    None
+                };
+                Ok(account(context.dbtx(), module.epoch_config(), request, current_price).await)
             }
         },
         api_endpoint! {
@@ -46,16 +67,160 @@ pub fn endpoints() -> Vec<ApiEndpoint<StabilityPool>> {
                     .ok_or(ApiError::not_found(format!("no action staged for account {}", request)))
             }
         },
+        // The staged action as the account signed it, for third parties to verify.
+        api_endpoint! {
+            "/action_signed",
+            async |_module: &StabilityPool, context, request: secp256k1_zkp::XOnlyPublicKey| -> ActionProposed {
+                db::get(context.dbtx(), &db::ActionSignedKey(request)).await
+                    .ok_or(ApiError::not_found(format!("no signed action staged for account {}", request)))
+            }
+        },
         api_endpoint! {
             "/action_propose",
             async |module: &StabilityPool, context, request: ActionProposed| -> () {
-                propose_action(context.dbtx(), &module.proposed_db, request).await
+                propose_action(context.dbtx(), module.epoch_config(), module.clock(), &module.proposed_db, request).await
+            }
+        },
+        // Admin only: vote to settle a stuck epoch at an operator supplied price.
+        api_endpoint! {
+            "/force_settle",
+            async |module: &StabilityPool, context, request: ForceSettle| -> () {
+                if !context.has_auth() {
+                    return Err(ApiError::unauthorized());
+                }
+                module.force_settle.set(request);
+                Ok(())
+            }
+        },
+        // Admin only: vote for a governance action, such as raising the TVL cap.
+        api_endpoint! {
+            "/governance_propose",
+            async |module: &StabilityPool, context, request: GovernanceAction| -> () {
+                if !context.has_auth() {
+                    return Err(ApiError::unauthorized());
+                }
+                module.governance.set(request);
+                Ok(())
+            }
+        },
+        // Liabilities of the module, for solvency monitoring.
+        api_endpoint! {
+            "/solvency",
+            async |_module: &StabilityPool, context, _request: ()| -> Solvency {
+                Ok(StabilityPool::solvency(context.dbtx()).await)
+            }
+        },
+        // Dry run of `/action_propose`, reporting why an action would be rejected.
+        api_endpoint! {
+            "/action_validate",
+            async |module: &StabilityPool, context, request: ActionProposed| -> () {
+                validate_action(context.dbtx(), module.epoch_config(), module.clock(), &module.proposed_db, &request).await
+            }
+        },
+        // Aggregated staged bids and seeker demand for the staging epoch. This is
+        // advisory, the actual matching happens at settlement.
+        api_endpoint! {
+            "/order_book",
+            async |module: &StabilityPool, context, _request: ()| -> OrderBook {
+                Ok(order_book(context.dbtx(), module.epoch_config()).await)
+            }
+        },
+        // Balance change log for indexers, paged by sequence number.
+        api_endpoint! {
+            "/balance_changes",
+            async |module: &StabilityPool, context, request: BalanceChangesRequest| -> Vec<BalanceChangeEntry> {
+                let limit = request.limit.unwrap_or(MAX_BALANCE_CHANGES).min(MAX_BALANCE_CHANGES);
+                let retention = module.epoch_config().balance_change_retention;
+                let changes = balance_changes(context.dbtx(), retention, request.after, limit).await;
+                Ok(changes
+                    .into_iter()
+                    .map(|(seq, change)| BalanceChangeEntry { seq, change })
+                    .collect())
+            }
+        },
+        // Fee a provider earned in a settled epoch.
+        api_endpoint! {
+            "/provider_revenue",
+            async |module: &StabilityPool, context, request: ProviderRevenueRequest| -> u64 {
+                provider_revenue(context.dbtx(), module.epoch_config(), request.account, request.epoch_id).await
             }
         },
+        // Fees charged across the pool in a settled epoch.
+        api_endpoint! {
+            "/epoch_fees",
+            async |_module: &StabilityPool, context, epoch_id: EpochId| -> EpochFeesResponse {
+                epoch_fees(context.dbtx(), epoch_id).await
+            }
+        },
+        // Advisory yield providers earned over recent settled epochs.
+        api_endpoint! {
+            "/provider_apy_estimate",
+            async |module: &StabilityPool, context, request: ProviderApyRequest| -> ProviderApyEstimate {
+                let epochs = request.epochs.unwrap_or(DEFAULT_APY_EPOCHS).min(MAX_APY_EPOCHS);
+                provider_apy_estimate(context.dbtx(), module.epoch_config(), epochs).await
+            }
+        },
+        // Epochs in which an account had a locked position, paged by epoch.
+        api_endpoint! {
+            "/account_epochs",
+            async |_module: &StabilityPool, context, request: AccountEpochsRequest| -> Vec<EpochId> {
+                let limit = request.limit.unwrap_or(MAX_ACCOUNT_EPOCHS).min(MAX_ACCOUNT_EPOCHS);
+                Ok(account_epochs(context.dbtx(), request.account, request.start_after, limit).await)
+            }
+        },
+        // Actions staged for the staging epoch, paged by account.
+        api_endpoint! {
+            "/staged_actions",
+            async |_module: &StabilityPool, context, request: StagedActionsRequest| -> Vec<ActionStaged> {
+                let limit = request.limit.unwrap_or(MAX_STAGED_ACTIONS).min(MAX_STAGED_ACTIONS);
+                Ok(staged_actions(context.dbtx(), request.start_after, limit).await)
+            }
+        },
+        // Standing provider bids and their terms, paged by account.
+        api_endpoint! {
+            "/active_providers",
+            async |_module: &StabilityPool, context, request: ActiveProvidersRequest| -> Vec<ActiveProvider> {
+                let limit = request.limit.unwrap_or(MAX_ACTIVE_PROVIDERS).min(MAX_ACTIVE_PROVIDERS);
+                Ok(active_providers(context.dbtx(), request.start_after, limit).await)
+            }
+        },
+        // Module consensus and API versions this peer runs.
+        api_endpoint! {
+            "/version",
+            async |_module: &StabilityPool, _context, _request: ()| -> VersionResponse {
+                Ok(version())
+            }
+        },
+        // Outcomes of a range of epochs in the compact binary encoding, base64
+        // encoded.
+        api_endpoint! {
+            "/epoch_history_binary",
+            async |_module: &StabilityPool, context, request: EpochHistoryRequest| -> String {
+                let bytes = epoch_history_binary(context.dbtx(), request.from, request.to).await?;
+                Ok(base64::engine::general_purpose::STANDARD.encode(bytes))
+            }
+        },
+        // Epoch parameters clients need to check their actions against.
+        api_endpoint! {
+            "/epoch_params",
+            async |module: &StabilityPool, _context, _request: ()| -> EpochParams {
+                Ok(EpochParams::from(module.epoch_config()))
+            }
+        },
+        // Returns the state, gzip compressed if requested. Accounts and staged
+        // actions are capped at `MAX_STATE_ACCOUNTS`; use the paginated
+        // endpoints to walk a large pool.
         api_endpoint! {
             "/state",
-            async |_module: &StabilityPool, context, _request: ()| -> State {
-                Ok(state(context.dbtx()).await)
+            async |_module: &StabilityPool, context, request: Option<StateRequest>| -> StateResponse {
+                let state = state(context.dbtx(), MAX_STATE_ACCOUNTS).await;
+                if request.map_or(false, |r| r.compress) {
+                    compress_state(&state)
+                        .map(StateResponse::Compressed)
+                        .map_err(|e| ApiError::server_error(e.to_string()))
+                } else {
+                    Ok(StateResponse::Plain(state))
+                }
             }
         },
     ]
@@ -63,7 +228,7 @@ pub fn endpoints() -> Vec<ApiEndpoint<StabilityPool>> {
 
 pub async fn epoch_outcome(
     dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
-    epoch_id: u64,
+    epoch_id: EpochId,
 ) -> Result<EpochOutcome, ApiError> {
     db::get(dbtx, &db::EpochOutcomeKey(epoch_id))
         .await
@@ -73,19 +238,107 @@ pub async fn epoch_outcome(
         )))
 }
 
+/// Maximum number of epochs covered by a single `/epoch_history_binary`
+/// request.
+pub const MAX_EPOCH_HISTORY: u64 = 10_000;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct EpochHistoryRequest {
+    pub from: EpochId,
+    /// Last epoch of the range, inclusive
+    pub to: EpochId,
+}
+
+/// Outcomes of the epochs from `from` to `to` that have one, encoded with
+/// [`epoch::encode_epoch_history`]. The response of `/epoch_history_binary` is
+/// the base64 of these bytes.
+pub async fn epoch_history_binary(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    from: EpochId,
+    to: EpochId,
+) -> Result<Vec<u8>, ApiError> {
+    if to < from || to.0 - from.0 >= MAX_EPOCH_HISTORY {
+        return Err(ApiError::bad_request(format!(
+            "epoch range must span 1 to {} epochs",
+            MAX_EPOCH_HISTORY
+        )));
+    }
+
+    let mut entries = vec![];
+    for epoch_id in (from.0..=to.0).map(EpochId) {
+        if let Some(outcome) = db::get(dbtx, &db::EpochOutcomeKey(epoch_id)).await {
+            entries.push(epoch::EpochHistoryEntry { epoch_id, outcome });
+        }
+    }
+    // already in epoch order, sorting keeps it guaranteed if lookups change
+    entries.sort();
+    Ok(epoch::encode_epoch_history(&entries))
+}
+
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize, PartialEq, Eq)]
 pub struct BalanceResponse {
     pub unlocked: u64,
     pub locked: Option<LockedBalanceResponse>,
 }
 
+/// Locked position of an account in the current epoch.
+///
+/// Amounts are in msats and prices are in cents per BTC. The JSON field names
+/// are kept unsuffixed for compatibility with existing clients.
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize, PartialEq, Eq)]
 pub struct LockedBalanceResponse {
-    pub value: u64,
+    /// Locked amount in msats
+    #[serde(rename = "value")]
+    pub value_msat: u64,
     pub side: SideResponse,
-    pub epoch_id: u64,
-    pub epoch_start_price: u64,
+    pub epoch_id: EpochId,
+    /// Settled price (cents/BTC) the current epoch started at
+    #[serde(rename = "epoch_start_price")]
+    pub epoch_start_price_cents: u64,
     pub epoch: EpochOutcome,
+    /// Whether `epoch_id` has ended but is still waiting for its settlement
+    /// price, e.g. during an oracle outage. The position is then still the one
+    /// locked in that epoch.
+    #[serde(default)]
+    pub pending_settlement: bool,
+    /// `value_msat` at `epoch_start_price_cents`, as a decimal amount of the
+    /// quote currency rounded to the cent, e.g. `"1234.56"`
+    #[serde(default)]
+    pub value_quote: String,
+    /// `epoch_start_price_cents` as a decimal amount of the quote currency,
+    /// e.g. `"23456.78"`
+    #[serde(default)]
+    pub epoch_start_price_quote: String,
+    /// Exact feerate of `epoch` in parts per million, e.g. `"12.5"`. Display
+    /// this rather than rounding `epoch.feerate` so every client shows the
+    /// same rate.
+    #[serde(default)]
+    pub feerate_ppm: String,
+    /// Fee in msats the position pays in `epoch` as a seeker, or earns as a
+    /// provider
+    #[serde(default)]
+    pub fee_msat: u64,
+    /// What the position would pay out, fee included, if `epoch` settled at
+    /// the federation's current oracle price. [`None`] if the oracle could not
+    /// be reached.
+    #[serde(default)]
+    pub current_value_msat: Option<u64>,
+    /// `current_value_msat` less `value_msat`
+    #[serde(default)]
+    pub pnl_msat: Option<i64>,
+}
+
+/// Format `cents` as a decimal amount with two fraction digits, e.g. `123456`
+/// as `"1234.56"`.
+pub fn format_cents(cents: u128) -> String {
+    format!("{}.{:02}", cents / 100, cents % 100)
+}
+
+/// Value in cents of `msats` at `price_cents` per BTC, rounded half up to the
+/// nearest cent.
+pub fn msats_to_cents(msats: u64, price_cents: u64) -> u128 {
+    const MSATS_PER_BTC: u128 = 100_000_000_000;
+    (msats as u128 * price_cents as u128 + MSATS_PER_BTC / 2) / MSATS_PER_BTC
 }
 
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize, PartialEq, Eq)]
@@ -94,9 +347,36 @@ pub enum SideResponse {
     Seeker,
 }
 
+/// Fee of a position of `locked` msats on `side` in an epoch at `feerate`, and
+/// what it pays out if the epoch goes from `start_price` to `end_price`, as
+/// settlement would compute them.
+pub fn position_value(
+    side: &SideResponse,
+    locked: u64,
+    feerate: stability_core::EpochFeerate,
+    ratio: stability_core::CollateralRatio,
+    start_price: u64,
+    end_price: u64,
+) -> (u64, u64) {
+    match side {
+        SideResponse::Seeker => (
+            stability_core::seeker_fee(feerate, locked),
+            stability_core::seeker_payout(locked, feerate, start_price, end_price, ratio),
+        ),
+        SideResponse::Provider => (
+            stability_core::provider_fee(feerate, locked, ratio),
+            stability_core::provider_payout(locked, feerate, start_price, end_price, ratio),
+        ),
+    }
+}
+
+/// Balance of `account_id`, valuing its locked position at `current_price`
+/// if there is one.
 pub async fn account(
     dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &EpochConfig,
     account_id: secp256k1_zkp::XOnlyPublicKey,
+    current_price: Option<u64>,
 ) -> BalanceResponse {
     let epoch_state = EpochState::from_db(dbtx).await;
     let epoch_id = epoch_state.current_epoch_id();
@@ -111,40 +391,64 @@ pub async fn account(
         }
     };
 
+    let (side, locked) = match account.locked {
+        LockedBalance::Seeker(locked) => (SideResponse::Seeker, locked),
+        LockedBalance::Provider(locked) => (SideResponse::Provider, locked),
+        LockedBalance::None => {
+            return BalanceResponse {
+                unlocked: account.unlocked.msats,
+                locked: None,
+            }
+        }
+    };
+
+    // until the last ended epoch is settled, positions are still the ones
+    // locked in it and the next epoch has no outcome yet
+    let pending_settlement = !epoch_state.is_settled();
+    let epoch_id = if pending_settlement {
+        epoch_id.saturating_prev()
+    } else {
+        epoch_id
+    };
     let epoch_outcome = db::get(dbtx, &db::EpochOutcomeKey(epoch_id))
         .await
         .expect("must exist");
-    let epoch_start_price = db::get(dbtx, &db::EpochOutcomeKey(epoch_id.saturating_sub(1)))
+    // an epoch can only end once the one before it settled
+    let epoch_start_price = db::get(dbtx, &db::EpochOutcomeKey(epoch_id.saturating_prev()))
         .await
         .expect("must exist")
         .settled_price
         .expect("should be settled");
 
-    match account.locked {
-        LockedBalance::Seeker(locked) => BalanceResponse {
-            unlocked: account.unlocked.msats,
-            locked: Some(LockedBalanceResponse {
-                value: locked.msats,
-                side: SideResponse::Seeker,
-                epoch_id,
-                epoch_start_price,
-                epoch: epoch_outcome,
-            }),
-        },
-        LockedBalance::Provider(locked) => BalanceResponse {
-            unlocked: account.unlocked.msats,
-            locked: Some(LockedBalanceResponse {
-                value: locked.msats,
-                side: SideResponse::Provider,
-                epoch_id,
-                epoch_start_price,
-                epoch: epoch_outcome,
-            }),
-        },
-        LockedBalance::None => BalanceResponse {
-            unlocked: account.unlocked.msats,
-            locked: None,
-        },
+    let value_at = |end_price| {
+        position_value(
+            &side,
+            locked.msats,
+            epoch_outcome.feerate,
+            config.collateral_ratio,
+            epoch_start_price,
+            end_price,
+        )
+    };
+    let (fee_msat, _) = value_at(epoch_start_price);
+    let current_value_msat = current_price.map(|price| value_at(price).1);
+
+    BalanceResponse {
+        unlocked: account.unlocked.msats,
+        locked: Some(LockedBalanceResponse {
+            value_msat: locked.msats,
+            side,
+            epoch_id,
+            epoch_start_price_cents: epoch_start_price,
+            pending_settlement,
+            value_quote: format_cents(msats_to_cents(locked.msats, epoch_start_price)),
+            epoch_start_price_quote: format_cents(epoch_start_price as u128),
+            feerate_ppm: epoch_outcome.feerate.ppm_feerate_decimal(),
+            epoch: epoch_outcome,
+            fee_msat,
+            current_value_msat,
+            pnl_msat: current_value_msat.map(|value| value as i64 - locked.msats as i64),
+        }),
     }
     // match epoch_state.current_epoch_id() {
     //     Some(epoch_id) => {
@@ -156,96 +460,1802 @@ pub async fn account(
     // }
 }
 
+/// Error code for an action signed for an epoch that no longer accepts
+/// actions. Clients can re-sign the action for the next epoch and retry.
+pub const EPOCH_CLOSED: i32 = 410;
+
+/// Error code for an action signed for an epoch after the next one.
+pub const EPOCH_TOO_FAR_AHEAD: i32 = 425;
+
+/// Error code for an action submitted within
+/// [`EpochConfig::action_cutoff_secs_before_epoch_end`] of the end of the
+/// current epoch.
+pub const ACTION_WINDOW_CLOSED: i32 = 423;
+
+/// Refuse actions submitted at `now` if the current epoch `epoch_id` has
+/// passed its [`EpochConfig::action_cutoff`].
+pub fn check_action_window(
+    config: &EpochConfig,
+    epoch_id: EpochId,
+    now: time::OffsetDateTime,
+) -> Result<(), ApiError> {
+    if config.action_cutoff_secs_before_epoch_end == 0 {
+        return Ok(());
+    }
+    let cutoff = config.action_cutoff(epoch_id);
+    if now >= cutoff {
+        return Err(ApiError::new(
+            ACTION_WINDOW_CLOSED,
+            format!(
+                "action window closed for epoch {}, it closed at {}",
+                epoch_id.next(),
+                cutoff.unix_timestamp()
+            ),
+        ));
+    }
+    Ok(())
+}
+
 pub async fn propose_action(
     dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &EpochConfig,
+    clock: &dyn Clock,
     proposed_db: &ActionProposedDb,
     request: ActionProposed,
 ) -> Result<(), ApiError> {
+    validate_action(dbtx, config, clock, proposed_db, &request).await?;
+    Ok(proposed_db.insert(request))
+}
+
+/// Check whether `request` would be accepted by [`propose_action`] without
+/// staging it. `clock` decides whether the action window is still open.
+pub async fn validate_action(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &EpochConfig,
+    clock: &dyn Clock,
+    proposed_db: &ActionProposedDb,
+    request: &ActionProposed,
+) -> Result<(), ApiError> {
+    if let Some(max_bytes) = config.max_action_bytes {
+        let size = request
+            .consensus_encode_to_vec()
+            .map_err(|e| ApiError::bad_request(e.to_string()))?
+            .len() as u64;
+        if size > max_bytes {
+            return Err(ApiError::bad_request(format!(
+                "action of {} bytes exceeds the limit of {} bytes",
+                size, max_bytes
+            )));
+        }
+    }
+
     request
         .verify_signature()
         .map_err(|_| ApiError::bad_request(format!("bad signature")))?;
 
+    if governance::is_closed(dbtx).await {
+        return Err(ApiError::bad_request("pool is closed".to_string()));
+    }
+
     let account_id = request.account_id();
-    let next_epoch = EpochState::from_db(dbtx).await.staging_epoch_id();
+    let epoch_state = EpochState::from_db(dbtx).await;
+    let next_epoch = epoch_state.staging_epoch_id();
 
-    if request.epoch_id() != next_epoch {
-        return Err(ApiError::bad_request(format!(
-            "next epoch is {}",
-            next_epoch
-        )));
+    if request.epoch_id() < next_epoch {
+        return Err(ApiError::new(
+            EPOCH_CLOSED,
+            format!(
+                "epoch {} is already closed, next epoch is {}",
+                request.epoch_id(),
+                next_epoch
+            ),
+        ));
     }
+    if request.epoch_id() > next_epoch {
+        return Err(ApiError::new(
+            EPOCH_TOO_FAR_AHEAD,
+            format!(
+                "epoch {} is too far ahead, next epoch is {}",
+                request.epoch_id(),
+                next_epoch
+            ),
+        ));
+    }
+    check_action_window(config, epoch_state.current_epoch_id(), clock.now())?;
 
-    let mut most_recent: Option<ActionStaged> = proposed_db.get(account_id).map(Into::into);
+    // the latest (epoch, sequence) of the account, proposed or staged
+    let mut most_recent = proposed_db
+        .get(account_id)
+        .map(|proposed| (proposed.epoch_id(), proposed.sequence()));
     if most_recent.is_none() {
-        most_recent = db::get(dbtx, &db::ActionStagedKey(account_id)).await;
+        most_recent = match db::get(dbtx, &db::AccountBalanceKey(account_id)).await {
+            Some(account) => Some((account.last_epoch, account.last_seq)),
+            // accounts without a balance have no record to track it in
+            None => db::get(dbtx, &db::ActionStagedKey(account_id))
+                .await
+                .map(|staged| (staged.epoch_id(), staged.sequence())),
+        };
     }
 
-    if let Some(recent) = most_recent {
-        if request.epoch_id() == recent.epoch_id() && request.sequence() <= recent.sequence() {
+    if let Some((recent_epoch, recent_sequence)) = most_recent {
+        if request.epoch_id() == recent_epoch && request.sequence() <= recent_sequence {
             return Err(ApiError::bad_request(format!(
                 "seeker action sequence should be greater than previous {}",
-                recent.sequence()
+                recent_sequence
+            )));
+        }
+        let max_sequence = config.max_sequence(recent_sequence);
+        if request.epoch_id() == recent_epoch && request.sequence() > max_sequence {
+            return Err(ApiError::bad_request(format!(
+                "action sequence should be at most {}",
+                max_sequence
             )));
         }
     }
 
-    Ok(proposed_db.insert(request))
+    match request {
+        ActionProposed::Seeker(signed) => signed.body.validate(dbtx, config, account_id).await,
+        ActionProposed::Provider(signed) => signed.body.validate(dbtx, config, account_id).await,
+    }
+    .map_err(ApiError::bad_request)?;
+
+    if let Some(max_actions) = config.max_actions_per_epoch {
+        if action::staged_action_count(dbtx, account_id, next_epoch).await >= max_actions {
+            return Err(ApiError::bad_request(format!(
+                "account reached the limit of {} actions for epoch {}",
+                max_actions, next_epoch
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct VersionResponse {
+    pub consensus: u32,
+    pub api: Vec<ApiVersionResponse>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct ApiVersionResponse {
+    pub major: u32,
+    pub minor: u32,
+}
+
+pub fn version() -> VersionResponse {
+    VersionResponse {
+        consensus: MODULE_CONSENSUS_VERSION.0,
+        api: SUPPORTED_API_VERSIONS
+            .iter()
+            .map(|version| ApiVersionResponse {
+                major: version.major,
+                minor: version.minor,
+            })
+            .collect(),
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct EpochParams {
+    pub collateral_ratio: stability_core::CollateralRatio,
+    /// Epochs never settle at a feerate (ppm) below this
+    #[serde(default)]
+    pub min_feerate_ppm: u64,
+    /// Bids above this feerate (ppm) are rejected
+    pub max_feerate_ppm: u64,
+    /// Length of an epoch in seconds
+    pub epoch_length: u64,
+    pub price_threshold: u32,
+    /// Start of the first epoch (unix timestamp), from which all epoch
+    /// boundaries and the times prices are read for follow
+    #[serde(default)]
+    pub start_epoch_at: u64,
+}
+
+impl From<&EpochConfig> for EpochParams {
+    fn from(config: &EpochConfig) -> Self {
+        Self {
+            collateral_ratio: config.collateral_ratio,
+            min_feerate_ppm: config.min_feerate_ppm,
+            max_feerate_ppm: config.max_feerate_ppm,
+            epoch_length: config.epoch_length,
+            price_threshold: config.price_threshold,
+            start_epoch_at: config.start_epoch_at().unix_timestamp() as u64,
+        }
+    }
+}
+
+/// Maximum number of balance changes returned by a single request.
+pub const MAX_BALANCE_CHANGES: u64 = 1_000;
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct BalanceChangesRequest {
+    /// Only return changes with a greater sequence number
+    #[serde(default)]
+    pub after: Option<u64>,
+    #[serde(default)]
+    pub limit: Option<u64>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct BalanceChangeEntry {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub change: BalanceChange,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct ProviderRevenueRequest {
+    pub account: secp256k1_zkp::XOnlyPublicKey,
+    #[serde(rename = "epoch")]
+    pub epoch_id: EpochId,
+}
+
+/// Fee (msats) `account` earned as a provider in the settled epoch `epoch_id`,
+/// zero if it was not a provider in that epoch.
+pub async fn provider_revenue(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &EpochConfig,
+    account: secp256k1_zkp::XOnlyPublicKey,
+    epoch_id: EpochId,
+) -> Result<u64, ApiError> {
+    let outcome = epoch_outcome(dbtx, epoch_id).await?;
+    if outcome.settled_price.is_none() {
+        return Err(ApiError::bad_request(format!(
+            "epoch {} is not settled yet",
+            epoch_id
+        )));
+    }
+
+    let locked = db::get(dbtx, &db::ProviderLockedKey(epoch_id, account))
+        .await
+        .unwrap_or(0);
+    Ok(stability_core::provider_fee(
+        outcome.feerate,
+        locked,
+        config.collateral_ratio,
+    ))
 }
 
+/// Fees charged across the pool in a settled epoch, see [`epoch::EpochFees`].
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, PartialEq, Eq)]
+pub struct EpochFeesResponse {
+    pub seeker_fees_msat: u64,
+    pub provider_fees_msat: u64,
+    /// Provider fees less seeker fees. Fees are redistributed rather than
+    /// burned, so this is only the rounding of the seeker fees.
+    pub net_msat: i64,
+}
+
+pub async fn epoch_fees(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    epoch_id: EpochId,
+) -> Result<EpochFeesResponse, ApiError> {
+    let fees = db::get(dbtx, &db::EpochFeesKey(epoch_id))
+        .await
+        .ok_or(ApiError::not_found(format!(
+            "no fees for epoch {}, it is not settled yet",
+            epoch_id
+        )))?;
+    Ok(EpochFeesResponse {
+        seeker_fees_msat: fees.seeker_fees_msat,
+        provider_fees_msat: fees.provider_fees_msat,
+        net_msat: fees.provider_fees_msat as i64 - fees.seeker_fees_msat as i64,
+    })
+}
+
+/// Number of settled epochs `/provider_apy_estimate` averages over by default.
+pub const DEFAULT_APY_EPOCHS: u64 = 100;
+
+/// Maximum number of settled epochs a `/provider_apy_estimate` request can
+/// average over.
+pub const MAX_APY_EPOCHS: u64 = 1_000;
+
+const SECONDS_PER_YEAR: u128 = 365 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct ProviderApyRequest {
+    /// Number of most recent settled epochs to average over
+    #[serde(default)]
+    pub epochs: Option<u64>,
+}
+
+/// Estimated yield of provider collateral, for providers deciding whether to
+/// bid. This is advisory only and assumes that:
+///
+/// - future epochs pay the same fees per locked msat as the averaged epochs,
+///   weighted by how much collateral each of them locked
+/// - collateral is locked every epoch of the year, so bids that don't get
+///   matched earn less
+/// - fees are not reinvested, i.e. there is no compounding
+/// - price moves, which shift value between seekers and providers on top of
+///   the fees, average out
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, PartialEq, Eq)]
+pub struct ProviderApyEstimate {
+    /// Settled epochs with fee records the estimate is based on
+    pub epochs_used: u64,
+    /// Fees paid to providers in those epochs
+    pub provider_fees_msat: u64,
+    /// Provider collateral locked in those epochs, summed over the epochs
+    pub provider_locked_msat: u64,
+    /// Fees per epoch in parts per million of the locked collateral
+    pub epoch_yield_ppm: u64,
+    /// `epoch_yield_ppm` times the number of epochs in a 365 day year
+    pub apy_ppm: u64,
+}
+
+/// Estimate provider yield from the fees and collateral of the last `epochs`
+/// settled epochs, see [`ProviderApyEstimate`] for the assumptions made.
+pub async fn provider_apy_estimate(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &EpochConfig,
+    epochs: u64,
+) -> Result<ProviderApyEstimate, ApiError> {
+    let latest_settled = EpochState::from_db(dbtx)
+        .await
+        .latest_settled
+        .ok_or(ApiError::not_found("no epoch is settled yet".to_string()))?;
+
+    let mut estimate = ProviderApyEstimate {
+        epochs_used: 0,
+        provider_fees_msat: 0,
+        provider_locked_msat: 0,
+        epoch_yield_ppm: 0,
+        apy_ppm: 0,
+    };
+    let first = latest_settled.0.saturating_sub(epochs.saturating_sub(1));
+    for epoch_id in (first..=latest_settled.0).map(EpochId) {
+        let fees = db::get(dbtx, &db::EpochFeesKey(epoch_id)).await;
+        let outcome = db::get(dbtx, &db::EpochOutcomeKey(epoch_id)).await;
+        if let (Some(fees), Some(outcome)) = (fees, outcome) {
+            estimate.epochs_used += 1;
+            estimate.provider_fees_msat = estimate
+                .provider_fees_msat
+                .saturating_add(fees.provider_fees_msat);
+            estimate.provider_locked_msat = estimate
+                .provider_locked_msat
+                .saturating_add(outcome.total_provider_locked);
+        }
+    }
+
+    if estimate.provider_locked_msat > 0 {
+        let fees = estimate.provider_fees_msat as u128 * 1_000_000;
+        let locked = estimate.provider_locked_msat as u128;
+        estimate.epoch_yield_ppm = (fees / locked).try_into().unwrap_or(u64::MAX);
+        estimate.apy_ppm = (fees * SECONDS_PER_YEAR / (locked * config.epoch_length as u128))
+            .try_into()
+            .unwrap_or(u64::MAX);
+    }
+    Ok(estimate)
+}
+
+/// Maximum number of epochs returned by a single `/account_epochs` request.
+pub const MAX_ACCOUNT_EPOCHS: u64 = 1_000;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct AccountEpochsRequest {
+    pub account: secp256k1_zkp::XOnlyPublicKey,
+    /// Only return epochs after this one
+    #[serde(default)]
+    pub start_after: Option<EpochId>,
+    #[serde(default)]
+    pub limit: Option<u64>,
+}
+
+/// Up to `limit` epochs in which `account` had a locked position, in
+/// ascending order and starting after `start_after`.
+pub async fn account_epochs(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    account: secp256k1_zkp::XOnlyPublicKey,
+    start_after: Option<EpochId>,
+    limit: u64,
+) -> Vec<EpochId> {
+    dbtx.find_by_prefix(&db::AccountEpochKeyAccountPrefix(account))
+        .await
+        .map(|(key, _)| key.1)
+        .skip_while(|epoch_id| {
+            future::ready(start_after.map_or(false, |start_after| *epoch_id <= start_after))
+        })
+        .take(limit as usize)
+        .collect()
+        .await
+}
+
+/// Maximum number of staged actions returned by a single request.
+pub const MAX_STAGED_ACTIONS: u64 = 1_000;
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct StagedActionsRequest {
+    /// Only return actions of accounts ordered after this one
+    #[serde(default)]
+    pub start_after: Option<secp256k1_zkp::XOnlyPublicKey>,
+    #[serde(default)]
+    pub limit: Option<u64>,
+}
+
+/// Up to `limit` actions staged for the staging epoch, ordered by account and
+/// starting after `start_after`.
+pub async fn staged_actions(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    start_after: Option<secp256k1_zkp::XOnlyPublicKey>,
+    limit: u64,
+) -> Vec<ActionStaged> {
+    let epoch_id = EpochState::from_db(dbtx).await.staging_epoch_id();
+    dbtx.find_by_prefix(&db::ActionStagedKeyPrefix)
+        .await
+        .map(|(key, action)| (key.0, action))
+        .skip_while(|(account, _)| {
+            future::ready(start_after.map_or(false, |start_after| *account <= start_after))
+        })
+        .filter_map(|(_, action)| future::ready((action.epoch_id() == epoch_id).then_some(action)))
+        .take(limit as usize)
+        .collect()
+        .await
+}
+
+/// Maximum number of providers returned by a single `/active_providers`
+/// request.
+pub const MAX_ACTIVE_PROVIDERS: u64 = 1_000;
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct ActiveProvidersRequest {
+    /// Only return providers ordered after this account
+    #[serde(default)]
+    pub start_after: Option<secp256k1_zkp::XOnlyPublicKey>,
+    #[serde(default)]
+    pub limit: Option<u64>,
+}
+
+/// Terms of a provider's standing bid, which enters every settlement until it
+/// is replaced. Settlement caps `max_amount_msat` at the provider's balance
+/// and drops bids above the feerate caps.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct ActiveProvider {
+    pub account: secp256k1_zkp::XOnlyPublicKey,
+    pub max_amount_msat: u64,
+    pub min_feerate_ppm: u64,
+}
+
+/// Up to `limit` standing provider bids, ordered by account and starting after
+/// `start_after`. Cancelled bids are left out.
+pub async fn active_providers(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    start_after: Option<secp256k1_zkp::XOnlyPublicKey>,
+    limit: u64,
+) -> Vec<ActiveProvider> {
+    dbtx.find_by_prefix(&db::ActionStagedKeyPrefix)
+        .await
+        .map(|(key, action)| (key.0, action))
+        .skip_while(|(account, _)| {
+            future::ready(start_after.map_or(false, |start_after| *account <= start_after))
+        })
+        .filter_map(|(account, action)| {
+            future::ready(match action {
+                ActionStaged::Provider(bid) if bid.body.max_amount.msats > 0 => {
+                    Some(ActiveProvider {
+                        account,
+                        max_amount_msat: bid.body.max_amount.msats,
+                        min_feerate_ppm: bid.body.min_feerate,
+                    })
+                }
+                _ => None,
+            })
+        })
+        .take(limit as usize)
+        .collect()
+        .await
+}
+
+/// Staged supply and demand going into the staging epoch.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct OrderBook {
+    pub epoch_id: EpochId,
+    /// Total value (msats) seekers want to lock
+    pub seeker_demand: u64,
+    /// Provider bids aggregated by feerate, cheapest first
+    pub bids: Vec<OrderBookLevel>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct OrderBookLevel {
+    /// Minimum feerate (ppm) of the bids at this level
+    pub feerate: u64,
+    /// Provider collateral (msats) bid at this feerate
+    pub value: u64,
+    /// Provider collateral (msats) available at this feerate or cheaper
+    pub cumulative_value: u64,
+}
+
+/// Aggregate staged actions the way settlement sees them. Seeker demand only
+/// covers staged locks; positions rolled over from the current epoch depend on
+/// the settlement price and are not included.
+pub async fn order_book(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &EpochConfig,
+) -> OrderBook {
+    let epoch_id = EpochState::from_db(dbtx).await.staging_epoch_id();
+    let balances = epoch::unlocked_balances(dbtx).await;
+    let (seeker_actions, provider_actions) = epoch::staged_actions(dbtx).await;
+    let (seeker_locks, provider_bids) = stability_core::compute_desired_positions(
+        &balances,
+        BTreeMap::new(),
+        seeker_actions,
+        provider_actions,
+    );
+
+    let seeker_demand = seeker_locks.map(|lock| lock.value).sum();
+    let provider_bids = provider_bids.collect::<Vec<_>>();
+
+    let mut levels = BTreeMap::<u64, u64>::new();
+    for bid in governance::bids_within_feerate_cap(dbtx, config, provider_bids).await {
+        *levels.entry(bid.min_feerate).or_default() += bid.max_value;
+    }
+
+    let mut cumulative_value = 0;
+    let bids = levels
+        .into_iter()
+        .map(|(feerate, value)| {
+            cumulative_value += value;
+            OrderBookLevel {
+                feerate,
+                value,
+                cumulative_value,
+            }
+        })
+        .collect();
+
+    OrderBook {
+        epoch_id,
+        seeker_demand,
+        bids,
+    }
+}
+
+/// Version of the JSON shape of [`State`] and [`StateEpoch`], sent as their
+/// `schema_version`.
+///
+/// It is bumped whenever a field of either, or of a type they embed, is
+/// renamed, removed or changes meaning, and when a field is added. Clients
+/// should refuse versions newer than the ones they know. Responses from before
+/// the version was introduced deserialize with a `schema_version` of 0.
+pub const STATE_SCHEMA_VERSION: u32 = 2;
+
+/// Maximum number of accounts, and of staged actions, returned by `/state`.
+/// Larger pools are truncated and should be walked with `/accounts` and
+/// `/staged_actions` instead.
+pub const MAX_STATE_ACCOUNTS: usize = 10_000;
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
 pub struct State {
+    #[serde(default)]
+    pub schema_version: u32,
     pub previous_epoch: StateEpoch,
     pub current_epoch: StateEpoch,
 
     pub accounts: BTreeMap<secp256k1_zkp::XOnlyPublicKey, AccountBalance>,
     pub staged: BTreeMap<secp256k1_zkp::XOnlyPublicKey, ActionStaged>,
+    /// Whether `accounts` or `staged` were cut short by the response limit
+    #[serde(default)]
+    pub truncated: bool,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
 pub struct StateEpoch {
-    pub epoch_id: u64,
+    #[serde(default)]
+    pub schema_version: u32,
+    pub epoch_id: EpochId,
     #[serde(flatten, skip_serializing_if = "Option::is_none")]
     pub outcome: Option<EpochOutcome>,
 }
 
-pub async fn state(dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>) -> State {
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct StateRequest {
+    /// Whether the response should be gzip compressed
+    #[serde(default)]
+    pub compress: bool,
+}
+
+/// Response of `/state`. The uncompressed variant serializes exactly like
+/// [`State`] so existing consumers are unaffected.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum StateResponse {
+    Plain(State),
+    Compressed(CompressedState),
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq, Eq)]
+pub struct CompressedState {
+    /// Hex encoded gzip of the JSON serialized [`State`]
+    pub gzip: String,
+}
+
+pub fn compress_state(state: &State) -> anyhow::Result<CompressedState> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    serde_json::to_writer(&mut encoder, state)?;
+    Ok(CompressedState {
+        gzip: encoder.finish()?.to_hex(),
+    })
+}
+
+pub fn decompress_state(compressed: &CompressedState) -> anyhow::Result<State> {
+    let bytes = Vec::<u8>::from_hex(&compressed.gzip)?;
+    Ok(serde_json::from_reader(GzDecoder::new(&bytes[..]))?)
+}
+
+/// Pool state with at most `max_accounts` accounts and as many staged actions,
+/// each taken in key order. Only one entry past the limit is read from the
+/// database, so the cost stays bounded however large the pool grows.
+pub async fn state(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    max_accounts: usize,
+) -> State {
     let epoch_state = EpochState::from_db(dbtx).await;
 
-    let previous_epoch_id = epoch_state.latest_ended.unwrap_or(0);
+    let previous_epoch_id = epoch_state.latest_ended.unwrap_or_default();
     let current_epoch_id = epoch_state.current_epoch_id();
 
     let previous_epoch = db::get(dbtx, &db::EpochOutcomeKey(previous_epoch_id)).await;
     let current_epoch = db::get(dbtx, &db::EpochOutcomeKey(current_epoch_id)).await;
 
-    // let accounts: BTreeMap<bitcoin::XOnlyPublicKey, AccountBalance> = dbtx
-    let accounts: BTreeMap<bitcoin::XOnlyPublicKey, AccountBalance> = dbtx
+    let mut accounts = dbtx
         .find_by_prefix(&db::AccountBalanceKeyPrefix)
         .await
+        .take(max_accounts + 1)
         .map(|(key, value)| (key.0, value))
-        .collect::<BTreeMap<_, _>>()
+        .collect::<Vec<_>>()
         .await;
-    // .await
-    // .map(Result::unwrap)
-    // .map(|(k, balance)| (k, balance))
-    // .collect::<BTreeMap<_, _>>();
 
-    let staged = dbtx
+    let mut staged = dbtx
         .find_by_prefix(&db::ActionStagedKeyPrefix)
         .await
+        .take(max_accounts + 1)
         .map(|(_, action)| (action.account_id(), action))
-        .collect::<BTreeMap<_, _>>()
+        .collect::<Vec<_>>()
         .await;
 
+    let truncated = accounts.len() > max_accounts || staged.len() > max_accounts;
+    accounts.truncate(max_accounts);
+    staged.truncate(max_accounts);
+
     State {
+        schema_version: STATE_SCHEMA_VERSION,
         previous_epoch: StateEpoch {
+            schema_version: STATE_SCHEMA_VERSION,
             epoch_id: previous_epoch_id,
             outcome: previous_epoch,
         },
         current_epoch: StateEpoch {
+            schema_version: STATE_SCHEMA_VERSION,
             epoch_id: current_epoch_id,
             outcome: current_epoch,
         },
-        accounts,
-        staged,
+        accounts: accounts.into_iter().collect(),
+        staged: staged.into_iter().collect(),
+        truncated,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::hashes::Hash;
+    use bitcoin::secp256k1::{KeyPair, Secp256k1};
+    use stabilitypool::clock::SystemClock;
+    use stabilitypool::{Action, ConsensusItemOutcome, ProviderBid, SeekerAction, SignedAction};
+
+    use super::*;
+
+    fn epoch_config() -> EpochConfig {
+        EpochConfig {
+            max_feerate_ppm: 1_000,
+            max_actions_per_epoch: Some(1),
+            ..Default::default()
+        }
+    }
+
+    fn sign<T: Encodable>(keypair: &KeyPair, action: Action<T>) -> SignedAction<T> {
+        let b = action
+            .consensus_encode_to_vec()
+            .expect("encoding to vec can't fail");
+        let hash = bitcoin::hashes::sha256::Hash::hash(&b);
+        let signature = Secp256k1::signing_only().sign_schnorr_no_aux_rand(&hash.into(), keypair);
+        SignedAction { action, signature }
+    }
+
+    fn new_action<T>(keypair: &KeyPair, epoch_id: EpochId, sequence: u64, body: T) -> Action<T> {
+        Action {
+            epoch_id,
+            sequence,
+            account_id: keypair.x_only_public_key().0,
+            body,
+        }
+    }
+
+    fn lock(msats: u64) -> SeekerAction {
+        SeekerAction::Lock {
+            amount: fedimint_core::msats(msats),
+        }
+    }
+
+    async fn rejection(
+        dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+        proposed_db: &ActionProposedDb,
+        request: impl Into<ActionProposed>,
+    ) -> Option<String> {
+        validate_action(
+            dbtx,
+            &epoch_config(),
+            &SystemClock,
+            proposed_db,
+            &request.into(),
+        )
+        .await
+        .err()
+        .map(|e| e.message)
+    }
+
+    #[test]
+    fn compressed_state_round_trip() {
+        let state = State {
+            schema_version: STATE_SCHEMA_VERSION,
+            previous_epoch: StateEpoch {
+                schema_version: STATE_SCHEMA_VERSION,
+                epoch_id: EpochId(0),
+                outcome: None,
+            },
+            current_epoch: StateEpoch {
+                schema_version: STATE_SCHEMA_VERSION,
+                epoch_id: EpochId(1),
+                outcome: None,
+            },
+            accounts: BTreeMap::new(),
+            staged: BTreeMap::new(),
+            truncated: false,
+        };
+
+        let compressed = compress_state(&state).unwrap();
+        assert_eq!(decompress_state(&compressed).unwrap(), state);
+
+        // both variants survive a JSON round trip through the untagged enum
+        for response in [
+            StateResponse::Plain(state.clone()),
+            StateResponse::Compressed(compressed),
+        ] {
+            let json = serde_json::to_string(&response).unwrap();
+            assert_eq!(
+                serde_json::from_str::<StateResponse>(&json).unwrap(),
+                response
+            );
+        }
+    }
+
+    #[test]
+    fn state_matches_golden_shape() {
+        // bump `STATE_SCHEMA_VERSION` along with `test_vectors/state.json`
+        assert_eq!(STATE_SCHEMA_VERSION, 2);
+
+        let account = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+            .parse::<secp256k1_zkp::XOnlyPublicKey>()
+            .unwrap();
+        let balance = AccountBalance {
+            unlocked: fedimint_core::msats(5_000),
+            locked: LockedBalance::Seeker(fedimint_core::msats(100_000)),
+            last_seq: 3,
+            last_epoch: EpochId(5),
+        };
+        let state = State {
+            schema_version: STATE_SCHEMA_VERSION,
+            previous_epoch: StateEpoch {
+                schema_version: STATE_SCHEMA_VERSION,
+                epoch_id: EpochId(4),
+                outcome: Some(EpochOutcome {
+                    total_seeker_locked: 100_000,
+                    total_provider_locked: 100_000,
+                    settled_price: Some(2_000_000),
+                    feerate: stability_core::EpochFeerate::from_ppm_feerate(10_000),
+                    num_seekers: 1,
+                    num_providers: 1,
+                    settled_at: Some(1_700_000_000),
+                    ..Default::default()
+                }),
+            },
+            current_epoch: StateEpoch {
+                schema_version: STATE_SCHEMA_VERSION,
+                epoch_id: EpochId(5),
+                outcome: None,
+            },
+            accounts: BTreeMap::from([(account, balance)]),
+            staged: BTreeMap::new(),
+            truncated: false,
+        };
+
+        let golden: serde_json::Value =
+            serde_json::from_str(include_str!("../test_vectors/state.json")).unwrap();
+        assert_eq!(serde_json::to_value(&state).unwrap(), golden);
+        assert_eq!(serde_json::from_value::<State>(golden).unwrap(), state);
+    }
+
+    #[tokio::test]
+    async fn state_is_capped() {
+        test_dbtx!(dbtx);
+
+        let secp = Secp256k1::new();
+        for i in 1..=5u8 {
+            let keypair = KeyPair::from_seckey_slice(&secp, &[i; 32]).unwrap();
+            let balance = AccountBalance {
+                unlocked: fedimint_core::msats(1_000),
+                ..Default::default()
+            };
+            db::set(
+                &mut dbtx,
+                &db::AccountBalanceKey(keypair.x_only_public_key().0),
+                &balance,
+            )
+            .await;
+        }
+
+        let capped = state(&mut dbtx, 3).await;
+        assert_eq!(capped.accounts.len(), 3);
+        assert!(capped.truncated);
+
+        let full = state(&mut dbtx, 5).await;
+        assert_eq!(full.accounts.len(), 5);
+        assert!(!full.truncated);
+        // the capped response is a prefix of the full one
+        assert!(capped
+            .accounts
+            .iter()
+            .zip(full.accounts.iter())
+            .all(|(a, b)| a == b));
+    }
+
+    #[tokio::test]
+    async fn account_is_valued_at_the_current_price() {
+        test_dbtx!(dbtx);
+        let config = epoch_config();
+        let feerate = stability_core::EpochFeerate::from_ppm_feerate(10_000);
+        let outcome = |settled_price| EpochOutcome {
+            feerate,
+            total_seeker_locked: 1_000_000,
+            total_provider_locked: 1_000_000,
+            settled_price,
+            num_seekers: 1,
+            num_providers: 1,
+            ..Default::default()
+        };
+        db::set(
+            &mut dbtx,
+            &db::EpochOutcomeKey(EpochId(0)),
+            &outcome(Some(2_000_000)),
+        )
+        .await;
+        db::set(&mut dbtx, &db::EpochOutcomeKey(EpochId(1)), &outcome(None)).await;
+        db::set(&mut dbtx, &db::LastEpochSettledKey, &EpochId(0)).await;
+        db::set(&mut dbtx, &db::LastEpochEndedKey, &EpochId(0)).await;
+
+        let new_account = || {
+            KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng())
+                .x_only_public_key()
+                .0
+        };
+        let (seeker, provider) = (new_account(), new_account());
+        let locked = fedimint_core::msats(1_000_000);
+        for (account_id, locked) in [
+            (seeker, LockedBalance::Seeker(locked)),
+            (provider, LockedBalance::Provider(locked)),
+        ] {
+            let balance = AccountBalance {
+                unlocked: fedimint_core::msats(500),
+                locked,
+                ..Default::default()
+            };
+            db::set(&mut dbtx, &db::AccountBalanceKey(account_id), &balance).await;
+        }
+
+        // the price dropped, so seekers gain and providers lose
+        let ratio = config.collateral_ratio;
+        let seeker_response = account(&mut dbtx, &config, seeker, Some(1_900_000))
+            .await
+            .locked
+            .unwrap();
+        let payout =
+            stability_core::seeker_payout(locked.msats, feerate, 2_000_000, 1_900_000, ratio);
+        assert_eq!(
+            seeker_response.fee_msat,
+            stability_core::seeker_fee(feerate, locked.msats)
+        );
+        assert_eq!(seeker_response.current_value_msat, Some(payout));
+        assert_eq!(
+            seeker_response.pnl_msat,
+            Some(payout as i64 - locked.msats as i64)
+        );
+
+        let provider_response = account(&mut dbtx, &config, provider, Some(1_900_000))
+            .await
+            .locked
+            .unwrap();
+        let payout =
+            stability_core::provider_payout(locked.msats, feerate, 2_000_000, 1_900_000, ratio);
+        assert_eq!(
+            provider_response.fee_msat,
+            stability_core::provider_fee(feerate, locked.msats, ratio)
+        );
+        assert!(provider_response.fee_msat > 0);
+        assert_eq!(provider_response.current_value_msat, Some(payout));
+        assert!(provider_response.pnl_msat.unwrap() < 0);
+
+        // without an oracle price only the fee is known
+        let offline = account(&mut dbtx, &config, provider, None)
+            .await
+            .locked
+            .unwrap();
+        assert_eq!(offline.fee_msat, provider_response.fee_msat);
+        assert_eq!(offline.current_value_msat, None);
+        assert_eq!(offline.pnl_msat, None);
+    }
+
+    #[tokio::test]
+    async fn account_during_unsettled_epoch() {
+        test_dbtx!(dbtx);
+        let account_id = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng())
+            .x_only_public_key()
+            .0;
+
+        // epoch 1 was locked at 2_000_000 and ended without a price
+        let outcome = |settled_price| EpochOutcome {
+            total_seeker_locked: 1_000,
+            total_provider_locked: 1_000,
+            settled_price,
+            num_seekers: 1,
+            num_providers: 1,
+            ..Default::default()
+        };
+        db::set(
+            &mut dbtx,
+            &db::EpochOutcomeKey(EpochId(0)),
+            &outcome(Some(2_000_000)),
+        )
+        .await;
+        db::set(&mut dbtx, &db::EpochOutcomeKey(EpochId(1)), &outcome(None)).await;
+        db::set(&mut dbtx, &db::LastEpochSettledKey, &EpochId(0)).await;
+        db::set(&mut dbtx, &db::LastEpochEndedKey, &EpochId(1)).await;
+        let balance = AccountBalance {
+            unlocked: fedimint_core::msats(500),
+            locked: LockedBalance::Seeker(fedimint_core::msats(1_000)),
+            ..Default::default()
+        };
+        db::set(&mut dbtx, &db::AccountBalanceKey(account_id), &balance).await;
+
+        let response = account(&mut dbtx, &epoch_config(), account_id, None).await;
+        let locked = response.locked.unwrap();
+        assert!(locked.pending_settlement);
+        assert_eq!(locked.epoch_id, EpochId(1));
+        assert_eq!(locked.epoch_start_price_cents, 2_000_000);
+        assert_eq!(locked.epoch.settled_price, None);
+        assert_eq!(locked.value_msat, 1_000);
+
+        // once settled, the position is reported for the next epoch
+        db::set(
+            &mut dbtx,
+            &db::EpochOutcomeKey(EpochId(1)),
+            &outcome(Some(2_100_000)),
+        )
+        .await;
+        db::set(&mut dbtx, &db::EpochOutcomeKey(EpochId(2)), &outcome(None)).await;
+        db::set(&mut dbtx, &db::LastEpochSettledKey, &EpochId(1)).await;
+        let locked = account(&mut dbtx, &epoch_config(), account_id, None)
+            .await
+            .locked
+            .unwrap();
+        assert!(!locked.pending_settlement);
+        assert_eq!(locked.epoch_id, EpochId(2));
+        assert_eq!(locked.epoch_start_price_cents, 2_100_000);
+        assert_eq!(locked.epoch_start_price_quote, "21000.00");
+        assert_eq!(locked.value_quote, "0.00");
+    }
+
+    #[test]
+    fn usd_amounts_round_to_the_cent() {
+        assert_eq!(format_cents(0), "0.00");
+        assert_eq!(format_cents(5), "0.05");
+        assert_eq!(format_cents(123_456), "1234.56");
+
+        // 1 BTC at $23,456.78
+        let price = 2_345_678;
+        assert_eq!(
+            format_cents(msats_to_cents(100_000_000_000, price)),
+            "23456.78"
+        );
+        // half a cent rounds up, less than half rounds down
+        assert_eq!(msats_to_cents(21_316, price), 1);
+        assert_eq!(msats_to_cents(21_315, price), 0);
+        assert_eq!(msats_to_cents(50_000_000, 1_000), 1);
+        assert_eq!(msats_to_cents(49_999_999, 1_000), 0);
+        // no precision is lost for large amounts
+        assert_eq!(
+            format_cents(msats_to_cents(u64::MAX, u64::MAX)),
+            "34028236692093846342648111.93"
+        );
+    }
+
+    #[tokio::test]
+    async fn validate_action_rejections() {
+        test_dbtx!(dbtx);
+        let proposed_db = ActionProposedDb::default();
+        let keypair = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng());
+        let account_id = keypair.x_only_public_key().0;
+
+        db::set(
+            &mut dbtx,
+            &db::AccountBalanceKey(account_id),
+            &AccountBalance {
+                unlocked: fedimint_core::msats(1_000),
+                locked: LockedBalance::None,
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let mut tampered = sign(&keypair, new_action(&keypair, EpochId(1), 0, lock(1_000)));
+        tampered.action.sequence = 1;
+        assert_eq!(
+            rejection(&mut dbtx, &proposed_db, tampered).await.unwrap(),
+            "bad signature"
+        );
+
+        let stale = sign(&keypair, new_action(&keypair, EpochId(0), 0, lock(1_000)));
+        let error = validate_action(
+            &mut dbtx,
+            &epoch_config(),
+            &SystemClock,
+            &proposed_db,
+            &stale.into(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.code, EPOCH_CLOSED);
+        assert_eq!(error.message, "epoch 0 is already closed, next epoch is 1");
+
+        let future = sign(&keypair, new_action(&keypair, EpochId(2), 0, lock(1_000)));
+        let error = validate_action(
+            &mut dbtx,
+            &epoch_config(),
+            &SystemClock,
+            &proposed_db,
+            &future.into(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.code, EPOCH_TOO_FAR_AHEAD);
+        assert_eq!(error.message, "epoch 2 is too far ahead, next epoch is 1");
+
+        let too_large = sign(&keypair, new_action(&keypair, EpochId(1), 0, lock(1_001)));
+        assert!(rejection(&mut dbtx, &proposed_db, too_large)
+            .await
+            .unwrap()
+            .contains("exceeds unlocked balance"));
+
+        let bid = ProviderBid {
+            min_feerate: 1_001,
+            max_amount: fedimint_core::msats(1_000),
+        };
+        let expensive = sign(&keypair, new_action(&keypair, EpochId(1), 0, bid));
+        assert!(rejection(&mut dbtx, &proposed_db, expensive)
+            .await
+            .unwrap()
+            .contains("exceeds max feerate"));
+
+        // a staged action blocks lower sequences and uses up the action limit
+        let staged = sign(&keypair, new_action(&keypair, EpochId(1), 5, lock(1_000)));
+        assert_eq!(
+            action::process_action(&mut dbtx, &epoch_config(), staged).await,
+            ConsensusItemOutcome::Applied
+        );
+        let stale = sign(&keypair, new_action(&keypair, EpochId(1), 5, lock(1_000)));
+        assert!(rejection(&mut dbtx, &proposed_db, stale)
+            .await
+            .unwrap()
+            .contains("sequence should be greater"));
+
+        db::set(
+            &mut dbtx,
+            &db::ActionCountKey(account_id),
+            &action::ActionCount {
+                epoch_id: EpochId(1),
+                count: 1,
+            },
+        )
+        .await;
+        let over_limit = sign(&keypair, new_action(&keypair, EpochId(1), 6, lock(1_000)));
+        assert!(rejection(&mut dbtx, &proposed_db, over_limit)
+            .await
+            .unwrap()
+            .contains("limit of 1 actions"));
+
+        // validation never stages anything
+        assert!(proposed_db.is_empty());
+    }
+
+    #[tokio::test]
+    async fn oversized_actions_are_rejected() {
+        test_dbtx!(dbtx);
+        let proposed_db = ActionProposedDb::default();
+        let keypair = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng());
+
+        let action: ActionProposed =
+            sign(&keypair, new_action(&keypair, EpochId(1), 0, lock(0))).into();
+        let size = action.consensus_encode_to_vec().unwrap().len() as u64;
+        let config = EpochConfig {
+            max_action_bytes: Some(size - 1),
+            ..epoch_config()
+        };
+
+        let error = propose_action(&mut dbtx, &config, &SystemClock, &proposed_db, action)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.message,
+            format!(
+                "action of {} bytes exceeds the limit of {} bytes",
+                size,
+                size - 1
+            )
+        );
+        assert!(proposed_db.is_empty());
+    }
+
+    #[tokio::test]
+    async fn actions_need_a_balance_to_act_on() {
+        test_dbtx!(dbtx);
+        let proposed_db = ActionProposedDb::default();
+        let empty = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng());
+        let funded = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng());
+        let provider = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng());
+        for (keypair, unlocked, locked) in [
+            (&funded, 1_000, LockedBalance::None),
+            (
+                &provider,
+                0,
+                LockedBalance::Provider(fedimint_core::msats(1_000)),
+            ),
+        ] {
+            let balance = AccountBalance {
+                unlocked: fedimint_core::msats(unlocked),
+                locked,
+                ..Default::default()
+            };
+            let key = db::AccountBalanceKey(keypair.x_only_public_key().0);
+            db::set(&mut dbtx, &key, &balance).await;
+        }
+        let bid = |keypair: &KeyPair, max_amount| {
+            let bid = ProviderBid {
+                min_feerate: 0,
+                max_amount: fedimint_core::msats(max_amount),
+            };
+            sign(keypair, new_action(keypair, EpochId(1), 0, bid))
+        };
+        let lock_from = |keypair: &KeyPair, msats| {
+            sign(keypair, new_action(keypair, EpochId(1), 0, lock(msats)))
+        };
+
+        // an account without balance can only cancel its bid
+        assert!(rejection(&mut dbtx, &proposed_db, lock_from(&empty, 1))
+            .await
+            .unwrap()
+            .contains("exceeds unlocked balance"));
+        assert!(rejection(&mut dbtx, &proposed_db, lock_from(&empty, 0))
+            .await
+            .unwrap()
+            .contains("positive amount"));
+        assert!(rejection(&mut dbtx, &proposed_db, bid(&empty, 1_000))
+            .await
+            .unwrap()
+            .contains("without balance"));
+        assert_eq!(
+            rejection(&mut dbtx, &proposed_db, bid(&empty, 0)).await,
+            None
+        );
+
+        // locks are bounded by the unlocked balance, bids by none
+        assert!(
+            rejection(&mut dbtx, &proposed_db, lock_from(&funded, 1_001))
+                .await
+                .unwrap()
+                .contains("exceeds unlocked balance")
+        );
+        assert_eq!(
+            rejection(&mut dbtx, &proposed_db, lock_from(&funded, 1_000)).await,
+            None
+        );
+        assert_eq!(
+            rejection(&mut dbtx, &proposed_db, bid(&provider, 2_000)).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn provider_bids_respect_feerate_caps() {
+        test_dbtx!(dbtx);
+        let proposed_db = ActionProposedDb::default();
+        let capped = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng());
+        let uncapped = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng());
+        db::set(
+            &mut dbtx,
+            &db::FeerateCapKey(capped.x_only_public_key().0),
+            &500,
+        )
+        .await;
+        for keypair in [&capped, &uncapped] {
+            let balance = AccountBalance {
+                unlocked: fedimint_core::msats(1_000),
+                ..Default::default()
+            };
+            let key = db::AccountBalanceKey(keypair.x_only_public_key().0);
+            db::set(&mut dbtx, &key, &balance).await;
+        }
+
+        let bid = |keypair: &KeyPair, min_feerate| {
+            let bid = ProviderBid {
+                min_feerate,
+                max_amount: fedimint_core::msats(1_000),
+            };
+            sign(keypair, new_action(keypair, EpochId(1), 0, bid))
+        };
+
+        // the override is tighter than the global cap of 1_000
+        assert!(rejection(&mut dbtx, &proposed_db, bid(&capped, 501))
+            .await
+            .unwrap()
+            .contains("exceeds max feerate 500"));
+        assert_eq!(
+            rejection(&mut dbtx, &proposed_db, bid(&capped, 500)).await,
+            None
+        );
+
+        // without an override the global cap applies
+        assert_eq!(
+            rejection(&mut dbtx, &proposed_db, bid(&uncapped, 1_000)).await,
+            None
+        );
+        assert!(rejection(&mut dbtx, &proposed_db, bid(&uncapped, 1_001))
+            .await
+            .unwrap()
+            .contains("exceeds max feerate 1000"));
+    }
+
+    #[tokio::test]
+    async fn order_book_aggregates_staged_actions() {
+        test_dbtx!(dbtx);
+
+        // (min_feerate, max_amount, balance)
+        let bids = [
+            (100, 3_000, 3_000),
+            (50, 2_000, 2_000),
+            (100, 5_000, 1_000),   // capped by balance
+            (2_000, 4_000, 4_000), // above max feerate
+        ];
+        for (min_feerate, max_amount, balance) in bids {
+            let keypair = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng());
+            let account_id = keypair.x_only_public_key().0;
+            db::set(
+                &mut dbtx,
+                &db::AccountBalanceKey(account_id),
+                &AccountBalance {
+                    unlocked: fedimint_core::msats(balance),
+                    locked: LockedBalance::None,
+                    ..Default::default()
+                },
+            )
+            .await;
+            let bid = ProviderBid {
+                min_feerate,
+                max_amount: fedimint_core::msats(max_amount),
+            };
+            db::set(
+                &mut dbtx,
+                &db::ActionStagedKey(account_id),
+                &ActionStaged::Provider(new_action(&keypair, EpochId(1), 0, bid)),
+            )
+            .await;
+        }
+
+        let keypair = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng());
+        let account_id = keypair.x_only_public_key().0;
+        db::set(
+            &mut dbtx,
+            &db::AccountBalanceKey(account_id),
+            &AccountBalance {
+                unlocked: fedimint_core::msats(1_500),
+                locked: LockedBalance::None,
+                ..Default::default()
+            },
+        )
+        .await;
+        db::set(
+            &mut dbtx,
+            &db::ActionStagedKey(account_id),
+            &ActionStaged::Seeker(new_action(&keypair, EpochId(1), 0, lock(1_500))),
+        )
+        .await;
+
+        let book = order_book(&mut dbtx, &epoch_config()).await;
+        assert_eq!(book.epoch_id, EpochId(1));
+        assert_eq!(book.seeker_demand, 1_500);
+        assert_eq!(
+            book.bids,
+            vec![
+                OrderBookLevel {
+                    feerate: 50,
+                    value: 2_000,
+                    cumulative_value: 2_000,
+                },
+                OrderBookLevel {
+                    feerate: 100,
+                    value: 4_000,
+                    cumulative_value: 6_000,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn staged_actions_are_paged_by_account() {
+        test_dbtx!(dbtx);
+
+        let mut accounts = Vec::new();
+        for epoch_id in [1, 1, 1, 1, 0] {
+            let keypair = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng());
+            db::set(
+                &mut dbtx,
+                &db::ActionStagedKey(keypair.x_only_public_key().0),
+                &ActionStaged::Seeker(new_action(&keypair, EpochId(epoch_id), 0, lock(1_000))),
+            )
+            .await;
+            if epoch_id == 1 {
+                accounts.push(keypair.x_only_public_key().0);
+            }
+        }
+        accounts.sort();
+
+        let first = staged_actions(&mut dbtx, None, 3).await;
+        let ids = first
+            .iter()
+            .map(ActionStaged::account_id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, accounts[..3]);
+
+        // actions staged for an earlier epoch are left out
+        let rest = staged_actions(&mut dbtx, ids.last().copied(), 3).await;
+        let ids = rest
+            .iter()
+            .map(ActionStaged::account_id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, accounts[3..]);
+
+        assert!(staged_actions(&mut dbtx, Some(accounts[3]), 3)
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn active_providers_list_standing_bids() {
+        test_dbtx!(dbtx);
+
+        // bids stay active in later epochs, cancelled bids and seekers do not
+        let mut providers = Vec::new();
+        for (epoch_id, max_amount, min_feerate) in [(1, 1_000, 10), (0, 2_000, 20), (1, 0, 30)] {
+            let keypair = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng());
+            let bid = ProviderBid {
+                min_feerate,
+                max_amount: fedimint_core::msats(max_amount),
+            };
+            let account = keypair.x_only_public_key().0;
+            let action = new_action(&keypair, EpochId(epoch_id), 0, bid);
+            db::set(
+                &mut dbtx,
+                &db::ActionStagedKey(account),
+                &ActionStaged::Provider(action),
+            )
+            .await;
+            if max_amount > 0 {
+                providers.push(ActiveProvider {
+                    account,
+                    max_amount_msat: max_amount,
+                    min_feerate_ppm: min_feerate,
+                });
+            }
+        }
+        let seeker = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng());
+        db::set(
+            &mut dbtx,
+            &db::ActionStagedKey(seeker.x_only_public_key().0),
+            &ActionStaged::Seeker(new_action(&seeker, EpochId(1), 0, lock(1_000))),
+        )
+        .await;
+        providers.sort_by_key(|provider| provider.account);
+
+        assert_eq!(active_providers(&mut dbtx, None, 10).await, providers);
+        assert_eq!(active_providers(&mut dbtx, None, 1).await, providers[..1]);
+        assert_eq!(
+            active_providers(&mut dbtx, Some(providers[0].account), 10).await,
+            providers[1..]
+        );
+    }
+
+    #[tokio::test]
+    async fn provider_revenue_matches_core_math() {
+        use crate::fixtures::Fixture;
+
+        test_dbtx!(dbtx);
+        let mut fixture = Fixture::new(4, [2_000_000, 2_100_000]);
+        let accounts = fixture.fund_accounts(&mut dbtx, 2, 1_000_000).await;
+        let (seeker, provider) = (&accounts[0], &accounts[1]);
+        fixture.propose(&mut dbtx, seeker, lock(100_000)).await;
+        let bid = ProviderBid {
+            min_feerate: 5_000,
+            max_amount: fedimint_core::msats(1_000_000),
+        };
+        fixture.propose(&mut dbtx, provider, bid).await;
+
+        // settling epoch 0 matches the bid for epoch 1
+        fixture.advance_epoch(&mut dbtx).await;
+        let config = fixture.pool.epoch_config().clone();
+        let provider_id = provider.x_only_public_key().0;
+        let seeker_id = seeker.x_only_public_key().0;
+        let locked = match fixture.balance(&mut dbtx, provider).await.locked {
+            LockedBalance::Provider(locked) => locked.msats,
+            locked => panic!("provider is not locked: {:?}", locked),
+        };
+        let err = provider_revenue(&mut dbtx, &config, provider_id, EpochId(1))
+            .await
+            .unwrap_err();
+        assert_eq!(err.message, "epoch 1 is not settled yet");
+
+        fixture.advance_epoch(&mut dbtx).await;
+        let outcome = epoch_outcome(&mut dbtx, EpochId(1)).await.unwrap();
+        let revenue = provider_revenue(&mut dbtx, &config, provider_id, EpochId(1))
+            .await
+            .unwrap();
+        assert!(revenue > 0);
+        assert_eq!(
+            revenue,
+            stability_core::provider_fee(outcome.feerate, locked, config.collateral_ratio)
+        );
+
+        // seekers and epochs without matched bids earn nothing
+        for (account, epoch_id) in [(seeker_id, EpochId(1)), (provider_id, EpochId(0))] {
+            assert_eq!(
+                provider_revenue(&mut dbtx, &config, account, epoch_id)
+                    .await
+                    .unwrap(),
+                0
+            );
+        }
+        assert!(
+            provider_revenue(&mut dbtx, &config, provider_id, EpochId(9))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn provider_apy_averages_recent_epochs() {
+        test_dbtx!(dbtx);
+        let config = EpochConfig {
+            epoch_length: 3_600,
+            ..epoch_config()
+        };
+        assert!(provider_apy_estimate(&mut dbtx, &config, 3).await.is_err());
+
+        // epoch 1 yields 100%, then 100 ppm, 50 ppm and nothing locked
+        let history = [
+            (1, 1_000_000, 1_000_000),
+            (2, 1_000_000, 100),
+            (3, 3_000_000, 150),
+            (4, 0, 0),
+        ];
+        for (epoch_id, provider_locked, provider_fees) in history {
+            let outcome = EpochOutcome {
+                total_provider_locked: provider_locked,
+                settled_price: Some(2_000_000),
+                num_seekers: 1,
+                num_providers: 1,
+                ..Default::default()
+            };
+            db::set(&mut dbtx, &db::EpochOutcomeKey(EpochId(epoch_id)), &outcome).await;
+            let fees = epoch::EpochFees {
+                seeker_fees_msat: provider_fees,
+                provider_fees_msat: provider_fees,
+            };
+            db::set(&mut dbtx, &db::EpochFeesKey(EpochId(epoch_id)), &fees).await;
+        }
+        db::set(&mut dbtx, &db::LastEpochEndedKey, &EpochId(4)).await;
+        db::set(&mut dbtx, &db::LastEpochSettledKey, &EpochId(4)).await;
+
+        // 250 msat on 4_000_000 locked is 62.5 ppm per hour long epoch
+        assert_eq!(
+            provider_apy_estimate(&mut dbtx, &config, 3).await.unwrap(),
+            ProviderApyEstimate {
+                epochs_used: 3,
+                provider_fees_msat: 250,
+                provider_locked_msat: 4_000_000,
+                epoch_yield_ppm: 62,
+                apy_ppm: 547_500,
+            }
+        );
+
+        // going back further than the history only uses what is there
+        let estimate = provider_apy_estimate(&mut dbtx, &config, 10).await.unwrap();
+        assert_eq!(estimate.epochs_used, 4);
+        assert_eq!(estimate.epoch_yield_ppm, 200_050);
+
+        // sums saturate instead of overflowing
+        for epoch_id in [EpochId(3), EpochId(4)] {
+            let mut outcome = db::get(&mut dbtx, &db::EpochOutcomeKey(epoch_id))
+                .await
+                .unwrap();
+            outcome.total_provider_locked = u64::MAX;
+            db::set(&mut dbtx, &db::EpochOutcomeKey(epoch_id), &outcome).await;
+            let fees = epoch::EpochFees {
+                seeker_fees_msat: 0,
+                provider_fees_msat: u64::MAX,
+            };
+            db::set(&mut dbtx, &db::EpochFeesKey(epoch_id), &fees).await;
+        }
+        let estimate = provider_apy_estimate(&mut dbtx, &config, 2).await.unwrap();
+        assert_eq!(estimate.provider_fees_msat, u64::MAX);
+        assert_eq!(estimate.provider_locked_msat, u64::MAX);
+        assert_eq!(estimate.epoch_yield_ppm, 1_000_000);
+    }
+
+    #[tokio::test]
+    async fn signed_action_verifies() {
+        use crate::fixtures::Fixture;
+
+        test_dbtx!(dbtx);
+        let mut fixture = Fixture::new(4, [2_000_000]);
+        let accounts = fixture.fund_accounts(&mut dbtx, 1, 1_000_000).await;
+        let account = accounts[0].x_only_public_key().0;
+        fixture
+            .propose(&mut dbtx, &accounts[0], lock(100_000))
+            .await;
+
+        let signed = db::get(&mut dbtx, &db::ActionSignedKey(account))
+            .await
+            .expect("staged action was signed");
+        assert!(signed.verify_signature().is_ok());
+        assert_eq!(
+            ActionStaged::from(signed),
+            db::get(&mut dbtx, &db::ActionStagedKey(account))
+                .await
+                .unwrap()
+        );
+
+        // the seeker action is consumed at settlement along with its signature
+        fixture.advance_epoch(&mut dbtx).await;
+        assert_eq!(
+            db::get(&mut dbtx, &db::ActionStagedKey(account)).await,
+            None
+        );
+        assert_eq!(
+            db::get(&mut dbtx, &db::ActionSignedKey(account)).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn account_epochs_lists_locked_epochs() {
+        use crate::fixtures::Fixture;
+
+        test_dbtx!(dbtx);
+        let mut fixture = Fixture::new(4, [2_000_000]);
+        let accounts = fixture.fund_accounts(&mut dbtx, 3, 1_000_000).await;
+        let (seeker, provider, bystander) = (&accounts[0], &accounts[1], &accounts[2]);
+        fixture.propose(&mut dbtx, seeker, lock(100_000)).await;
+        let bid = ProviderBid {
+            min_feerate: 0,
+            max_amount: fedimint_core::msats(1_000_000),
+        };
+        fixture.propose(&mut dbtx, provider, bid).await;
+
+        // positions are relocked every epoch, from epoch 1 on
+        for _ in 0..4 {
+            fixture.advance_epoch(&mut dbtx).await;
+        }
+        let epochs = [EpochId(1), EpochId(2), EpochId(3), EpochId(4)];
+        for account in [seeker, provider] {
+            let account = account.x_only_public_key().0;
+            assert_eq!(account_epochs(&mut dbtx, account, None, 10).await, epochs);
+            assert_eq!(
+                account_epochs(&mut dbtx, account, None, 2).await,
+                epochs[..2]
+            );
+            assert_eq!(
+                account_epochs(&mut dbtx, account, Some(EpochId(2)), 10).await,
+                epochs[2..]
+            );
+        }
+
+        let bystander = bystander.x_only_public_key().0;
+        assert!(account_epochs(&mut dbtx, bystander, None, 10)
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn epoch_history_round_trips_through_binary() {
+        use crate::fixtures::Fixture;
+
+        test_dbtx!(dbtx);
+        let mut fixture = Fixture::new(4, [2_000_000, 2_100_000, 1_900_000]);
+        let accounts = fixture.fund_accounts(&mut dbtx, 2, 1_000_000).await;
+        fixture
+            .propose(&mut dbtx, &accounts[0], lock(100_000))
+            .await;
+        let bid = ProviderBid {
+            min_feerate: 0,
+            max_amount: fedimint_core::msats(1_000_000),
+        };
+        fixture.propose(&mut dbtx, &accounts[1], bid).await;
+        for _ in 0..3 {
+            fixture.advance_epoch(&mut dbtx).await;
+        }
+
+        // epochs 1 and 2 are settled, epoch 3 has started so its outcome has
+        // no settled price yet, and epoch 4 has no outcome
+        let bytes = epoch_history_binary(&mut dbtx, EpochId(1), EpochId(4))
+            .await
+            .unwrap();
+        assert_eq!(bytes[0], epoch::EPOCH_HISTORY_VERSION);
+        let history = epoch::decode_epoch_history(&bytes).unwrap();
+        let mut expected = vec![];
+        for epoch_id in [EpochId(1), EpochId(2), EpochId(3)] {
+            let outcome = epoch_outcome(&mut dbtx, epoch_id).await.unwrap();
+            expected.push(epoch::EpochHistoryEntry { epoch_id, outcome });
+        }
+        assert_eq!(history, expected);
+        assert!(history[..2]
+            .iter()
+            .all(|entry| entry.outcome.settled_price.is_some()));
+        assert_eq!(history[2].outcome.settled_price, None);
+
+        // much smaller than the same outcomes as JSON
+        let json = serde_json::to_vec(&expected).unwrap();
+        assert!(
+            bytes.len() * 2 < json.len(),
+            "{} {}",
+            bytes.len(),
+            json.len()
+        );
+
+        assert!(epoch::decode_epoch_history(&bytes[1..]).is_err());
+        assert!(epoch::decode_epoch_history(&bytes[..bytes.len() - 1]).is_err());
+        assert!(epoch_history_binary(&mut dbtx, EpochId(2), EpochId(1))
+            .await
+            .is_err());
+        assert!(
+            epoch_history_binary(&mut dbtx, EpochId(0), EpochId(MAX_EPOCH_HISTORY))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn replay_protection_survives_losing_staged_actions() {
+        use crate::fixtures::Fixture;
+
+        test_dbtx!(dbtx);
+        let mut fixture = Fixture::new(4, [2_000_000]);
+        let accounts = fixture.fund_accounts(&mut dbtx, 1, 1_000_000).await;
+        let keypair = &accounts[0];
+        let account_id = keypair.x_only_public_key().0;
+        fixture.propose(&mut dbtx, keypair, lock(1_000)).await;
+        fixture.propose(&mut dbtx, keypair, lock(2_000)).await;
+
+        let account = fixture.balance(&mut dbtx, keypair).await;
+        assert_eq!((account.last_epoch, account.last_seq), (EpochId(1), 2));
+
+        // with neither the staged action nor the proposal around, e.g. after
+        // a restart, the account record still rejects replays
+        db::pop(&mut dbtx, &db::ActionStagedKey(account_id)).await;
+        let config = fixture.pool.epoch_config().clone();
+        let proposed_db = ActionProposedDb::default();
+        let replay = sign(keypair, new_action(keypair, EpochId(1), 2, lock(1_000)));
+        let error = validate_action(
+            &mut dbtx,
+            &config,
+            &SystemClock,
+            &proposed_db,
+            &replay.into(),
+        )
+        .await
+        .unwrap_err();
+        assert!(error
+            .message
+            .contains("sequence should be greater than previous 2"));
+        let next = sign(keypair, new_action(keypair, EpochId(1), 3, lock(1_000)));
+        validate_action(&mut dbtx, &config, &SystemClock, &proposed_db, &next.into())
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn version_reports_module_versions() {
+        let version = version();
+        assert_eq!(version.consensus, MODULE_CONSENSUS_VERSION.0);
+        assert_eq!(version.api, vec![ApiVersionResponse { major: 1, minor: 1 }]);
+    }
+
+    #[test]
+    fn epoch_params_match_config() {
+        let config = epoch_config();
+        let params = EpochParams::from(&config);
+        assert_eq!(params.collateral_ratio, config.collateral_ratio);
+        assert_eq!(params.min_feerate_ppm, config.min_feerate_ppm);
+        assert_eq!(params.max_feerate_ppm, config.max_feerate_ppm);
+        assert_eq!(params.epoch_length, config.epoch_length);
+        assert_eq!(params.price_threshold, config.price_threshold);
+        assert_eq!(params.start_epoch_at, config.start_epoch_at);
+    }
+
+    #[test]
+    fn actions_are_refused_within_cutoff_of_epoch_end() {
+        let at = |secs| time::OffsetDateTime::from_unix_timestamp(secs).unwrap();
+        let mut config = epoch_config();
+        // epoch 2 ends at 80
+        check_action_window(&config, EpochId(2), at(79)).unwrap();
+
+        config.action_cutoff_secs_before_epoch_end = 10;
+        check_action_window(&config, EpochId(2), at(69)).unwrap();
+        let error = check_action_window(&config, EpochId(2), at(70)).unwrap_err();
+        assert_eq!(error.code, ACTION_WINDOW_CLOSED);
+        assert!(error.message.contains("action window closed for epoch 3"));
+        // the next epoch opens the window again
+        check_action_window(&config, EpochId(3), at(80)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn action_window_follows_the_module_clock() {
+        use crate::fixtures::{Fixture, MockClock};
+
+        test_dbtx!(dbtx);
+        let mut fixture = Fixture::new(4, [2_000_000]);
+        fixture
+            .pool
+            .cfg
+            .consensus
+            .epoch
+            .action_cutoff_secs_before_epoch_end = 10;
+        let clock = MockClock::new(time::OffsetDateTime::from_unix_timestamp(25).unwrap());
+        fixture.pool.clock = Box::new(clock.clone());
+        let accounts = fixture.fund_accounts(&mut dbtx, 1, 1_000_000).await;
+        // epoch 1 ends at 40, so actions for epoch 2 are taken until 30
+        assert_eq!(fixture.advance_epoch(&mut dbtx).await, EpochId(0));
+
+        let early = fixture.sign(&mut dbtx, &accounts[0], lock(1_000)).await;
+        let late = fixture.sign(&mut dbtx, &accounts[0], lock(2_000)).await;
+        let pool = &fixture.pool;
+        propose_action(
+            &mut dbtx,
+            pool.epoch_config(),
+            pool.clock(),
+            &pool.proposed_db,
+            early.into(),
+        )
+        .await
+        .unwrap();
+
+        clock.advance(time::Duration::seconds(5));
+        let error = propose_action(
+            &mut dbtx,
+            pool.epoch_config(),
+            pool.clock(),
+            &pool.proposed_db,
+            late.into(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.code, ACTION_WINDOW_CLOSED);
     }
 }
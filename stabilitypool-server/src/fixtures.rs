@@ -0,0 +1,410 @@
+//! Fixtures to drive an in-memory [`StabilityPool`] through consensus the way
+//! a federation would, deterministically.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use bitcoin::secp256k1::{KeyPair, Secp256k1};
+use fedimint_core::core::ModuleInstanceId;
+use fedimint_core::db::ModuleDatabaseTransaction;
+use fedimint_core::encoding::Encodable;
+use fedimint_core::{BitcoinHash, NumPeers, PeerId, ServerModule};
+use stabilitypool::clock::Clock;
+use stabilitypool::config::{EpochConfig, PoolConfig, PoolConfigConsensus, PoolConfigPrivate};
+use stabilitypool::{
+    db, AccountBalance, Action, ActionProposed, BackOffConfig, EpochEnd, EpochId, EpochState,
+    OracleClient, PoolConsensusItem, PoolInput, PoolOutput, SignedAction,
+};
+use time::{Duration, OffsetDateTime};
+
+use crate::{apply_deposit, apply_withdrawal, validate_withdrawal, PoolError, StabilityPool};
+
+/// Oracle returning scripted prices, one per request, repeating the last one
+/// once the script runs out.
+#[derive(Debug)]
+pub struct ScriptedOracle {
+    prices: Mutex<VecDeque<u64>>,
+}
+
+impl ScriptedOracle {
+    pub fn new(prices: impl IntoIterator<Item = u64>) -> Self {
+        Self {
+            prices: Mutex::new(prices.into_iter().collect()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl OracleClient for ScriptedOracle {
+    async fn price_at_time(&self, _datetime: OffsetDateTime) -> anyhow::Result<u64> {
+        let mut prices = self.prices.lock().unwrap();
+        match prices.len() {
+            0 => Err(anyhow::anyhow!("scripted oracle has no prices")),
+            1 => Ok(prices[0]),
+            _ => Ok(prices.pop_front().expect("checked length")),
+        }
+    }
+}
+
+/// Clock that stands still until told otherwise. Clones share the time, so a
+/// test can keep one to move the time of the pool it hands the other to.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<OffsetDateTime>>,
+}
+
+impl MockClock {
+    pub fn new(now: OffsetDateTime) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> OffsetDateTime {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// A [`StabilityPool`] run by `num_peers` synthetic peers that all agree on
+/// the prices of a [`ScriptedOracle`].
+pub struct Fixture {
+    pub pool: StabilityPool,
+    num_peers: u16,
+    sequence: u64,
+}
+
+impl Fixture {
+    pub fn new(num_peers: u16, prices: impl IntoIterator<Item = u64>) -> Self {
+        let peers = (0..num_peers).map(PeerId::from).collect::<Vec<_>>();
+        let cfg = PoolConfig {
+            private: PoolConfigPrivate {
+                peer_id: PeerId::from(0),
+                backoff: BackOffConfig::default(),
+                max_actions_per_proposal: None,
+            },
+            consensus: PoolConfigConsensus {
+                epoch: EpochConfig {
+                    price_threshold: peers[..].threshold() as _,
+                    num_peers: Some(num_peers as _),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        };
+
+        let mut pool = StabilityPool::new(cfg);
+        pool.oracle = Box::new(ScriptedOracle::new(prices));
+        Self {
+            pool,
+            num_peers,
+            sequence: 0,
+        }
+    }
+
+    /// Create `count` accounts, each funded with a deposit of `msats`.
+    pub async fn fund_accounts(
+        &self,
+        dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+        count: usize,
+        msats: u64,
+    ) -> Vec<KeyPair> {
+        let mut accounts = Vec::with_capacity(count);
+        for _ in 0..count {
+            let keypair = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng());
+            self.deposit(dbtx, &keypair, msats).await;
+            accounts.push(keypair);
+        }
+        accounts
+    }
+
+    pub async fn deposit(
+        &self,
+        dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+        keypair: &KeyPair,
+        msats: u64,
+    ) {
+        let deposit = PoolOutput {
+            account: keypair.x_only_public_key().0,
+            amount: fedimint_core::msats(msats),
+            intent: None,
+        };
+        apply_deposit(dbtx, self.pool.epoch_config(), &deposit)
+            .await
+            .unwrap();
+    }
+
+    pub async fn withdraw(
+        &self,
+        dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+        keypair: &KeyPair,
+        msats: u64,
+    ) -> Result<(), PoolError> {
+        let withdrawal = PoolInput {
+            account: keypair.x_only_public_key().0,
+            amount: fedimint_core::msats(msats),
+        };
+        let fee = fedimint_core::msats(self.pool.cfg.consensus.withdrawal_fee_msat);
+        validate_withdrawal(dbtx, &withdrawal, fee).await?;
+        apply_withdrawal(dbtx, self.pool.epoch_config(), &withdrawal, fee).await;
+        Ok(())
+    }
+
+    /// Sign `body` for the staging epoch and submit it through consensus.
+    pub async fn propose<T: Encodable>(
+        &mut self,
+        dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+        keypair: &KeyPair,
+        body: T,
+    ) where
+        SignedAction<T>: Into<ActionProposed>,
+    {
+        let proposed = self.sign(dbtx, keypair, body).await.into();
+        let items = vec![(PeerId::from(0), PoolConsensusItem::ActionProposed(proposed))];
+        self.pool.begin_consensus_epoch(dbtx, items).await;
+    }
+
+    /// Sign `body` for the staging epoch with the next sequence.
+    pub async fn sign<T: Encodable>(
+        &mut self,
+        dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+        keypair: &KeyPair,
+        body: T,
+    ) -> SignedAction<T> {
+        self.sequence += 1;
+        let action = Action {
+            epoch_id: EpochState::from_db(dbtx).await.staging_epoch_id(),
+            sequence: self.sequence,
+            account_id: keypair.x_only_public_key().0,
+            body,
+        };
+        let hash = bitcoin::hashes::sha256::Hash::hash(
+            &action
+                .consensus_encode_to_vec()
+                .expect("encoding to vec can't fail"),
+        );
+        let signature = Secp256k1::signing_only().sign_schnorr_no_aux_rand(&hash.into(), keypair);
+        SignedAction { action, signature }
+    }
+
+    /// End and settle the current epoch at the next scripted price, with every
+    /// peer voting for it. Returns the settled epoch.
+    pub async fn advance_epoch(
+        &self,
+        dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    ) -> EpochId {
+        let (epoch_id, _) = EpochState::from_db(dbtx).await.expected_epoch_end_id();
+        let settlement_time = self.pool.epoch_config().settlement_time(epoch_id);
+        let price = self
+            .pool
+            .oracle()
+            .price_at_time(settlement_time)
+            .await
+            .expect("scripted oracle has a price");
+
+        let items = (0..self.num_peers)
+            .map(|peer| {
+                let epoch_end = EpochEnd {
+                    epoch_id,
+                    price: Some(price),
+                    time: settlement_time.unix_timestamp() as u64,
+                };
+                (PeerId::from(peer), PoolConsensusItem::EpochEnd(epoch_end))
+            })
+            .collect();
+        self.pool.begin_consensus_epoch(dbtx, items).await;
+
+        assert_eq!(
+            db::get(dbtx, &db::LastEpochSettledKey).await,
+            Some(epoch_id),
+            "epoch {} did not settle",
+            epoch_id
+        );
+        epoch_id
+    }
+
+    pub async fn balance(
+        &self,
+        dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+        keypair: &KeyPair,
+    ) -> AccountBalance {
+        db::get(dbtx, &db::AccountBalanceKey(keypair.x_only_public_key().0))
+            .await
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fedimint_core::OutPoint;
+    use stabilitypool::{ActionStaged, DepositIntent, LockedBalance, ProviderBid, SeekerAction};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn seeker_keeps_value_when_price_drops() {
+        test_dbtx!(dbtx);
+        let mut fixture = Fixture::new(4, [2_000_000, 1_600_000]);
+        let accounts = fixture.fund_accounts(&mut dbtx, 2, 1_000_000).await;
+        let (seeker, provider) = (&accounts[0], &accounts[1]);
+
+        let lock = SeekerAction::Lock {
+            amount: fedimint_core::msats(100_000),
+        };
+        fixture.propose(&mut dbtx, seeker, lock).await;
+        let bid = ProviderBid {
+            min_feerate: 0,
+            max_amount: fedimint_core::msats(1_000_000),
+        };
+        fixture.propose(&mut dbtx, provider, bid).await;
+
+        // settling epoch 0 locks the staged actions for epoch 1
+        assert_eq!(fixture.advance_epoch(&mut dbtx).await, EpochId(0));
+        assert_eq!(
+            fixture.balance(&mut dbtx, seeker).await.locked,
+            LockedBalance::Seeker(fedimint_core::msats(100_000))
+        );
+
+        // the price drops by a fifth, so the seeker is paid 25% more msats
+        assert_eq!(fixture.advance_epoch(&mut dbtx).await, EpochId(1));
+        let seeker_balance = fixture.balance(&mut dbtx, seeker).await;
+        let provider_balance = fixture.balance(&mut dbtx, provider).await;
+        let seeker_total = seeker_balance.unlocked.msats + seeker_balance.locked.amount().msats;
+        let provider_total =
+            provider_balance.unlocked.msats + provider_balance.locked.amount().msats;
+        assert!(seeker_total.abs_diff(1_025_000) <= 1, "{}", seeker_total);
+        assert_eq!(seeker_total + provider_total, 2_000_000);
+
+        // the relocked payout stays locked, the rest can be withdrawn
+        fixture
+            .withdraw(&mut dbtx, seeker, seeker_balance.unlocked.msats)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn unlocked_shortfall_is_withdrawable_next_epoch() {
+        test_dbtx!(dbtx);
+        let mut fixture = Fixture::new(4, [2_000_000]);
+        let accounts = fixture.fund_accounts(&mut dbtx, 2, 1_000_000).await;
+        let (seeker, provider) = (&accounts[0], &accounts[1]);
+
+        let lock = SeekerAction::Lock {
+            amount: fedimint_core::msats(1_000_000),
+        };
+        fixture.propose(&mut dbtx, seeker, lock).await;
+        let bid = ProviderBid {
+            min_feerate: 0,
+            max_amount: fedimint_core::msats(1_000_000),
+        };
+        fixture.propose(&mut dbtx, provider, bid).await;
+        fixture.advance_epoch(&mut dbtx).await;
+
+        // everything is locked, so the withdrawal waits for an unlock of it
+        assert!(fixture.withdraw(&mut dbtx, seeker, 400_000).await.is_err());
+        let unlock = SeekerAction::Unlock {
+            amount: fedimint_core::msats(400_000),
+        };
+        fixture.propose(&mut dbtx, seeker, unlock).await;
+        fixture.advance_epoch(&mut dbtx).await;
+
+        fixture.withdraw(&mut dbtx, seeker, 400_000).await.unwrap();
+        assert_eq!(
+            fixture.balance(&mut dbtx, seeker).await.locked,
+            LockedBalance::Seeker(fedimint_core::msats(600_000))
+        );
+    }
+
+    #[tokio::test]
+    async fn provider_bid_stands_until_canceled() {
+        test_dbtx!(dbtx);
+        let mut fixture = Fixture::new(4, [2_000_000]);
+        let accounts = fixture.fund_accounts(&mut dbtx, 2, 1_000_000).await;
+        let (seeker, provider) = (&accounts[0], &accounts[1]);
+
+        let lock = SeekerAction::Lock {
+            amount: fedimint_core::msats(100_000),
+        };
+        fixture.propose(&mut dbtx, seeker, lock).await;
+        let bid = ProviderBid {
+            min_feerate: 0,
+            max_amount: fedimint_core::msats(1_000_000),
+        };
+        fixture.propose(&mut dbtx, provider, bid).await;
+
+        // the bid is matched in every epoch without being proposed again
+        for _ in 0..3 {
+            let epoch_id = fixture.advance_epoch(&mut dbtx).await;
+            let outcome = db::get(&mut dbtx, &db::EpochOutcomeKey(epoch_id.next()))
+                .await
+                .unwrap();
+            assert_eq!(outcome.num_providers, 1);
+            assert!(matches!(
+                fixture.balance(&mut dbtx, provider).await.locked,
+                LockedBalance::Provider(_)
+            ));
+        }
+
+        let cancel = ProviderBid {
+            min_feerate: 0,
+            max_amount: fedimint_core::Amount::ZERO,
+        };
+        fixture.propose(&mut dbtx, provider, cancel).await;
+        let epoch_id = fixture.advance_epoch(&mut dbtx).await;
+        let outcome = db::get(&mut dbtx, &db::EpochOutcomeKey(epoch_id.next()))
+            .await
+            .unwrap();
+        assert_eq!(outcome.num_providers, 0);
+        assert_eq!(
+            fixture.balance(&mut dbtx, provider).await.locked,
+            LockedBalance::None
+        );
+    }
+
+    #[tokio::test]
+    async fn deposit_intent_has_to_be_signed_by_the_depositor() {
+        test_dbtx!(dbtx);
+        let mut fixture = Fixture::new(4, [2_000_000]);
+        let accounts = fixture.fund_accounts(&mut dbtx, 2, 1_000).await;
+        let (depositor, other) = (&accounts[0], &accounts[1]);
+        let lock = SeekerAction::Lock {
+            amount: fedimint_core::msats(100_000),
+        };
+        let deposit = |intent| PoolOutput {
+            account: depositor.x_only_public_key().0,
+            amount: fedimint_core::msats(100_000),
+            intent: Some(intent),
+        };
+
+        let foreign = DepositIntent::Seeker(fixture.sign(&mut dbtx, other, lock.clone()).await);
+        assert!(fixture
+            .pool
+            .validate_output(&mut dbtx, &deposit(foreign))
+            .await
+            .is_err());
+
+        let intent = DepositIntent::Seeker(fixture.sign(&mut dbtx, depositor, lock).await);
+        let outpoint = OutPoint {
+            txid: fedimint_core::TransactionId::all_zeros(),
+            out_idx: 0,
+        };
+        fixture
+            .pool
+            .apply_output(&mut dbtx, &deposit(intent), outpoint)
+            .await
+            .unwrap();
+        assert!(matches!(
+            db::get(
+                &mut dbtx,
+                &db::ActionStagedKey(depositor.x_only_public_key().0)
+            )
+            .await,
+            Some(ActionStaged::Seeker(_))
+        ));
+    }
+}
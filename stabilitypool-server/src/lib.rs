@@ -1,18 +1,29 @@
+#[cfg(test)]
+#[macro_use]
+extern crate stabilitypool;
+
 pub mod api;
+#[cfg(test)]
+mod fixtures;
 
 use std::collections::{BTreeMap, HashSet};
 use std::ffi::OsString;
 use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
 use async_trait::async_trait;
+use bitcoin::hashes::Hash;
 use fedimint_core::config::{
-    ConfigGenParams, DkgResult, ModuleConfigResponse, ModuleGenParams, ServerModuleConfig,
-    TypedServerModuleConfig, TypedServerModuleConsensusConfig,
+    ConfigGenParams, DkgError, DkgResult, ModuleConfigResponse, ModuleGenParams,
+    ServerModuleConfig, TypedServerModuleConfig, TypedServerModuleConsensusConfig,
 };
 use fedimint_core::core::ModuleInstanceId;
-use fedimint_core::db::{Database, DatabaseVersion, ModuleDatabaseTransaction};
-use fedimint_core::encoding::Encodable;
+use fedimint_core::db::{
+    Database, DatabaseRecord, DatabaseTransaction, DatabaseVersion, MigrationMap,
+    ModuleDatabaseTransaction,
+};
+use fedimint_core::encoding::{Decodable, Encodable};
 use fedimint_core::module::__reexports::serde_json;
 use fedimint_core::module::audit::Audit;
 use fedimint_core::module::interconnect::ModuleInterconect;
@@ -22,45 +33,141 @@ use fedimint_core::module::{
     TransactionItemAmount,
 };
 use fedimint_core::server::DynServerModule;
-use fedimint_core::task::TaskGroup;
+use fedimint_core::task::{TaskGroup, TaskHandle};
 use fedimint_core::{NumPeers, OutPoint, PeerId, ServerModule};
+use futures::{FutureExt, StreamExt};
 use serde::{Deserialize, Serialize};
 use stabilitypool::db::AccountBalanceKeyPrefix;
 use stabilitypool::stability_core::CollateralRatio;
+use strum::IntoEnumIterator;
 
 use stabilitypool::common::PoolModuleTypes;
 use stabilitypool::config::{
-    EpochConfig, OracleConfig, PoolConfig, PoolConfigConsensus, PoolConfigPrivate,
+    default_allow_account_autocreation, default_quote_currency, EpochConfig, OracleConfig,
+    PoolConfig, PoolConfigConsensus, PoolConfigPrivate, PriceAggregation,
 };
 use stabilitypool::{
-    db, ActionProposedDb, BackOff, ConsensusItemOutcome, OracleClient, PoolCommonGen,
-    PoolConsensusItem, PoolInput, PoolOutput, PoolOutputOutcome,
+    db, governance, log_balance_change, total_balance, AccountBalance, Action, ActionCount,
+    ActionProposed, ActionProposedDb, ActionStaged, BackOff, BackOffConfig, BalanceChange,
+    BalanceChangeReason, ConsensusItemOutcome, EpochEnd, EpochFees, EpochId, EpochOutcome,
+    ForceSettle, ForceSettleProposal, LockedBalance, OracleClient, PoolCommonGen,
+    PoolConsensusItem, PoolInput, PoolOutput, PoolOutputOutcome, SeekerAction, SettlementDigest,
 };
 
 use stabilitypool::action;
+use stabilitypool::clock::{Clock, SystemClock};
 use stabilitypool::epoch;
+use stabilitypool::governance::{DegradedQuorumPolicy, GovernanceAction, GovernanceProposal};
 // pub use stabilitypool::epoch::*;
 // pub use stabilitypool::price::*;
 
-// The default global max feerate.
-// TODO: Have this actually in config.
-pub const DEFAULT_GLOBAL_MAX_FEERATE: u64 = 100_000;
+pub use stabilitypool::config::{DEFAULT_EPOCH_LENGTH, DEFAULT_GLOBAL_MAX_FEERATE};
+
+/// Version of the consensus items and how they are processed.
+pub const MODULE_CONSENSUS_VERSION: ModuleConsensusVersion = ModuleConsensusVersion(24);
+
+/// Versions of the API endpoints the module serves.
+pub const SUPPORTED_API_VERSIONS: &[ApiVersion] = &[ApiVersion { major: 1, minor: 1 }];
+
+/// How long `await_consensus_proposal` waits before checking again whether
+/// there is anything to propose.
+pub const CONSENSUS_PROPOSAL_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The default number of actions an account can stage per epoch.
+pub const DEFAULT_MAX_ACTIONS_PER_EPOCH: u64 = 10;
+
+/// The default number of user actions a peer proposes per consensus round.
+pub const DEFAULT_MAX_ACTIONS_PER_PROPOSAL: u64 = 100;
+
+/// The default maximum encoded size in bytes of a proposed action, well above
+/// that of any action the module currently defines.
+pub const DEFAULT_MAX_ACTION_BYTES: u64 = 1_024;
 
-/// The default epoch length is 24hrs (represented in seconds).
-// pub const DEFAULT_EPOCH_LENGTH: u64 = 24 * 60 * 60;
-pub const DEFAULT_EPOCH_LENGTH: u64 = 40; // TODO: This is just for testing
+/// The default smallest deposit, 1 sat.
+pub const DEFAULT_MIN_DEPOSIT_MSAT: u64 = 1_000;
+
+fn default_min_deposit_msat() -> u64 {
+    DEFAULT_MIN_DEPOSIT_MSAT
+}
+
+/// The default number of balance change records kept for indexers.
+pub const DEFAULT_BALANCE_CHANGE_RETENTION: u64 = 100_000;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PoolConfigGenParams {
-    pub important_param: u64,
+    /// Smallest deposit (msats) the pool accepts
+    #[serde(default = "default_min_deposit_msat")]
+    pub min_deposit_msat: u64,
+    /// Start of the first epoch (UTC). The trusted dealer defaults to the time
+    /// it generates the config, distributed generation requires it to be set
     #[serde(default)]
     pub start_epoch_at: Option<time::PrimitiveDateTime>,
     /// this is in seconds
     pub epoch_length: u64,
+    /// Align the start of epochs to a multiple of this many seconds, e.g.
+    /// `86400` for midnight UTC
+    #[serde(default)]
+    pub align_to_secs: Option<u64>,
     pub oracle_config: OracleConfig,
+    /// Currency the pool keeps stable
+    #[serde(default = "default_quote_currency")]
+    pub quote_currency: String,
     /// The ratio of seeker position to provider collateral
     #[serde(default)]
     pub collateral_ratio: CollateralRatio,
+    /// The lowest feerate (ppm) an epoch settles at
+    #[serde(default)]
+    pub min_feerate_ppm: u64,
+    /// The maximum number of actions an account can stage per epoch
+    #[serde(default)]
+    pub max_actions_per_epoch: Option<u64>,
+    /// How far an action's sequence can jump ahead of the account's previous
+    /// one in the same epoch
+    #[serde(default)]
+    pub max_sequence_gap: Option<u64>,
+    /// The maximum encoded size in bytes of an action proposed to the API
+    #[serde(default)]
+    pub max_action_bytes: Option<u64>,
+    /// Number of peers that have to submit an oracle price, defaults to
+    /// the consensus threshold
+    #[serde(default)]
+    pub oracle_quorum: Option<u32>,
+    /// How submitted prices are combined into the settled price
+    #[serde(default)]
+    pub price_aggregation: PriceAggregation,
+    /// Seconds to wait after an epoch ends before settling it
+    #[serde(default)]
+    pub settlement_delay_secs: u64,
+    /// Seconds before an epoch ends from which new actions are refused
+    #[serde(default)]
+    pub action_cutoff_secs_before_epoch_end: u64,
+    /// Number of balance change records kept for indexers
+    #[serde(default)]
+    pub balance_change_retention: Option<u64>,
+    /// How old (seconds) an oracle price can be to settle an epoch with
+    #[serde(default)]
+    pub max_price_staleness_secs: Option<u64>,
+    /// Epochs a seeker position stays locked before it can be unlocked
+    #[serde(default)]
+    pub min_lock_epochs: Option<u64>,
+    /// Exchange settlement digests to detect peers diverging
+    #[serde(default)]
+    pub settlement_digests: bool,
+    /// Maximum total value (msats) the pool accepts
+    #[serde(default)]
+    pub max_tvl_msat: Option<u64>,
+    /// Fee (msats) kept by the module out of every withdrawal
+    #[serde(default)]
+    pub withdrawal_fee_msat: u64,
+    /// Whether deposits create accounts, rather than needing them registered
+    #[serde(default = "default_allow_account_autocreation")]
+    pub allow_account_autocreation: bool,
+    /// How long to wait before retrying a failing oracle
+    #[serde(default)]
+    pub backoff: BackOffConfig,
+    /// The maximum number of user actions a peer proposes per consensus round
+    #[serde(default)]
+    pub max_actions_per_proposal: Option<u64>,
 }
 
 impl ModuleGenParams for PoolConfigGenParams {}
@@ -68,28 +175,187 @@ impl ModuleGenParams for PoolConfigGenParams {}
 impl Default for PoolConfigGenParams {
     fn default() -> Self {
         Self {
-            important_param: 3,
+            min_deposit_msat: DEFAULT_MIN_DEPOSIT_MSAT,
             start_epoch_at: None,
             epoch_length: DEFAULT_EPOCH_LENGTH,
+            align_to_secs: None,
             oracle_config: OracleConfig::default(),
+            quote_currency: default_quote_currency(),
             collateral_ratio: Default::default(),
+            min_feerate_ppm: 0,
+            max_actions_per_epoch: Some(DEFAULT_MAX_ACTIONS_PER_EPOCH),
+            max_sequence_gap: None,
+            max_action_bytes: Some(DEFAULT_MAX_ACTION_BYTES),
+            oracle_quorum: None,
+            price_aggregation: PriceAggregation::default(),
+            settlement_delay_secs: 0,
+            action_cutoff_secs_before_epoch_end: 0,
+            balance_change_retention: Some(DEFAULT_BALANCE_CHANGE_RETENTION),
+            max_price_staleness_secs: None,
+            min_lock_epochs: None,
+            settlement_digests: false,
+            max_tvl_msat: None,
+            withdrawal_fee_msat: 0,
+            allow_account_autocreation: default_allow_account_autocreation(),
+            backoff: BackOffConfig::default(),
+            max_actions_per_proposal: Some(DEFAULT_MAX_ACTIONS_PER_PROPOSAL),
+        }
+    }
+}
+
+impl PoolConfigGenParams {
+    /// Consensus config of a federation of `num_peers` that settles with
+    /// `price_threshold` votes. The first epoch starts at `default_start` if
+    /// the params leave the start open.
+    pub fn consensus_config(
+        &self,
+        price_threshold: u32,
+        num_peers: u32,
+        default_start: time::OffsetDateTime,
+    ) -> PoolConfigConsensus {
+        PoolConfigConsensus {
+            epoch: EpochConfig {
+                start_epoch_at: self
+                    .start_epoch_at
+                    .map(|prim_datetime| prim_datetime.assume_utc())
+                    .unwrap_or(default_start)
+                    .unix_timestamp() as _,
+                epoch_length: self.epoch_length,
+                align_to_secs: self.align_to_secs,
+                price_threshold,
+                num_peers: Some(num_peers),
+                oracle_quorum: self.oracle_quorum,
+                price_aggregation: self.price_aggregation,
+                min_feerate_ppm: self.min_feerate_ppm,
+                max_feerate_ppm: DEFAULT_GLOBAL_MAX_FEERATE,
+                collateral_ratio: self.collateral_ratio,
+                max_actions_per_epoch: self.max_actions_per_epoch,
+                max_sequence_gap: self.max_sequence_gap,
+                max_action_bytes: self.max_action_bytes,
+                settlement_delay_secs: self.settlement_delay_secs,
+                action_cutoff_secs_before_epoch_end: self.action_cutoff_secs_before_epoch_end,
+                balance_change_retention: self.balance_change_retention,
+                max_price_staleness_secs: self.max_price_staleness_secs,
+                min_lock_epochs: self.min_lock_epochs,
+                settlement_digests: self.settlement_digests,
+            },
+            oracle: self.oracle_config.clone(),
+            quote_currency: self.quote_currency.clone(),
+            max_tvl_msat: self.max_tvl_msat,
+            min_deposit_msat: self.min_deposit_msat,
+            withdrawal_fee_msat: self.withdrawal_fee_msat,
+            allow_account_autocreation: self.allow_account_autocreation,
         }
     }
 }
 
+/// Start of the first epoch when peers generate the config together. Every
+/// peer would read its own clock, so there is no default they are sure to
+/// agree on and the params have to set it.
+fn distributed_start(params: &PoolConfigGenParams) -> anyhow::Result<time::OffsetDateTime> {
+    params
+        .start_epoch_at
+        .map(|prim_datetime| prim_datetime.assume_utc())
+        .ok_or_else(|| {
+            anyhow::format_err!(
+                "start_epoch_at must be set to generate the config with other peers"
+            )
+        })
+}
+
+/// Commitment to the consensus encoding of `config`, which peers exchange
+/// during config generation to check they all generated the same one.
+fn consensus_commitment(config: &PoolConfigConsensus) -> bitcoin::secp256k1::PublicKey {
+    let bytes = config
+        .consensus_encode_to_vec()
+        .expect("encoding to a vec cannot fail");
+    let hash = bitcoin::hashes::sha256::Hash::hash(&bytes);
+    // the pubkey exchange is the only one config generation offers, so the
+    // hash is committed to as a secret key
+    let secret = bitcoin::secp256k1::SecretKey::from_slice(&hash[..])
+        .expect("hash is a valid secret key with overwhelming probability");
+    secret.public_key(&bitcoin::secp256k1::Secp256k1::signing_only())
+}
+
+/// Peers whose [`consensus_commitment`] differs from `ours`.
+fn peers_with_other_config(
+    ours: bitcoin::secp256k1::PublicKey,
+    commitments: &BTreeMap<PeerId, bitcoin::secp256k1::PublicKey>,
+) -> Vec<PeerId> {
+    commitments
+        .iter()
+        .filter(|(_, commitment)| **commitment != ours)
+        .map(|(peer, _)| *peer)
+        .collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct PoolConfigGenerator;
 
+/// The items [`PoolConfigGenerator::dump_database`] yields for one table.
+type DumpTable = Box<dyn Iterator<Item = (String, Box<dyn erased_serde::Serialize + Send>)> + Send>;
+
+/// Every record under `$prefix` as a [`DumpTable`] named after `$table`. The
+/// records are read in one pass and only boxed into items as they are
+/// consumed.
+macro_rules! table_entries {
+    ($dbtx:ident, $prefix:expr, $table:ident) => {{
+        let entries = $dbtx
+            .find_by_prefix(&$prefix)
+            .await
+            .collect::<Vec<_>>()
+            .await;
+        let table = $table.to_string();
+        Box::new(entries.into_iter().map(move |entry| {
+            let entry: Box<dyn erased_serde::Serialize + Send> = Box::new(entry);
+            (table.clone(), entry)
+        })) as DumpTable
+    }};
+}
+
+/// The record under the single `$key` as a [`DumpTable`], empty if there is
+/// none.
+macro_rules! table_value {
+    ($dbtx:ident, $key:expr, $table:ident) => {{
+        let value = db::get($dbtx, &$key).await;
+        let table = $table.to_string();
+        Box::new(value.into_iter().map(move |value| {
+            let value: Box<dyn erased_serde::Serialize + Send> = Box::new(value);
+            (table.clone(), value)
+        })) as DumpTable
+    }};
+}
+
 impl ExtendsCommonModuleGen for PoolConfigGenerator {
     type Common = PoolCommonGen;
 }
 
 #[async_trait]
 impl ServerModuleGen for PoolConfigGenerator {
-    const DATABASE_VERSION: DatabaseVersion = DatabaseVersion(1);
+    const DATABASE_VERSION: DatabaseVersion = DatabaseVersion(6);
 
     fn versions(&self, _core: CoreConsensusVersion) -> &[ModuleConsensusVersion] {
-        &[ModuleConsensusVersion(0)]
+        &[MODULE_CONSENSUS_VERSION]
+    }
+
+    fn get_database_migrations(&self) -> MigrationMap {
+        let mut migrations = MigrationMap::new();
+        migrations.insert(DatabaseVersion(1), move |dbtx| {
+            migrate_account_nonces(dbtx).boxed()
+        });
+        migrations.insert(DatabaseVersion(2), move |dbtx| {
+            migrate_epoch_end_votes(dbtx).boxed()
+        });
+        migrations.insert(DatabaseVersion(3), move |dbtx| {
+            migrate_total_balance(dbtx).boxed()
+        });
+        migrations.insert(DatabaseVersion(4), move |dbtx| {
+            migrate_epoch_outcomes(dbtx).boxed()
+        });
+        migrations.insert(DatabaseVersion(5), move |dbtx| {
+            migrate_peer_last_seen(dbtx).boxed()
+        });
+        migrations
     }
 
     async fn init(
@@ -97,9 +363,11 @@ impl ServerModuleGen for PoolConfigGenerator {
         cfg: ServerModuleConfig,
         _db: Database,
         _env: &BTreeMap<OsString, OsString>,
-        _task_group: &mut TaskGroup,
+        task_group: &mut TaskGroup,
     ) -> anyhow::Result<DynServerModule> {
-        Ok(StabilityPool::new(cfg.to_typed()?).into())
+        let mut pool = StabilityPool::new(cfg.to_typed()?);
+        pool.shutdown = Some(task_group.make_handle());
+        Ok(pool.into())
     }
 
     fn trusted_dealer_gen(
@@ -111,25 +379,22 @@ impl ServerModuleGen for PoolConfigGenerator {
             .to_typed::<PoolConfigGenParams>()
             .expect("Invalid mint params");
 
+        // every peer gets the same start
+        let now = time::OffsetDateTime::now_utc();
         let mint_cfg: BTreeMap<_, PoolConfig> = peers
             .iter()
             .map(|&peer| {
                 let config = PoolConfig {
-                    private: PoolConfigPrivate { peer_id: peer },
-                    consensus: PoolConfigConsensus {
-                        epoch: EpochConfig {
-                            start_epoch_at: params
-                                .start_epoch_at
-                                .map(|prim_datetime| prim_datetime.assume_utc())
-                                .unwrap_or_else(|| time::OffsetDateTime::now_utc())
-                                .unix_timestamp() as _,
-                            epoch_length: params.epoch_length,
-                            price_threshold: peers.threshold() as _,
-                            max_feerate_ppm: DEFAULT_GLOBAL_MAX_FEERATE,
-                            collateral_ratio: params.collateral_ratio,
-                        },
-                        oracle: params.oracle_config.clone(),
+                    private: PoolConfigPrivate {
+                        peer_id: peer,
+                        backoff: params.backoff,
+                        max_actions_per_proposal: params.max_actions_per_proposal,
                     },
+                    consensus: params.consensus_config(
+                        peers.threshold() as _,
+                        peers.len() as _,
+                        now,
+                    ),
                 };
                 (peer, config)
             })
@@ -150,24 +415,31 @@ impl ServerModuleGen for PoolConfigGenerator {
             .to_typed::<PoolConfigGenParams>()
             .expect("Invalid mint params");
 
+        let start = distributed_start(&params).map_err(DkgError::Failed)?;
+        let consensus =
+            params.consensus_config(peers.peers.threshold() as _, peers.peers.len() as _, start);
+
+        // every peer generates the consensus config on its own, so they have
+        // to arrive at the same one or settlement diverges
+        let ours = consensus_commitment(&consensus);
+        let commitments = peers
+            .exchange_pubkeys("stabilitypool-params".to_string(), ours)
+            .await?;
+        let mismatched = peers_with_other_config(ours, &commitments);
+        if !mismatched.is_empty() {
+            return Err(DkgError::Failed(anyhow::format_err!(
+                "peers {:?} generated a different consensus config",
+                mismatched
+            )));
+        }
+
         let server = PoolConfig {
             private: PoolConfigPrivate {
                 peer_id: peers.our_id,
+                backoff: params.backoff,
+                max_actions_per_proposal: params.max_actions_per_proposal,
             },
-            consensus: PoolConfigConsensus {
-                epoch: EpochConfig {
-                    start_epoch_at: params
-                        .start_epoch_at
-                        .map(|prim_datetime| prim_datetime.assume_utc())
-                        .unwrap_or_else(|| time::OffsetDateTime::now_utc())
-                        .unix_timestamp() as _,
-                    epoch_length: params.epoch_length,
-                    price_threshold: peers.peers.threshold() as _,
-                    max_feerate_ppm: DEFAULT_GLOBAL_MAX_FEERATE,
-                    collateral_ratio: params.collateral_ratio,
-                },
-                oracle: params.oracle_config,
-            },
+            consensus,
         };
 
         Ok(server.to_erased())
@@ -189,21 +461,234 @@ impl ServerModuleGen for PoolConfigGenerator {
         config.to_typed::<PoolConfig>()?.validate_config(identity)
     }
 
+    /// Entries of the tables named in `prefix_names`, all of them if it is
+    /// empty, chained table by table. Tables that are not requested are never
+    /// read. The returned iterator can neither await nor borrow `dbtx`, so each
+    /// requested table is read here in one pass, and its entries are only
+    /// boxed into items as the iterator reaches them.
     async fn dump_database(
         &self,
-        _dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
-        _prefix_names: Vec<String>,
+        dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+        prefix_names: Vec<String>,
     ) -> Box<dyn Iterator<Item = (String, Box<dyn erased_serde::Serialize + Send>)> + '_> {
-        Box::new(BTreeMap::new().into_iter())
+        let mut dump: Vec<DumpTable> = vec![];
+        let tables = db::DbKeyPrefix::iter().filter(|table| {
+            prefix_names.is_empty() || prefix_names.contains(&table.to_string().to_lowercase())
+        });
+        for table in tables {
+            dump.push(match table {
+                db::DbKeyPrefix::Account => {
+                    table_entries!(dbtx, db::AccountBalanceKeyPrefix, table)
+                }
+                db::DbKeyPrefix::DepositOutcome => {
+                    table_entries!(dbtx, db::DepositOutcomePrefix, table)
+                }
+                db::DbKeyPrefix::EpochOutcome => {
+                    table_entries!(dbtx, db::EpochOutcomeKeyPrefix, table)
+                }
+                db::DbKeyPrefix::LastEpochEnded => table_value!(dbtx, db::LastEpochEndedKey, table),
+                db::DbKeyPrefix::LastEpochSettled => {
+                    table_value!(dbtx, db::LastEpochSettledKey, table)
+                }
+                db::DbKeyPrefix::EpochEnd => table_entries!(dbtx, db::EpochEndKeyPrefix, table),
+                db::DbKeyPrefix::ActionStaged => {
+                    table_entries!(dbtx, db::ActionStagedKeyPrefix, table)
+                }
+                db::DbKeyPrefix::ForceSettle => {
+                    table_entries!(dbtx, db::ForceSettleKeyPrefix, table)
+                }
+                db::DbKeyPrefix::ActionCount => {
+                    table_entries!(dbtx, db::ActionCountKeyPrefix, table)
+                }
+                db::DbKeyPrefix::BalanceChange => {
+                    table_entries!(dbtx, db::BalanceChangeKeyPrefix, table)
+                }
+                db::DbKeyPrefix::BalanceChangeSeq => {
+                    table_value!(dbtx, db::BalanceChangeSeqKey, table)
+                }
+                db::DbKeyPrefix::GovernanceVote => {
+                    table_entries!(dbtx, db::GovernanceVoteKeyPrefix, table)
+                }
+                db::DbKeyPrefix::TvlCap => table_value!(dbtx, db::TvlCapKey, table),
+                db::DbKeyPrefix::TotalBalance => table_value!(dbtx, db::TotalBalanceKey, table),
+                db::DbKeyPrefix::FrozenAccount => {
+                    table_entries!(dbtx, db::FrozenAccountKeyPrefix, table)
+                }
+                db::DbKeyPrefix::CollectedFees => table_value!(dbtx, db::CollectedFeesKey, table),
+                db::DbKeyPrefix::FeerateCap => table_entries!(dbtx, db::FeerateCapKeyPrefix, table),
+                db::DbKeyPrefix::DegradedQuorumPolicy => {
+                    table_value!(dbtx, db::DegradedQuorumPolicyKey, table)
+                }
+                db::DbKeyPrefix::PeerLastSeen => {
+                    table_entries!(dbtx, db::PeerLastSeenKeyPrefix, table)
+                }
+                db::DbKeyPrefix::ProviderLocked => {
+                    table_entries!(dbtx, db::ProviderLockedKeyPrefix, table)
+                }
+                db::DbKeyPrefix::AccountEpoch => {
+                    table_entries!(dbtx, db::AccountEpochKeyPrefix, table)
+                }
+                db::DbKeyPrefix::PoolClosed => table_value!(dbtx, db::PoolClosedKey, table),
+                db::DbKeyPrefix::EpochFees => table_entries!(dbtx, db::EpochFeesKeyPrefix, table),
+                db::DbKeyPrefix::SeekerLockedSince => {
+                    table_entries!(dbtx, db::SeekerLockedSinceKeyPrefix, table)
+                }
+                db::DbKeyPrefix::SettlementDigest => {
+                    table_value!(dbtx, db::SettlementDigestKey, table)
+                }
+                db::DbKeyPrefix::PeerSettlementDigest => {
+                    table_entries!(dbtx, db::PeerSettlementDigestKeyPrefix, table)
+                }
+                db::DbKeyPrefix::ActionSigned => {
+                    table_entries!(dbtx, db::ActionSignedKeyPrefix, table)
+                }
+                db::DbKeyPrefix::ConsensusSessions => {
+                    table_value!(dbtx, db::ConsensusSessionsKey, table)
+                }
+            });
+        }
+        Box::new(dump.into_iter().flatten())
+    }
+}
+
+/// Migrate from database version 1, whose account records did not track the
+/// account's latest staged action. Accounts continue from the action they
+/// have staged, if any.
+async fn migrate_account_nonces(dbtx: &mut DatabaseTransaction<'_>) -> anyhow::Result<()> {
+    let accounts = dbtx
+        .find_by_prefix(&db::AccountBalanceV1KeyPrefix)
+        .await
+        .collect::<Vec<_>>()
+        .await;
+    for (key, account) in accounts {
+        let staged = dbtx.get_value(&db::ActionStagedKey(key.0)).await;
+        let account = AccountBalance {
+            unlocked: account.unlocked,
+            locked: account.locked,
+            last_seq: staged.as_ref().map_or(0, ActionStaged::sequence),
+            last_epoch: staged.as_ref().map_or(EpochId(0), ActionStaged::epoch_id),
+        };
+        dbtx.insert_entry(&db::AccountBalanceKey(key.0), &account)
+            .await;
+    }
+    Ok(())
+}
+
+/// Migrate from database version 2, whose epoch end votes did not carry the
+/// voter's clock. The votes are dropped rather than given a made up time:
+/// peers keep proposing their vote until the epoch settles, so the pending
+/// epoch is voted on again.
+async fn migrate_epoch_end_votes(dbtx: &mut DatabaseTransaction<'_>) -> anyhow::Result<()> {
+    dbtx.remove_by_prefix(&db::EpochEndKeyPrefix).await;
+    Ok(())
+}
+
+/// Migrate from database version 3, which only started tracking the total of
+/// all account balances once a deposit or withdrawal needed it. The total is
+/// computed from the accounts so that it is tracked from the start.
+async fn migrate_total_balance(dbtx: &mut DatabaseTransaction<'_>) -> anyhow::Result<()> {
+    let total = dbtx
+        .find_by_prefix(&db::AccountBalanceKeyPrefix)
+        .await
+        .map(|(_, account)| {
+            account
+                .total_balance()
+                .expect("account balance cannot overflow")
+                .msats
+        })
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .sum::<u64>();
+    dbtx.insert_entry(&db::TotalBalanceKey, &total).await;
+    Ok(())
+}
+
+/// Migrate from database version 4, whose epoch outcomes did not record how
+/// the epoch settled nor how many positions were locked in it. Force
+/// settlement did not exist yet, so no outcome was forced. Settlement times
+/// were not recorded and positions cannot be counted after the fact, as their
+/// balances have been settled since.
+async fn migrate_epoch_outcomes(dbtx: &mut DatabaseTransaction<'_>) -> anyhow::Result<()> {
+    let outcomes = dbtx
+        .find_by_prefix(&db::EpochOutcomeV1KeyPrefix)
+        .await
+        .collect::<Vec<_>>()
+        .await;
+    for (key, outcome) in outcomes {
+        let outcome = EpochOutcome {
+            total_seeker_locked: outcome.total_seeker_locked,
+            total_provider_locked: outcome.total_provider_locked,
+            settled_price: outcome.settled_price,
+            feerate: outcome.feerate,
+            forced: false,
+            num_seekers: 0,
+            num_providers: 0,
+            settled_at: None,
+            degraded_quorum: None,
+        };
+        dbtx.insert_entry(&db::EpochOutcomeKey(key.0), &outcome)
+            .await;
     }
+    Ok(())
+}
+
+/// Migrate from database version 5, which recorded the pool epoch in which
+/// peers were last seen rather than the consensus session. The records are
+/// dropped: every peer counts as seen before the first session, and online
+/// peers are seen again in the next one.
+async fn migrate_peer_last_seen(dbtx: &mut DatabaseTransaction<'_>) -> anyhow::Result<()> {
+    dbtx.remove_by_prefix(&db::PeerLastSeenKeyPrefix).await;
+    Ok(())
 }
 
 #[derive(Debug)]
 pub struct StabilityPool {
     pub cfg: PoolConfig,
     pub oracle: Box<dyn OracleClient>,
+    /// Clock deciding when we vote to end epochs and when we stop taking
+    /// actions for the next one
+    pub clock: Box<dyn Clock>,
     pub backoff: BackOff,
     pub proposed_db: ActionProposedDb,
+    pub force_settle: ForceSettleProposal,
+    pub governance: GovernanceProposal,
+    /// Handle of the task group the module runs in, to stop waiting for
+    /// consensus proposals once the group shuts down
+    pub shutdown: Option<TaskHandle>,
+    pub settlement_in_progress: SettlementInProgress,
+}
+
+/// Set while an epoch may be settling, so that deposits submitted
+/// concurrently are turned away instead of racing the settlement. It is only
+/// set for the calls that can settle, not while an ended epoch waits for its
+/// price. Transactions applied in consensus run after these calls and never
+/// observe the flag, so it cannot cause peers to diverge.
+#[derive(Debug, Default)]
+pub struct SettlementInProgress {
+    flag: AtomicBool,
+}
+
+impl SettlementInProgress {
+    pub fn is_set(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+
+    /// Set the flag until the returned guard is dropped.
+    pub fn begin(&self) -> SettlementGuard<'_> {
+        self.flag.store(true, Ordering::SeqCst);
+        SettlementGuard { flag: &self.flag }
+    }
+}
+
+pub struct SettlementGuard<'a> {
+    flag: &'a AtomicBool,
+}
+
+impl Drop for SettlementGuard<'_> {
+    fn drop(&mut self) {
+        self.flag.store(false, Ordering::SeqCst);
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -219,6 +704,30 @@ impl StabilityPool {
     fn oracle(&self) -> &dyn OracleClient {
         &*self.oracle
     }
+
+    fn clock(&self) -> &dyn Clock {
+        &*self.clock
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        self.shutdown
+            .as_ref()
+            .map_or(false, |handle| handle.is_shutting_down())
+    }
+
+    /// Sleep for `duration`, waking up early if the task group shuts down.
+    #[cfg(not(target_family = "wasm"))]
+    async fn sleep_unless_shutdown(&self, duration: Duration) {
+        let sleep = fedimint_core::task::sleep(duration);
+        futures::pin_mut!(sleep);
+        match &self.shutdown {
+            Some(handle) => {
+                let shutdown_rx = handle.make_shutdown_rx().await;
+                futures::future::select(sleep, shutdown_rx).await;
+            }
+            None => sleep.await,
+        }
+    }
 }
 
 #[async_trait]
@@ -228,10 +737,7 @@ impl ServerModule for StabilityPool {
     type VerificationCache = PoolVerificationCache;
 
     fn versions(&self) -> (ModuleConsensusVersion, &[ApiVersion]) {
-        (
-            ModuleConsensusVersion(1),
-            &[ApiVersion { major: 1, minor: 1 }],
-        )
+        (MODULE_CONSENSUS_VERSION, SUPPORTED_API_VERSIONS)
     }
 
     async fn await_consensus_proposal(
@@ -242,18 +748,57 @@ impl ServerModule for StabilityPool {
         // We block until at least one of these happens:
         // * At least one proposed action is avaliable
         // * Duration past requires us to send `PoolConsensusItem::EpochEnd`
+        // * Our operator requested a `PoolConsensusItem::ForceSettle`
+        // * Our digest of the latest settlement has not been submitted
+        // * The task group we run in shuts down
         loop {
+            if self.is_shutting_down() {
+                tracing::debug!("shutting down: stop waiting for proposals");
+                return;
+            }
             if action::can_propose(dbtx, &self.proposed_db).await {
                 tracing::debug!("can propose: action");
                 return;
             }
-            if epoch::can_propose(dbtx, &self.backoff, self.epoch_config()).await {
+            let now = self.clock().now();
+            if epoch::can_propose(dbtx, &self.backoff, self.epoch_config(), now).await {
                 tracing::debug!("can propose: epoch");
                 return;
             }
+            if !epoch::force_settle_proposal(dbtx, &self.force_settle, self.cfg.private.peer_id)
+                .await
+                .is_empty()
+            {
+                tracing::debug!("can propose: force settle");
+                return;
+            }
+            if !governance::consensus_proposal(
+                dbtx,
+                &self.cfg.consensus,
+                &self.governance,
+                self.cfg.private.peer_id,
+            )
+            .await
+            .is_empty()
+            {
+                tracing::debug!("can propose: governance");
+                return;
+            }
+            if !epoch::settlement_digest_proposal(
+                dbtx,
+                self.epoch_config(),
+                self.cfg.private.peer_id,
+            )
+            .await
+            .is_empty()
+            {
+                tracing::debug!("can propose: settlement digest");
+                return;
+            }
 
             #[cfg(not(target_family = "wasm"))]
-            fedimint_core::task::sleep(Duration::from_secs(5)).await;
+            self.sleep_unless_shutdown(CONSENSUS_PROPOSAL_POLL_INTERVAL)
+                .await;
         }
     }
 
@@ -263,11 +808,47 @@ impl ServerModule for StabilityPool {
     ) -> ConsensusProposal<PoolConsensusItem> {
         let mut items = Vec::new();
 
+        // our clock only decides when we vote, see `epoch::can_propose`
+        let now = self.clock().now();
+        items.append(
+            &mut epoch::consensus_proposal(
+                dbtx,
+                &self.backoff,
+                self.epoch_config(),
+                self.oracle(),
+                now,
+            )
+            .await,
+        );
         items.append(
-            &mut epoch::consensus_proposal(dbtx, &self.backoff, self.epoch_config(), self.oracle())
+            &mut epoch::force_settle_proposal(dbtx, &self.force_settle, self.cfg.private.peer_id)
                 .await,
         );
-        items.append(&mut action::consensus_proposal(dbtx, &self.proposed_db).await);
+        items.append(
+            &mut governance::consensus_proposal(
+                dbtx,
+                &self.cfg.consensus,
+                &self.governance,
+                self.cfg.private.peer_id,
+            )
+            .await,
+        );
+        items.append(
+            &mut epoch::settlement_digest_proposal(
+                dbtx,
+                self.epoch_config(),
+                self.cfg.private.peer_id,
+            )
+            .await,
+        );
+        items.append(
+            &mut action::consensus_proposal(
+                dbtx,
+                &self.proposed_db,
+                self.cfg.private.max_actions_per_proposal,
+            )
+            .await,
+        );
         ConsensusProposal::Contribute(items)
     }
 
@@ -276,15 +857,39 @@ impl ServerModule for StabilityPool {
         dbtx: &mut ModuleDatabaseTransaction<'b, ModuleInstanceId>,
         consensus_items: Vec<(PeerId, PoolConsensusItem)>,
     ) {
+        {
+            let _settling = self.settlement_in_progress.begin();
+            epoch::begin_session(dbtx, self.epoch_config()).await;
+        }
         for (peer_id, item) in consensus_items {
+            epoch::record_peer_seen(dbtx, peer_id).await;
             let outcome = match item {
                 PoolConsensusItem::ActionProposed(action_proposed) => {
-                    action::process_consensus_item(dbtx, &self.proposed_db, action_proposed).await
+                    action::process_consensus_item(
+                        dbtx,
+                        self.epoch_config(),
+                        &self.proposed_db,
+                        action_proposed,
+                    )
+                    .await
                 }
                 PoolConsensusItem::EpochEnd(epoch_end) => {
+                    let _settling = self.settlement_in_progress.begin();
                     epoch::process_consensus_item(dbtx, self.epoch_config(), peer_id, epoch_end)
                         .await
                 }
+                PoolConsensusItem::ForceSettle(force_settle) => {
+                    let _settling = self.settlement_in_progress.begin();
+                    epoch::process_force_settle(dbtx, self.epoch_config(), peer_id, force_settle)
+                        .await
+                }
+                PoolConsensusItem::Governance(action) => {
+                    governance::process_consensus_item(dbtx, &self.cfg.consensus, peer_id, action)
+                        .await
+                }
+                PoolConsensusItem::SettlementDigest(digest) => {
+                    epoch::process_settlement_digest(dbtx, peer_id, digest).await
+                }
             };
 
             match outcome {
@@ -308,6 +913,10 @@ impl ServerModule for StabilityPool {
         PoolVerificationCache
     }
 
+    // A withdrawal only provides value to its transaction, which the
+    // transaction's outputs spend, be it on ecash, a peg-out or a lightning
+    // contract. The interconnect only gives read access to other modules' APIs
+    // so it cannot route funds itself.
     async fn validate_input<'a, 'b>(
         &self,
         _interconnect: &dyn ModuleInterconect,
@@ -315,31 +924,10 @@ impl ServerModule for StabilityPool {
         _verification_cache: &Self::VerificationCache,
         withdrawal: &'a PoolInput,
     ) -> Result<InputMeta, ModuleError> {
-        let avaliable = dbtx
-            .get_value(&db::AccountBalanceKey(withdrawal.account))
+        let fee = fedimint_core::msats(self.cfg.consensus.withdrawal_fee_msat);
+        validate_withdrawal(dbtx, withdrawal, fee)
             .await
-            .map(|acc| acc.unlocked)
-            .unwrap_or(fedimint_core::Amount::ZERO);
-
-        // TODO: we should also deduct seeker/provider actions that are set for the next
-        // round
-
-        if avaliable < withdrawal.amount {
-            return Err(WithdrawalError::UnavaliableFunds {
-                amount: withdrawal.amount,
-                avaliable,
-            })
-            .into_module_error_other();
-        }
-
-        Ok(InputMeta {
-            amount: TransactionItemAmount {
-                amount: withdrawal.amount,
-                // TODO: Figure out how to do fees later.
-                fee: fedimint_core::Amount::ZERO,
-            },
-            puk_keys: [withdrawal.account].into(),
-        })
+            .into_module_error_other()
     }
 
     async fn apply_input<'a, 'b, 'c>(
@@ -355,19 +943,8 @@ impl ServerModule for StabilityPool {
 
         tracing::debug!(account = %withdrawal.account, amount = %meta.amount.amount, "Stability pool withdrawal");
 
-        let mut account = dbtx
-            .get_value(&db::AccountBalanceKey(withdrawal.account))
-            .await
-            .unwrap_or_default();
-
-        account.unlocked.msats = account
-            .unlocked
-            .msats
-            .checked_sub(withdrawal.amount.msats)
-            .expect("withdrawal amount should already be checked");
-
-        dbtx.insert_entry(&db::AccountBalanceKey(withdrawal.account), &account)
-            .await;
+        let fee = fedimint_core::msats(self.cfg.consensus.withdrawal_fee_msat);
+        apply_withdrawal(dbtx, self.epoch_config(), withdrawal, fee).await;
 
         Ok(meta)
     }
@@ -377,15 +954,66 @@ impl ServerModule for StabilityPool {
         dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
         deposit: &PoolOutput,
     ) -> Result<TransactionItemAmount, ModuleError> {
-        // TODO: Maybe some checks into minimum deposit amount?
+        let min_deposit = fedimint_core::msats(self.cfg.consensus.min_deposit_msat);
+        if deposit.amount < min_deposit {
+            return Err(PoolError::from(StabilityPoolError::DepositTooSmall {
+                min_deposit,
+            }))
+            .into_module_error_other();
+        }
+
+        if governance::is_closed(dbtx).await {
+            return Err(PoolError::from(StabilityPoolError::PoolClosed)).into_module_error_other();
+        }
+
+        if self.settlement_in_progress.is_set() {
+            return Err(PoolError::from(StabilityPoolError::SettlementInProgress))
+                .into_module_error_other();
+        }
+
+        if let Some(intent) = &deposit.intent {
+            if intent.account_id() != deposit.account || intent.verify_signature().is_err() {
+                return Err(PoolError::from(StabilityPoolError::InvalidDepositIntent {
+                    account: deposit.account,
+                }))
+                .into_module_error_other();
+            }
+        }
+
+        if governance::is_frozen(dbtx, deposit.account).await {
+            return Err(PoolError::from(StabilityPoolError::AccountFrozen {
+                account: deposit.account,
+            }))
+            .into_module_error_other();
+        }
 
         // check deposit does not result in balance overflow
-        if let Some(account) = dbtx
+        match dbtx
             .get_value(&db::AccountBalanceKey(deposit.account))
             .await
         {
-            if !account.can_add_amount(deposit.amount) {
-                return Err(StabilityPoolError::DepositTooLarge).into_module_error_other();
+            Some(account) => {
+                if !account.can_add_amount(deposit.amount) {
+                    return Err(PoolError::from(StabilityPoolError::DepositTooLarge))
+                        .into_module_error_other();
+                }
+            }
+            None if !self.cfg.consensus.allow_account_autocreation => {
+                return Err(PoolError::from(StabilityPoolError::AccountNotRegistered {
+                    account: deposit.account,
+                }))
+                .into_module_error_other();
+            }
+            None => {}
+        }
+
+        if let Some(max_tvl) = governance::max_tvl(dbtx, &self.cfg.consensus).await {
+            let total = total_balance(dbtx).await;
+            if total.saturating_add(deposit.amount.msats) > max_tvl {
+                return Err(PoolError::from(StabilityPoolError::TvlCapExceeded {
+                    max_tvl: fedimint_core::msats(max_tvl),
+                }))
+                .into_module_error_other();
             }
         }
 
@@ -402,20 +1030,24 @@ impl ServerModule for StabilityPool {
         deposit: &'a PoolOutput,
         outpoint: OutPoint,
     ) -> Result<TransactionItemAmount, ModuleError> {
+        // a replayed outpoint must not credit the account twice
+        if dbtx
+            .get_value(&db::DepositOutcomeKey(outpoint))
+            .await
+            .is_some()
+        {
+            return Err(PoolError::from(StabilityPoolError::DepositAlreadyApplied {
+                outpoint,
+            }))
+            .into_module_error_other();
+        }
+
         let txo_amount = self.validate_output(dbtx, deposit).await?;
 
-        let mut account = dbtx
-            .get_value(&db::AccountBalanceKey(deposit.account))
+        apply_deposit(dbtx, self.epoch_config(), deposit)
             .await
-            .unwrap_or_default();
-        account.unlocked.msats = account
-            .unlocked
-            .msats
-            .checked_add(deposit.amount.msats)
-            .expect("already checked overflow");
-
-        dbtx.insert_entry(&db::AccountBalanceKey(deposit.account), &account)
-            .await;
+            .into_module_error_other()?;
+        action::stage_deposit_intent(dbtx, self.epoch_config(), deposit).await;
 
         dbtx.insert_new_entry(&db::DepositOutcomeKey(outpoint), &deposit.account)
             .await;
@@ -446,9 +1078,16 @@ impl ServerModule for StabilityPool {
         dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
         audit: &mut Audit,
     ) {
+        // account balances and fees not yet paid out are owed by the module
         audit
             .add_items(dbtx, &AccountBalanceKeyPrefix, |_, v| {
-                ((v.unlocked + v.locked.amount()).msats) as i64
+                -i64::try_from((v.unlocked + v.locked.amount()).msats)
+                    .expect("account balance exceeds i64")
+            })
+            .await;
+        audit
+            .add_items(dbtx, &db::CollectedFeesPrefix, |_, v| {
+                -i64::try_from(*v).expect("collected fees exceed i64")
             })
             .await;
     }
@@ -461,51 +1100,1956 @@ impl ServerModule for StabilityPool {
 impl StabilityPool {
     /// Create new module instance
     pub fn new(cfg: PoolConfig) -> Self {
-        let oracle = cfg.consensus.oracle.oracle_client();
+        let oracle = cfg
+            .consensus
+            .oracle
+            .oracle_client(&cfg.consensus.quote_currency);
+        let backoff = BackOff::new(cfg.private.backoff);
         Self {
             cfg,
             oracle,
-            backoff: Default::default(),
+            clock: Box::new(SystemClock),
+            backoff,
             proposed_db: Default::default(),
+            force_settle: Default::default(),
+            governance: Default::default(),
+            shutdown: None,
+            settlement_in_progress: Default::default(),
         }
     }
-}
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
-pub enum StabilityPoolError {
-    SomethingDummyWentWrong,
-    DepositTooLarge,
-}
+    /// Report what the module owes, computed in one pass over the accounts,
+    /// and whether the running total balance agrees with it.
+    pub async fn solvency(dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>) -> Solvency {
+        let (total_unlocked_msat, total_locked_msat) = dbtx
+            .find_by_prefix(&AccountBalanceKeyPrefix)
+            .await
+            .fold(
+                (0_u64, 0_u64),
+                |(unlocked, locked), (_, account)| async move {
+                    (
+                        unlocked + account.unlocked.msats,
+                        locked + account.locked.amount().msats,
+                    )
+                },
+            )
+            .await;
+        let total_balance_msat = total_unlocked_msat + total_locked_msat;
+        let collected_fees_msat = db::get(dbtx, &db::CollectedFeesKey).await.unwrap_or(0);
+        let tracked_total_balance_msat = db::get(dbtx, &db::TotalBalanceKey).await;
+
+        Solvency {
+            total_unlocked_msat,
+            total_locked_msat,
+            collected_fees_msat,
+            total_liabilities_msat: total_balance_msat + collected_fees_msat,
+            // the same items `audit` adds up
+            audit_msat: -i64::try_from(total_balance_msat + collected_fees_msat)
+                .expect("liabilities exceed i64"),
+            tracked_total_balance_msat,
+            total_balance_in_sync: tracked_total_balance_msat
+                .map_or(true, |tracked| tracked == total_balance_msat),
+        }
+    }
 
-impl std::fmt::Display for StabilityPoolError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::SomethingDummyWentWrong => write!(f, "placeholder error"),
-            Self::DepositTooLarge => write!(f, "that deposit pukking big"),
+    /// Export every record of the module, e.g. to reproduce an issue from
+    /// production against a fresh module with [`Self::import_snapshot`].
+    pub async fn export_snapshot(
+        dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    ) -> Snapshot {
+        Snapshot {
+            accounts: dbtx
+                .find_by_prefix(&db::AccountBalanceKeyPrefix)
+                .await
+                .collect()
+                .await,
+            deposit_outcomes: dbtx
+                .find_by_prefix(&db::DepositOutcomePrefix)
+                .await
+                .collect()
+                .await,
+            epoch_outcomes: dbtx
+                .find_by_prefix(&db::EpochOutcomeKeyPrefix)
+                .await
+                .collect()
+                .await,
+            last_epoch_ended: db::get(dbtx, &db::LastEpochEndedKey).await,
+            last_epoch_settled: db::get(dbtx, &db::LastEpochSettledKey).await,
+            epoch_ends: dbtx
+                .find_by_prefix(&db::EpochEndKeyPrefix)
+                .await
+                .collect()
+                .await,
+            staged_actions: dbtx
+                .find_by_prefix(&db::ActionStagedKeyPrefix)
+                .await
+                .collect()
+                .await,
+            force_settles: dbtx
+                .find_by_prefix(&db::ForceSettleKeyPrefix)
+                .await
+                .collect()
+                .await,
+            action_counts: dbtx
+                .find_by_prefix(&db::ActionCountKeyPrefix)
+                .await
+                .collect()
+                .await,
+            balance_changes: dbtx
+                .find_by_prefix(&db::BalanceChangeKeyPrefix)
+                .await
+                .collect()
+                .await,
+            balance_change_seq: db::get(dbtx, &db::BalanceChangeSeqKey).await,
+            governance_votes: dbtx
+                .find_by_prefix(&db::GovernanceVoteKeyPrefix)
+                .await
+                .collect()
+                .await,
+            tvl_cap: db::get(dbtx, &db::TvlCapKey).await,
+            total_balance: db::get(dbtx, &db::TotalBalanceKey).await,
+            frozen_accounts: dbtx
+                .find_by_prefix(&db::FrozenAccountKeyPrefix)
+                .await
+                .collect()
+                .await,
+            collected_fees: db::get(dbtx, &db::CollectedFeesKey).await,
+            feerate_caps: dbtx
+                .find_by_prefix(&db::FeerateCapKeyPrefix)
+                .await
+                .collect()
+                .await,
+            degraded_quorum_policy: db::get(dbtx, &db::DegradedQuorumPolicyKey).await,
+            peers_last_seen: dbtx
+                .find_by_prefix(&db::PeerLastSeenKeyPrefix)
+                .await
+                .collect()
+                .await,
+            provider_locked: dbtx
+                .find_by_prefix(&db::ProviderLockedKeyPrefix)
+                .await
+                .collect()
+                .await,
+            account_epochs: dbtx
+                .find_by_prefix(&db::AccountEpochKeyPrefix)
+                .await
+                .collect()
+                .await,
+            pool_closed: db::get(dbtx, &db::PoolClosedKey).await,
+            epoch_fees: dbtx
+                .find_by_prefix(&db::EpochFeesKeyPrefix)
+                .await
+                .collect()
+                .await,
+            seeker_locked_since: dbtx
+                .find_by_prefix(&db::SeekerLockedSinceKeyPrefix)
+                .await
+                .collect()
+                .await,
+            settlement_digest: db::get(dbtx, &db::SettlementDigestKey).await,
+            peer_settlement_digests: dbtx
+                .find_by_prefix(&db::PeerSettlementDigestKeyPrefix)
+                .await
+                .collect()
+                .await,
+            signed_actions: dbtx
+                .find_by_prefix(&db::ActionSignedKeyPrefix)
+                .await
+                .collect()
+                .await,
+            consensus_sessions: db::get(dbtx, &db::ConsensusSessionsKey).await,
+        }
+    }
+
+    /// Replace every record of the module with those of `snapshot`.
+    pub async fn import_snapshot(
+        dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+        snapshot: Snapshot,
+    ) {
+        db::prefix_remove_all(dbtx, &db::AccountBalanceKeyPrefix).await;
+        db::prefix_remove_all(dbtx, &db::DepositOutcomePrefix).await;
+        db::prefix_remove_all(dbtx, &db::EpochOutcomeKeyPrefix).await;
+        db::prefix_remove_all(dbtx, &db::LastEpochEndedPrefix).await;
+        db::prefix_remove_all(dbtx, &db::LastEpochSettledPrefix).await;
+        db::prefix_remove_all(dbtx, &db::EpochEndKeyPrefix).await;
+        db::prefix_remove_all(dbtx, &db::ActionStagedKeyPrefix).await;
+        db::prefix_remove_all(dbtx, &db::ForceSettleKeyPrefix).await;
+        db::prefix_remove_all(dbtx, &db::ActionCountKeyPrefix).await;
+        db::prefix_remove_all(dbtx, &db::BalanceChangeKeyPrefix).await;
+        db::prefix_remove_all(dbtx, &db::BalanceChangeSeqPrefix).await;
+        db::prefix_remove_all(dbtx, &db::GovernanceVoteKeyPrefix).await;
+        db::prefix_remove_all(dbtx, &db::TvlCapPrefix).await;
+        db::prefix_remove_all(dbtx, &db::TotalBalancePrefix).await;
+        db::prefix_remove_all(dbtx, &db::FrozenAccountKeyPrefix).await;
+        db::prefix_remove_all(dbtx, &db::CollectedFeesPrefix).await;
+        db::prefix_remove_all(dbtx, &db::FeerateCapKeyPrefix).await;
+        db::prefix_remove_all(dbtx, &db::DegradedQuorumPolicyPrefix).await;
+        db::prefix_remove_all(dbtx, &db::PeerLastSeenKeyPrefix).await;
+        db::prefix_remove_all(dbtx, &db::ProviderLockedKeyPrefix).await;
+        db::prefix_remove_all(dbtx, &db::AccountEpochKeyPrefix).await;
+        db::prefix_remove_all(dbtx, &db::PoolClosedPrefix).await;
+        db::prefix_remove_all(dbtx, &db::EpochFeesKeyPrefix).await;
+        db::prefix_remove_all(dbtx, &db::SeekerLockedSinceKeyPrefix).await;
+        db::prefix_remove_all(dbtx, &db::SettlementDigestPrefix).await;
+        db::prefix_remove_all(dbtx, &db::PeerSettlementDigestKeyPrefix).await;
+        db::prefix_remove_all(dbtx, &db::ActionSignedKeyPrefix).await;
+        db::prefix_remove_all(dbtx, &db::ConsensusSessionsPrefix).await;
+
+        set_all(dbtx, snapshot.accounts).await;
+        set_all(dbtx, snapshot.deposit_outcomes).await;
+        set_all(dbtx, snapshot.epoch_outcomes).await;
+        if let Some(epoch_id) = snapshot.last_epoch_ended {
+            db::set(dbtx, &db::LastEpochEndedKey, &epoch_id).await;
+        }
+        if let Some(epoch_id) = snapshot.last_epoch_settled {
+            db::set(dbtx, &db::LastEpochSettledKey, &epoch_id).await;
+        }
+        set_all(dbtx, snapshot.epoch_ends).await;
+        set_all(dbtx, snapshot.staged_actions).await;
+        set_all(dbtx, snapshot.force_settles).await;
+        set_all(dbtx, snapshot.action_counts).await;
+        set_all(dbtx, snapshot.balance_changes).await;
+        if let Some(seq) = snapshot.balance_change_seq {
+            db::set(dbtx, &db::BalanceChangeSeqKey, &seq).await;
+        }
+        set_all(dbtx, snapshot.governance_votes).await;
+        if let Some(tvl_cap) = snapshot.tvl_cap {
+            db::set(dbtx, &db::TvlCapKey, &tvl_cap).await;
+        }
+        if let Some(total_balance) = snapshot.total_balance {
+            db::set(dbtx, &db::TotalBalanceKey, &total_balance).await;
+        }
+        set_all(dbtx, snapshot.frozen_accounts).await;
+        if let Some(collected_fees) = snapshot.collected_fees {
+            db::set(dbtx, &db::CollectedFeesKey, &collected_fees).await;
+        }
+        set_all(dbtx, snapshot.feerate_caps).await;
+        if let Some(policy) = snapshot.degraded_quorum_policy {
+            db::set(dbtx, &db::DegradedQuorumPolicyKey, &policy).await;
+        }
+        set_all(dbtx, snapshot.peers_last_seen).await;
+        set_all(dbtx, snapshot.provider_locked).await;
+        set_all(dbtx, snapshot.account_epochs).await;
+        if let Some(epoch_id) = snapshot.pool_closed {
+            db::set(dbtx, &db::PoolClosedKey, &epoch_id).await;
+        }
+        set_all(dbtx, snapshot.epoch_fees).await;
+        set_all(dbtx, snapshot.seeker_locked_since).await;
+        if let Some(digest) = snapshot.settlement_digest {
+            db::set(dbtx, &db::SettlementDigestKey, &digest).await;
+        }
+        set_all(dbtx, snapshot.peer_settlement_digests).await;
+        set_all(dbtx, snapshot.signed_actions).await;
+        if let Some(sessions) = snapshot.consensus_sessions {
+            db::set(dbtx, &db::ConsensusSessionsKey, &sessions).await;
         }
     }
 }
 
-impl std::error::Error for StabilityPoolError {}
+async fn set_all<K, V>(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    entries: Vec<(K, V)>,
+) where
+    K: Encodable + Decodable + fmt::Debug + DatabaseRecord<Value = V>,
+{
+    for (key, value) in entries {
+        db::set(dbtx, &key, &value).await;
+    }
+}
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
-pub enum WithdrawalError {
-    UnavaliableFunds {
-        amount: fedimint_core::Amount,
-        avaliable: fedimint_core::Amount,
-    },
+/// All records of the module, with one field per [`db::DbKeyPrefix`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Snapshot {
+    pub accounts: Vec<(db::AccountBalanceKey, AccountBalance)>,
+    pub deposit_outcomes: Vec<(db::DepositOutcomeKey, secp256k1_zkp::XOnlyPublicKey)>,
+    pub epoch_outcomes: Vec<(db::EpochOutcomeKey, EpochOutcome)>,
+    pub last_epoch_ended: Option<EpochId>,
+    pub last_epoch_settled: Option<EpochId>,
+    pub epoch_ends: Vec<(db::EpochEndKey, EpochEnd)>,
+    pub staged_actions: Vec<(db::ActionStagedKey, ActionStaged)>,
+    pub force_settles: Vec<(db::ForceSettleKey, ForceSettle)>,
+    pub action_counts: Vec<(db::ActionCountKey, ActionCount)>,
+    pub balance_changes: Vec<(db::BalanceChangeKey, BalanceChange)>,
+    pub balance_change_seq: Option<u64>,
+    pub governance_votes: Vec<(db::GovernanceVoteKey, GovernanceAction)>,
+    pub tvl_cap: Option<u64>,
+    pub total_balance: Option<u64>,
+    pub frozen_accounts: Vec<(db::FrozenAccountKey, EpochId)>,
+    pub collected_fees: Option<u64>,
+    pub feerate_caps: Vec<(db::FeerateCapKey, u64)>,
+    pub degraded_quorum_policy: Option<DegradedQuorumPolicy>,
+    pub peers_last_seen: Vec<(db::PeerLastSeenKey, u64)>,
+    pub provider_locked: Vec<(db::ProviderLockedKey, u64)>,
+    pub account_epochs: Vec<(db::AccountEpochKey, LockedBalance)>,
+    pub pool_closed: Option<EpochId>,
+    pub epoch_fees: Vec<(db::EpochFeesKey, EpochFees)>,
+    pub seeker_locked_since: Vec<(db::SeekerLockedSinceKey, EpochId)>,
+    pub settlement_digest: Option<SettlementDigest>,
+    pub peer_settlement_digests: Vec<(db::PeerSettlementDigestKey, SettlementDigest)>,
+    pub signed_actions: Vec<(db::ActionSignedKey, ActionProposed)>,
+    pub consensus_sessions: Option<u64>,
 }
 
-impl std::fmt::Display for WithdrawalError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            WithdrawalError::UnavaliableFunds { amount, avaliable } => write!(
-                f,
-                "attempted to withdraw {} when only {} was avaliable",
-                amount, avaliable
-            ),
+/// What the module owes its users, for solvency monitoring.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Solvency {
+    pub total_unlocked_msat: u64,
+    pub total_locked_msat: u64,
+    /// Withdrawal fees held by the module
+    pub collected_fees_msat: u64,
+    /// Account balances plus collected fees
+    pub total_liabilities_msat: u64,
+    /// What the module contributes to the federation audit
+    pub audit_msat: i64,
+    /// The running total balance, if one is stored
+    pub tracked_total_balance_msat: Option<u64>,
+    /// Whether the running total balance matches the account balances, see
+    /// [`GovernanceAction::ReconstructDerived`] to fix it
+    pub total_balance_in_sync: bool,
+}
+
+/// Validate `withdrawal`, of which the module keeps `fee`.
+async fn validate_withdrawal(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    withdrawal: &PoolInput,
+    fee: fedimint_core::Amount,
+) -> Result<InputMeta, PoolError> {
+    if governance::is_frozen(dbtx, withdrawal.account).await {
+        return Err(StabilityPoolError::AccountFrozen {
+            account: withdrawal.account,
         }
+        .into());
     }
-}
 
-impl std::error::Error for WithdrawalError {}
+    if withdrawal.amount < fee {
+        return Err(WithdrawalError::BelowFee {
+            amount: withdrawal.amount,
+            fee,
+        }
+        .into());
+    }
+
+    let avaliable = withdrawable_balance(dbtx, withdrawal.account).await;
+
+    if avaliable < withdrawal.amount {
+        return Err(WithdrawalError::UnavaliableFunds {
+            amount: withdrawal.amount,
+            avaliable,
+        }
+        .into());
+    }
+
+    Ok(InputMeta {
+        amount: TransactionItemAmount {
+            amount: withdrawal.amount - fee,
+            // the fee is kept by the module rather than by the transaction
+            fee: fedimint_core::Amount::ZERO,
+        },
+        puk_keys: [withdrawal.account].into(),
+    })
+}
+
+/// Unlocked balance of `account` that is not committed to a staged seeker lock.
+/// Provider bids are not deducted as they are capped by the balance at
+/// settlement.
+async fn withdrawable_balance(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    account: secp256k1_zkp::XOnlyPublicKey,
+) -> fedimint_core::Amount {
+    let unlocked = dbtx
+        .get_value(&db::AccountBalanceKey(account))
+        .await
+        .map(|acc| acc.unlocked)
+        .unwrap_or(fedimint_core::Amount::ZERO);
+
+    let staged_lock = match dbtx.get_value(&db::ActionStagedKey(account)).await {
+        Some(ActionStaged::Seeker(Action {
+            body: SeekerAction::Lock { amount },
+            ..
+        })) => amount,
+        _ => fedimint_core::Amount::ZERO,
+    };
+
+    fedimint_core::msats(unlocked.msats.saturating_sub(staged_lock.msats))
+}
+
+/// Debit an already validated withdrawal from the account, collecting `fee`.
+async fn apply_withdrawal(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &EpochConfig,
+    withdrawal: &PoolInput,
+    fee: fedimint_core::Amount,
+) {
+    let total = total_balance(dbtx).await;
+    let previous = dbtx
+        .get_value(&db::AccountBalanceKey(withdrawal.account))
+        .await;
+    let mut account = previous.clone().unwrap_or_default();
+
+    account.unlocked.msats = account
+        .unlocked
+        .msats
+        .checked_sub(withdrawal.amount.msats)
+        .expect("withdrawal amount should already be checked");
+
+    set_account_balance(dbtx, withdrawal.account, previous, account).await;
+    db::set(
+        dbtx,
+        &db::TotalBalanceKey,
+        &(total - withdrawal.amount.msats),
+    )
+    .await;
+
+    let change = BalanceChange {
+        account: withdrawal.account,
+        reason: BalanceChangeReason::Withdrawal,
+        epoch_id: epoch::EpochState::from_db(dbtx).await.current_epoch_id(),
+        credit: fedimint_core::Amount::ZERO,
+        debit: withdrawal.amount,
+    };
+    log_balance_change(dbtx, config.balance_change_retention, change).await;
+
+    let collected = db::get(dbtx, &db::CollectedFeesKey).await.unwrap_or(0);
+    db::set(dbtx, &db::CollectedFeesKey, &(collected + fee.msats)).await;
+}
+
+/// Credit an already validated deposit to the account.
+///
+/// Overflow is checked again rather than trusted to validation, so that a
+/// deposit the balances can not hold is rejected without writing anything
+/// instead of halting consensus.
+async fn apply_deposit(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    config: &EpochConfig,
+    deposit: &PoolOutput,
+) -> Result<(), PoolError> {
+    let previous = dbtx
+        .get_value(&db::AccountBalanceKey(deposit.account))
+        .await;
+    let mut account = previous.clone().unwrap_or_default();
+    if !account.can_add_amount(deposit.amount) {
+        return Err(StabilityPoolError::DepositTooLarge.into());
+    }
+    let total = total_balance(dbtx)
+        .await
+        .checked_add(deposit.amount.msats)
+        .ok_or(StabilityPoolError::DepositTooLarge)?;
+    account.unlocked = account.unlocked + deposit.amount;
+
+    set_account_balance(dbtx, deposit.account, previous, account).await;
+    db::set(dbtx, &db::TotalBalanceKey, &total).await;
+
+    let change = BalanceChange {
+        account: deposit.account,
+        reason: BalanceChangeReason::Deposit,
+        epoch_id: epoch::EpochState::from_db(dbtx).await.current_epoch_id(),
+        credit: deposit.amount,
+        debit: fedimint_core::Amount::ZERO,
+    };
+    log_balance_change(dbtx, config.balance_change_retention, change).await;
+    Ok(())
+}
+
+/// Store the new `balance` of `account`, skipping the write if it equals the
+/// `previous` one.
+///
+/// Deposits and withdrawals read, modify and write the balance within the
+/// module transaction of the consensus epoch. Inputs and outputs are applied
+/// one after another in consensus order on that same transaction, so each one
+/// sees the writes of those before it and several deposits to one account in
+/// a batch accumulate. Validation runs against the same state, so a withdrawal
+/// can spend a deposit applied earlier in the batch.
+async fn set_account_balance(
+    dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+    account: secp256k1_zkp::XOnlyPublicKey,
+    previous: Option<AccountBalance>,
+    balance: AccountBalance,
+) {
+    if previous.unwrap_or_default() == balance {
+        return;
+    }
+    dbtx.insert_entry(&db::AccountBalanceKey(account), &balance)
+        .await;
+}
+
+/// All errors the module can return from transaction validation.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum PoolError {
+    Withdrawal(WithdrawalError),
+    StabilityPool(StabilityPoolError),
+}
+
+impl std::fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Withdrawal(e) => write!(f, "withdrawal error: {}", e),
+            Self::StabilityPool(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for PoolError {}
+
+impl From<WithdrawalError> for PoolError {
+    fn from(value: WithdrawalError) -> Self {
+        Self::Withdrawal(value)
+    }
+}
+
+impl From<StabilityPoolError> for PoolError {
+    fn from(value: StabilityPoolError) -> Self {
+        Self::StabilityPool(value)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum StabilityPoolError {
+    SomethingDummyWentWrong,
+    DepositTooLarge,
+    TvlCapExceeded {
+        max_tvl: fedimint_core::Amount,
+    },
+    DepositTooSmall {
+        min_deposit: fedimint_core::Amount,
+    },
+    AccountFrozen {
+        account: secp256k1_zkp::XOnlyPublicKey,
+    },
+    PoolClosed,
+    SettlementInProgress,
+    AccountNotRegistered {
+        account: secp256k1_zkp::XOnlyPublicKey,
+    },
+    DepositAlreadyApplied {
+        outpoint: OutPoint,
+    },
+    InvalidDepositIntent {
+        account: secp256k1_zkp::XOnlyPublicKey,
+    },
+}
+
+impl std::fmt::Display for StabilityPoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SomethingDummyWentWrong => write!(f, "placeholder error"),
+            Self::DepositTooLarge => write!(f, "that deposit pukking big"),
+            Self::TvlCapExceeded { max_tvl } => {
+                write!(f, "deposit would exceed the pool's cap of {}", max_tvl)
+            }
+            Self::DepositTooSmall { min_deposit } => {
+                write!(f, "deposit is below the minimum of {}", min_deposit)
+            }
+            Self::AccountFrozen { account } => {
+                write!(f, "account {} is frozen by the federation", account)
+            }
+            Self::PoolClosed => write!(f, "the pool is closed"),
+            Self::SettlementInProgress => {
+                write!(f, "an epoch is being settled, try again shortly")
+            }
+            Self::AccountNotRegistered { account } => {
+                write!(
+                    f,
+                    "account {} has to be registered before deposits",
+                    account
+                )
+            }
+            Self::DepositAlreadyApplied { outpoint } => {
+                write!(f, "a deposit was already applied for {}", outpoint)
+            }
+            Self::InvalidDepositIntent { account } => {
+                write!(f, "deposit intent is not signed by account {}", account)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StabilityPoolError {}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum WithdrawalError {
+    UnavaliableFunds {
+        amount: fedimint_core::Amount,
+        avaliable: fedimint_core::Amount,
+    },
+    BelowFee {
+        amount: fedimint_core::Amount,
+        fee: fedimint_core::Amount,
+    },
+}
+
+impl std::fmt::Display for WithdrawalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WithdrawalError::UnavaliableFunds { amount, avaliable } => write!(
+                f,
+                "attempted to withdraw {} when only {} was avaliable",
+                amount, avaliable
+            ),
+            WithdrawalError::BelowFee { amount, fee } => write!(
+                f,
+                "attempted to withdraw {} which does not cover the fee of {}",
+                amount, fee
+            ),
+        }
+    }
+}
+
+impl std::error::Error for WithdrawalError {}
+
+#[cfg(test)]
+mod tests {
+    use fedimint_core::db::mem_impl::MemDatabase;
+
+    use super::*;
+
+    fn pool_config(max_tvl_msat: Option<u64>) -> PoolConfig {
+        PoolConfig {
+            private: PoolConfigPrivate {
+                peer_id: PeerId::from(0),
+                backoff: BackOffConfig::default(),
+                max_actions_per_proposal: None,
+            },
+            consensus: PoolConfigConsensus {
+                max_tvl_msat,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn random_account() -> secp256k1_zkp::XOnlyPublicKey {
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        let (_, public_key) = secp.generate_keypair(&mut rand::thread_rng());
+        public_key.x_only_public_key().0
+    }
+
+    #[test]
+    fn pool_error_wraps_module_errors() {
+        let withdrawal = WithdrawalError::UnavaliableFunds {
+            amount: fedimint_core::msats(2),
+            avaliable: fedimint_core::msats(1),
+        };
+        assert_eq!(
+            PoolError::from(withdrawal.clone()),
+            PoolError::Withdrawal(withdrawal)
+        );
+
+        for error in [
+            StabilityPoolError::SomethingDummyWentWrong,
+            StabilityPoolError::DepositTooLarge,
+        ] {
+            assert_eq!(
+                PoolError::from(error.clone()).to_string(),
+                error.to_string()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn deposit_and_withdrawal_log_balance_changes() {
+        test_dbtx!(dbtx);
+        let config = pool_config(None).consensus.epoch;
+        let account = random_account();
+        db::set(&mut dbtx, &db::LastEpochEndedKey, &EpochId(2)).await;
+
+        let deposit = PoolOutput {
+            account,
+            amount: fedimint_core::msats(1_000),
+            intent: None,
+        };
+        apply_deposit(&mut dbtx, &config, &deposit).await.unwrap();
+        let withdrawal = PoolInput {
+            account,
+            amount: fedimint_core::msats(400),
+        };
+        apply_withdrawal(&mut dbtx, &config, &withdrawal, fedimint_core::Amount::ZERO).await;
+
+        let changes = stabilitypool::balance_changes(&mut dbtx, None, None, 10).await;
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    0,
+                    BalanceChange {
+                        account,
+                        reason: BalanceChangeReason::Deposit,
+                        epoch_id: EpochId(3),
+                        credit: fedimint_core::msats(1_000),
+                        debit: fedimint_core::Amount::ZERO,
+                    }
+                ),
+                (
+                    1,
+                    BalanceChange {
+                        account,
+                        reason: BalanceChangeReason::Withdrawal,
+                        epoch_id: EpochId(3),
+                        credit: fedimint_core::Amount::ZERO,
+                        debit: fedimint_core::msats(400),
+                    }
+                ),
+            ]
+        );
+        assert_eq!(changes[1].1.delta_msats(), -400);
+    }
+
+    #[tokio::test]
+    async fn staged_lock_reduces_withdrawable_balance() {
+        test_dbtx!(dbtx);
+        let account = random_account();
+
+        db::set(
+            &mut dbtx,
+            &db::AccountBalanceKey(account),
+            &stabilitypool::AccountBalance {
+                unlocked: fedimint_core::msats(1_000),
+                locked: stabilitypool::LockedBalance::None,
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(
+            withdrawable_balance(&mut dbtx, account).await,
+            fedimint_core::msats(1_000)
+        );
+
+        let stage = |body| {
+            ActionStaged::Seeker(Action {
+                epoch_id: EpochId(1),
+                sequence: 0,
+                account_id: account,
+                body,
+            })
+        };
+
+        let lock = stage(SeekerAction::Lock {
+            amount: fedimint_core::msats(700),
+        });
+        db::set(&mut dbtx, &db::ActionStagedKey(account), &lock).await;
+        assert_eq!(
+            withdrawable_balance(&mut dbtx, account).await,
+            fedimint_core::msats(300)
+        );
+
+        // a lock larger than the balance leaves nothing to withdraw
+        let lock = stage(SeekerAction::Lock {
+            amount: fedimint_core::msats(1_500),
+        });
+        db::set(&mut dbtx, &db::ActionStagedKey(account), &lock).await;
+        assert_eq!(
+            withdrawable_balance(&mut dbtx, account).await,
+            fedimint_core::Amount::ZERO
+        );
+
+        // unlocks don't commit any funds
+        let unlock = stage(SeekerAction::Unlock {
+            amount: fedimint_core::msats(500),
+        });
+        db::set(&mut dbtx, &db::ActionStagedKey(account), &unlock).await;
+        assert_eq!(
+            withdrawable_balance(&mut dbtx, account).await,
+            fedimint_core::msats(1_000)
+        );
+    }
+
+    #[tokio::test]
+    async fn replayed_deposit_outpoints_are_rejected() {
+        use fedimint_core::BitcoinHash;
+
+        test_dbtx!(dbtx);
+        let pool = StabilityPool::new(pool_config(None));
+        let (first, second) = (random_account(), random_account());
+
+        let deposit = |account| PoolOutput {
+            account,
+            amount: fedimint_core::msats(1_000),
+            intent: None,
+        };
+        let outpoint = OutPoint {
+            txid: fedimint_core::TransactionId::all_zeros(),
+            out_idx: 0,
+        };
+
+        pool.apply_output(&mut dbtx, &deposit(first), outpoint)
+            .await
+            .unwrap();
+        for account in [first, second] {
+            assert!(pool
+                .apply_output(&mut dbtx, &deposit(account), outpoint)
+                .await
+                .is_err());
+        }
+
+        assert_eq!(total_balance(&mut dbtx).await, 1_000);
+        assert_eq!(
+            db::get(&mut dbtx, &db::AccountBalanceKey(second)).await,
+            None
+        );
+        assert_eq!(
+            db::get(&mut dbtx, &db::DepositOutcomeKey(outpoint)).await,
+            Some(first)
+        );
+    }
+
+    #[tokio::test]
+    async fn overflowing_deposits_in_a_batch_are_rejected() {
+        use fedimint_core::BitcoinHash;
+
+        test_dbtx!(dbtx);
+        let pool = StabilityPool::new(pool_config(None));
+        let (first, second) = (random_account(), random_account());
+
+        let deposit = |account, msats| PoolOutput {
+            account,
+            amount: fedimint_core::msats(msats),
+            intent: None,
+        };
+        let outpoint = |out_idx| OutPoint {
+            txid: fedimint_core::TransactionId::all_zeros(),
+            out_idx,
+        };
+
+        pool.apply_output(&mut dbtx, &deposit(first, u64::MAX - 10), outpoint(0))
+            .await
+            .unwrap();
+        assert!(pool
+            .apply_output(&mut dbtx, &deposit(first, 20), outpoint(1))
+            .await
+            .is_err());
+        // fits the account, but not the total of all balances, which only
+        // applying the deposit checks
+        assert!(pool
+            .validate_output(&mut dbtx, &deposit(second, 20))
+            .await
+            .is_ok());
+        assert!(pool
+            .apply_output(&mut dbtx, &deposit(second, 20), outpoint(2))
+            .await
+            .is_err());
+
+        assert_eq!(total_balance(&mut dbtx).await, u64::MAX - 10);
+        assert_eq!(
+            db::get(&mut dbtx, &db::AccountBalanceKey(second)).await,
+            None
+        );
+        for out_idx in [1, 2] {
+            assert_eq!(
+                db::get(&mut dbtx, &db::DepositOutcomeKey(outpoint(out_idx))).await,
+                None
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn deposits_are_capped_at_max_tvl() {
+        test_dbtx!(dbtx);
+        let pool = StabilityPool::new(pool_config(Some(1_000)));
+        let (first, second) = (random_account(), random_account());
+
+        let deposit = |account, msats| PoolOutput {
+            account,
+            amount: fedimint_core::msats(msats),
+            intent: None,
+        };
+
+        apply_deposit(&mut dbtx, pool.epoch_config(), &deposit(first, 600))
+            .await
+            .unwrap();
+        assert_eq!(total_balance(&mut dbtx).await, 600);
+
+        // up to the cap is accepted, a single msat more is not
+        assert!(pool
+            .validate_output(&mut dbtx, &deposit(second, 401))
+            .await
+            .is_err());
+        assert!(pool
+            .validate_output(&mut dbtx, &deposit(second, 400))
+            .await
+            .is_ok());
+        apply_deposit(&mut dbtx, pool.epoch_config(), &deposit(second, 400))
+            .await
+            .unwrap();
+        assert_eq!(total_balance(&mut dbtx).await, 1_000);
+
+        // withdrawals free up room under the cap
+        let withdrawal = PoolInput {
+            account: first,
+            amount: fedimint_core::msats(100),
+        };
+        apply_withdrawal(
+            &mut dbtx,
+            pool.epoch_config(),
+            &withdrawal,
+            fedimint_core::Amount::ZERO,
+        )
+        .await;
+        assert_eq!(total_balance(&mut dbtx).await, 900);
+        assert!(pool
+            .validate_output(&mut dbtx, &deposit(second, 100))
+            .await
+            .is_ok());
+
+        // a cap raised through governance takes precedence over the config
+        db::set(&mut dbtx, &db::TvlCapKey, &2_000).await;
+        assert!(pool
+            .validate_output(&mut dbtx, &deposit(second, 1_100))
+            .await
+            .is_ok());
+        assert!(pool
+            .validate_output(&mut dbtx, &deposit(second, 1_101))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn deposits_below_minimum_are_rejected() {
+        test_dbtx!(dbtx);
+        let mut config = pool_config(None);
+        config.consensus.min_deposit_msat = 1_000;
+        let pool = StabilityPool::new(config);
+        let account = random_account();
+
+        let deposit = |msats| PoolOutput {
+            account,
+            amount: fedimint_core::msats(msats),
+            intent: None,
+        };
+        assert!(pool
+            .validate_output(&mut dbtx, &deposit(999))
+            .await
+            .is_err());
+        assert!(pool
+            .validate_output(&mut dbtx, &deposit(1_000))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn deposits_wait_for_settlement() {
+        test_dbtx!(dbtx);
+        let pool = StabilityPool::new(pool_config(None));
+        let deposit = PoolOutput {
+            account: random_account(),
+            amount: fedimint_core::msats(1_000),
+            intent: None,
+        };
+
+        // epoch 0 ended and waits for its settlement price, which does not
+        // hold up deposits
+        db::set(&mut dbtx, &db::LastEpochEndedKey, &EpochId(0)).await;
+        assert!(pool.validate_output(&mut dbtx, &deposit).await.is_ok());
+
+        let settling = pool.settlement_in_progress.begin();
+        assert!(pool.validate_output(&mut dbtx, &deposit).await.is_err());
+
+        drop(settling);
+        assert!(pool.validate_output(&mut dbtx, &deposit).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn deposits_create_accounts_by_default() {
+        use fedimint_core::BitcoinHash;
+
+        test_dbtx!(dbtx);
+        let pool = StabilityPool::new(pool_config(None));
+        let deposit = PoolOutput {
+            account: random_account(),
+            amount: fedimint_core::msats(1_000),
+            intent: None,
+        };
+
+        let outpoint = OutPoint {
+            txid: fedimint_core::TransactionId::all_zeros(),
+            out_idx: 0,
+        };
+
+        pool.apply_output(&mut dbtx, &deposit, outpoint)
+            .await
+            .unwrap();
+        assert_eq!(
+            db::get(&mut dbtx, &db::AccountBalanceKey(deposit.account))
+                .await
+                .map(|account| account.unlocked),
+            Some(fedimint_core::msats(1_000))
+        );
+    }
+
+    #[tokio::test]
+    async fn deposits_need_registration_without_autocreation() {
+        test_dbtx!(dbtx);
+        let mut config = pool_config(None);
+        config.consensus.allow_account_autocreation = false;
+        let pool = StabilityPool::new(config);
+
+        let deposit = PoolOutput {
+            account: random_account(),
+            amount: fedimint_core::msats(1_000),
+            intent: None,
+        };
+        assert!(pool.validate_output(&mut dbtx, &deposit).await.is_err());
+
+        // operators register the account
+        let register = GovernanceAction::RegisterAccount {
+            account: deposit.account,
+        };
+        let items = (0..pool.epoch_config().price_threshold)
+            .map(|peer| {
+                (
+                    PeerId::from(peer as u16),
+                    PoolConsensusItem::Governance(register),
+                )
+            })
+            .collect();
+        pool.begin_consensus_epoch(&mut dbtx, items).await;
+
+        assert!(pool.validate_output(&mut dbtx, &deposit).await.is_ok());
+        // registering stages nothing
+        assert_eq!(
+            db::get(&mut dbtx, &db::ActionStagedKey(deposit.account)).await,
+            None
+        );
+    }
+
+    #[test]
+    fn price_threshold_must_be_positive() {
+        let mut config = pool_config(None);
+        config.consensus.epoch.price_threshold = 0;
+        assert!(config.validate_config(&PeerId::from(0)).is_err());
+    }
+
+    #[test]
+    fn oracle_quorum_above_number_of_peers_is_invalid() {
+        let mut config = pool_config(None);
+        config.consensus.epoch.num_peers = Some(4);
+        config.consensus.epoch.oracle_quorum = Some(4);
+        assert!(config.validate_config(&PeerId::from(0)).is_ok());
+
+        config.consensus.epoch.oracle_quorum = Some(5);
+        assert!(config.validate_config(&PeerId::from(0)).is_err());
+    }
+
+    #[test]
+    fn min_deposit_above_tvl_cap_is_invalid() {
+        let mut config = pool_config(Some(1_000));
+        config.consensus.min_deposit_msat = 1_000;
+        assert!(config.validate_config(&PeerId::from(0)).is_ok());
+
+        config.consensus.min_deposit_msat = 1_001;
+        assert!(config.validate_config(&PeerId::from(0)).is_err());
+    }
+
+    #[derive(Debug)]
+    struct FailingOracle;
+
+    #[async_trait]
+    impl OracleClient for FailingOracle {
+        async fn price_at_time(&self, _datetime: time::OffsetDateTime) -> anyhow::Result<u64> {
+            Err(anyhow::anyhow!("oracle is down"))
+        }
+    }
+
+    #[tokio::test]
+    async fn migration_tracks_staged_sequence_on_accounts() {
+        use stabilitypool::AccountBalanceV1;
+
+        let db = Database::new(MemDatabase::new(), Default::default());
+        let mut dbtx = db.begin_transaction().await;
+        let (idle, active) = (random_account(), random_account());
+        let v1 = AccountBalanceV1 {
+            unlocked: fedimint_core::msats(1_000),
+            locked: LockedBalance::Seeker(fedimint_core::msats(500)),
+        };
+        for account in [idle, active] {
+            dbtx.insert_entry(&db::AccountBalanceV1Key(account), &v1)
+                .await;
+        }
+        let staged = Action {
+            epoch_id: EpochId(3),
+            sequence: 7,
+            account_id: active,
+            body: SeekerAction::Lock {
+                amount: fedimint_core::msats(100),
+            },
+        };
+        dbtx.insert_entry(&db::ActionStagedKey(active), &ActionStaged::Seeker(staged))
+            .await;
+
+        migrate_account_nonces(&mut dbtx).await.unwrap();
+
+        let migrated = |last_seq, last_epoch| AccountBalance {
+            unlocked: v1.unlocked,
+            locked: v1.locked,
+            last_seq,
+            last_epoch,
+        };
+        assert_eq!(
+            dbtx.get_value(&db::AccountBalanceKey(active)).await,
+            Some(migrated(7, EpochId(3)))
+        );
+        assert_eq!(
+            dbtx.get_value(&db::AccountBalanceKey(idle)).await,
+            Some(migrated(0, EpochId(0)))
+        );
+    }
+
+    #[tokio::test]
+    async fn migration_drops_epoch_end_votes() {
+        let db = Database::new(MemDatabase::new(), Default::default());
+        let mut dbtx = db.begin_transaction().await;
+        let vote = EpochEnd {
+            price: None,
+            epoch_id: EpochId(3),
+            time: 0,
+        };
+        dbtx.insert_entry(&db::EpochEndKey(PeerId::from(0)), &vote)
+            .await;
+        dbtx.insert_entry(&db::LastEpochEndedKey, &EpochId(2)).await;
+
+        migrate_epoch_end_votes(&mut dbtx).await.unwrap();
+
+        assert_eq!(
+            dbtx.get_value(&db::EpochEndKey(PeerId::from(0))).await,
+            None
+        );
+        assert_eq!(
+            dbtx.get_value(&db::LastEpochEndedKey).await,
+            Some(EpochId(2))
+        );
+    }
+
+    #[tokio::test]
+    async fn migration_computes_total_balance_from_accounts() {
+        let db = Database::new(MemDatabase::new(), Default::default());
+        let mut dbtx = db.begin_transaction().await;
+        let account = AccountBalance {
+            unlocked: fedimint_core::msats(300),
+            locked: LockedBalance::Seeker(fedimint_core::msats(200)),
+            ..Default::default()
+        };
+        for _ in 0..2 {
+            dbtx.insert_entry(&db::AccountBalanceKey(random_account()), &account)
+                .await;
+        }
+
+        migrate_total_balance(&mut dbtx).await.unwrap();
+
+        assert_eq!(dbtx.get_value(&db::TotalBalanceKey).await, Some(1_000));
+    }
+
+    #[tokio::test]
+    async fn migration_reads_epoch_outcomes_of_the_old_layout() {
+        use stabilitypool::epoch::{EpochFeerate, EpochOutcomeV1};
+
+        let db = Database::new(MemDatabase::new(), Default::default());
+        let mut dbtx = db.begin_transaction().await;
+        let v1 = EpochOutcomeV1 {
+            total_seeker_locked: 1_000,
+            total_provider_locked: 2_000,
+            settled_price: Some(20_000),
+            feerate: EpochFeerate::from_ppm_feerate(100),
+        };
+        dbtx.insert_entry(&db::EpochOutcomeV1Key(EpochId(3)), &v1)
+            .await;
+
+        migrate_epoch_outcomes(&mut dbtx).await.unwrap();
+
+        let migrated = dbtx
+            .get_value(&db::EpochOutcomeKey(EpochId(3)))
+            .await
+            .expect("outcome is migrated");
+        assert_eq!(migrated.total_seeker_locked, v1.total_seeker_locked);
+        assert_eq!(migrated.total_provider_locked, v1.total_provider_locked);
+        assert_eq!(migrated.settled_price, v1.settled_price);
+        assert_eq!(migrated.feerate, v1.feerate);
+        assert!(!migrated.forced);
+        assert_eq!((migrated.num_seekers, migrated.num_providers), (0, 0));
+        assert_eq!(migrated.settled_at, None);
+    }
+
+    #[tokio::test]
+    async fn migration_drops_peers_last_seen() {
+        let db = Database::new(MemDatabase::new(), Default::default());
+        let mut dbtx = db.begin_transaction().await;
+        dbtx.insert_entry(&db::PeerLastSeenKey(PeerId::from(0)), &7)
+            .await;
+
+        migrate_peer_last_seen(&mut dbtx).await.unwrap();
+
+        assert_eq!(
+            dbtx.get_value(&db::PeerLastSeenKey(PeerId::from(0))).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn consensus_proposal_wait_stops_on_shutdown() {
+        use futures::future::{select, Either};
+
+        test_dbtx!(dbtx);
+        let task_group = TaskGroup::new();
+        let pool = StabilityPool {
+            shutdown: Some(task_group.make_handle()),
+            ..StabilityPool::new(pool_config(None))
+        };
+        // no epoch is left to settle and nothing else is pending
+        db::set(&mut dbtx, &db::LastEpochSettledKey, &EpochId(u64::MAX)).await;
+
+        let wait = pool.await_consensus_proposal(&mut dbtx);
+        futures::pin_mut!(wait);
+        let timeout = fedimint_core::task::sleep(Duration::from_millis(100));
+        futures::pin_mut!(timeout);
+        assert!(matches!(
+            select(wait.as_mut(), timeout).await,
+            Either::Right(_)
+        ));
+
+        // shutting down interrupts the sleep between polls
+        task_group.shutdown().await;
+        let timeout = fedimint_core::task::sleep(Duration::from_secs(1));
+        futures::pin_mut!(timeout);
+        assert!(matches!(select(wait, timeout).await, Either::Left(_)));
+    }
+
+    #[tokio::test]
+    async fn mock_clock_ends_epoch_without_sleeping() {
+        use crate::fixtures::MockClock;
+
+        test_dbtx!(dbtx);
+        // epochs up to 3 are settled, the clock calls for ending the next one at 120
+        let clock = MockClock::new(time::OffsetDateTime::from_unix_timestamp(100).unwrap());
+        let pool = StabilityPool {
+            clock: Box::new(clock.clone()),
+            ..StabilityPool::new(pool_config(None))
+        };
+        db::set(&mut dbtx, &db::LastEpochEndedKey, &EpochId(3)).await;
+        db::set(&mut dbtx, &db::LastEpochSettledKey, &EpochId(3)).await;
+
+        let items = |proposal: ConsensusProposal<PoolConsensusItem>| match proposal {
+            ConsensusProposal::Contribute(items) => items,
+            _ => panic!("expected the module to contribute items"),
+        };
+
+        let now = pool.clock().now();
+        assert!(!epoch::can_propose(&mut dbtx, &pool.backoff, pool.epoch_config(), now).await);
+        assert!(items(pool.consensus_proposal(&mut dbtx).await).is_empty());
+
+        clock.advance(time::Duration::seconds(20));
+        let now = pool.clock().now();
+        assert!(epoch::can_propose(&mut dbtx, &pool.backoff, pool.epoch_config(), now).await);
+        assert_eq!(
+            items(pool.consensus_proposal(&mut dbtx).await),
+            vec![EpochEnd {
+                price: None,
+                epoch_id: EpochId(4),
+                time: 120,
+            }
+            .into()]
+        );
+    }
+
+    #[tokio::test]
+    async fn failing_oracle_does_not_block_actions() {
+        use bitcoin::hashes::Hash;
+        use bitcoin::secp256k1::{KeyPair, Secp256k1};
+        use stabilitypool::{ActionProposed, SignedAction};
+
+        test_dbtx!(dbtx);
+        let pool = StabilityPool {
+            oracle: Box::new(FailingOracle),
+            ..StabilityPool::new(pool_config(None))
+        };
+
+        let keypair = KeyPair::new(&Secp256k1::new(), &mut rand::thread_rng());
+        let action = Action {
+            epoch_id: EpochId(1),
+            sequence: 0,
+            account_id: keypair.x_only_public_key().0,
+            body: SeekerAction::Lock {
+                amount: fedimint_core::msats(1_000),
+            },
+        };
+        let hash = bitcoin::hashes::sha256::Hash::hash(&action.consensus_encode_to_vec().unwrap());
+        let signature = Secp256k1::signing_only().sign_schnorr_no_aux_rand(&hash.into(), &keypair);
+        let proposed = ActionProposed::Seeker(SignedAction { action, signature });
+        pool.proposed_db.insert(proposed.clone());
+
+        let items = |proposal: ConsensusProposal<PoolConsensusItem>| match proposal {
+            ConsensusProposal::Contribute(items) => items,
+            _ => panic!("expected the module to contribute items"),
+        };
+
+        // the epoch is ended without a price and the action is still proposed
+        let proposal = items(pool.consensus_proposal(&mut dbtx).await);
+        assert!(matches!(
+            proposal[0],
+            PoolConsensusItem::EpochEnd(EpochEnd {
+                price: None,
+                epoch_id: EpochId(0),
+                ..
+            })
+        ));
+        assert_eq!(proposal[1..], [proposed.clone().into()]);
+
+        // the oracle is backed off but actions keep being proposed
+        let proposal = items(pool.consensus_proposal(&mut dbtx).await);
+        assert_eq!(proposal, vec![proposed.into()]);
+        let now = time::OffsetDateTime::now_utc();
+        assert!(!epoch::can_propose(&mut dbtx, &pool.backoff, pool.epoch_config(), now).await);
+    }
+
+    #[test]
+    fn backoff_is_configured_from_private_config() {
+        let mut config = pool_config(None);
+        config.private.backoff = BackOffConfig {
+            base_delay: 5,
+            max_delay: 60,
+            multiplier: 4,
+            attempts_per_round: 2,
+            retry_delay_ms: 100,
+        };
+        let pool = StabilityPool::new(config.clone());
+        assert_eq!(pool.backoff.config, config.private.backoff);
+    }
+
+    #[tokio::test]
+    async fn frozen_accounts_cannot_deposit_or_withdraw() {
+        test_dbtx!(dbtx);
+        let pool = StabilityPool::new(pool_config(None));
+        let (frozen, unfrozen) = (random_account(), random_account());
+
+        for account in [frozen, unfrozen] {
+            let deposit = PoolOutput {
+                account,
+                amount: fedimint_core::msats(1_000),
+                intent: None,
+            };
+            apply_deposit(&mut dbtx, pool.epoch_config(), &deposit)
+                .await
+                .unwrap();
+        }
+        db::set(&mut dbtx, &db::FrozenAccountKey(frozen), &EpochId(0)).await;
+
+        for (account, is_frozen) in [(frozen, true), (unfrozen, false)] {
+            let deposit = PoolOutput {
+                account,
+                amount: fedimint_core::msats(1_000),
+                intent: None,
+            };
+            let withdrawal = PoolInput {
+                account,
+                amount: fedimint_core::msats(1_000),
+            };
+            assert_eq!(
+                pool.validate_output(&mut dbtx, &deposit).await.is_err(),
+                is_frozen
+            );
+            assert_eq!(
+                validate_withdrawal(&mut dbtx, &withdrawal, fedimint_core::Amount::ZERO)
+                    .await
+                    .err(),
+                is_frozen.then(|| PoolError::from(StabilityPoolError::AccountFrozen { account }))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn batched_deposits_accumulate() {
+        test_dbtx!(dbtx);
+        let pool = StabilityPool::new(pool_config(None));
+        let account = random_account();
+
+        // a zero deposit leaves no record behind
+        let deposit = |msats| PoolOutput {
+            account,
+            amount: fedimint_core::msats(msats),
+            intent: None,
+        };
+        apply_deposit(&mut dbtx, pool.epoch_config(), &deposit(0))
+            .await
+            .unwrap();
+        assert_eq!(dbtx.get_value(&db::AccountBalanceKey(account)).await, None);
+
+        for msats in [300, 200, 500] {
+            apply_deposit(&mut dbtx, pool.epoch_config(), &deposit(msats))
+                .await
+                .unwrap();
+        }
+
+        // a withdrawal in the same batch sees the earlier deposits
+        let withdrawal = PoolInput {
+            account,
+            amount: fedimint_core::msats(1_000),
+        };
+        assert!(
+            validate_withdrawal(&mut dbtx, &withdrawal, fedimint_core::Amount::ZERO)
+                .await
+                .is_ok()
+        );
+        apply_withdrawal(
+            &mut dbtx,
+            pool.epoch_config(),
+            &withdrawal,
+            fedimint_core::Amount::ZERO,
+        )
+        .await;
+        apply_deposit(&mut dbtx, pool.epoch_config(), &deposit(50))
+            .await
+            .unwrap();
+
+        let balance = dbtx.get_value(&db::AccountBalanceKey(account)).await;
+        assert_eq!(balance.map(|b| b.unlocked), Some(fedimint_core::msats(50)));
+        assert_eq!(total_balance(&mut dbtx).await, 50);
+    }
+
+    #[tokio::test]
+    async fn solvency_reports_liabilities() {
+        use stabilitypool::LockedBalance;
+
+        test_dbtx!(dbtx);
+
+        for (unlocked, locked) in [
+            (1_000, LockedBalance::None),
+            (200, LockedBalance::Seeker(fedimint_core::msats(300))),
+            (0, LockedBalance::Provider(fedimint_core::msats(400))),
+        ] {
+            let balance = AccountBalance {
+                unlocked: fedimint_core::msats(unlocked),
+                locked,
+                ..Default::default()
+            };
+            db::set(
+                &mut dbtx,
+                &db::AccountBalanceKey(random_account()),
+                &balance,
+            )
+            .await;
+        }
+        db::set(&mut dbtx, &db::CollectedFeesKey, &100).await;
+        db::set(&mut dbtx, &db::TotalBalanceKey, &1_900).await;
+
+        let solvency = StabilityPool::solvency(&mut dbtx).await;
+        assert_eq!(
+            solvency,
+            Solvency {
+                total_unlocked_msat: 1_200,
+                total_locked_msat: 700,
+                collected_fees_msat: 100,
+                total_liabilities_msat: 2_000,
+                audit_msat: -2_000,
+                tracked_total_balance_msat: Some(1_900),
+                total_balance_in_sync: true,
+            }
+        );
+
+        db::set(&mut dbtx, &db::TotalBalanceKey, &42).await;
+        assert!(
+            !StabilityPool::solvency(&mut dbtx)
+                .await
+                .total_balance_in_sync
+        );
+    }
+
+    #[tokio::test]
+    async fn withdrawal_fees_reconcile_in_audit() {
+        test_dbtx!(dbtx);
+        let mut config = pool_config(None);
+        config.consensus.withdrawal_fee_msat = 10;
+        let pool = StabilityPool::new(config);
+        let account = random_account();
+        let fee = fedimint_core::msats(10);
+
+        let deposit = PoolOutput {
+            account,
+            amount: fedimint_core::msats(1_000),
+            intent: None,
+        };
+        apply_deposit(&mut dbtx, pool.epoch_config(), &deposit)
+            .await
+            .unwrap();
+
+        let withdrawal = |msats| PoolInput {
+            account,
+            amount: fedimint_core::msats(msats),
+        };
+        assert_eq!(
+            validate_withdrawal(&mut dbtx, &withdrawal(9), fee)
+                .await
+                .err(),
+            Some(PoolError::from(WithdrawalError::BelowFee {
+                amount: fedimint_core::msats(9),
+                fee,
+            }))
+        );
+
+        let mut audit_before = Audit::default();
+        pool.audit(&mut dbtx, &mut audit_before).await;
+
+        let meta = validate_withdrawal(&mut dbtx, &withdrawal(400), fee)
+            .await
+            .unwrap();
+        assert_eq!(meta.amount.amount, fedimint_core::msats(390));
+        apply_withdrawal(&mut dbtx, pool.epoch_config(), &withdrawal(400), fee).await;
+        assert_eq!(db::get(&mut dbtx, &db::CollectedFeesKey).await, Some(10));
+
+        // the module owes what was deposited minus what the withdrawal paid out
+        let mut audit = Audit::default();
+        pool.audit(&mut dbtx, &mut audit).await;
+        assert_eq!(audit.sum().milli_sat, -(1_000 - 390));
+
+        // the account is debited the full amount, which the issued ecash and
+        // the collected fee account for exactly
+        let balance = db::get(&mut dbtx, &db::AccountBalanceKey(account))
+            .await
+            .unwrap()
+            .total_balance()
+            .unwrap();
+        let debit = -((1_000 - balance.msats) as i64);
+        let issued = meta.amount.amount.msats as i64;
+        let collected = db::get(&mut dbtx, &db::CollectedFeesKey).await.unwrap() as i64;
+        assert_eq!(debit + issued + collected, 0);
+        // so counting the ecash, the federation owes what it did before
+        assert_eq!(audit.sum().milli_sat - issued, audit_before.sum().milli_sat);
+    }
+
+    /// Feed an `EpochEnd` vote for `epoch_id` through consensus from each of
+    /// the given `(peer, price)` pairs, as the federation would.
+    async fn submit_epoch_ends(
+        pool: &StabilityPool,
+        dbtx: &mut ModuleDatabaseTransaction<'_, ModuleInstanceId>,
+        epoch_id: EpochId,
+        votes: &[(u16, u64)],
+    ) {
+        let time = pool
+            .epoch_config()
+            .settlement_time(epoch_id)
+            .unix_timestamp() as u64;
+        let items = votes
+            .iter()
+            .map(|&(peer, price)| {
+                let epoch_end = EpochEnd {
+                    epoch_id,
+                    price: Some(price),
+                    time,
+                };
+                (PeerId::from(peer), PoolConsensusItem::EpochEnd(epoch_end))
+            })
+            .collect();
+        pool.begin_consensus_epoch(dbtx, items).await;
+    }
+
+    #[tokio::test]
+    async fn quorum_unreachable_from_the_start_degrades() {
+        test_dbtx!(dbtx);
+        let mut config = pool_config(None);
+        config.consensus.epoch.price_threshold = 3;
+        config.consensus.epoch.num_peers = Some(4);
+        let pool = StabilityPool::new(config);
+        let policy = DegradedQuorumPolicy {
+            offline_sessions: 2,
+            min_quorum: 2,
+        };
+        db::set(&mut dbtx, &db::DegradedQuorumPolicyKey, &policy).await;
+
+        // peers 2 and 3 never come online, so no epoch ends and epoch ids
+        // stay put while peers 0 and 1 keep proposing their votes
+        for _ in 0..2 {
+            submit_epoch_ends(
+                &pool,
+                &mut dbtx,
+                EpochId(0),
+                &[(0, 2_000_000), (1, 2_000_000)],
+            )
+            .await;
+            assert_eq!(db::get(&mut dbtx, &db::LastEpochEndedKey).await, None);
+        }
+
+        // once they have missed enough sessions the remaining votes suffice
+        submit_epoch_ends(
+            &pool,
+            &mut dbtx,
+            EpochId(0),
+            &[(0, 2_000_000), (1, 2_000_000)],
+        )
+        .await;
+        assert_eq!(
+            db::get(&mut dbtx, &db::LastEpochSettledKey).await,
+            Some(EpochId(0))
+        );
+        let outcome = db::get(&mut dbtx, &db::EpochOutcomeKey(EpochId(0)))
+            .await
+            .unwrap();
+        assert_eq!(outcome.degraded_quorum, Some(2));
+    }
+
+    #[tokio::test]
+    async fn settlement_requires_price_threshold_prices() {
+        test_dbtx!(dbtx);
+        let mut config = pool_config(None);
+        config.consensus.epoch.price_threshold = 3;
+        let pool = StabilityPool::new(config);
+
+        // two of four peers are not enough to end the epoch
+        submit_epoch_ends(
+            &pool,
+            &mut dbtx,
+            EpochId(0),
+            &[(0, 2_000_000), (1, 2_000_000)],
+        )
+        .await;
+        assert_eq!(db::get(&mut dbtx, &db::LastEpochEndedKey).await, None);
+
+        submit_epoch_ends(&pool, &mut dbtx, EpochId(0), &[(2, 2_000_000)]).await;
+        assert_eq!(
+            db::get(&mut dbtx, &db::LastEpochSettledKey).await,
+            Some(EpochId(0))
+        );
+
+        // votes left over from the previous epoch do not count towards this one
+        submit_epoch_ends(&pool, &mut dbtx, EpochId(1), &[(0, 2_000_000)]).await;
+        assert_eq!(
+            db::get(&mut dbtx, &db::LastEpochEndedKey).await,
+            Some(EpochId(0))
+        );
+
+        submit_epoch_ends(&pool, &mut dbtx, EpochId(1), &[(1, 2_000_000)]).await;
+        assert_eq!(
+            db::get(&mut dbtx, &db::LastEpochEndedKey).await,
+            Some(EpochId(0))
+        );
+
+        // the third vote settles the epoch at the median price
+        submit_epoch_ends(&pool, &mut dbtx, EpochId(1), &[(2, 2_100_000)]).await;
+        assert_eq!(
+            db::get(&mut dbtx, &db::LastEpochSettledKey).await,
+            Some(EpochId(1))
+        );
+        let outcome = db::get(&mut dbtx, &db::EpochOutcomeKey(EpochId(1)))
+            .await
+            .unwrap();
+        assert_eq!(outcome.settled_price, Some(2_000_000));
+    }
+
+    #[tokio::test]
+    async fn single_peer_federation_settles_on_its_own_votes() {
+        use crate::fixtures::Fixture;
+
+        test_dbtx!(dbtx);
+        let fixture = Fixture::new(1, [2_000_000, 1_600_000]);
+        assert_eq!(fixture.pool.epoch_config().price_threshold, 1);
+        assert!(fixture.pool.cfg.validate_config(&PeerId::from(0)).is_ok());
+
+        let accounts = fixture.fund_accounts(&mut dbtx, 2, 1_000_000).await;
+        let (seeker, provider) = (&accounts[0], &accounts[1]);
+        let lock = SeekerAction::Lock {
+            amount: fedimint_core::msats(100_000),
+        };
+        fixture.propose(&mut dbtx, seeker, lock).await;
+        let bid = stabilitypool::ProviderBid {
+            min_feerate: 0,
+            max_amount: fedimint_core::msats(1_000_000),
+        };
+        fixture.propose(&mut dbtx, provider, bid).await;
+
+        // the peer's own epoch end locks the positions, the next settles them
+        assert_eq!(fixture.advance_epoch(&mut dbtx).await, EpochId(0));
+        assert_eq!(
+            fixture.balance(&mut dbtx, seeker).await.locked,
+            LockedBalance::Seeker(fedimint_core::msats(100_000))
+        );
+        assert_eq!(fixture.advance_epoch(&mut dbtx).await, EpochId(1));
+        let outcome = db::get(&mut dbtx, &db::EpochOutcomeKey(EpochId(1)))
+            .await
+            .unwrap();
+        assert_eq!(outcome.settled_price, Some(1_600_000));
+
+        // the price fell by a fifth, so the seeker is paid a quarter more
+        let seeker = fixture.balance(&mut dbtx, seeker).await;
+        assert_eq!(
+            seeker.unlocked + seeker.locked.amount(),
+            fedimint_core::msats(1_025_000)
+        );
+        assert_eq!(total_balance(&mut dbtx).await, 2_000_000);
+    }
+
+    #[tokio::test]
+    async fn closed_pool_unlocks_and_refuses_deposits() {
+        use crate::fixtures::Fixture;
+
+        test_dbtx!(dbtx);
+        let mut fixture = Fixture::new(4, [2_000_000]);
+        let accounts = fixture.fund_accounts(&mut dbtx, 2, 1_000_000).await;
+        let (seeker, provider) = (&accounts[0], &accounts[1]);
+
+        let lock = SeekerAction::Lock {
+            amount: fedimint_core::msats(100_000),
+        };
+        fixture.propose(&mut dbtx, seeker, lock).await;
+        let bid = stabilitypool::ProviderBid {
+            min_feerate: 0,
+            max_amount: fedimint_core::msats(1_000_000),
+        };
+        fixture.propose(&mut dbtx, provider, bid).await;
+        fixture.advance_epoch(&mut dbtx).await;
+        assert_ne!(
+            fixture.balance(&mut dbtx, seeker).await.locked,
+            LockedBalance::None
+        );
+
+        // two votes are below the threshold, the third closes the pool
+        for peer in 0..3 {
+            assert!(!governance::is_closed(&mut dbtx).await);
+            let item = PoolConsensusItem::Governance(GovernanceAction::ClosePool);
+            fixture
+                .pool
+                .begin_consensus_epoch(&mut dbtx, vec![(PeerId::from(peer), item)])
+                .await;
+        }
+        assert!(governance::is_closed(&mut dbtx).await);
+
+        // the current epoch settles one last time and nothing is locked again
+        fixture.advance_epoch(&mut dbtx).await;
+        for account in [seeker, provider] {
+            assert_eq!(
+                fixture.balance(&mut dbtx, account).await.locked,
+                LockedBalance::None
+            );
+        }
+
+        let lock = SeekerAction::Lock {
+            amount: fedimint_core::msats(100_000),
+        };
+        fixture.propose(&mut dbtx, seeker, lock).await;
+        let account = seeker.x_only_public_key().0;
+        assert_eq!(
+            db::get(&mut dbtx, &db::ActionStagedKey(account)).await,
+            None
+        );
+
+        let deposit = PoolOutput {
+            account,
+            amount: fedimint_core::msats(1_000),
+            intent: None,
+        };
+        assert!(fixture
+            .pool
+            .validate_output(&mut dbtx, &deposit)
+            .await
+            .is_err());
+
+        // everyone can still withdraw everything they have
+        for account in [seeker, provider] {
+            let unlocked = fixture.balance(&mut dbtx, account).await.unlocked;
+            fixture
+                .withdraw(&mut dbtx, account, unlocked.msats)
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn unlocks_wait_for_min_lock_epochs() {
+        use crate::fixtures::Fixture;
+
+        test_dbtx!(dbtx);
+        let mut fixture = Fixture::new(4, [2_000_000]);
+        fixture.pool.cfg.consensus.epoch.min_lock_epochs = Some(2);
+        let accounts = fixture.fund_accounts(&mut dbtx, 2, 1_000_000).await;
+        let (seeker, provider) = (&accounts[0], &accounts[1]);
+
+        let lock = SeekerAction::Lock {
+            amount: fedimint_core::msats(100_000),
+        };
+        fixture.propose(&mut dbtx, seeker, lock).await;
+        let bid = stabilitypool::ProviderBid {
+            min_feerate: 0,
+            max_amount: fedimint_core::msats(1_000_000),
+        };
+        fixture.propose(&mut dbtx, provider, bid).await;
+        fixture.advance_epoch(&mut dbtx).await;
+        let account = seeker.x_only_public_key().0;
+        assert_eq!(
+            db::get(&mut dbtx, &db::SeekerLockedSinceKey(account)).await,
+            Some(EpochId(1))
+        );
+
+        let unlock = SeekerAction::Unlock {
+            amount: fedimint_core::msats(100_000),
+        };
+        // the position has only been locked for epoch 1 when it settles
+        fixture.propose(&mut dbtx, seeker, unlock.clone()).await;
+        fixture.advance_epoch(&mut dbtx).await;
+        assert_eq!(
+            fixture.balance(&mut dbtx, seeker).await.locked,
+            LockedBalance::Seeker(fedimint_core::msats(100_000))
+        );
+        assert_eq!(
+            db::get(&mut dbtx, &db::SeekerLockedSinceKey(account)).await,
+            Some(EpochId(1))
+        );
+
+        // after epoch 2 it has been locked long enough
+        fixture.propose(&mut dbtx, seeker, unlock).await;
+        fixture.advance_epoch(&mut dbtx).await;
+        assert_eq!(
+            fixture.balance(&mut dbtx, seeker).await.locked,
+            LockedBalance::None
+        );
+        assert_eq!(
+            db::get(&mut dbtx, &db::SeekerLockedSinceKey(account)).await,
+            None
+        );
+    }
+
+    #[test]
+    fn mismatched_consensus_configs_are_detected() {
+        let start = time::OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let params = PoolConfigGenParams {
+            start_epoch_at: Some(time::PrimitiveDateTime::new(start.date(), start.time())),
+            ..PoolConfigGenParams::default()
+        };
+        let other_ratio = PoolConfigGenParams {
+            collateral_ratio: CollateralRatio::new(1, 2).unwrap(),
+            ..params.clone()
+        };
+        // local parameters don't have to match
+        let other_local = PoolConfigGenParams {
+            max_actions_per_proposal: None,
+            ..params.clone()
+        };
+        assert_ne!(params.collateral_ratio, other_ratio.collateral_ratio);
+
+        let commitment = |params: &PoolConfigGenParams| {
+            let start = distributed_start(params).unwrap();
+            consensus_commitment(&params.consensus_config(3, 4, start))
+        };
+        let commitments = [
+            commitment(&params),
+            commitment(&other_local),
+            commitment(&other_ratio),
+            commitment(&params),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(peer, commitment)| (PeerId::from(peer as u16), commitment))
+        .collect::<BTreeMap<_, _>>();
+        let ours = commitment(&params);
+        assert_eq!(
+            peers_with_other_config(ours, &commitments),
+            vec![PeerId::from(2)]
+        );
+
+        let agreeing = commitments
+            .into_iter()
+            .filter(|(peer, _)| *peer != PeerId::from(2))
+            .collect();
+        assert!(peers_with_other_config(ours, &agreeing).is_empty());
+
+        // peers would each read their own clock, so there is no default start
+        assert!(distributed_start(&PoolConfigGenParams::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn dump_database_honors_prefix_names() {
+        use crate::fixtures::Fixture;
+
+        test_dbtx!(dbtx);
+        let mut fixture = Fixture::new(4, [2_000_000]);
+        let accounts = fixture.fund_accounts(&mut dbtx, 3, 1_000_000).await;
+        let lock = SeekerAction::Lock {
+            amount: fedimint_core::msats(100_000),
+        };
+        fixture.propose(&mut dbtx, &accounts[0], lock).await;
+
+        let gen = PoolConfigGenerator;
+        let dump = gen
+            .dump_database(&mut dbtx, vec!["account".to_string()])
+            .await
+            .map(|(table, entry)| (table, serde_json::to_value(&*entry).unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(dump.len(), accounts.len());
+        assert!(dump.iter().all(|(table, _)| table == "Account"));
+
+        // tables are chained one after the other, and the dump can be stopped
+        // early without boxing the rest
+        let mut dump = gen
+            .dump_database(
+                &mut dbtx,
+                vec!["actionstaged".to_string(), "account".to_string()],
+            )
+            .await
+            .map(|(table, _)| table);
+        let first = dump.by_ref().take(accounts.len()).collect::<Vec<_>>();
+        assert!(first.iter().all(|table| table == "Account"));
+        assert_eq!(dump.next().as_deref(), Some("ActionStaged"));
+        assert_eq!(dump.next(), None);
+
+        // every table when none is named
+        let tables = gen
+            .dump_database(&mut dbtx, vec![])
+            .await
+            .map(|(table, _)| table)
+            .collect::<HashSet<_>>();
+        assert!(tables.contains("Account"));
+        assert!(tables.contains("ActionStaged"));
+    }
+
+    #[tokio::test]
+    async fn snapshot_round_trip() {
+        use strum::IntoEnumIterator;
+
+        use crate::fixtures::Fixture;
+
+        // every prefix needs a field in `Snapshot`
+        assert_eq!(db::DbKeyPrefix::iter().count(), 28);
+
+        test_dbtx!(dbtx);
+        let mut fixture = Fixture::new(4, [2_000_000, 1_900_000]);
+        let accounts = fixture.fund_accounts(&mut dbtx, 2, 1_000_000).await;
+        let lock = SeekerAction::Lock {
+            amount: fedimint_core::msats(100_000),
+        };
+        fixture.propose(&mut dbtx, &accounts[0], lock).await;
+        let bid = stabilitypool::ProviderBid {
+            min_feerate: 0,
+            max_amount: fedimint_core::msats(1_000_000),
+        };
+        fixture.propose(&mut dbtx, &accounts[1], bid).await;
+        fixture.advance_epoch(&mut dbtx).await;
+        fixture.advance_epoch(&mut dbtx).await;
+        db::set(&mut dbtx, &db::TvlCapKey, &5_000_000).await;
+        db::set(&mut dbtx, &db::CollectedFeesKey, &10).await;
+        let frozen = random_account();
+        db::set(&mut dbtx, &db::FrozenAccountKey(frozen), &EpochId(2)).await;
+        db::set(&mut dbtx, &db::FeerateCapKey(frozen), &500).await;
+
+        let snapshot = StabilityPool::export_snapshot(&mut dbtx).await;
+        assert!(!snapshot.accounts.is_empty());
+        assert!(!snapshot.epoch_ends.is_empty());
+        assert!(!snapshot.balance_changes.is_empty());
+        assert!(!snapshot.peers_last_seen.is_empty());
+        assert!(!snapshot.provider_locked.is_empty());
+        assert!(!snapshot.account_epochs.is_empty());
+        assert!(!snapshot.epoch_fees.is_empty());
+        assert!(!snapshot.seeker_locked_since.is_empty());
+        assert!(!snapshot.signed_actions.is_empty());
+        assert!(snapshot.consensus_sessions.is_some());
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let snapshot = serde_json::from_str::<Snapshot>(&json).unwrap();
+
+        let restored_db = Database::new(MemDatabase::new(), Default::default());
+        let mut restored_dbtx = restored_db.begin_transaction().await;
+        let mut restored_dbtx = restored_dbtx.with_module_prefix(0);
+        // records not in the snapshot are removed
+        db::set(
+            &mut restored_dbtx,
+            &db::AccountBalanceKey(random_account()),
+            &AccountBalance::default(),
+        )
+        .await;
+        StabilityPool::import_snapshot(&mut restored_dbtx, snapshot.clone()).await;
+
+        assert_eq!(
+            StabilityPool::export_snapshot(&mut restored_dbtx).await,
+            snapshot
+        );
+    }
+}